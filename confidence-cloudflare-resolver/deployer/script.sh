@@ -10,6 +10,8 @@ CLOUDFLARE_ACCOUNT_ID=${CLOUDFLARE_ACCOUNT_ID:=}
 RESOLVE_TOKEN_ENCRYPTION_KEY=${RESOLVE_TOKEN_ENCRYPTION_KEY:=}
 CONFIDENCE_ACCOUNT_ID=${CONFIDENCE_ACCOUNT_ID:=}
 CONFIDENCE_RESOLVER_ALLOWED_ORIGIN=${CONFIDENCE_RESOLVER_ALLOWED_ORIGIN:=}
+CONFIDENCE_RESOLVER_ALLOWED_ORIGINS=${CONFIDENCE_RESOLVER_ALLOWED_ORIGINS:=}
+CONFIDENCE_RESOLVER_SETTINGS=${CONFIDENCE_RESOLVER_SETTINGS:=}
 CONFIDENCE_RESOLVER_STATE_URL=${CONFIDENCE_RESOLVER_STATE_URL:=}
 CONFIDENCE_RESOLVER_STATE_ETAG_URL=${CONFIDENCE_RESOLVER_STATE_ETAG_URL:=}
 CONFIDENCE_CLIENT_ID=${CONFIDENCE_CLIENT_ID:=}
@@ -103,6 +105,8 @@ mkdir -p data
 RESPONSE_FILE="data/resolver_state_current.pb"
 ETAG_TOML=""
 ALLOWED_ORIGIN_TOML=""
+ALLOWED_ORIGINS_TOML=""
+RESOLVER_SETTINGS_TOML=""
 VERSION_TOML=""
 CLIENT_ID_TOML=""
 CLIENT_SECRET_TOML=""
@@ -254,6 +258,16 @@ if [ -n "$CONFIDENCE_RESOLVER_ALLOWED_ORIGIN" ]; then
     ALLOWED_ORIGIN_TOML=$(printf '%s' "$CONFIDENCE_RESOLVER_ALLOWED_ORIGIN" | sed 's/\\/\\\\/g; s/\"/\\\"/g')
 fi
 
+# Prepare ALLOWED_ORIGINS for TOML, a JSON object of origins per client credential
+if [ -n "$CONFIDENCE_RESOLVER_ALLOWED_ORIGINS" ]; then
+    ALLOWED_ORIGINS_TOML=$(printf '%s' "$CONFIDENCE_RESOLVER_ALLOWED_ORIGINS" | sed 's/\\/\\\\/g; s/\"/\\\"/g')
+fi
+
+# Prepare RESOLVER_SETTINGS for TOML, a base64 encoded ResolverSettings proto
+if [ -n "$CONFIDENCE_RESOLVER_SETTINGS" ]; then
+    RESOLVER_SETTINGS_TOML=$(printf '%s' "$CONFIDENCE_RESOLVER_SETTINGS" | sed 's/\\/\\\\/g; s/\"/\\\"/g')
+fi
+
 # Prepare RESOLVER_VERSION for TOML
 if [ -n "$DEPLOYER_VERSION" ]; then
     VERSION_TOML=$(printf '%s' "$DEPLOYER_VERSION" | sed 's/\\/\\\\/g; s/\"/\\\"/g')
@@ -268,20 +282,25 @@ if [ -n "$CONFIDENCE_CLIENT_SECRET" ]; then
 fi
 
 # Update [vars] table with ALLOWED_ORIGIN, RESOLVER_STATE_ETAG and RESOLVER_VERSION, without duplicating the table
-if [ -n "$ALLOWED_ORIGIN_TOML" ] || [ -n "$ETAG_TOML" ] || [ -n "$DEPLOYER_VERSION" ] || [ -n "$CLIENT_ID_TOML" ] || [ -n "$CLIENT_SECRET_TOML" ]; then
+if [ -n "$ALLOWED_ORIGIN_TOML" ] || [ -n "$ALLOWED_ORIGINS_TOML" ] || [ -n "$RESOLVER_SETTINGS_TOML" ] || [ -n "$ETAG_TOML" ] || [ -n "$DEPLOYER_VERSION" ] || [ -n "$CLIENT_ID_TOML" ] || [ -n "$CLIENT_SECRET_TOML" ]; then
     # Remove any existing definitions to avoid duplicates
     sed -i.tmp '/^ALLOWED_ORIGIN *= *.*$/d' wrangler.toml || true
+    sed -i.tmp '/^ALLOWED_ORIGINS *= *.*$/d' wrangler.toml || true
+    sed -i.tmp '/^RESOLVER_SETTINGS *= *.*$/d' wrangler.toml || true
     sed -i.tmp '/^RESOLVER_STATE_ETAG *= *.*$/d' wrangler.toml || true
     sed -i.tmp '/^RESOLVER_VERSION *= *.*$/d' wrangler.toml || true
     sed -i.tmp '/^DEPLOYER_VERSION *= *.*$/d' wrangler.toml || true
     sed -i.tmp '/^CONFIDENCE_CLIENT_ID *= *.*$/d' wrangler.toml || true
     sed -i.tmp '/^CONFIDENCE_CLIENT_SECRET *= *.*$/d' wrangler.toml || true
-    awk -v allowed="${ALLOWED_ORIGIN_TOML}" -v etag="${ETAG_TOML}" -v version="${DEPLOYER_VERSION}" -v client_id="${CLIENT_ID_TOML}" -v client_secret="${CLIENT_SECRET_TOML}" '
-        BEGIN{inserted=0}
+    # ALLOWED_ORIGINS is passed through the environment, since awk -v would unescape its JSON quotes
+    ALLOWED_ORIGINS_TOML="${ALLOWED_ORIGINS_TOML}" awk -v allowed="${ALLOWED_ORIGIN_TOML}" -v settings="${RESOLVER_SETTINGS_TOML}" -v etag="${ETAG_TOML}" -v version="${DEPLOYER_VERSION}" -v client_id="${CLIENT_ID_TOML}" -v client_secret="${CLIENT_SECRET_TOML}" '
+        BEGIN{inserted=0; allowed_per_credential=ENVIRON["ALLOWED_ORIGINS_TOML"]}
         {
             print $0
             if (!inserted && $0 ~ /^\[vars\]/) {
                 if (allowed != "") print "ALLOWED_ORIGIN = \"" allowed "\""
+                if (allowed_per_credential != "") print "ALLOWED_ORIGINS = \"" allowed_per_credential "\""
+                if (settings != "") print "RESOLVER_SETTINGS = \"" settings "\""
                 if (etag != "") print "RESOLVER_STATE_ETAG = \"" etag "\""
                 if (version != "") print "DEPLOYER_VERSION = \"" version "\""
                 if (client_id != "") print "CONFIDENCE_CLIENT_ID = \"" client_id "\""
@@ -293,6 +312,12 @@ if [ -n "$ALLOWED_ORIGIN_TOML" ] || [ -n "$ETAG_TOML" ] || [ -n "$DEPLOYER_VERSI
     if [ -n "$ALLOWED_ORIGIN_TOML" ]; then
         echo "✅ ALLOWED_ORIGIN set to \"$CONFIDENCE_RESOLVER_ALLOWED_ORIGIN\" in wrangler.toml"
     fi
+    if [ -n "$ALLOWED_ORIGINS_TOML" ]; then
+        echo "✅ ALLOWED_ORIGINS set in wrangler.toml"
+    fi
+    if [ -n "$RESOLVER_SETTINGS_TOML" ]; then
+        echo "✅ RESOLVER_SETTINGS set in wrangler.toml"
+    fi
     if [ -n "$ETAG_TOML" ]; then
         echo "✅ RESOLVER_STATE_ETAG set to \"$ETAG_TOML\" in wrangler.toml"
     fi
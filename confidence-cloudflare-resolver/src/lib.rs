@@ -1,21 +1,28 @@
 use confidence_resolver::{
-    assign_logger::AssignLogger,
-    flag_logger,
-    proto::{confidence, google::Struct},
-    FlagToApply, Host, ResolvedValue, ResolverState,
+    api_json::ApiJson,
+    drift, flag_logger,
+    flag_logs::FlagLogs,
+    proto::{
+        confidence::{self, flags::admin::v1::ResolverState as ResolverStatePb},
+        google::Struct,
+        Message as _,
+    },
+    state_builder::ResolverStateBuilder,
+    EncryptionKey, FlagToApply, GetResolverError, Host, LoadOptions, ResolvedValue, ResolverConfig,
+    ResolverState,
 };
 use worker::*;
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use bytes::Bytes;
 use serde_json::from_slice;
 use serde_json::json;
 
-use confidence::flags::resolver::v1::{ApplyFlagsRequest, ApplyFlagsResponse, ResolveFlagsRequest};
+use confidence::flags::resolver::v1::{
+    ApplyFlagsRequest, ApplyFlagsResponse, ResolveFlagsRequest, ResolverSettings,
+};
 
-static RESOLVE_LOGGER: LazyLock<ResolveLogger<H>> = LazyLock::new(ResolveLogger::new);
-static ASSIGN_LOGGER: LazyLock<AssignLogger> = LazyLock::new(AssignLogger::new);
+static FLAG_LOGS: LazyLock<FlagLogs<H>> = LazyLock::new(FlagLogs::new);
 
 use confidence_resolver::Client;
 use once_cell::sync::Lazy;
@@ -26,20 +33,55 @@ const ENCRYPTION_KEY_BASE64: &str = include_str!("../../data/encryption_key");
 
 use confidence::flags::resolver::v1::Sdk;
 use confidence_resolver::proto::confidence::flags::resolver::v1::WriteFlagLogsRequest;
-use confidence_resolver::resolve_logger::ResolveLogger;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{LazyLock, OnceLock};
 
 static FLAGS_LOGS_QUEUE: OnceLock<Queue> = OnceLock::new();
 
+/// Cloudflare queues reject messages larger than 128 KB.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 128_000;
+/// Logs are checkpointed by their protobuf size, the JSON sent to the queue is usually at
+/// most this many times larger.
+const JSON_EXPANSION: usize = 2;
+/// Bounds the queue messages of a single flush, what is left is sent by the next one.
+const MAX_MESSAGES_PER_FLUSH: usize = 16;
+
+static MAX_MESSAGE_BYTES: OnceLock<usize> = OnceLock::new();
+// counted since the isolate started, logged when they change; dropped messages are those with
+// a single entry too large to send and those the queue failed to take
+static OVERSIZED_MESSAGES: AtomicU64 = AtomicU64::new(0);
+static DROPPED_MESSAGES: AtomicU64 = AtomicU64::new(0);
+
 static CONFIDENCE_CLIENT_ID: OnceLock<String> = OnceLock::new();
 static CONFIDENCE_CLIENT_SECRET: OnceLock<String> = OnceLock::new();
 
+// The origins allowed per client credential name, from the optional ALLOWED_ORIGINS env var, a
+// JSON object like {"clients/web/clientCredentials/app": ["https://app.example.com"]}.
+// Credentials that aren't listed are served with ALLOWED_ORIGIN.
+static ALLOWED_ORIGINS: OnceLock<HashMap<String, Vec<String>>> = OnceLock::new();
+
+// The config of the state, from the optional RESOLVER_SETTINGS env var, a base64 encoded
+// ResolverSettings proto. Set by every handler before the state is first used.
+static RESOLVER_CONFIG: OnceLock<ResolverConfig> = OnceLock::new();
+
 static RESOLVER_STATE: Lazy<ResolverState> = Lazy::new(|| {
-    ResolverState::from_proto(STATE_JSON.to_owned().try_into().unwrap(), ACCOUNT_ID).unwrap()
+    let state_pb = ResolverStatePb::decode(STATE_JSON).unwrap();
+    ResolverStateBuilder::new(ACCOUNT_ID)
+        .fingerprint(STATE_JSON)
+        .options(LoadOptions {
+            config: RESOLVER_CONFIG.get().cloned().unwrap_or_default(),
+            ..Default::default()
+        })
+        .build(state_pb)
+        .unwrap()
+        .0
 });
 
 trait ResponseExt {
-    fn with_cors_headers(self, allowed_origin: &str) -> Result<Self>
+    /// Leaves out `Access-Control-Allow-Origin` when `allowed_origin` is `None`, which makes
+    /// browsers reject the response.
+    fn with_cors_headers(self, allowed_origin: Option<&str>) -> Result<Self>
     where
         Self: Sized;
 }
@@ -47,6 +89,13 @@ trait ResponseExt {
 struct H {}
 
 impl Host for H {
+    fn get_encryption_key(_client_credential: &str) -> std::result::Result<EncryptionKey, String> {
+        let key = STANDARD
+            .decode(ENCRYPTION_KEY_BASE64)
+            .map_err(|e| format!("invalid encryption key: {}", e))?;
+        EncryptionKey::try_from(key.as_slice())
+    }
+
     fn log_resolve(
         resolve_id: &str,
         evaluation_context: &Struct,
@@ -54,7 +103,7 @@ impl Host for H {
         client: &Client,
         sdk: &Option<Sdk>,
     ) {
-        RESOLVE_LOGGER.log_resolve(
+        FLAG_LOGS.resolve_logger().log_resolve(
             resolve_id,
             evaluation_context,
             client.client_credential_name.as_str(),
@@ -71,7 +120,13 @@ impl Host for H {
         client: &Client,
         sdk: &Option<Sdk>,
     ) {
-        ASSIGN_LOGGER.log_assigns(resolve_id, evaluation_context, assigned_flags, client, sdk);
+        FLAG_LOGS.assign_logger().log_assigns(
+            resolve_id,
+            evaluation_context,
+            assigned_flags,
+            client,
+            sdk,
+        );
     }
 }
 
@@ -88,8 +143,93 @@ fn set_client_creds(env: &Env) {
     }
 }
 
-#[event(fetch)]
-pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+fn set_resolver_config(env: &Env) {
+    if RESOLVER_CONFIG.get().is_some() {
+        return;
+    }
+    let config = match env.var("RESOLVER_SETTINGS") {
+        Ok(var) => STANDARD
+            .decode(var.to_string())
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| ResolverSettings::decode(bytes.as_slice()).map_err(|e| e.to_string()))
+            .map(|settings| ResolverConfig::from(&settings))
+            .unwrap_or_else(|e| {
+                console_warn!("invalid RESOLVER_SETTINGS, the defaults are used: {}", e);
+                ResolverConfig::default()
+            }),
+        Err(_) => ResolverConfig::default(),
+    };
+    let _ = RESOLVER_CONFIG.set(config);
+}
+
+fn set_max_message_bytes(env: &Env) {
+    if MAX_MESSAGE_BYTES.get().is_some() {
+        return;
+    }
+    let max_bytes = env
+        .var("FLAG_LOGS_MAX_MESSAGE_BYTES")
+        .ok()
+        .and_then(|var| var.to_string().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES);
+    let _ = MAX_MESSAGE_BYTES.set(max_bytes);
+}
+
+fn set_allowed_origins(env: &Env) {
+    if ALLOWED_ORIGINS.get().is_some() {
+        return;
+    }
+    let origins = match env.var("ALLOWED_ORIGINS") {
+        Ok(var) => serde_json::from_str(&var.to_string()).unwrap_or_else(|e| {
+            console_warn!(
+                "invalid ALLOWED_ORIGINS, only ALLOWED_ORIGIN is used: {}",
+                e
+            );
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    };
+    let _ = ALLOWED_ORIGINS.set(origins);
+}
+
+/// The `Access-Control-Allow-Origin` of a request from `origin` with `client_secret`. A
+/// credential with an allowlist gets `origin` echoed back if it's listed and `None` otherwise,
+/// other requests get `fallback`.
+fn cors_origin(
+    state: &ResolverState,
+    client_secret: &str,
+    origin: Option<&str>,
+    fallback: &str,
+) -> Option<String> {
+    let allowlist = state
+        .secrets
+        .get(client_secret)
+        .and_then(|client| ALLOWED_ORIGINS.get()?.get(&client.client_credential_name));
+    match allowlist {
+        Some(allowlist) => origin
+            .filter(|origin| allowlist.iter().any(|allowed| allowed == origin))
+            .map(str::to_string),
+        None => Some(fallback.to_string()),
+    }
+}
+
+/// Preflights don't carry a client secret, so they allow `origin` if any credential does.
+fn preflight_cors_origin(origin: Option<&str>, fallback: &str) -> String {
+    let listed = |origin: &&str| {
+        ALLOWED_ORIGINS
+            .get()
+            .is_some_and(|origins| origins.values().flatten().any(|allowed| allowed == origin))
+    };
+    origin.filter(listed).unwrap_or(fallback).to_string()
+}
+
+fn origin_not_allowed() -> Result<Response> {
+    Response::error("Origin not allowed", 403)?.with_cors_headers(None)
+}
+
+fn set_flag_logs_queue(env: &Env) {
+    if FLAGS_LOGS_QUEUE.get().is_some() {
+        return;
+    }
     match env.queue("flag_logs_queue") {
         Ok(queue) => {
             let _ = FLAGS_LOGS_QUEUE.set(queue);
@@ -98,8 +238,16 @@ pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
             console_log!("flag_logs_queue binding is missing; logging disabled");
         }
     }
+}
+
+#[event(fetch)]
+pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    set_resolver_config(&env);
+    set_flag_logs_queue(&env);
+    set_max_message_bytes(&env);
 
     set_client_creds(&env);
+    set_allowed_origins(&env);
 
     let allowed_origin_env = env
         .var("ALLOWED_ORIGIN")
@@ -118,8 +266,11 @@ pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
         .map(|var| var.to_string())
         .unwrap_or_default();
 
+    let origin = req.headers().get("Origin")?;
+
     if req.method() == Method::Options {
-        return Response::ok("")?.with_cors_headers(&allowed_origin_env);
+        let allowed_origin = preflight_cors_origin(origin.as_deref(), &allowed_origin_env);
+        return Response::ok("")?.with_cors_headers(Some(&allowed_origin));
     }
 
     let state = &RESOLVER_STATE;
@@ -128,7 +279,7 @@ pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
     let response = router
         // GET endpoint to expose the current deployment state etag and resolver version
         .get_async("/v1/state:etag", |_req, _ctx| {
-            let allowed_origin = allowed_origin_env.clone();
+            let allowed_origin = Some(allowed_origin_env.clone());
             let etag_value = state_etag_env.clone();
             let version_value = resolver_version_env.clone();
             async move {
@@ -136,13 +287,15 @@ pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
                     "etag": etag_value,
                     "version": version_value,
                 });
-                Response::from_json(&body)?.with_cors_headers(&allowed_origin)
+                Response::from_json(&body)?.with_cors_headers(allowed_origin.as_deref())
             }
         })
         // Router treats ":name" as parameters, which is incompatible without URLs
         // so we use "*path" to match the whole path and do the matching in the handler
         .post_async("/v1/*path", |mut req, ctx| {
-            let allowed_origin = allowed_origin_env.clone();
+            let fallback = allowed_origin_env.clone();
+            let allowed_origin = Some(fallback.clone());
+            let origin = origin.clone();
             async move {
                 let path = ctx.param("path").unwrap();
                 match path.as_str() {
@@ -155,28 +308,51 @@ pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
                                     format!("Invalid request payload: {}", e),
                                     400,
                                 )?
-                                .with_cors_headers(&allowed_origin);
+                                .with_cors_headers(allowed_origin.as_deref());
                             }
                         };
+                        let allowed_origin = cors_origin(
+                            state,
+                            &resolver_request.client_secret,
+                            origin.as_deref(),
+                            &fallback,
+                        );
+                        if origin.is_some() && allowed_origin.is_none() {
+                            return origin_not_allowed();
+                        }
                         let evaluation_context = resolver_request
                             .evaluation_context
                             .clone()
                             .unwrap_or_default();
-                        match state.get_resolver::<H>(
+                        match state.get_resolver_with_host_key::<H>(
                             &resolver_request.client_secret,
                             evaluation_context,
-                            &Bytes::from(STANDARD.decode(ENCRYPTION_KEY_BASE64).unwrap()),
                         ) {
-                            Ok(resolver) => match resolver.resolve_flags(&resolver_request) {
-                                Ok(response) => Response::from_json(&response)?
-                                    .with_cors_headers(&allowed_origin),
-                                Err(msg) => {
-                                    Response::error(msg, 500)?.with_cors_headers(&allowed_origin)
+                            Ok(resolver) => {
+                                // flushes the resolve and assigns of an applied resolve together
+                                let _scope = FLAG_LOGS.log_scope();
+                                match resolver.resolve_flags(&resolver_request) {
+                                    Ok(response) => Response::from_json(&ApiJson(&response))?
+                                        .with_cors_headers(allowed_origin.as_deref()),
+                                    Err(msg) => Response::error(msg, 500)?
+                                        .with_cors_headers(allowed_origin.as_deref()),
+                                }
+                            }
+                            Err(GetResolverError::UnknownClientSecret) => {
+                                match H::resolve_unknown_secret(&resolver_request) {
+                                    Some(Ok(response)) => Response::from_json(&ApiJson(&response))?
+                                        .with_cors_headers(allowed_origin.as_deref()),
+                                    Some(Err(msg)) => Response::error(msg, 500)?
+                                        .with_cors_headers(allowed_origin.as_deref()),
+                                    None => Response::error(
+                                        String::from(GetResolverError::UnknownClientSecret),
+                                        404,
+                                    )?
+                                    .with_cors_headers(allowed_origin.as_deref()),
                                 }
-                            },
-                            Err(msg) => {
-                                Response::error(msg, 500)?.with_cors_headers(&allowed_origin)
                             }
+                            Err(e) => Response::error(String::from(e), 500)?
+                                .with_cors_headers(allowed_origin.as_deref()),
                         }
                     }
                     "flags:apply" => {
@@ -188,27 +364,38 @@ pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
                                     format!("Invalid request payload: {}", e),
                                     400,
                                 )?
-                                .with_cors_headers(&allowed_origin);
+                                .with_cors_headers(allowed_origin.as_deref());
                             }
                         };
+                        let allowed_origin = cors_origin(
+                            state,
+                            &apply_flag_req.client_secret,
+                            origin.as_deref(),
+                            &fallback,
+                        );
+                        if origin.is_some() && allowed_origin.is_none() {
+                            return origin_not_allowed();
+                        }
 
-                        match state.get_resolver::<H>(
+                        match state.get_resolver_with_host_key::<H>(
                             &apply_flag_req.client_secret,
                             Struct::default(),
-                            &Bytes::from(STANDARD.decode(ENCRYPTION_KEY_BASE64).unwrap()),
                         ) {
                             Ok(resolver) => match resolver.apply_flags(&apply_flag_req) {
                                 Ok(()) => Response::from_json(&ApplyFlagsResponse::default()),
-                                Err(msg) => {
-                                    Response::error(msg, 500)?.with_cors_headers(&allowed_origin)
-                                }
+                                Err(msg) => Response::error(msg, 500)?
+                                    .with_cors_headers(allowed_origin.as_deref()),
                             },
-                            Err(msg) => {
-                                Response::error(msg, 500)?.with_cors_headers(&allowed_origin)
+                            Err(e @ GetResolverError::UnknownClientSecret) => {
+                                Response::error(String::from(e), 404)?
+                                    .with_cors_headers(allowed_origin.as_deref())
                             }
+                            Err(e) => Response::error(String::from(e), 500)?
+                                .with_cors_headers(allowed_origin.as_deref()),
                         }
                     }
-                    _ => Response::error("Not found", 404)?.with_cors_headers(&allowed_origin),
+                    _ => Response::error("Not found", 404)?
+                        .with_cors_headers(allowed_origin.as_deref()),
                 }
             }
         })
@@ -216,17 +403,122 @@ pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
         .await;
 
     // Use ctx.waitUntil to run logging after response is returned
-    ctx.wait_until(async move {
-        let aggregated: confidence_resolver::proto::confidence::flags::resolver::v1::WriteFlagLogsRequest
-            = checkpoint();
-        if let Ok(converted) = serde_json::to_string(&aggregated) {
+    ctx.wait_until(flush_logs(false));
+
+    response
+}
+
+// Cron triggers flush the logs of isolates that see few requests, since logs are otherwise
+// only flushed after a request. The resolver state is compiled into the worker, so there is
+// no state to refresh here yet.
+#[event(scheduled)]
+pub async fn scheduled(_event: ScheduledEvent, env: Env, ctx: ScheduleContext) {
+    set_resolver_config(&env);
+    set_flag_logs_queue(&env);
+    set_max_message_bytes(&env);
+    ctx.wait_until(flush_logs(true));
+}
+
+async fn flush_logs(skip_empty: bool) {
+    let max_bytes = MAX_MESSAGE_BYTES
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES);
+    let oversized = OVERSIZED_MESSAGES.load(Ordering::Relaxed);
+    let dropped = DROPPED_MESSAGES.load(Ordering::Relaxed);
+    for i in 0..MAX_MESSAGES_PER_FLUSH {
+        let (req, more) = checkpoint(max_bytes / JSON_EXPANSION);
+        if is_empty(&req) && (skip_empty || i > 0) {
+            break;
+        }
+        for message in to_queue_messages(req, max_bytes) {
             if let Some(queue) = FLAGS_LOGS_QUEUE.get() {
-                let _ = queue.send(converted).await;
+                if let Err(e) = queue.send(message).await {
+                    DROPPED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+                    console_error!("failed to send flag logs to the queue: {}", e);
+                }
             }
         }
-    });
+        if !more {
+            break;
+        }
+    }
+    let total_oversized = OVERSIZED_MESSAGES.load(Ordering::Relaxed);
+    let total_dropped = DROPPED_MESSAGES.load(Ordering::Relaxed);
+    if total_oversized != oversized || total_dropped != dropped {
+        console_warn!(
+            "{}",
+            json!({
+                "flagLogsQueue": {
+                    "maxMessageBytes": max_bytes,
+                    "oversizedMessages": total_oversized,
+                    "droppedMessages": total_dropped,
+                }
+            })
+        );
+    }
+}
 
-    response
+/// Serializes `req` into queue messages of at most `max_bytes`, splitting it when it's too
+/// large. A single entry that doesn't fit is dropped.
+fn to_queue_messages(req: WriteFlagLogsRequest, max_bytes: usize) -> Vec<String> {
+    let Ok(json) = serde_json::to_string(&req) else {
+        return vec![];
+    };
+    if json.len() <= max_bytes {
+        return vec![json];
+    }
+    OVERSIZED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+    match split(req) {
+        Some((first, second)) => {
+            let mut messages = to_queue_messages(first, max_bytes);
+            messages.extend(to_queue_messages(second, max_bytes));
+            messages
+        }
+        None => {
+            DROPPED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+            vec![]
+        }
+    }
+}
+
+/// Splits off half of the largest kind of entries of `req`, `None` if it has a single entry.
+/// Exposures are split off from the other entries first.
+fn split(mut req: WriteFlagLogsRequest) -> Option<(WriteFlagLogsRequest, WriteFlagLogsRequest)> {
+    let mut rest = WriteFlagLogsRequest::default();
+    let assigned = req.flag_assigned.len();
+    let flags = req.flag_resolve_info.len();
+    let clients = req.client_resolve_info.len();
+    let exposed = req.flag_exposed.len();
+    if exposed > 1 && exposed >= assigned {
+        rest.flag_exposed = req.flag_exposed.split_off(exposed / 2);
+    } else if exposed > 0 && assigned + flags + clients > 0 {
+        rest.flag_exposed = std::mem::take(&mut req.flag_exposed);
+    } else if assigned > 1 && assigned >= flags && assigned >= clients {
+        rest.flag_assigned = req.flag_assigned.split_off(assigned / 2);
+    } else if flags > 1 && flags >= clients {
+        rest.flag_resolve_info = req.flag_resolve_info.split_off(flags / 2);
+    } else if clients > 1 {
+        rest.client_resolve_info = req.client_resolve_info.split_off(clients / 2);
+    } else if assigned + flags + clients > 1 {
+        // one entry of two or three kinds
+        if assigned == 1 {
+            rest.flag_assigned = std::mem::take(&mut req.flag_assigned);
+        } else {
+            rest.flag_resolve_info = std::mem::take(&mut req.flag_resolve_info);
+        }
+    } else {
+        return None;
+    }
+    Some((req, rest))
+}
+
+fn is_empty(req: &WriteFlagLogsRequest) -> bool {
+    req.flag_resolve_info.is_empty()
+        && req.flag_assigned.is_empty()
+        && req.client_resolve_info.is_empty()
+        && req.flag_exposed.is_empty()
+        && req.telemetry_data.is_none()
 }
 
 #[event(queue)]
@@ -235,6 +527,7 @@ pub async fn consume_flag_logs_queue(
     env: Env,
     _ctx: Context,
 ) -> Result<()> {
+    set_resolver_config(&env);
     set_client_creds(&env);
 
     if let Ok(messages) = message_batch.messages() {
@@ -247,6 +540,7 @@ pub async fn consume_flag_logs_queue(
                 flag_resolve_info: v.flag_resolve_info,
                 flag_assigned: v.flag_assigned,
                 client_resolve_info: v.client_resolve_info,
+                flag_exposed: v.flag_exposed,
             })
             .collect();
         let req = flag_logger::aggregate_batch(logs);
@@ -261,10 +555,13 @@ pub async fn consume_flag_logs_queue(
     Ok(())
 }
 
-fn checkpoint() -> WriteFlagLogsRequest {
-    let mut req = RESOLVE_LOGGER.checkpoint();
-    ASSIGN_LOGGER.checkpoint_fill(&mut req);
-    req
+/// Checkpoints at most `limit_bytes` of protobuf encoded logs, and whether there are more. The
+/// rules that drifted in them are reported in their telemetry if RESOLVER_SETTINGS turns that
+/// on.
+fn checkpoint(limit_bytes: usize) -> (WriteFlagLogsRequest, bool) {
+    let (mut req, more) = FLAG_LOGS.checkpoint_with_limit(limit_bytes);
+    drift::report_drift(&RESOLVER_STATE, &mut req);
+    (req, more)
 }
 
 fn get_token(client_id: &str, client_secret: &str) -> String {
@@ -296,13 +593,19 @@ async fn send_flags_logs(
 }
 
 impl ResponseExt for Response {
-    fn with_cors_headers(mut self, allowed_origin: &str) -> Result<Self>
+    fn with_cors_headers(mut self, allowed_origin: Option<&str>) -> Result<Self>
     where
         Self: Sized,
     {
         let headers = self.headers_mut();
 
-        headers.set("Access-Control-Allow-Origin", allowed_origin)?;
+        if let Some(allowed_origin) = allowed_origin {
+            headers.set("Access-Control-Allow-Origin", allowed_origin)?;
+        }
+        // caches must not serve a response echoing one origin to another
+        if allowed_origin != Some("*") {
+            headers.append("Vary", "Origin")?;
+        }
         headers.set("Access-Control-Allow-Methods", "POST, GET, OPTIONS")?;
         headers.set("Access-Control-Allow-Headers", "*")?;
 
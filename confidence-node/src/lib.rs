@@ -0,0 +1,251 @@
+//! A native Node.js module exposing the resolver, for server-side Node users who can load
+//! native addons. It offers the operations of the wasm guest without going through the
+//! wasm-msg ABI: requests and responses are the same protos, passed as `Buffer`s.
+//!
+//! ```js
+//! const resolver = require('@spotify-confidence/resolver-native');
+//! resolver.loadState(stateBytes, 'my-account');
+//! const response = ResolveFlagsResponse.decode(resolver.resolve(ResolveFlagsRequest.encode(request)));
+//! ```
+//!
+//! Like the wasm guest, resolve tokens are not encrypted; the module keeps a single state,
+//! shared by every resolve, and collects flag logs until they are taken with `flushLogs`.
+//! Applies with tokens that fail to decrypt in bulk are held by a decrypt circuit breaker
+//! rather than lost, and applied with `replayHeldApplies`.
+
+use std::sync::{Arc, LazyLock};
+
+use arc_swap::ArcSwapOption;
+use napi::bindgen_prelude::Buffer;
+use napi_derive::napi;
+use prost::Message;
+
+use confidence_resolver::decrypt_breaker::{DecryptBreaker, DecryptBreakerConfig};
+use confidence_resolver::drift;
+use confidence_resolver::flag_logs::FlagLogs;
+use confidence_resolver::proto::confidence::flags::admin::v1::ResolverState as ResolverStatePb;
+use confidence_resolver::proto::confidence::flags::resolver::v1::{
+    ApplyFlagsRequest, ResolveFlagsRequest, ResolveWithStickyRequest, ResolverSettings, Sdk,
+};
+use confidence_resolver::proto::google::Struct;
+use confidence_resolver::state_builder::ResolverStateBuilder;
+use confidence_resolver::{
+    compat, AccountResolver, Client, EncryptionKey, FlagToApply, Host, LoadOptions, ResolvedValue,
+    ResolverState, StateDuplicate,
+};
+
+const LOG_TARGET_BYTES: usize = 4 * 1024 * 1024; // 4 mb
+const ENCRYPTION_KEY: EncryptionKey = EncryptionKey::ZERO;
+
+static RESOLVER_STATE: ArcSwapOption<ResolverState> = ArcSwapOption::const_empty();
+static FLAG_LOGS: LazyLock<FlagLogs<NodeHost>> = LazyLock::new(FlagLogs::new);
+
+struct NodeHost;
+
+impl Host for NodeHost {
+    fn log(message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn log_resolve(
+        resolve_id: &str,
+        evaluation_context: &Struct,
+        values: &[ResolvedValue<'_>],
+        client: &Client,
+        sdk: &Option<Sdk>,
+    ) {
+        FLAG_LOGS.resolve_logger().log_resolve(
+            resolve_id,
+            evaluation_context,
+            &client.client_credential_name,
+            values,
+            client,
+            sdk,
+        );
+    }
+
+    fn log_assign(
+        resolve_id: &str,
+        evaluation_context: &Struct,
+        assigned_flags: &[FlagToApply],
+        client: &Client,
+        sdk: &Option<Sdk>,
+    ) {
+        FLAG_LOGS.assign_logger().log_assigns(
+            resolve_id,
+            evaluation_context,
+            assigned_flags,
+            client,
+            sdk,
+        );
+    }
+
+    fn encrypt_resolve_token(
+        token_data: &[u8],
+        _encryption_key: &EncryptionKey,
+    ) -> Result<Vec<u8>, String> {
+        Ok(token_data.to_vec())
+    }
+
+    fn decrypt_resolve_token(
+        token_data: &[u8],
+        _encryption_key: &EncryptionKey,
+    ) -> Result<Vec<u8>, String> {
+        Ok(token_data.to_vec())
+    }
+}
+
+fn error(message: impl Into<String>) -> napi::Error {
+    napi::Error::from_reason(message.into())
+}
+
+fn decode<M: Message + Default>(bytes: &[u8], what: &str) -> napi::Result<M> {
+    M::decode(bytes).map_err(|e| error(format!("Failed to decode {}: {}", what, e)))
+}
+
+fn get_resolver_state() -> napi::Result<Arc<ResolverState>> {
+    RESOLVER_STATE
+        .load_full()
+        .ok_or_else(|| error("Resolver state not set"))
+}
+
+fn resolver<'a>(
+    state: &'a ResolverState,
+    client_secret: &str,
+    evaluation_context: Option<Struct>,
+) -> napi::Result<AccountResolver<'a, NodeHost>> {
+    state
+        .get_resolver::<NodeHost>(
+            client_secret,
+            evaluation_context.unwrap_or_default(),
+            &ENCRYPTION_KEY,
+        )
+        .map_err(|e| error(String::from(e)))
+}
+
+/// Replaces the resolver state with an encoded `ResolverState` proto, sharing what is unchanged
+/// with the previous one. `settings` is an encoded `ResolverSettings` proto, the defaults are
+/// used without it.
+#[napi]
+pub fn load_state(state: Buffer, account_id: String, settings: Option<Buffer>) -> napi::Result<()> {
+    let state_pb: ResolverStatePb = decode(&state, "resolver state")?;
+    let settings: ResolverSettings = match &settings {
+        Some(settings) => decode(settings, "resolver settings")?,
+        None => ResolverSettings::default(),
+    };
+    for warning in compat::check(&state_pb).warnings() {
+        NodeHost::log(&warning);
+    }
+    let previous = RESOLVER_STATE.load_full();
+    let mut builder = ResolverStateBuilder::new(&account_id)
+        .fingerprint(&state)
+        .options(LoadOptions {
+            config: (&settings).into(),
+            ..Default::default()
+        });
+    if let Some(previous) = &previous {
+        builder = builder.reusing(previous);
+    }
+    let (mut state, report) = builder
+        .build(state_pb)
+        .map_err(|e| error(format!("Failed to load resolver state: {}", e)))?;
+    StateDuplicate::report::<NodeHost>(&report.duplicates);
+    // the builder carries the breaker of the previous state over
+    if state.decrypt_breaker.is_none() {
+        state.decrypt_breaker = Some(Arc::new(DecryptBreaker::new(DecryptBreakerConfig {
+            accept_applies: true,
+            ..Default::default()
+        })));
+    }
+    RESOLVER_STATE.store(Some(Arc::new(state)));
+    Ok(())
+}
+
+/// Resolves an encoded `ResolveFlagsRequest` to an encoded `ResolveFlagsResponse`.
+#[napi]
+pub fn resolve(request: Buffer) -> napi::Result<Buffer> {
+    let request: ResolveFlagsRequest = decode(&request, "resolve request")?;
+    let state = get_resolver_state()?;
+    let _scope = FLAG_LOGS.log_scope();
+    let response = resolver(
+        &state,
+        &request.client_secret,
+        request.evaluation_context.clone(),
+    )?
+    .resolve_flags(&request)
+    .map_err(error)?;
+    Ok(response.encode_to_vec().into())
+}
+
+/// Resolves an encoded `ResolveWithStickyRequest` to an encoded `ResolveWithStickyResponse`.
+#[napi]
+pub fn resolve_with_sticky(request: Buffer) -> napi::Result<Buffer> {
+    let request: ResolveWithStickyRequest = decode(&request, "resolve request")?;
+    let resolve_request = request
+        .resolve_request
+        .as_ref()
+        .ok_or_else(|| error("resolve_request is required"))?;
+    let state = get_resolver_state()?;
+    let _scope = FLAG_LOGS.log_scope();
+    let response = resolver(
+        &state,
+        &resolve_request.client_secret,
+        resolve_request.evaluation_context.clone(),
+    )?
+    .resolve_flags_sticky(&request)
+    .map_err(error)?;
+    Ok(response.encode_to_vec().into())
+}
+
+/// Applies the flags of an encoded `ApplyFlagsRequest`.
+#[napi]
+pub fn apply(request: Buffer) -> napi::Result<()> {
+    let request: ApplyFlagsRequest = decode(&request, "apply request")?;
+    let state = get_resolver_state()?;
+    resolver(&state, &request.client_secret, None)?
+        .apply_flags(&request)
+        .map_err(error)
+}
+
+/// Applies the applies held while resolve tokens failed to decrypt, returning how many of them
+/// still failed. Failed ones are logged and dropped.
+#[napi]
+pub fn replay_held_applies() -> napi::Result<u32> {
+    let state = get_resolver_state()?;
+    let Some(breaker) = &state.decrypt_breaker else {
+        return Ok(0);
+    };
+    let mut failed: u32 = 0;
+    for held in breaker.take_held_applies() {
+        let outcome = resolver(&state, &held.request.client_secret, None)
+            .map_err(|e| e.reason)
+            .and_then(|resolver| resolver.replay_held_apply(&held));
+        if let Err(e) = outcome {
+            NodeHost::log(&format!("Failed to replay a held apply: {}", e));
+            failed = failed.saturating_add(1);
+        }
+    }
+    Ok(failed)
+}
+
+#[napi(object)]
+pub struct FlushedLogs {
+    /// An encoded `WriteFlagLogsRequest`.
+    pub logs: Buffer,
+    /// Whether logs were left for another `flushLogs`.
+    pub more: bool,
+}
+
+/// Takes the flag logs collected since the last call, at most about 4 MB of them, with the
+/// rules that drifted in them reported in their telemetry if the settings turn that on.
+#[napi]
+pub fn flush_logs() -> FlushedLogs {
+    let (mut request, more) = FLAG_LOGS.checkpoint_with_limit(LOG_TARGET_BYTES);
+    if let Some(state) = RESOLVER_STATE.load_full() {
+        drift::report_drift(&state, &mut request);
+    }
+    FlushedLogs {
+        logs: request.encode_to_vec().into(),
+        more,
+    }
+}
@@ -0,0 +1,383 @@
+//! Resolves flags against a resolver state file and prints the result as JSON, to reproduce a
+//! resolve without writing code.
+//!
+//! ```text
+//! confidence-resolve --state resolver_state.pb --account my-account --secret <client secret> \
+//!     --context '{"targeting_key": "user-1"}' [--explain] [--watch] [--legacy-state] [flag ...]
+//! ```
+
+mod watch;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use confidence_resolver::proto::confidence::flags::admin::v1::ResolverState as ResolverStatePb;
+use confidence_resolver::proto::confidence::flags::resolver::v1::ResolveFlagsRequest;
+use confidence_resolver::proto::Message;
+use confidence_resolver::std_host::StdHost;
+use confidence_resolver::{compat, AccountResolver, EncryptionKey, ResolverState};
+use serde_json::{json, Value};
+use watch::{StateDiff, StateSource};
+
+const USAGE: &str = "\
+usage: confidence-resolve (--state <file> | --state-url <url>) --account <account>
+                          --secret <client secret> [--context <json>] [--explain]
+                          [--watch [--interval <seconds>]] [--legacy-state] [flag ...]
+
+Resolves the given flags, or all flags of the client, against a resolver state file.
+
+  --state <file>       resolver state, as exported by Confidence
+  --state-url <url>    fetch the resolver state from a URL instead
+  --account <account>  account the state belongs to, without the accounts/ prefix
+  --secret <secret>    client secret to resolve with
+  --context <json>     evaluation context, defaults to {}
+  --explain            also print the rules that matched and why rules were skipped
+  --watch              resolve again whenever the state changes, printing what changed
+  --interval <seconds> how often to check the state for changes, defaults to 1
+  --legacy-state       fill in the fields of a state exported by an older control plane";
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    source: StateSource,
+    account: String,
+    secret: String,
+    context: String,
+    explain: bool,
+    watch: bool,
+    interval: Duration,
+    legacy_state: bool,
+    flags: Vec<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut state = None;
+    let mut account = None;
+    let mut secret = None;
+    let mut context = None;
+    let mut explain = false;
+    let mut watch = false;
+    let mut interval = Duration::from_secs(1);
+    let mut legacy_state = false;
+    let mut flags = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--state" => state = Some(StateSource::File(PathBuf::from(value(&arg)?))),
+            "--state-url" => state = Some(StateSource::Url(value(&arg)?)),
+            "--account" => account = Some(value(&arg)?),
+            "--secret" => secret = Some(value(&arg)?),
+            "--context" => context = Some(value(&arg)?),
+            "--explain" => explain = true,
+            "--watch" => watch = true,
+            "--legacy-state" => legacy_state = true,
+            "--interval" => {
+                let seconds: f64 = value(&arg)?
+                    .parse()
+                    .map_err(|_| "--interval must be a number of seconds")?;
+                interval = Duration::try_from_secs_f64(seconds)
+                    .map_err(|_| "--interval must be a number of seconds")?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if arg.starts_with("flags/") => flags.push(arg),
+            _ => flags.push(format!("flags/{}", arg)),
+        }
+    }
+    Ok(Args {
+        source: state.ok_or("--state or --state-url is required")?,
+        account: account.ok_or("--account is required")?,
+        secret: secret.ok_or("--secret is required")?,
+        context: context.unwrap_or_else(|| "{}".to_string()),
+        explain,
+        watch,
+        interval,
+        legacy_state,
+        flags,
+    })
+}
+
+fn load_state(bytes: &[u8], args: &Args) -> Result<ResolverState, String> {
+    let mut state_pb = ResolverStatePb::decode(bytes)
+        .map_err(|e| format!("failed to decode resolver state: {}", e))?;
+    let report = if args.legacy_state {
+        compat::upgrade(&mut state_pb)
+    } else {
+        compat::check(&state_pb)
+    };
+    for warning in report.warnings() {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(ResolverState::from_proto(state_pb, &args.account)?)
+}
+
+fn run(args: &Args) -> Result<Value, String> {
+    let state = load_state(&args.source.read()?, args)?;
+    resolve(&state, args)
+}
+
+/// Resolves on every change of the state until interrupted. Failing to load or resolve a state
+/// is reported and retried on the next change, as the state may be saved while being edited.
+fn watch(args: &Args) -> ! {
+    let mut last: Option<(Vec<u8>, ResolverState)> = None;
+    let mut version = None;
+    loop {
+        let next_version = args.source.version();
+        if last.is_none() || next_version.is_none() || next_version != version {
+            version = next_version;
+            if let Err(e) = reload(args, &mut last) {
+                eprintln!("error: {}", e);
+            }
+        }
+        std::thread::sleep(args.interval);
+    }
+}
+
+fn reload(args: &Args, last: &mut Option<(Vec<u8>, ResolverState)>) -> Result<(), String> {
+    let bytes = args.source.read()?;
+    if last.as_ref().is_some_and(|(old, _)| *old == bytes) {
+        return Ok(());
+    }
+    let state = load_state(&bytes, args)?;
+    if let Some((_, old)) = last {
+        eprintln!("state changed:\n{}", StateDiff::new(old, &state));
+    }
+    let (_, state) = last.insert((bytes, state));
+    print(&resolve(state, args)?);
+    Ok(())
+}
+
+fn print(output: &Value) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(output).unwrap_or_default()
+    );
+}
+
+fn resolve(state: &ResolverState, args: &Args) -> Result<Value, String> {
+    let resolver: AccountResolver<'_, StdHost> =
+        state.get_resolver_with_json_context(&args.secret, &args.context, &EncryptionKey::ZERO)?;
+
+    let response = resolver.resolve_flags(&ResolveFlagsRequest {
+        flags: args.flags.clone(),
+        client_secret: args.secret.clone(),
+        apply: false,
+        ..Default::default()
+    })?;
+    let mut output = serde_json::to_value(&response).map_err(|e| e.to_string())?;
+    if args.explain {
+        let explanations = state
+            .client_flags(&resolver.client.client_name)
+            .filter(|flag| args.flags.is_empty() || args.flags.contains(&flag.name))
+            .map(|flag| explain(&resolver, flag))
+            .collect::<Result<Vec<_>, String>>()?;
+        if let Value::Object(fields) = &mut output {
+            fields.insert("explain".to_string(), Value::Array(explanations));
+        }
+    }
+    Ok(output)
+}
+
+/// How `flag` got its value: the rule that assigned it, the fallthrough rules passed on the
+/// way, and the rules that were skipped because of their configuration.
+fn explain(
+    resolver: &AccountResolver<'_, StdHost>,
+    flag: &confidence_resolver::proto::confidence::flags::admin::v1::Flag,
+) -> Result<Value, String> {
+    let result = resolver
+        .resolve_flag(flag, BTreeMap::new())
+        .map_err(String::from)?;
+    let value = &result.resolved_value;
+    let assignment = value.assignment_match.as_ref().map(|m| {
+        json!({
+            "rule": m.rule.name,
+            "segment": m.segment.name,
+            "assignmentId": m.assignment_id,
+            "targetingKey": m.targeting_key,
+            "variant": m.variant.map(|v| &v.name),
+        })
+    });
+    let fallthrough_rules: Vec<Value> = value
+        .fallthrough_rules
+        .iter()
+        .map(|f| {
+            json!({
+                "rule": f.rule.name,
+                "assignmentId": f.assignment_id,
+                "targetingKey": f.targeting_key,
+            })
+        })
+        .collect();
+    let skipped_rules: Vec<Value> = value
+        .skipped_rules
+        .iter()
+        .map(|f| {
+            json!({
+                "rule": f.rule.name,
+                "reason": format!("{:?}", f.reason),
+                "targetingKey": f.targeting_key,
+            })
+        })
+        .collect();
+    Ok(json!({
+        "flag": flag.name,
+        "reason": format!("{:?}", value.reason),
+        "match": assignment,
+        "killedVariant": value.killed_variant.map(|v| &v.name),
+        "fallthroughRules": fallthrough_rules,
+        "skippedRules": skipped_rules,
+        "warnings": serde_json::to_value(&result.warnings).map_err(|e| e.to_string())?,
+    }))
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.watch {
+        watch(&args);
+    }
+    match run(&args) {
+        Ok(output) => {
+            print(&output);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE: &str = "../confidence-resolver/test-payloads/resolver_state.pb";
+    const SECRET: &str = "mkjJruAATQWjeY7foFIWfVAcBWnci2YF";
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_args() {
+        let parsed = args(&[
+            "--state",
+            "state.pb",
+            "--account",
+            "acme",
+            "--secret",
+            "s",
+            "--explain",
+            "my-flag",
+            "flags/other",
+        ])
+        .unwrap();
+        assert_eq!(
+            parsed,
+            Args {
+                source: StateSource::File(PathBuf::from("state.pb")),
+                account: "acme".to_string(),
+                secret: "s".to_string(),
+                context: "{}".to_string(),
+                explain: true,
+                watch: false,
+                interval: Duration::from_secs(1),
+                legacy_state: false,
+                flags: vec!["flags/my-flag".to_string(), "flags/other".to_string()],
+            }
+        );
+        assert!(args(&["--state"]).unwrap_err().contains("needs a value"));
+        assert!(args(&["--state", "s.pb"])
+            .unwrap_err()
+            .contains("--account"));
+        assert!(args(&["--verbose"]).unwrap_err().contains("unknown option"));
+
+        let parsed = args(&[
+            "--state-url",
+            "https://example.com/state.pb",
+            "--account",
+            "acme",
+            "--secret",
+            "s",
+            "--watch",
+            "--interval",
+            "0.5",
+        ])
+        .unwrap();
+        assert_eq!(
+            parsed.source,
+            StateSource::Url("https://example.com/state.pb".to_string())
+        );
+        assert!(parsed.watch);
+        assert_eq!(parsed.interval, Duration::from_millis(500));
+        assert!(args(&["--interval", "-1"]).is_err());
+    }
+
+    #[test]
+    fn reloads_changed_states() {
+        let path = std::env::temp_dir().join(format!("state-{}.pb", std::process::id()));
+        std::fs::copy(STATE, &path).unwrap();
+        let args = Args {
+            source: StateSource::File(path.clone()),
+            ..args(&[
+                "--state",
+                STATE,
+                "--account",
+                "confidence-demo-june",
+                "--secret",
+                SECRET,
+            ])
+            .unwrap()
+        };
+        let mut last = None;
+        reload(&args, &mut last).unwrap();
+        let (bytes, _) = last.as_ref().unwrap();
+        assert_eq!(bytes, &std::fs::read(STATE).unwrap());
+
+        std::fs::write(&path, b"not a state").unwrap();
+        assert!(reload(&args, &mut last).is_err());
+        // the last good state is kept
+        assert!(last.is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resolves_and_explains() {
+        let output = run(&args(&[
+            "--state",
+            STATE,
+            "--account",
+            "confidence-demo-june",
+            "--secret",
+            SECRET,
+            "--context",
+            r#"{"visitor_id": "tutorial_visitor"}"#,
+            "--explain",
+            "tutorial-feature",
+        ])
+        .unwrap())
+        .unwrap();
+        assert_eq!(
+            output["resolvedFlags"][0]["variant"],
+            "flags/tutorial-feature/variants/exciting-welcome"
+        );
+        let explanation = &output["explain"][0];
+        assert_eq!(explanation["reason"], "Match");
+        assert_eq!(
+            explanation["match"]["rule"],
+            "flags/tutorial-feature/rules/tutorial-visitor-override"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Reloading of the resolver state for `--watch`, and a summary of what changed between two
+//! states.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use confidence_resolver::ResolverState;
+
+/// Where the resolver state is read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateSource {
+    File(PathBuf),
+    Url(String),
+}
+
+impl StateSource {
+    pub fn read(&self) -> Result<Vec<u8>, String> {
+        match self {
+            StateSource::File(path) => {
+                std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))
+            }
+            StateSource::Url(url) => {
+                let response = reqwest::blocking::get(url)
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("failed to fetch {}: {}", url, e))?;
+                let bytes = response
+                    .bytes()
+                    .map_err(|e| format!("failed to fetch {}: {}", url, e))?;
+                Ok(bytes.to_vec())
+            }
+        }
+    }
+
+    /// Modification time of a state file, to check for changes without reading it. URLs have
+    /// none; they are fetched on every check and compared by contents.
+    pub fn version(&self) -> Option<SystemTime> {
+        match self {
+            StateSource::File(path) => std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+            StateSource::Url(_) => None,
+        }
+    }
+}
+
+/// Names of the flags and segments that differ between two states.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    pub flags: NameDiff,
+    pub segments: NameDiff,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameDiff {
+    pub added: BTreeSet<String>,
+    pub removed: BTreeSet<String>,
+    pub changed: BTreeSet<String>,
+}
+
+impl StateDiff {
+    pub fn new(from: &ResolverState, to: &ResolverState) -> StateDiff {
+        StateDiff {
+            flags: NameDiff::new(&from.flags, &to.flags),
+            segments: NameDiff::new(&from.segments, &to.segments),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty() && self.segments.is_empty()
+    }
+}
+
+impl NameDiff {
+    fn new<V: PartialEq>(from: &HashMap<String, V>, to: &HashMap<String, V>) -> NameDiff {
+        let mut diff = NameDiff::default();
+        for (name, value) in to {
+            match from.get(name) {
+                None => diff.added.insert(name.clone()),
+                Some(old) if old != value => diff.changed.insert(name.clone()),
+                Some(_) => false,
+            };
+        }
+        for name in from.keys().filter(|name| !to.contains_key(*name)) {
+            diff.removed.insert(name.clone());
+        }
+        diff
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no flag or segment changes");
+        }
+        let mut lines = vec![];
+        for (kind, diff) in [("flag", &self.flags), ("segment", &self.segments)] {
+            for (change, names) in [
+                ("added", &diff.added),
+                ("removed", &diff.removed),
+                ("changed", &diff.changed),
+            ] {
+                for name in names {
+                    lines.push(format!("{} {} {}", kind, change, name));
+                }
+            }
+        }
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_names() {
+        let from: HashMap<String, i32> = [("a", 1), ("b", 2), ("c", 3)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let to: HashMap<String, i32> = [("a", 1), ("b", 4), ("d", 5)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let diff = NameDiff::new(&from, &to);
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        assert_eq!(diff.added, names(&["d"]));
+        assert_eq!(diff.removed, names(&["c"]));
+        assert_eq!(diff.changed, names(&["b"]));
+
+        let state = StateDiff {
+            flags: diff,
+            segments: NameDiff::default(),
+        };
+        assert_eq!(
+            state.to_string(),
+            "flag added d\nflag removed c\nflag changed b"
+        );
+        assert_eq!(
+            StateDiff::default().to_string(),
+            "no flag or segment changes"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! The JSON of a `ResolveFlagsResponse` as the hosted resolver's REST API writes it, so that
+//! edge deployments such as the Cloudflare worker answer with the same bytes. Available with
+//! the `json` feature.
+//!
+//! The generated serde implementation already follows the protobuf JSON mapping, with
+//! camelCase fields, enum names and a base64 resolve token, but leaves out fields at their
+//! default value. The hosted resolver writes every field, in proto order, with `null` for a
+//! flag that resolved to no value, which [`ApiJson`] does too.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::proto::confidence::flags::resolver::v1::{
+    ResolveFlagsResponse, ResolveReason, ResolvedFlag,
+};
+
+/// Serializes the wrapped message like the hosted resolver, see the [module docs](self).
+pub struct ApiJson<'a, T>(pub &'a T);
+
+impl ResolveFlagsResponse {
+    pub fn to_api_json(&self) -> Result<String, String> {
+        serde_json::to_string(&ApiJson(self)).map_err(|e| e.to_string())
+    }
+}
+
+impl Serialize for ApiJson<'_, ResolveFlagsResponse> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let response = self.0;
+        let resolved_flags: Vec<_> = response.resolved_flags.iter().map(ApiJson).collect();
+        let mut json = serializer.serialize_struct("ResolveFlagsResponse", 3)?;
+        json.serialize_field("resolvedFlags", &resolved_flags)?;
+        json.serialize_field("resolveToken", &STANDARD.encode(&response.resolve_token))?;
+        json.serialize_field("resolveId", &response.resolve_id)?;
+        json.end()
+    }
+}
+
+impl Serialize for ApiJson<'_, ResolvedFlag> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let flag = self.0;
+        let mut json = serializer.serialize_struct("ResolvedFlag", 6)?;
+        json.serialize_field("flag", &flag.flag)?;
+        json.serialize_field("variant", &flag.variant)?;
+        json.serialize_field("value", &flag.value)?;
+        json.serialize_field("flagSchema", &flag.flag_schema)?;
+        // like the protobuf JSON mapping, unknown enum values are written as numbers
+        match ResolveReason::try_from(flag.reason) {
+            Ok(reason) => json.serialize_field("reason", &reason)?,
+            Err(_) => json.serialize_field("reason", &flag.reason)?,
+        }
+        json.serialize_field("shouldApply", &flag.should_apply)?;
+        json.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::types::v1::flag_schema::{SchemaType, StructFlagSchema};
+    use crate::proto::confidence::flags::types::v1::FlagSchema;
+    use crate::proto::google::{value::Kind, Struct, Value};
+    use std::collections::BTreeMap;
+
+    fn response() -> ResolveFlagsResponse {
+        ResolveFlagsResponse {
+            resolved_flags: vec![
+                ResolvedFlag {
+                    flag: "flags/tutorial-feature".to_string(),
+                    variant: "flags/tutorial-feature/variants/exciting-welcome".to_string(),
+                    value: Some(Struct {
+                        fields: BTreeMap::from([(
+                            "title".to_string(),
+                            Value {
+                                kind: Some(Kind::StringValue("Welcome to Confidence!".to_string())),
+                            },
+                        )]),
+                    }),
+                    flag_schema: Some(StructFlagSchema {
+                        schema: BTreeMap::from([(
+                            "title".to_string(),
+                            FlagSchema {
+                                schema_type: Some(SchemaType::StringSchema(Default::default())),
+                            },
+                        )]),
+                    }),
+                    reason: ResolveReason::Match as i32,
+                    should_apply: true,
+                },
+                ResolvedFlag {
+                    flag: "flags/no-match".to_string(),
+                    reason: ResolveReason::NoSegmentMatch as i32,
+                    ..Default::default()
+                },
+            ],
+            resolve_token: vec![1, 2, 3, 4],
+            resolve_id: "resolve-id".to_string(),
+        }
+    }
+
+    fn position(json: &str, needle: &str) -> usize {
+        json.find(needle)
+            .unwrap_or_else(|| panic!("{needle} not in {json}"))
+    }
+
+    #[test]
+    fn writes_every_field_in_proto_order() {
+        let json = response().to_api_json().unwrap();
+        let fields = ["\"resolvedFlags\":", "\"resolveToken\":", "\"resolveId\":"];
+        let positions: Vec<_> = fields.iter().map(|f| position(&json, f)).collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "{json}");
+
+        let no_match = &json[position(&json, "\"flag\":\"flags/no-match\"")..];
+        let fields = [
+            "\"flag\":",
+            "\"variant\":\"\"",
+            "\"value\":null",
+            "\"flagSchema\":null",
+            "\"reason\":\"RESOLVE_REASON_NO_SEGMENT_MATCH\"",
+            "\"shouldApply\":false",
+        ];
+        let positions: Vec<_> = fields.iter().map(|f| position(no_match, f)).collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "{json}");
+    }
+
+    #[test]
+    fn writes_the_resolve_token_as_padded_standard_base64() {
+        let json = response().to_api_json().unwrap();
+        position(&json, "\"resolveToken\":\"AQIDBA==\"");
+    }
+
+    #[test]
+    fn writes_unknown_reasons_as_numbers() {
+        let mut response = response();
+        response.resolved_flags[1].reason = 99;
+        let json = response.to_api_json().unwrap();
+        position(&json, "\"reason\":99");
+    }
+
+    #[test]
+    fn parses_back_to_the_response() {
+        let json = response().to_api_json().unwrap();
+        let parsed: ResolveFlagsResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, response());
+    }
+}
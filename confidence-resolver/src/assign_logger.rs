@@ -1,6 +1,9 @@
-use std::collections::VecDeque;
-use std::sync::Mutex;
+use core::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
 
+use crate::flag_logger::{self, LogBacklog};
 use crate::proto::confidence::flags::resolver::v1::WriteFlagLogsRequest;
 use crate::FlagToApply;
 use prost::{length_delimiter_len, Message};
@@ -10,9 +13,11 @@ mod pb {
         flag_assigned::{
             self, applied_flag::Assignment, AppliedFlag, AssignmentInfo, DefaultAssignment,
         },
-        ClientInfo,
+        ClientInfo, FlagExposed,
+    };
+    pub use crate::proto::confidence::flags::resolver::v1::{
+        events::FlagAssigned, telemetry_data::ApplySkew, ResolveReason, TelemetryData,
     };
-    pub use crate::proto::confidence::flags::resolver::v1::{events::FlagAssigned, ResolveReason};
     pub use flag_assigned::default_assignment::DefaultAssignmentReason;
 }
 
@@ -22,10 +27,140 @@ struct State {
     pending_bytes: usize,
 }
 
+/// Inclusive upper bounds in milliseconds of the absolute clock skew buckets reported in
+/// [`pb::ApplySkew`].
+pub const SKEW_BUCKET_BOUNDS_MILLIS: [i64; 7] =
+    [100, 1_000, 10_000, 60_000, 600_000, 3_600_000, 86_400_000];
+
+fn record_skew(stats: &mut pb::ApplySkew, skew_millis: i64) {
+    if stats.count == 0 {
+        stats.min_millis = skew_millis;
+        stats.max_millis = skew_millis;
+        stats.bucket_bounds_millis = SKEW_BUCKET_BOUNDS_MILLIS.to_vec();
+        stats.bucket_counts = vec![0; SKEW_BUCKET_BOUNDS_MILLIS.len().saturating_add(1)];
+    }
+    stats.count = stats.count.saturating_add(1);
+    stats.min_millis = stats.min_millis.min(skew_millis);
+    stats.max_millis = stats.max_millis.max(skew_millis);
+    let bucket = SKEW_BUCKET_BOUNDS_MILLIS
+        .iter()
+        .take_while(|bound| skew_millis.unsigned_abs() > bound.unsigned_abs())
+        .count();
+    if let Some(count) = stats.bucket_counts.get_mut(bucket) {
+        *count = count.saturating_add(1);
+    }
+}
+
+/// Combines skew statistics of the same SDK, e.g. from several checkpoints.
+pub fn merge_skew(into: &mut pb::ApplySkew, other: &pb::ApplySkew) {
+    if other.count == 0 {
+        return;
+    }
+    if into.count == 0 {
+        *into = other.clone();
+        return;
+    }
+    into.count = into.count.saturating_add(other.count);
+    into.min_millis = into.min_millis.min(other.min_millis);
+    into.max_millis = into.max_millis.max(other.max_millis);
+    for (count, other) in into.bucket_counts.iter_mut().zip(&other.bucket_counts) {
+        *count = count.saturating_add(*other);
+    }
+}
+
+/// Upper bound on the (resolve id, flag) pairs remembered for deduplication, the oldest are
+/// forgotten first when it's reached.
+const MAX_DEDUPE_ENTRIES: usize = 100_000;
+
+/// Remembers which flags of which resolves were applied recently. Time is taken from the
+/// applied events themselves and only moves forward.
+#[derive(Debug)]
+struct Dedupe {
+    window_seconds: i64,
+    now: i64,
+    seen: HashMap<(String, String), i64>,
+    order: VecDeque<(i64, (String, String))>,
+}
+
+impl Dedupe {
+    fn new(window: Duration) -> Self {
+        Dedupe {
+            window_seconds: i64::try_from(window.as_secs()).unwrap_or(i64::MAX),
+            now: i64::MIN,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records the flag as applied at `time`, returning false if it already was within the
+    /// window.
+    fn first_apply(&mut self, resolve_id: &str, flag: &str, time: i64) -> bool {
+        self.now = self.now.max(time);
+        let expired_before = self.now.saturating_sub(self.window_seconds);
+        while let Some((time, _)) = self.order.front() {
+            if *time >= expired_before && self.order.len() < MAX_DEDUPE_ENTRIES {
+                break;
+            }
+            if let Some((time, key)) = self.order.pop_front() {
+                // only forget the key if it wasn't seen again later
+                if self.seen.get(&key) == Some(&time) {
+                    self.seen.remove(&key);
+                }
+            }
+        }
+        let key = (resolve_id.to_string(), flag.to_string());
+        if self.seen.contains_key(&key) {
+            return false;
+        }
+        self.seen.insert(key.clone(), self.now);
+        self.order.push_back((self.now, key));
+        true
+    }
+}
+
+/// What an [`AssignLogger`] that holds its maximum number of pending events does with the
+/// next one, see [`AssignLogger::with_max_pending`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drops the oldest pending event to make room for the new one.
+    DropOldest,
+    /// Drops the new event.
+    DropNewest,
+    /// Waits until a checkpoint makes room. Only for hosts that checkpoint on another thread
+    /// than they log on, otherwise logging never returns.
+    Block,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MaxPending {
+    events: usize,
+    policy: DropPolicy,
+}
+
 #[derive(Debug, Default)]
 pub struct AssignLogger {
-    assigned: crossbeam_queue::SegQueue<pb::FlagAssigned>,
+    // events with their encoded length
+    assigned: crossbeam_queue::SegQueue<(pb::FlagAssigned, usize)>,
+    assigned_bytes: AtomicUsize,
     state: Mutex<State>,
+    dedupe: Option<Mutex<Dedupe>>,
+    // clock skew statistics keyed by the encoded SDK
+    skew: Mutex<HashMap<Vec<u8>, pb::ApplySkew>>,
+    max_pending: Option<MaxPending>,
+    // events dropped since the last checkpoint
+    dropped: AtomicI64,
+    // notified by checkpoints for loggers blocking on max_pending
+    space: Condvar,
+    // exposure events with their encoded length, if enabled
+    exposures: Option<Mutex<VecDeque<(pb::FlagExposed, usize)>>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(g) => g,
+        // lock errors if another holder panics, still we acquire the lock
+        Err(err) => err.into_inner(),
+    }
 }
 
 impl AssignLogger {
@@ -35,25 +170,85 @@ impl AssignLogger {
         }
     }
 
+    /// A logger that drops repeated applies of the same flag for the same resolve within
+    /// `window`, for hosts whose clients may apply a resolve token more than once.
+    pub fn with_dedupe_window(window: Duration) -> Self {
+        Self {
+            dedupe: Some(Mutex::new(Dedupe::new(window))),
+            ..Default::default()
+        }
+    }
+
+    /// Limits the events this logger holds between checkpoints, assigns and exposures alike,
+    /// to `max_events`, handling further events according to `policy`. The events of one apply
+    /// are kept or dropped together; when they alone exceed the limit they are still taken
+    /// into an empty logger. Dropped events are counted in the `dropped_assign_events`
+    /// telemetry of the next checkpoint.
+    pub fn with_max_pending(self, max_events: usize, policy: DropPolicy) -> Self {
+        Self {
+            max_pending: Some(MaxPending {
+                events: max_events.max(1),
+                policy,
+            }),
+            ..self
+        }
+    }
+
+    /// Also logs a `FlagExposed` event for every applied flag that assigned a unit a variant,
+    /// for experimentation pipelines that expect exposures rather than applies. Killed flags
+    /// and assignments without a targeting key expose no unit and are left out. Exposures are
+    /// checkpointed into `flag_exposed`, after the assigns, and count toward
+    /// [`AssignLogger::with_max_pending`].
+    pub fn with_exposures(self) -> Self {
+        Self {
+            exposures: Some(Mutex::new(VecDeque::new())),
+            ..self
+        }
+    }
+
     pub fn log_assigns(
         &self,
         resolve_id: &str,
-        _evaluation_context: &crate::proto::google::Struct,
+        evaluation_context: &crate::proto::google::Struct,
         assigned_flags: &[FlagToApply],
         client: &crate::Client,
         sdk: &Option<crate::flags_resolver::Sdk>,
     ) {
+        if assigned_flags.iter().any(|f| f.clock_skew_millis.is_some()) {
+            let mut skew = lock(&self.skew);
+            let stats = skew
+                .entry(sdk.as_ref().map(Message::encode_to_vec).unwrap_or_default())
+                .or_insert_with(|| pb::ApplySkew {
+                    sdk: sdk.clone(),
+                    ..Default::default()
+                });
+            for skew_millis in assigned_flags.iter().filter_map(|f| f.clock_skew_millis) {
+                record_skew(stats, skew_millis);
+            }
+        }
+        let mut dedupe = self.dedupe.as_ref().map(lock);
         let client_info = Some(pb::ClientInfo {
             client: client.client_name.to_string(),
             client_credential: client.client_credential_name.to_string(),
             sdk: sdk.clone(),
+            synthetic: flag_logger::is_synthetic(evaluation_context),
         });
-        let flags = assigned_flags
+        let flags: Vec<pb::AppliedFlag> = assigned_flags
             .iter()
+            .filter(|f| match &mut dedupe {
+                Some(dedupe) => dedupe.first_apply(
+                    resolve_id,
+                    &f.assigned_flag.flag,
+                    f.skew_adjusted_applied_time.seconds,
+                ),
+                None => true,
+            })
             .map(
                 |FlagToApply {
                      assigned_flag: f,
                      skew_adjusted_applied_time,
+                     state_fingerprint,
+                     ..
                  }| {
                     let assignment = if !f.variant.is_empty() {
                         let assignment_info = pb::AssignmentInfo {
@@ -73,6 +268,9 @@ impl AssignLogger {
                                 Ok(pb::ResolveReason::FlagArchived) => {
                                     pb::DefaultAssignmentReason::FlagArchived
                                 }
+                                Ok(pb::ResolveReason::FlagKilled) => {
+                                    pb::DefaultAssignmentReason::FlagKilled
+                                }
                                 _ => pb::DefaultAssignmentReason::Unspecified,
                             };
                         Some(pb::Assignment::DefaultAssignment(pb::DefaultAssignment {
@@ -86,18 +284,125 @@ impl AssignLogger {
                         assignment_id: f.assignment_id.clone(),
                         rule: f.rule.clone(),
                         fallthrough_assignments: f.fallthrough_assignments.clone(),
+                        skipped_rules: f.skipped_rules.clone(),
                         apply_time: Some(skew_adjusted_applied_time.clone()),
                         assignment,
+                        state_fingerprint: state_fingerprint.clone(),
                     }
                 },
             )
             .collect();
+        drop(dedupe);
+        if flags.is_empty() && !assigned_flags.is_empty() {
+            // every flag was a duplicate
+            return;
+        }
 
-        self.assigned.push(pb::FlagAssigned {
-            resolve_id: resolve_id.to_string(),
-            client_info,
-            flags,
-        });
+        let exposed: Vec<_> = match &self.exposures {
+            Some(_) => flags
+                .iter()
+                .filter_map(|flag| exposure(resolve_id, &client_info, flag))
+                .map(|exposed| {
+                    let len = AssignLogger::encoded_len(&exposed);
+                    (exposed, len)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        self.enqueue(
+            pb::FlagAssigned {
+                resolve_id: resolve_id.to_string(),
+                client_info,
+                flags,
+            },
+            exposed,
+        );
+    }
+
+    fn enqueue(&self, assigned: pb::FlagAssigned, exposed: Vec<(pb::FlagExposed, usize)>) {
+        let len = AssignLogger::encoded_len(&assigned);
+        let Some(max) = self.max_pending else {
+            self.push(assigned, len, exposed);
+            return;
+        };
+        let events = exposed.len().saturating_add(1);
+        // push while holding the lock so concurrent loggers can't exceed the limit
+        let mut state = lock(&self.state);
+        loop {
+            let held = self.held_events(&state);
+            if held == 0 || held.saturating_add(events) <= max.events {
+                break;
+            }
+            match max.policy {
+                DropPolicy::DropNewest => {
+                    self.dropped.fetch_add(events as i64, Ordering::Relaxed);
+                    return;
+                }
+                DropPolicy::DropOldest => {
+                    // pending events were taken from the queue, so they are the oldest
+                    if let Some((_, len)) = state.pending.pop_front() {
+                        state.pending_bytes = state.pending_bytes.saturating_sub(len);
+                    } else if let Some((_, len)) = self.assigned.pop() {
+                        self.assigned_bytes.fetch_sub(len, Ordering::Relaxed);
+                    } else if let Some(exposures) = &self.exposures {
+                        lock(exposures).pop_front();
+                    }
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                DropPolicy::Block => {
+                    state = self
+                        .space
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+            }
+        }
+        self.push(assigned, len, exposed);
+    }
+
+    fn push(&self, assigned: pb::FlagAssigned, len: usize, exposed: Vec<(pb::FlagExposed, usize)>) {
+        if let (Some(exposures), false) = (&self.exposures, exposed.is_empty()) {
+            lock(exposures).extend(exposed);
+        }
+        self.assigned_bytes.fetch_add(len, Ordering::Relaxed);
+        self.assigned.push((assigned, len));
+    }
+
+    /// The assigns and exposures held for the next checkpoint, `state` being locked.
+    fn held_events(&self, state: &State) -> usize {
+        let exposures = self.exposures.as_ref().map_or(0, |e| lock(e).len());
+        self.assigned
+            .len()
+            .saturating_add(state.pending.len())
+            .saturating_add(exposures)
+    }
+
+    /// The events waiting for the next checkpoint.
+    pub fn backlog(&self) -> LogBacklog {
+        let state = lock(&self.state);
+        let (exposures, exposure_bytes) = match &self.exposures {
+            Some(exposures) => {
+                let exposures = lock(exposures);
+                let bytes = exposures
+                    .iter()
+                    .fold(0usize, |sum, (_, len)| sum.saturating_add(*len));
+                (exposures.len(), bytes)
+            }
+            None => (0, 0),
+        };
+        LogBacklog {
+            events: self
+                .assigned
+                .len()
+                .saturating_add(state.pending.len())
+                .saturating_add(exposures),
+            bytes: self
+                .assigned_bytes
+                .load(Ordering::Relaxed)
+                .saturating_add(state.pending_bytes)
+                .saturating_add(exposure_bytes),
+        }
     }
 
     pub fn checkpoint(&self) -> WriteFlagLogsRequest {
@@ -118,22 +423,27 @@ impl AssignLogger {
         self.checkpoint_fill_with_limit(&mut req, limit_bytes, require_full);
         req
     }
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            name = "assign_logger.checkpoint",
+            fields(limit_bytes, written = tracing::field::Empty)
+        )
+    )]
     pub fn checkpoint_fill_with_limit(
         &self,
         req: &mut WriteFlagLogsRequest,
         limit_bytes: usize,
         require_full: bool,
     ) -> usize {
-        let mut state = match self.state.lock() {
-            Ok(g) => g,
-            // lock errors if another holder panics, still we acquire the lock
-            Err(err) => err.into_inner(),
-        };
+        let mut state = lock(&self.state);
         let start = req.encoded_len();
         let limit_bytes = limit_bytes.saturating_sub(start);
         while state.pending_bytes < limit_bytes {
-            if let Some(assigned) = self.assigned.pop() {
-                let len = AssignLogger::encoded_len(&assigned);
+            if let Some((assigned, len)) = self.assigned.pop() {
+                self.assigned_bytes.fetch_sub(len, Ordering::Relaxed);
                 state.pending.push_back((assigned, len));
                 state.pending_bytes = state.pending_bytes.saturating_add(len);
             } else {
@@ -153,18 +463,76 @@ impl AssignLogger {
                 }
             }
             state.pending_bytes = state.pending_bytes.saturating_sub(written);
+            if let Some(exposures) = &self.exposures {
+                let mut exposures = lock(exposures);
+                while let Some((_, len)) = exposures.front() {
+                    if written.saturating_add(*len) > limit_bytes && (written > 0 || start > 0) {
+                        break;
+                    }
+                    written = written.saturating_add(*len);
+                    if let Some((exposed, _)) = exposures.pop_front() {
+                        req.flag_exposed.push(exposed);
+                    }
+                }
+            }
+            let skew = core::mem::take(&mut *lock(&self.skew));
+            if !skew.is_empty() {
+                req.telemetry_data
+                    .get_or_insert_with(pb::TelemetryData::default)
+                    .apply_skew
+                    .extend(skew.into_values());
+            }
+            let dropped = self.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                let telemetry = req
+                    .telemetry_data
+                    .get_or_insert_with(pb::TelemetryData::default);
+                telemetry.dropped_assign_events =
+                    telemetry.dropped_assign_events.saturating_add(dropped);
+            }
         }
+        if written > 0 {
+            self.space.notify_all();
+        }
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("written", written);
         written
     }
 
-    fn encoded_len(assigned: &pb::FlagAssigned) -> usize {
-        let len = assigned.encoded_len();
+    fn encoded_len(event: &impl Message) -> usize {
+        let len = event.encoded_len();
         // the extra one is for the proto type and field id
         len.saturating_add(length_delimiter_len(len))
             .saturating_add(1)
     }
 }
 
+/// The exposure of the unit of an applied flag, `None` if the flag didn't assign a unit a
+/// variant: killed flags have no assignment and keyless assignments no unit to hash.
+fn exposure(
+    resolve_id: &str,
+    client_info: &Option<pb::ClientInfo>,
+    flag: &pb::AppliedFlag,
+) -> Option<pb::FlagExposed> {
+    let Some(pb::Assignment::AssignmentInfo(info)) = &flag.assignment else {
+        return None;
+    };
+    if flag.assignment_id.is_empty() || flag.targeting_key.is_empty() {
+        return None;
+    }
+    Some(pb::FlagExposed {
+        resolve_id: resolve_id.to_string(),
+        client_info: client_info.clone(),
+        flag: flag.flag.clone(),
+        variant: info.variant.clone(),
+        unit_hash: format!("{:032x}", crate::hashing::hash(&flag.targeting_key)),
+        targeting_key_selector: flag.targeting_key_selector.clone(),
+        rule: flag.rule.clone(),
+        assignment_id: flag.assignment_id.clone(),
+        exposure_time: flag.apply_time.clone(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -192,7 +560,7 @@ mod tests {
     fn can_allow_less() {
         let logger = AssignLogger::new();
         // push a small event directly
-        logger.assigned.push(make_event());
+        logger.enqueue(make_event());
 
         let r = logger.checkpoint_with_limit(10_000, false);
         assert_eq!(r.flag_assigned.len(), 1);
@@ -204,9 +572,9 @@ mod tests {
 
         let logger = AssignLogger::new(); // tiny target forces immediate flush
                                           // two events
-        logger.assigned.push(make_event());
-        logger.assigned.push(make_event());
-        logger.assigned.push(make_event());
+        logger.enqueue(make_event());
+        logger.enqueue(make_event());
+        logger.enqueue(make_event());
         let r = logger.checkpoint_with_limit(3 * ev_size - 1, true);
         // At least one event should be flushed; with target 0, implementation may flush one
         assert_eq!(r.flag_assigned.len(), 2);
@@ -216,13 +584,323 @@ mod tests {
     fn first_event_exceeding_target_is_sent_alone() {
         // Target smaller than single event size
         let logger = AssignLogger::new();
-        logger.assigned.push(make_event());
-        logger.assigned.push(make_event());
+        logger.enqueue(make_event());
+        logger.enqueue(make_event());
 
         let r = logger.checkpoint_with_limit(1, true);
         assert_eq!(r.flag_assigned.len(), 1);
     }
 
+    fn apply(logger: &AssignLogger, resolve_id: &str, flags: &[&str], seconds: i64) {
+        apply_with_skew(logger, resolve_id, flags, seconds, None);
+    }
+
+    fn apply_with_skew(
+        logger: &AssignLogger,
+        resolve_id: &str,
+        flags: &[&str],
+        seconds: i64,
+        clock_skew_millis: Option<i64>,
+    ) {
+        let client = crate::Client {
+            account: crate::Account {
+                name: "accounts/test".to_string(),
+            },
+            client_name: "clients/test".to_string(),
+            client_credential_name: "clients/test/clientCredentials/test".to_string(),
+            context_logging: Default::default(),
+        };
+        let flags: Vec<FlagToApply> = flags
+            .iter()
+            .map(|flag| FlagToApply {
+                assigned_flag: crate::flags_resolver::resolve_token_v1::AssignedFlag {
+                    flag: flag.to_string(),
+                    ..Default::default()
+                },
+                skew_adjusted_applied_time: crate::Timestamp { seconds, nanos: 0 },
+                clock_skew_millis,
+                state_fingerprint: String::new(),
+            })
+            .collect();
+        logger.log_assigns(resolve_id, &Default::default(), &flags, &client, &None);
+    }
+
+    #[test]
+    fn synthetic_applies_are_tagged() {
+        let logger = AssignLogger::new();
+        let client = crate::Client {
+            account: crate::Account {
+                name: "accounts/test".to_string(),
+            },
+            client_name: "clients/test".to_string(),
+            client_credential_name: "clients/test/clientCredentials/test".to_string(),
+            context_logging: Default::default(),
+        };
+        let flags = [FlagToApply {
+            assigned_flag: crate::flags_resolver::resolve_token_v1::AssignedFlag {
+                flag: "flags/a".to_string(),
+                ..Default::default()
+            },
+            skew_adjusted_applied_time: crate::Timestamp::default(),
+            clock_skew_millis: None,
+            state_fingerprint: String::new(),
+        }];
+        let mut context = crate::proto::google::Struct::default();
+        logger.log_assigns("r1", &context, &flags, &client, &None);
+        context.fields.insert(
+            flag_logger::SYNTHETIC_ATTRIBUTE.to_string(),
+            crate::proto::google::Value {
+                kind: Some(crate::proto::google::value::Kind::BoolValue(true)),
+            },
+        );
+        logger.log_assigns("r2", &context, &flags, &client, &None);
+
+        let synthetic: Vec<_> = logger
+            .checkpoint()
+            .flag_assigned
+            .into_iter()
+            .map(|a| (a.resolve_id, a.client_info.unwrap().synthetic))
+            .collect();
+        assert_eq!(
+            synthetic,
+            vec![("r1".to_string(), false), ("r2".to_string(), true)]
+        );
+    }
+
+    #[test]
+    fn exposures_are_logged_for_assigned_variants() {
+        let logger = AssignLogger::new().with_exposures();
+        let client = crate::Client {
+            account: crate::Account {
+                name: "accounts/test".to_string(),
+            },
+            client_name: "clients/test".to_string(),
+            client_credential_name: "clients/test/clientCredentials/test".to_string(),
+            context_logging: Default::default(),
+        };
+        let flag = |flag: &str, variant: &str| FlagToApply {
+            assigned_flag: crate::flags_resolver::resolve_token_v1::AssignedFlag {
+                flag: flag.to_string(),
+                variant: variant.to_string(),
+                targeting_key: "user-1".to_string(),
+                assignment_id: "control".to_string(),
+                ..Default::default()
+            },
+            skew_adjusted_applied_time: crate::Timestamp {
+                seconds: 10,
+                nanos: 0,
+            },
+            clock_skew_millis: None,
+            state_fingerprint: String::new(),
+        };
+        let mut killed = flag("flags/c", "flags/c/variants/off");
+        killed.assigned_flag.assignment_id.clear();
+        killed.assigned_flag.targeting_key.clear();
+        let mut keyless = flag("flags/d", "flags/d/variants/on");
+        keyless.assigned_flag.targeting_key.clear();
+        let flags = [
+            flag("flags/a", "flags/a/variants/on"),
+            flag("flags/b", ""),
+            killed,
+            keyless,
+        ];
+        logger.log_assigns("r1", &Default::default(), &flags, &client, &None);
+        assert_eq!(logger.backlog().events, 2);
+
+        let req = logger.checkpoint();
+        assert_eq!(req.flag_assigned.len(), 1);
+        assert_eq!(
+            req.flag_exposed,
+            vec![pb::FlagExposed {
+                resolve_id: "r1".to_string(),
+                client_info: req.flag_assigned[0].client_info.clone(),
+                flag: "flags/a".to_string(),
+                variant: "flags/a/variants/on".to_string(),
+                unit_hash: format!("{:032x}", crate::hashing::hash("user-1")),
+                assignment_id: "control".to_string(),
+                exposure_time: Some(crate::Timestamp {
+                    seconds: 10,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            }]
+        );
+        assert_eq!(logger.backlog().events, 0);
+
+        // without exposures enabled only the assign is logged
+        let logger = AssignLogger::new();
+        logger.log_assigns("r1", &Default::default(), &flags, &client, &None);
+        assert!(logger.checkpoint().flag_exposed.is_empty());
+
+        // exposures count toward max_pending and are dropped with their assign
+        let logger = AssignLogger::new()
+            .with_exposures()
+            .with_max_pending(3, DropPolicy::DropNewest);
+        logger.log_assigns("r1", &Default::default(), &flags, &client, &None);
+        logger.log_assigns("r2", &Default::default(), &flags, &client, &None);
+        assert_eq!(logger.backlog().events, 2);
+        let req = logger.checkpoint();
+        assert_eq!(resolve_ids(&req), vec!["r1"]);
+        assert_eq!(req.flag_exposed.len(), 1);
+        assert_eq!(req.telemetry_data.unwrap().dropped_assign_events, 2);
+    }
+
+    fn applied(logger: &AssignLogger) -> Vec<(String, Vec<String>)> {
+        logger
+            .checkpoint()
+            .flag_assigned
+            .into_iter()
+            .map(|a| (a.resolve_id, a.flags.into_iter().map(|f| f.flag).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn duplicate_applies_are_dropped_within_window() {
+        let logger = AssignLogger::with_dedupe_window(Duration::from_secs(60));
+        apply(&logger, "r1", &["flags/a", "flags/b"], 1000);
+        apply(&logger, "r1", &["flags/a"], 1010);
+        apply(&logger, "r1", &["flags/a", "flags/c"], 1020);
+        apply(&logger, "r2", &["flags/a"], 1030);
+        assert_eq!(
+            applied(&logger),
+            vec![
+                (
+                    "r1".to_string(),
+                    vec!["flags/a".to_string(), "flags/b".to_string()]
+                ),
+                ("r1".to_string(), vec!["flags/c".to_string()]),
+                ("r2".to_string(), vec!["flags/a".to_string()]),
+            ]
+        );
+
+        // once the window has passed the apply is logged again
+        apply(&logger, "r1", &["flags/a"], 1061);
+        assert_eq!(
+            applied(&logger),
+            vec![("r1".to_string(), vec!["flags/a".to_string()])]
+        );
+    }
+
+    #[test]
+    fn backlog_counts_queued_and_pending_events() {
+        let logger = AssignLogger::new();
+        apply(&logger, "r1", &["flags/a"], 1000);
+        apply(&logger, "r2", &["flags/b"], 1000);
+        let backlog = logger.backlog();
+        assert_eq!(backlog.events, 2);
+
+        // the first event is written even though it exceeds the limit
+        let written = logger.checkpoint_with_limit(1, false);
+        assert_eq!(written.flag_assigned.len(), 1);
+        assert_eq!(logger.backlog().events, 1);
+        assert_eq!(
+            logger.backlog().bytes,
+            backlog.bytes - written.encoded_len()
+        );
+
+        logger.checkpoint();
+        assert_eq!(logger.backlog(), LogBacklog::default());
+    }
+
+    fn resolve_ids(req: &WriteFlagLogsRequest) -> Vec<&str> {
+        req.flag_assigned
+            .iter()
+            .map(|a| a.resolve_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn max_pending_drops_by_policy() {
+        let logger = AssignLogger::new().with_max_pending(2, DropPolicy::DropOldest);
+        for id in ["r1", "r2", "r3"] {
+            apply(&logger, id, &["flags/a"], 1000);
+        }
+        let req = logger.checkpoint();
+        assert_eq!(resolve_ids(&req), vec!["r2", "r3"]);
+        assert_eq!(req.telemetry_data.unwrap().dropped_assign_events, 1);
+
+        let logger = AssignLogger::new().with_max_pending(2, DropPolicy::DropNewest);
+        for id in ["r1", "r2", "r3", "r4"] {
+            apply(&logger, id, &["flags/a"], 1000);
+        }
+        let req = logger.checkpoint();
+        assert_eq!(resolve_ids(&req), vec!["r1", "r2"]);
+        assert_eq!(req.telemetry_data.unwrap().dropped_assign_events, 2);
+
+        // the count is a delta
+        apply(&logger, "r5", &["flags/a"], 1000);
+        assert!(logger.checkpoint().telemetry_data.is_none());
+    }
+
+    #[test]
+    fn max_pending_blocks_until_checkpoint() {
+        let logger =
+            std::sync::Arc::new(AssignLogger::new().with_max_pending(1, DropPolicy::Block));
+        apply(&logger, "r1", &["flags/a"], 1000);
+        let blocked = {
+            let logger = logger.clone();
+            std::thread::spawn(move || apply(&logger, "r2", &["flags/a"], 1000))
+        };
+        let mut ids: Vec<String> = vec![];
+        while ids.len() < 2 {
+            let req = logger.checkpoint();
+            assert!(req.telemetry_data.is_none());
+            ids.extend(resolve_ids(&req).into_iter().map(String::from));
+        }
+        blocked.join().unwrap();
+        assert_eq!(ids, vec!["r1", "r2"]);
+    }
+
+    #[test]
+    fn skew_statistics_are_checkpointed() {
+        let logger = AssignLogger::new();
+        apply_with_skew(&logger, "r1", &["flags/a", "flags/b"], 1000, Some(50));
+        apply_with_skew(&logger, "r2", &["flags/a"], 1000, Some(-5_000));
+        apply_with_skew(&logger, "r3", &["flags/a"], 1000, None);
+
+        let req = logger.checkpoint();
+        assert_eq!(req.flag_assigned.len(), 3);
+        let telemetry = req.telemetry_data.unwrap();
+        assert_eq!(telemetry.apply_skew.len(), 1);
+        let skew = &telemetry.apply_skew[0];
+        assert_eq!(skew.count, 3);
+        assert_eq!(skew.min_millis, -5_000);
+        assert_eq!(skew.max_millis, 50);
+        assert_eq!(
+            skew.bucket_bounds_millis,
+            SKEW_BUCKET_BOUNDS_MILLIS.to_vec()
+        );
+        assert_eq!(skew.bucket_counts, vec![2, 0, 1, 0, 0, 0, 0, 0]);
+
+        // statistics are reset by the checkpoint
+        assert!(logger.checkpoint().telemetry_data.is_none());
+    }
+
+    #[test]
+    fn skew_statistics_merge() {
+        let mut first = pb::ApplySkew::default();
+        record_skew(&mut first, 10);
+        let mut second = pb::ApplySkew::default();
+        record_skew(&mut second, 2_000);
+        record_skew(&mut second, -20);
+
+        let mut merged = pb::ApplySkew::default();
+        merge_skew(&mut merged, &first);
+        merge_skew(&mut merged, &second);
+        assert_eq!(merged.count, 3);
+        assert_eq!(merged.min_millis, -20);
+        assert_eq!(merged.max_millis, 2_000);
+        assert_eq!(merged.bucket_counts, vec![2, 0, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn applies_are_not_deduplicated_by_default() {
+        let logger = AssignLogger::new();
+        apply(&logger, "r1", &["flags/a"], 1000);
+        apply(&logger, "r1", &["flags/a"], 1000);
+        assert_eq!(applied(&logger).len(), 2);
+    }
+
     #[test]
     fn returns_none_when_under_target_and_not_allowed() {
         let logger = AssignLogger::new();
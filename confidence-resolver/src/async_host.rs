@@ -0,0 +1,60 @@
+//! Sticky resolves against materialization stores that can only be read asynchronously, such
+//! as Cloudflare KV or Redis. Available with the `async` feature.
+//!
+//! [`AccountResolver::resolve_flags_sticky`] needs every materialization it reads to be part of
+//! the request, and otherwise answers with the ones that are missing.
+//! [`AccountResolver::resolve_flags_sticky_async`] instead fetches those from the
+//! [`AsyncHost`] and resolves again, then hands the updates of the resolve to the host to
+//! persist. Resolving itself stays synchronous; only the host calls are awaited.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use crate::materialization;
+use crate::proto::confidence::flags::resolver::v1::resolve_with_sticky_response::{
+    MaterializationUpdate, MissingMaterializationItem,
+};
+use crate::proto::confidence::flags::resolver::v1::{
+    MaterializationMap, ResolveWithStickyRequest, ResolveWithStickyResponse,
+};
+use crate::{AccountResolver, Host};
+
+pub trait AsyncHost: Host {
+    /// The materializations `items` refer to, by unit. Units and materializations left out
+    /// are treated as not containing the unit.
+    fn read_materializations(
+        items: &[MissingMaterializationItem],
+    ) -> impl Future<Output = Result<BTreeMap<String, MaterializationMap>, String>>;
+
+    /// Persists the materializations written by a sticky resolve, keeping variants already
+    /// stored like [`materialization::write_updates`]. The default leaves it to the caller,
+    /// who gets the updates in the response.
+    fn write_materializations(
+        _updates: &[MaterializationUpdate],
+    ) -> impl Future<Output = Result<(), String>> {
+        async { Ok(()) }
+    }
+}
+
+impl<H: AsyncHost> AccountResolver<'_, H> {
+    /// Like [`AccountResolver::resolve_flags_sticky`], reading missing materializations from
+    /// [`AsyncHost::read_materializations`] and writing updates with
+    /// [`AsyncHost::write_materializations`]. Materializations in the request take precedence
+    /// over those read from the host. With `fail_fast_on_sticky` set the response doesn't list
+    /// what is missing, so the host is not asked.
+    pub async fn resolve_flags_sticky_async(
+        &self,
+        request: &ResolveWithStickyRequest,
+    ) -> Result<ResolveWithStickyResponse, String> {
+        let mut response = self.resolve_flags_sticky(request)?;
+        if let Some(items) = materialization::missing_items(&response) {
+            let read = H::read_materializations(items).await?;
+            let request = materialization::with_read_materializations(request, items, read)?;
+            response = self.resolve_flags_sticky(&request)?;
+        }
+        if let Some(updates) = materialization::written_updates(&response) {
+            H::write_materializations(updates).await?;
+        }
+        Ok(response)
+    }
+}
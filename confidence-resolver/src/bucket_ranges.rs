@@ -0,0 +1,289 @@
+//! Checks of the bucket ranges of assignment specs, which the resolver otherwise takes as they
+//! are: a unit is assigned by the first assignment with a range containing its bucket, so
+//! overlapping ranges silently shift traffic to the earlier assignment, and ranges outside
+//! `0..bucket_count` are never hit.
+
+use std::collections::HashMap;
+
+use crate::hashing::SEGMENT_BUCKETS;
+use crate::proto::confidence::flags::admin::v1::flag::rule::AssignmentSpec;
+use crate::ResolverState;
+
+/// A problem with the assignment spec of a rule, see [`bucket_range_issues`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketRangeIssue {
+    pub flag: String,
+    pub rule: String,
+    pub kind: BucketRangeIssueKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BucketRangeIssueKind {
+    /// The spec has no buckets, so no unit is ever assigned.
+    InvalidBucketCount { bucket_count: i32 },
+    /// A bucket count that doesn't divide [`SEGMENT_BUCKETS`], so the shares of the
+    /// assignments can't be expressed in the buckets segments are allocated in.
+    InconsistentBucketCount { bucket_count: i32 },
+    /// A range that is empty or not within `0..bucket_count`. It is left out of the other
+    /// checks.
+    InvalidRange {
+        assignment_id: String,
+        lower: i32,
+        upper: i32,
+    },
+    /// Buckets in ranges of two assignments. Units in them get the `first` assignment, the
+    /// one listed earlier.
+    Overlap {
+        first: String,
+        second: String,
+        lower: i32,
+        upper: i32,
+    },
+    /// Buckets in no range, before or between ranges. Units in them fall through to the next
+    /// rule, which is usually a mistake.
+    Gap { lower: i32, upper: i32 },
+    /// Buckets in no range after the last one, up to `bucket_count`. Units in them fall through
+    /// to the next rule, which is how rules allocate less than all traffic.
+    Unallocated { lower: i32, upper: i32 },
+}
+
+/// The issues of the assignment specs of every rule in `state`, sorted by flag and in rule
+/// order.
+pub fn bucket_range_issues(state: &ResolverState) -> Vec<BucketRangeIssue> {
+    let mut flags: Vec<_> = state.flags.values().collect();
+    flags.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut issues = Vec::new();
+    for flag in flags {
+        for rule in &flag.rules {
+            let Some(spec) = &rule.assignment_spec else {
+                continue;
+            };
+            issues.extend(spec_issues(spec).into_iter().map(|kind| BucketRangeIssue {
+                flag: flag.name.clone(),
+                rule: rule.name.clone(),
+                kind,
+            }));
+        }
+    }
+    issues
+}
+
+fn spec_issues(spec: &AssignmentSpec) -> Vec<BucketRangeIssueKind> {
+    let bucket_count = spec.bucket_count;
+    if bucket_count <= 0 {
+        return vec![BucketRangeIssueKind::InvalidBucketCount { bucket_count }];
+    }
+
+    let mut issues = Vec::new();
+    let divides = u64::try_from(bucket_count)
+        .ok()
+        .and_then(|count| SEGMENT_BUCKETS.checked_rem(count))
+        .is_some_and(|rem| rem == 0);
+    if !divides {
+        issues.push(BucketRangeIssueKind::InconsistentBucketCount { bucket_count });
+    }
+    // (lower, upper, index of the assignment)
+    let mut ranges = Vec::new();
+    for (index, assignment) in spec.assignments.iter().enumerate() {
+        for range in &assignment.bucket_ranges {
+            if range.lower < 0 || range.upper > bucket_count || range.lower >= range.upper {
+                issues.push(BucketRangeIssueKind::InvalidRange {
+                    assignment_id: assignment.assignment_id.clone(),
+                    lower: range.lower,
+                    upper: range.upper,
+                });
+            } else {
+                ranges.push((range.lower, range.upper, index));
+            }
+        }
+    }
+    ranges.sort();
+
+    let assignment_id = |index: usize| {
+        spec.assignments
+            .get(index)
+            .map(|assignment| assignment.assignment_id.clone())
+            .unwrap_or_default()
+    };
+    // the end of the buckets covered so far, and the range reaching it
+    let mut covered = 0;
+    let mut owner: Option<(i32, usize)> = None;
+    let mut overlaps: HashMap<(usize, usize), (i32, i32)> = HashMap::new();
+    for (lower, upper, index) in ranges {
+        if lower > covered {
+            issues.push(BucketRangeIssueKind::Gap {
+                lower: covered,
+                upper: lower,
+            });
+        }
+        if let Some((end, other)) = owner.filter(|(end, _)| lower < *end) {
+            if other != index {
+                let (first, second) = (other.min(index), other.max(index));
+                // consecutive overlapping ranges of the same pair are reported once
+                let overlap = overlaps.entry((first, second)).or_insert((lower, lower));
+                overlap.1 = overlap.1.max(end.min(upper));
+            }
+        }
+        if upper > covered {
+            covered = upper;
+            owner = Some((upper, index));
+        }
+    }
+    if covered < bucket_count {
+        issues.push(BucketRangeIssueKind::Unallocated {
+            lower: covered,
+            upper: bucket_count,
+        });
+    }
+
+    let mut overlaps: Vec<_> = overlaps.into_iter().collect();
+    overlaps.sort_by_key(|(_, (lower, _))| *lower);
+    issues.extend(
+        overlaps
+            .into_iter()
+            .map(
+                |((first, second), (lower, upper))| BucketRangeIssueKind::Overlap {
+                    first: assignment_id(first),
+                    second: assignment_id(second),
+                    lower,
+                    upper,
+                },
+            ),
+    );
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::admin::v1::flag::rule::{
+        assignment, Assignment, BucketRange,
+    };
+    use crate::proto::confidence::flags::admin::v1::flag::Rule;
+    use crate::proto::confidence::flags::admin::v1::Flag;
+    use std::sync::Arc;
+
+    fn spec(bucket_count: i32, ranges: &[(&str, i32, i32)]) -> AssignmentSpec {
+        AssignmentSpec {
+            bucket_count,
+            assignments: ranges
+                .iter()
+                .map(|(id, lower, upper)| Assignment {
+                    assignment_id: id.to_string(),
+                    assignment: Some(assignment::Assignment::Fallthrough(Default::default())),
+                    bucket_ranges: vec![BucketRange {
+                        lower: *lower,
+                        upper: *upper,
+                    }],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn complete_specs_have_no_issues() {
+        assert_eq!(
+            spec_issues(&spec(100, &[("a", 0, 50), ("b", 50, 100)])),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn reports_overlaps_gaps_and_invalid_ranges() {
+        let issues = spec_issues(&spec(
+            100,
+            &[("a", 0, 40), ("b", 30, 60), ("c", 70, 90), ("d", 95, 120)],
+        ));
+        assert_eq!(
+            issues,
+            vec![
+                BucketRangeIssueKind::InvalidRange {
+                    assignment_id: "d".to_string(),
+                    lower: 95,
+                    upper: 120,
+                },
+                BucketRangeIssueKind::Gap {
+                    lower: 60,
+                    upper: 70,
+                },
+                BucketRangeIssueKind::Unallocated {
+                    lower: 90,
+                    upper: 100,
+                },
+                BucketRangeIssueKind::Overlap {
+                    first: "a".to_string(),
+                    second: "b".to_string(),
+                    lower: 30,
+                    upper: 40,
+                },
+            ]
+        );
+        assert_eq!(
+            spec_issues(&spec(0, &[])),
+            vec![BucketRangeIssueKind::InvalidBucketCount { bucket_count: 0 }]
+        );
+        assert_eq!(
+            spec_issues(&spec(10, &[("a", 5, 10)])),
+            vec![BucketRangeIssueKind::Gap { lower: 0, upper: 5 }]
+        );
+    }
+
+    #[test]
+    fn reports_bucket_counts_not_dividing_segment_buckets() {
+        assert_eq!(spec_issues(&spec(10_000, &[("a", 0, 10_000)])), vec![]);
+        assert_eq!(
+            spec_issues(&spec(3, &[("a", 0, 3)])),
+            vec![BucketRangeIssueKind::InconsistentBucketCount { bucket_count: 3 }]
+        );
+        assert_eq!(
+            spec_issues(&spec(2_000_000, &[("a", 0, 2_000_000)])),
+            vec![BucketRangeIssueKind::InconsistentBucketCount {
+                bucket_count: 2_000_000
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_issues_per_rule() {
+        let flag = Flag {
+            name: "flags/f".to_string(),
+            rules: vec![
+                Rule {
+                    name: "flags/f/rules/ok".to_string(),
+                    assignment_spec: Some(spec(10, &[("a", 0, 10)])),
+                    ..Default::default()
+                },
+                Rule {
+                    name: "flags/f/rules/partial".to_string(),
+                    assignment_spec: Some(spec(10, &[("a", 0, 5)])),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let state = ResolverState {
+            flags: Arc::new(HashMap::from([(flag.name.clone(), flag)])),
+            secrets: HashMap::new(),
+            segments: Default::default(),
+            bitsets: Default::default(),
+            external_segments: Default::default(),
+            encryption_keys: Default::default(),
+            config: Default::default(),
+            kill_switches: Vec::new(),
+            fingerprint: String::new(),
+            decrypt_breaker: None,
+            derived: Default::default(),
+        };
+        assert_eq!(
+            bucket_range_issues(&state),
+            vec![BucketRangeIssue {
+                flag: "flags/f".to_string(),
+                rule: "flags/f/rules/partial".to_string(),
+                kind: BucketRangeIssueKind::Unallocated {
+                    lower: 5,
+                    upper: 10,
+                },
+            }]
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! What a build of the resolver supports, for hosts to report in diagnostics and to detect
+//! a guest built from other sources than the host expects.
+
+use crate::{proto, resolve_token};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Version of the `confidence_resolver` crate.
+    pub version: &'static str,
+    /// [`proto::file_descriptor_set_checksum`] of the protos compiled into the build.
+    pub proto_checksum: u32,
+    /// Resolve token format versions the build reads and writes. Tokens are written with the
+    /// first, or the second when the encryption key has an id.
+    pub token_versions: &'static [u8],
+    /// Cargo features the build was compiled with.
+    pub features: Vec<&'static str>,
+}
+
+pub fn build_info() -> BuildInfo {
+    let features = [
+        ("std", cfg!(feature = "std")),
+        ("json", cfg!(feature = "json")),
+        ("time", cfg!(feature = "time")),
+        ("tracing", cfg!(feature = "tracing")),
+        ("otel", cfg!(feature = "otel")),
+        ("reqwest", cfg!(feature = "reqwest")),
+        ("transcode", cfg!(feature = "transcode")),
+    ];
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        proto_checksum: proto::file_descriptor_set_checksum(),
+        token_versions: resolve_token::SUPPORTED_VERSIONS,
+        features: features
+            .into_iter()
+            .filter_map(|(name, enabled)| enabled.then_some(name))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_this_build() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.proto_checksum, proto::file_descriptor_set_checksum());
+        assert_eq!(info.token_versions, &[1, 2]);
+        assert_eq!(info.features.contains(&"std"), cfg!(feature = "std"));
+    }
+}
@@ -0,0 +1,135 @@
+//! Canonical form of evaluation contexts, for hosts that need a stable key for a context such
+//! as cache keys, deterministic resolve ids or deduplication.
+//!
+//! The canonical form is compact JSON with object keys sorted and numbers printed in their
+//! shortest form, so `{"b": 1.0, "a": -0}` and `{"a":0,"b":1}` are the same context. Values
+//! without a kind and non-finite numbers, which JSON can't represent, are written as `null`.
+
+use core::fmt::Write;
+
+use crate::hash;
+use crate::proto::google::{value::Kind, ListValue, Struct, Value};
+
+/// The canonical JSON serialization of `context`.
+pub fn canonical_context(context: &Struct) -> String {
+    let mut out = String::new();
+    write_struct(&mut out, context);
+    out
+}
+
+/// A hash of the canonical form of `context`, equal for contexts that only differ in key order
+/// or number formatting.
+pub fn context_hash(context: &Struct) -> u128 {
+    hash(&canonical_context(context))
+}
+
+fn write_struct(out: &mut String, value: &Struct) {
+    let mut fields: Vec<(&String, &Value)> = value.fields.iter().collect();
+    fields.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    out.push('{');
+    for (index, (key, value)) in fields.into_iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        write_string(out, key);
+        out.push(':');
+        write_value(out, value);
+    }
+    out.push('}');
+}
+
+fn write_list(out: &mut String, list: &ListValue) {
+    out.push('[');
+    for (index, value) in list.values.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        write_value(out, value);
+    }
+    out.push(']');
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    match &value.kind {
+        None | Some(Kind::NullValue(_)) => out.push_str("null"),
+        Some(Kind::BoolValue(b)) => out.push_str(if *b { "true" } else { "false" }),
+        Some(Kind::NumberValue(n)) if !n.is_finite() => out.push_str("null"),
+        // adding zero turns -0 into 0
+        Some(Kind::NumberValue(n)) => {
+            let _ = write!(out, "{}", n + 0.0);
+        }
+        Some(Kind::StringValue(s)) => write_string(out, s),
+        Some(Kind::ListValue(list)) => write_list(out, list),
+        Some(Kind::StructValue(s)) => write_struct(out, s),
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(json: &str) -> Struct {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn keys_are_sorted_at_every_level() {
+        assert_eq!(
+            canonical_context(&context(r#"{"b": {"y": 1, "x": [true, null]}, "a": "s"}"#)),
+            r#"{"a":"s","b":{"x":[true,null],"y":1}}"#
+        );
+    }
+
+    #[test]
+    fn numbers_are_normalized() {
+        assert_eq!(
+            canonical_context(&context(r#"{"a": 1.0, "b": -0.0, "c": 0.5, "d": 1e3}"#)),
+            r#"{"a":1,"b":0,"c":0.5,"d":1000}"#
+        );
+        let mut infinite = Struct::default();
+        infinite.fields.insert(
+            "a".to_string(),
+            Value {
+                kind: Some(Kind::NumberValue(f64::INFINITY)),
+            },
+        );
+        assert_eq!(canonical_context(&infinite), r#"{"a":null}"#);
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let canonical = canonical_context(&context(r#"{"k\"ey": "a\\b\n\u0001é"}"#));
+        assert_eq!(canonical, r#"{"k\"ey":"a\\b\n\u0001é"}"#);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&canonical).unwrap()["k\"ey"],
+            "a\\b\n\u{1}é"
+        );
+    }
+
+    #[test]
+    fn equivalent_contexts_hash_equal() {
+        let a = context(r#"{"user": {"id": 26, "country": "SE"}, "visitor_id": "v"}"#);
+        let b = context(r#"{"visitor_id": "v", "user": {"country": "SE", "id": 26.0}}"#);
+        let c = context(r#"{"visitor_id": "v", "user": {"country": "SE", "id": "26"}}"#);
+        assert_eq!(context_hash(&a), context_hash(&b));
+        assert_ne!(context_hash(&a), context_hash(&c));
+    }
+}
@@ -0,0 +1,170 @@
+//! Loading of resolver states exported by older control planes. Fields added to the state
+//! since then decode to their defaults, which for some of them changes what a flag resolves
+//! to, e.g. rules that all look disabled.
+//!
+//! A state can't be told apart from a current one that uses those defaults on purpose, such as
+//! an account with every rule disabled, so [`check`] only reports what a state looks to be
+//! missing, for hosts to log. [`upgrade`] fills the fields in the way the older control planes
+//! behaved, and is only meant for states known to be such exports.
+
+use std::fmt;
+
+use crate::proto::confidence::flags::admin::v1::ResolverState as ResolverStatePb;
+
+/// A field a state looks to be exported without.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LegacyField {
+    /// `Rule.enabled`; no rule of the state is enabled. Exports made before rules could be
+    /// disabled only contained rules that were in use.
+    RuleEnabled,
+    /// `Flag.clients`; no flag lists a client. Flags used to be available to every client of
+    /// the account.
+    FlagClients,
+}
+
+impl fmt::Display for LegacyField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LegacyField::RuleEnabled => {
+                "state has no enabled rules, as exports that predate disabling rules do"
+            }
+            LegacyField::FlagClients => {
+                "state has no flag clients, as exports that predate flag clients do"
+            }
+        })
+    }
+}
+
+/// What [`check`] found missing from a state, oldest field first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompatReport {
+    pub legacy_fields: Vec<LegacyField>,
+}
+
+impl CompatReport {
+    /// Whether the state has every field this resolver reads.
+    pub fn is_current(&self) -> bool {
+        self.legacy_fields.is_empty()
+    }
+
+    /// One warning per missing field, for hosts to log.
+    pub fn warnings(&self) -> Vec<String> {
+        self.legacy_fields.iter().map(|f| f.to_string()).collect()
+    }
+}
+
+/// The fields `state` looks to be exported without, leaving it as is.
+pub fn check(state: &ResolverStatePb) -> CompatReport {
+    let mut report = CompatReport::default();
+    let mut rules = state.flags.iter().flat_map(|flag| &flag.rules).peekable();
+    if rules.peek().is_some() && !rules.any(|rule| rule.enabled) {
+        report.legacy_fields.push(LegacyField::RuleEnabled);
+    }
+    if !state.clients.is_empty()
+        && !state.flags.is_empty()
+        && state.flags.iter().all(|flag| flag.clients.is_empty())
+    {
+        report.legacy_fields.push(LegacyField::FlagClients);
+    }
+    report
+}
+
+/// Fills in the fields [`check`] finds missing: enables the rules with an assignment spec and
+/// makes every flag available to every client. This rewrites current states that leave these
+/// fields empty on purpose, so only call it for states known to be exported by an older
+/// control plane.
+pub fn upgrade(state: &mut ResolverStatePb) -> CompatReport {
+    let report = check(state);
+    for field in &report.legacy_fields {
+        match field {
+            LegacyField::RuleEnabled => {
+                for rule in state
+                    .flags
+                    .iter_mut()
+                    .flat_map(|flag| flag.rules.iter_mut())
+                {
+                    rule.enabled = rule.assignment_spec.is_some();
+                }
+            }
+            LegacyField::FlagClients => {
+                let clients: Vec<String> = state.clients.iter().map(|c| c.name.clone()).collect();
+                for flag in &mut state.flags {
+                    flag.clients.clone_from(&clients);
+                }
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::admin::v1::flag::rule::AssignmentSpec;
+    use crate::proto::confidence::flags::admin::v1::flag::Rule;
+    use crate::proto::confidence::flags::admin::v1::Flag;
+    use crate::proto::confidence::iam::v1::Client;
+
+    fn legacy_state() -> ResolverStatePb {
+        ResolverStatePb {
+            flags: vec![Flag {
+                name: "flags/a".to_string(),
+                rules: vec![
+                    Rule {
+                        name: "flags/a/rules/assigning".to_string(),
+                        assignment_spec: Some(AssignmentSpec::default()),
+                        ..Default::default()
+                    },
+                    Rule {
+                        name: "flags/a/rules/empty".to_string(),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            }],
+            clients: vec![Client {
+                name: "clients/c".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn checks_without_changing_the_state() {
+        let state = legacy_state();
+        let before = state.clone();
+        let report = check(&state);
+        assert_eq!(
+            report.legacy_fields,
+            vec![LegacyField::RuleEnabled, LegacyField::FlagClients]
+        );
+        assert_eq!(report.warnings().len(), 2);
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn fills_in_legacy_fields() {
+        let mut state = legacy_state();
+        let report = upgrade(&mut state);
+        assert_eq!(report, check(&legacy_state()));
+        let flag = &state.flags[0];
+        assert!(flag.rules[0].enabled);
+        assert!(!flag.rules[1].enabled);
+        assert_eq!(flag.clients, vec!["clients/c".to_string()]);
+
+        // an upgraded state is current
+        assert!(upgrade(&mut state).is_current());
+    }
+
+    #[test]
+    fn leaves_current_states_alone() {
+        let mut state: ResolverStatePb = include_bytes!("../test-payloads/resolver_state.pb")
+            .to_vec()
+            .try_into()
+            .unwrap();
+        let before = state.clone();
+        assert!(upgrade(&mut state).is_current());
+        assert_eq!(state, before);
+    }
+}
@@ -0,0 +1,388 @@
+//! How well the targeting of flags matches a corpus of recorded evaluation contexts. Rules and
+//! criteria that match none of the contexts, and attributes none of them set, usually point at
+//! an SDK that doesn't send what the targeting was written against.
+//!
+//! Only targeting is evaluated: a rule matches a context when the context has the rule's
+//! targeting key and satisfies the targeting of the rule's segment. Allocations and bitsets
+//! are ignored, as they depend on the unit rather than on what the SDK sends.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::err::Fallible;
+use crate::proto::confidence::flags::admin::v1::flag::State;
+use crate::proto::google::{value::Kind, Struct, Value};
+use crate::requirements::context_requirements;
+use crate::{criterion, fail, value, ResolverState};
+
+/// Matches of the flags passed to [`criteria_coverage`] against a corpus of contexts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageReport {
+    /// Number of contexts analyzed.
+    pub contexts: usize,
+    /// Enabled rules of the flags, in flag and rule order.
+    pub rules: Vec<RuleCoverage>,
+    /// Attribute criteria of the segments the rules target, sorted by segment and criterion.
+    pub criteria: Vec<CriterionCoverage>,
+    /// Attributes the flags read, as in [`context_requirements`].
+    pub attributes: Vec<AttributeCoverage>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleCoverage {
+    pub flag: String,
+    pub rule: String,
+    /// Contexts the rule's targeting matched.
+    pub matched: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CriterionCoverage {
+    pub segment: String,
+    /// Id of the criterion in the segment's targeting.
+    pub criterion: String,
+    pub attribute: String,
+    /// Contexts the criterion matched on its own, regardless of the rest of the targeting.
+    pub matched: usize,
+    /// Contexts with a value that can't be compared to the criterion's, e.g. a string that
+    /// isn't a number for a number criterion. They don't match it, here or in rules.
+    pub type_mismatches: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttributeCoverage {
+    pub attribute: String,
+    /// Contexts without the attribute, or with it set to null.
+    pub missing: usize,
+    pub flags: BTreeSet<String>,
+}
+
+impl CoverageReport {
+    /// Rules that matched none of the contexts.
+    pub fn unmatched_rules(&self) -> impl Iterator<Item = &RuleCoverage> {
+        self.rules.iter().filter(|rule| rule.matched == 0)
+    }
+
+    /// Criteria that matched none of the contexts.
+    pub fn unmatched_criteria(&self) -> impl Iterator<Item = &CriterionCoverage> {
+        self.criteria
+            .iter()
+            .filter(|criterion| criterion.matched == 0)
+    }
+
+    /// Attributes that none of the contexts set.
+    pub fn missing_attributes(&self) -> impl Iterator<Item = &AttributeCoverage> {
+        self.attributes
+            .iter()
+            .filter(|attribute| attribute.missing == self.contexts)
+    }
+}
+
+/// Evaluates the targeting of `flags` against each of `contexts`. An empty `flags` means all
+/// active flags, as in [`context_requirements`].
+pub fn criteria_coverage(
+    state: &ResolverState,
+    flags: &[&str],
+    contexts: &[Struct],
+) -> Result<CoverageReport, String> {
+    let mut flags: Vec<_> = state
+        .flags
+        .values()
+        .filter(|flag| flag.state() == State::Active)
+        .filter(|flag| flags.is_empty() || flags.contains(&flag.name.as_str()))
+        .collect();
+    flags.sort_by(|a, b| a.name.cmp(&b.name));
+    let rules: Vec<_> = flags
+        .iter()
+        .flat_map(|flag| {
+            flag.rules
+                .iter()
+                .filter(|rule| rule.enabled)
+                .map(move |rule| (*flag, rule))
+        })
+        .collect();
+
+    let mut segments: Vec<_> = state
+        .segments_reachable_from(rules.iter().map(|(_, rule)| rule.segment.as_str()))
+        .into_iter()
+        .filter_map(|name| state.segments.get(name))
+        .collect();
+    segments.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut criteria = vec![];
+    for segment in segments {
+        let Some(targeting) = &segment.targeting else {
+            continue;
+        };
+        let sorted: BTreeMap<_, _> = targeting.criteria.iter().collect();
+        for (id, criterion) in sorted {
+            if let Some(criterion::Criterion::Attribute(attribute)) = &criterion.criterion {
+                criteria.push((segment.name.as_str(), id.as_str(), attribute));
+            }
+        }
+    }
+
+    let requirements = context_requirements(
+        state,
+        &flags
+            .iter()
+            .map(|flag| flag.name.as_str())
+            .collect::<Vec<_>>(),
+    );
+
+    let mut report = CoverageReport {
+        contexts: contexts.len(),
+        rules: rules
+            .iter()
+            .map(|(flag, rule)| RuleCoverage {
+                flag: flag.name.clone(),
+                rule: rule.name.clone(),
+                matched: 0,
+            })
+            .collect(),
+        criteria: criteria
+            .iter()
+            .map(|(segment, id, attribute)| CriterionCoverage {
+                segment: segment.to_string(),
+                criterion: id.to_string(),
+                attribute: attribute.attribute_name.clone(),
+                matched: 0,
+                type_mismatches: 0,
+            })
+            .collect(),
+        attributes: requirements
+            .into_iter()
+            .map(|requirement| AttributeCoverage {
+                attribute: requirement.attribute,
+                missing: 0,
+                flags: requirement.flags,
+            })
+            .collect(),
+    };
+
+    for context in contexts {
+        let mut matches = HashMap::new();
+        for ((flag, rule), coverage) in rules.iter().zip(&mut report.rules) {
+            let selector = state.targeting_key_selector(flag, rule);
+            if !is_missing(&crate::attribute_value(context, selector))
+                && targeting_match(
+                    state,
+                    &rule.segment,
+                    context,
+                    &mut matches,
+                    &mut HashSet::new(),
+                )?
+            {
+                coverage.matched = coverage.matched.saturating_add(1);
+            }
+        }
+        for ((_, _, attribute), coverage) in criteria.iter().zip(&mut report.criteria) {
+            match criterion_match(state, attribute, context) {
+                Some(true) => coverage.matched = coverage.matched.saturating_add(1),
+                Some(false) => {}
+                None => coverage.type_mismatches = coverage.type_mismatches.saturating_add(1),
+            }
+        }
+        for coverage in &mut report.attributes {
+            if is_missing(&crate::attribute_value(context, &coverage.attribute)) {
+                coverage.missing = coverage.missing.saturating_add(1);
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn is_missing(value: &Value) -> bool {
+    matches!(value.kind, None | Some(Kind::NullValue(_)))
+}
+
+/// Whether `context` matches `attribute`, `None` if its value can't be compared to the
+/// criterion's.
+fn criterion_match(
+    state: &ResolverState,
+    attribute: &criterion::AttributeCriterion,
+    context: &Struct,
+) -> Option<bool> {
+    let expected_value_type = value::expected_value_type(attribute);
+    let attribute_value = crate::attribute_value(context, &attribute.attribute_name);
+    let converted =
+        value::convert_to_targeting_value(&attribute_value, expected_value_type).ok()?;
+    Some(value::evaluate_criterion(
+        attribute,
+        &crate::list_wrapper(&converted),
+        &state.derived().versions,
+    ))
+}
+
+/// Whether `context` satisfies the targeting of `segment` and of the segments it refers to,
+/// ignoring their bitsets.
+fn targeting_match<'s>(
+    state: &'s ResolverState,
+    segment: &'s str,
+    context: &Struct,
+    matches: &mut HashMap<&'s str, bool>,
+    visited: &mut HashSet<&'s str>,
+) -> Fallible<bool> {
+    if let Some(matched) = matches.get(segment) {
+        return Ok(*matched);
+    }
+    if !visited.insert(segment) {
+        fail!("circular segment dependency found");
+    }
+    let Some(segment) = state.segments.get(segment) else {
+        return Ok(false);
+    };
+    let matched = match segment
+        .targeting
+        .as_ref()
+        .and_then(|t| t.expression.as_ref().map(|e| (t, e)))
+    {
+        None => true,
+        Some((targeting, expression)) => {
+            let mut criterion_evaluator = |id: &String| {
+                let Some(criterion) = targeting.criteria.get(id) else {
+                    return Ok(false);
+                };
+                match &criterion.criterion {
+                    Some(criterion::Criterion::Attribute(attribute)) => {
+                        Ok(criterion_match(state, attribute, context).unwrap_or(false))
+                    }
+                    Some(criterion::Criterion::Segment(segment_criterion)) => targeting_match(
+                        state,
+                        &segment_criterion.segment,
+                        context,
+                        matches,
+                        visited,
+                    ),
+                    None => Ok(false),
+                }
+            };
+            crate::evaluate_expression(expression, &mut criterion_evaluator)?
+        }
+    };
+    matches.insert(&segment.name, matched);
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::admin::v1::Segment;
+    use std::sync::Arc;
+
+    const FLAG: &str = "flags/tutorial-feature";
+
+    fn state() -> ResolverState {
+        let mut state = ResolverState::from_proto(
+            include_bytes!("../test-payloads/resolver_state.pb")
+                .to_vec()
+                .try_into()
+                .unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let segments = [
+            r#"{
+                "name": "segments/swedes",
+                "targeting": {
+                    "criteria": {
+                        "country": {"attribute": {"attributeName": "user.country", "eqRule": {"value": {"stringValue": "SE"}}}},
+                        "adults": {"segment": {"segment": "segments/adults"}}
+                    },
+                    "expression": {"and": {"operands": [{"ref": "country"}, {"ref": "adults"}]}}
+                }
+            }"#,
+            r#"{
+                "name": "segments/adults",
+                "targeting": {
+                    "criteria": {
+                        "age": {"attribute": {"attributeName": "user.age", "rangeRule": {"startInclusive": {"numberValue": 18}}}}
+                    },
+                    "expression": {"ref": "age"}
+                }
+            }"#,
+        ];
+        for json in segments {
+            let segment: Segment = serde_json::from_str(json).unwrap();
+            Arc::make_mut(&mut state.segments).insert(segment.name.clone(), segment);
+        }
+        let flag = Arc::make_mut(&mut state.flags).get_mut(FLAG).unwrap();
+        flag.rules.retain(|rule| rule.enabled);
+        flag.rules.truncate(1);
+        flag.rules[0].segment = "segments/swedes".to_string();
+        state
+    }
+
+    fn parse(json: &[&str]) -> Vec<Struct> {
+        json.iter()
+            .map(|json| serde_json::from_str(json).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn finds_unmatched_targeting() {
+        let state = state();
+        let contexts = parse(&[
+            r#"{"visitor_id": "a", "user": {"country": "SE"}}"#,
+            r#"{"visitor_id": "b", "user": {"country": "DK"}}"#,
+            r#"{"user": {"country": "SE", "age": null}}"#,
+        ]);
+        let report = criteria_coverage(&state, &[FLAG], &contexts).unwrap();
+        assert_eq!(report.contexts, 3);
+
+        let rules: Vec<_> = report.unmatched_rules().map(|r| r.flag.as_str()).collect();
+        assert_eq!(rules, vec![FLAG]);
+        let criteria: Vec<_> = report
+            .criteria
+            .iter()
+            .map(|c| (c.segment.as_str(), c.criterion.as_str(), c.matched))
+            .collect();
+        assert_eq!(
+            criteria,
+            vec![
+                ("segments/adults", "age", 0),
+                ("segments/swedes", "country", 2)
+            ]
+        );
+        let missing: Vec<_> = report
+            .attributes
+            .iter()
+            .map(|a| (a.attribute.as_str(), a.missing))
+            .collect();
+        assert_eq!(
+            missing,
+            vec![("user.age", 3), ("user.country", 0), ("visitor_id", 1)]
+        );
+        let missing: Vec<_> = report
+            .missing_attributes()
+            .map(|a| a.attribute.as_str())
+            .collect();
+        assert_eq!(missing, vec!["user.age"]);
+
+        // once the SDK sends the age, the rule matches adult swedes with a targeting key
+        let contexts = parse(&[
+            r#"{"visitor_id": "a", "user": {"country": "SE", "age": 30}}"#,
+            r#"{"visitor_id": "b", "user": {"country": "SE", "age": 12}}"#,
+            r#"{"user": {"country": "SE", "age": 30}}"#,
+        ]);
+        let report = criteria_coverage(&state, &[FLAG], &contexts).unwrap();
+        assert_eq!(report.rules[0].matched, 1);
+        assert_eq!(report.unmatched_criteria().count(), 0);
+        assert_eq!(report.missing_attributes().count(), 0);
+    }
+
+    #[test]
+    fn counts_type_mismatches() {
+        let state = state();
+        let contexts = parse(&[
+            r#"{"visitor_id": "a", "user": {"country": "SE", "age": "thirty"}}"#,
+            r#"{"visitor_id": "b", "user": {"country": "SE", "age": 30}}"#,
+        ]);
+        let report = criteria_coverage(&state, &[FLAG], &contexts).unwrap();
+        let age = report
+            .criteria
+            .iter()
+            .find(|c| c.criterion == "age")
+            .unwrap();
+        assert_eq!((age.matched, age.type_mismatches), (1, 1));
+        assert_eq!(report.rules[0].matched, 1);
+    }
+}
@@ -0,0 +1,231 @@
+//! A circuit breaker for resolve tokens that fail to decrypt.
+//!
+//! A failed decrypt fails its apply, which is right for a tampered or truncated token. When
+//! every token fails, e.g. because the encryption key was rotated on one side only, the
+//! failures are an incident: [`DecryptBreaker`] opens once `max_failures` happen within
+//! `window_seconds`, logs it through [`Host::log`] and reports
+//! [`metrics::DECRYPT_BREAKER_OPEN`]. While open it can accept applies it can't decrypt and
+//! hold them for the host to replay with [`crate::AccountResolver::replay_held_apply`] once the
+//! key is fixed, rather than losing them. The breaker closes once `close_after_successes`
+//! tokens in a row decrypt, so that a stray token that still decrypts, e.g. one issued before
+//! the rotation, doesn't close it.
+//!
+//! Set [`crate::ResolverState::decrypt_breaker`] to use one, sharing it between states so that
+//! state updates don't reset it.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::proto::confidence::flags::resolver::v1::ApplyFlagsRequest;
+use crate::proto::google::Timestamp;
+use crate::{metrics, Host};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptBreakerConfig {
+    /// Failures within `window_seconds` that open the breaker.
+    pub max_failures: usize,
+    pub window_seconds: i64,
+    /// Whether applies that fail to decrypt while the breaker is open succeed, with the
+    /// request held for [`DecryptBreaker::take_held_applies`].
+    pub accept_applies: bool,
+    /// Applies held at most; further ones fail as if `accept_applies` was off.
+    pub max_held_applies: usize,
+    /// Tokens that decrypt in a row, with no failure in between, that close an open breaker.
+    pub close_after_successes: usize,
+}
+
+impl Default for DecryptBreakerConfig {
+    fn default() -> Self {
+        DecryptBreakerConfig {
+            max_failures: 100,
+            window_seconds: 60,
+            accept_applies: false,
+            max_held_applies: 10_000,
+            close_after_successes: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DecryptBreaker {
+    config: DecryptBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+/// An apply held by an open breaker, with the time it was received, which its clock skew is
+/// computed against when it is replayed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeldApply {
+    pub request: ApplyFlagsRequest,
+    pub receive_time: Timestamp,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    /// Times of the failures within the window, in seconds.
+    failures: VecDeque<i64>,
+    open: bool,
+    /// Tokens decrypted in a row while open.
+    successes: usize,
+    held: Vec<HeldApply>,
+}
+
+impl DecryptBreaker {
+    pub fn new(config: DecryptBreakerConfig) -> Self {
+        DecryptBreaker {
+            config,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.lock().open
+    }
+
+    /// The applies accepted while the breaker was open, oldest first, to be replayed with
+    /// [`crate::AccountResolver::replay_held_apply`] once tokens decrypt.
+    pub fn take_held_applies(&self) -> Vec<HeldApply> {
+        core::mem::take(&mut self.lock().held)
+    }
+
+    pub(crate) fn record_success<H: Host>(&self) {
+        let mut state = self.lock();
+        state.failures.clear();
+        if !state.open {
+            return;
+        }
+        state.successes = state.successes.saturating_add(1);
+        if state.successes >= self.config.close_after_successes {
+            state.open = false;
+            state.successes = 0;
+            H::log("resolve tokens decrypt again, closing the decrypt circuit breaker");
+        }
+    }
+
+    pub(crate) fn record_failure<H: Host>(&self) {
+        let now = H::current_time().seconds;
+        let mut state = self.lock();
+        state.successes = 0;
+        let window_start = now.saturating_sub(self.config.window_seconds);
+        while state.failures.front().is_some_and(|&t| t <= window_start) {
+            state.failures.pop_front();
+        }
+        state.failures.push_back(now);
+        // the window only needs to hold enough failures to open the breaker
+        if state.failures.len() > self.config.max_failures {
+            state.failures.pop_front();
+        }
+        if !state.open && state.failures.len() >= self.config.max_failures {
+            state.open = true;
+            H::log(&format!(
+                "ERROR: {} resolve tokens failed to decrypt within {}s, opening the decrypt \
+                 circuit breaker; check that the encryption key matches the one tokens were \
+                 issued with",
+                state.failures.len(),
+                self.config.window_seconds
+            ));
+            H::on_metric(metrics::DECRYPT_BREAKER_OPEN, 1.0, &[]);
+        }
+    }
+
+    /// Holds `request` if the breaker is open and accepts applies, returning whether it did.
+    pub(crate) fn hold<H: Host>(
+        &self,
+        request: &ApplyFlagsRequest,
+        receive_time: &Timestamp,
+    ) -> bool {
+        let mut state = self.lock();
+        if !state.open
+            || !self.config.accept_applies
+            || state.held.len() >= self.config.max_held_applies
+        {
+            return false;
+        }
+        state.held.push(HeldApply {
+            request: request.clone(),
+            receive_time: receive_time.clone(),
+        });
+        H::on_metric(metrics::APPLY_HELD, 1.0, &[]);
+        true
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BreakerState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestHost;
+
+    fn breaker(accept_applies: bool) -> DecryptBreaker {
+        DecryptBreaker::new(DecryptBreakerConfig {
+            max_failures: 3,
+            window_seconds: 10,
+            accept_applies,
+            max_held_applies: 1,
+            close_after_successes: 2,
+        })
+    }
+
+    #[test]
+    fn opens_after_failures_within_the_window() {
+        TestHost::reset();
+        let breaker = breaker(false);
+        breaker.record_failure::<TestHost>();
+        breaker.record_failure::<TestHost>();
+        TestHost::advance_time(11);
+        // the first two failures are out of the window
+        breaker.record_failure::<TestHost>();
+        assert!(!breaker.is_open());
+        breaker.record_failure::<TestHost>();
+        breaker.record_failure::<TestHost>();
+        assert!(breaker.is_open());
+        assert_eq!(TestHost::metrics()[0].name, metrics::DECRYPT_BREAKER_OPEN);
+        assert!(TestHost::messages()[0].starts_with("ERROR: 3 resolve tokens"));
+
+        // an open breaker doesn't report again
+        breaker.record_failure::<TestHost>();
+        assert_eq!(TestHost::metrics().len(), 1);
+
+        // it takes two successes in a row to close
+        breaker.record_success::<TestHost>();
+        breaker.record_failure::<TestHost>();
+        breaker.record_success::<TestHost>();
+        assert!(breaker.is_open());
+        breaker.record_success::<TestHost>();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn holds_applies_while_open() {
+        TestHost::reset();
+        let request = ApplyFlagsRequest {
+            resolve_token: b"undecryptable".to_vec(),
+            ..Default::default()
+        };
+        let received = TestHost::current_time();
+        let rejecting = breaker(false);
+        let accepting = breaker(true);
+        assert!(!accepting.hold::<TestHost>(&request, &received));
+        for _ in 0..3 {
+            rejecting.record_failure::<TestHost>();
+            accepting.record_failure::<TestHost>();
+        }
+        assert!(!rejecting.hold::<TestHost>(&request, &received));
+        assert!(accepting.hold::<TestHost>(&request, &received));
+        // beyond max_held_applies
+        assert!(!accepting.hold::<TestHost>(&request, &received));
+        assert_eq!(
+            accepting.take_held_applies(),
+            vec![HeldApply {
+                request,
+                receive_time: received,
+            }]
+        );
+        assert!(accepting.take_held_applies().is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+
+use crate::proto::confidence::flags::admin::v1::flag::Rule;
+use crate::proto::confidence::flags::admin::v1::flag_resolve_info::RuleResolveInfo;
+use crate::proto::confidence::flags::resolver::v1::{
+    telemetry_data, TelemetryData, WriteFlagLogsRequest,
+};
+use crate::ResolverState;
+
+/// Thresholds used when comparing observed assignment counts against the configured
+/// bucket ranges of a rule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriftConfig {
+    /// Rules with fewer observed resolves than this are not evaluated.
+    pub min_samples: i64,
+    /// Upper-tail standard normal quantile used to derive the chi-squared critical value.
+    /// The default of 3.09 corresponds to a significance level of roughly 0.001.
+    pub z_threshold: f64,
+}
+
+impl Default for DriftConfig {
+    fn default() -> Self {
+        DriftConfig {
+            min_samples: 1000,
+            z_threshold: 3.09,
+        }
+    }
+}
+
+/// Result of a goodness-of-fit test for a single rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleDrift {
+    pub flag: String,
+    pub rule: String,
+    pub samples: i64,
+    pub chi_squared: f64,
+    pub degrees_of_freedom: usize,
+    pub critical_value: f64,
+    pub drifted: bool,
+}
+
+/// Compares the per-assignment counters of a resolve log checkpoint with the proportions
+/// implied by each rule's bucket ranges.
+///
+/// Only rules that are present in both `logs` and `state`, have at least two assignments
+/// with a non-empty bucket range and have reached `config.min_samples` resolves are reported.
+/// Rules that read a materialization are skipped, since units in it keep their assignment
+/// whatever their bucket.
+/// A rule is flagged as `drifted` when its chi-squared statistic exceeds the critical value,
+/// which typically points to a salt/hash mismatch or a truncated bitset.
+pub fn detect_drift(
+    state: &ResolverState,
+    logs: &WriteFlagLogsRequest,
+    config: &DriftConfig,
+) -> Vec<RuleDrift> {
+    let mut report = Vec::new();
+    for flag_info in &logs.flag_resolve_info {
+        let Some(flag) = state.flags.get(&flag_info.flag) else {
+            continue;
+        };
+        for rule_info in &flag_info.rule_resolve_info {
+            let Some(rule) = flag.rules.iter().find(|r| r.name == rule_info.rule) else {
+                continue;
+            };
+            let reads_materialization = rule
+                .materialization_spec
+                .as_ref()
+                .is_some_and(|spec| !spec.read_materialization.is_empty());
+            if reads_materialization {
+                continue;
+            }
+            if let Some(drift) = rule_drift(&flag.name, rule, rule_info, config) {
+                report.push(drift);
+            }
+        }
+    }
+    report
+}
+
+/// Adds the rules of `logs` that drifted according to [`detect_drift`] to its
+/// `TelemetryData::rule_drift`, for hosts to call on their checkpoints before sending them.
+/// Does nothing unless the check is turned on with [`ResolverConfig::drift`](crate::ResolverConfig)
+/// of `state`.
+pub fn report_drift(state: &ResolverState, logs: &mut WriteFlagLogsRequest) {
+    let Some(config) = &state.config.drift else {
+        return;
+    };
+    let drifted: Vec<_> = detect_drift(state, logs, config)
+        .into_iter()
+        .filter(|drift| drift.drifted)
+        .map(|drift| telemetry_data::RuleDrift {
+            flag: drift.flag,
+            rule: drift.rule,
+            samples: drift.samples,
+            chi_squared: drift.chi_squared,
+            degrees_of_freedom: drift.degrees_of_freedom as i64,
+            critical_value: drift.critical_value,
+        })
+        .collect();
+    if !drifted.is_empty() {
+        logs.telemetry_data
+            .get_or_insert_with(TelemetryData::default)
+            .rule_drift
+            .extend(drifted);
+    }
+}
+
+fn rule_drift(
+    flag: &str,
+    rule: &Rule,
+    rule_info: &RuleResolveInfo,
+    config: &DriftConfig,
+) -> Option<RuleDrift> {
+    let expected = expected_proportions(rule);
+    if expected.len() < 2 {
+        return None;
+    }
+
+    let mut observed: HashMap<&str, i64> = HashMap::new();
+    for info in &rule_info.assignment_resolve_info {
+        let count = observed.entry(info.assignment_id.as_str()).or_insert(0);
+        *count = count.saturating_add(info.count);
+    }
+    let samples = expected
+        .keys()
+        .map(|id| observed.get(id).copied().unwrap_or(0))
+        .fold(0i64, i64::saturating_add);
+    if samples <= 0 || samples < config.min_samples {
+        return None;
+    }
+
+    let chi_squared = expected
+        .iter()
+        .map(|(id, proportion)| {
+            let expected_count = proportion * samples as f64;
+            let diff = observed.get(id).copied().unwrap_or(0) as f64 - expected_count;
+            diff * diff / expected_count
+        })
+        .sum::<f64>();
+    let degrees_of_freedom = expected.len().saturating_sub(1);
+    let critical_value = chi_squared_critical_value(degrees_of_freedom, config.z_threshold);
+
+    Some(RuleDrift {
+        flag: flag.to_string(),
+        rule: rule.name.clone(),
+        samples,
+        chi_squared,
+        degrees_of_freedom,
+        critical_value,
+        drifted: chi_squared > critical_value,
+    })
+}
+
+/// Share of the covered buckets held by each assignment. Buckets not covered by any
+/// assignment never produce a count, so they are excluded from the denominator.
+fn expected_proportions(rule: &Rule) -> HashMap<&str, f64> {
+    let mut widths: HashMap<&str, i64> = HashMap::new();
+    let Some(spec) = &rule.assignment_spec else {
+        return HashMap::new();
+    };
+    for assignment in &spec.assignments {
+        let width = assignment
+            .bucket_ranges
+            .iter()
+            .map(|range| {
+                i64::from(range.upper)
+                    .saturating_sub(i64::from(range.lower))
+                    .max(0)
+            })
+            .fold(0i64, i64::saturating_add);
+        if width > 0 {
+            let total = widths.entry(assignment.assignment_id.as_str()).or_insert(0);
+            *total = total.saturating_add(width);
+        }
+    }
+    let covered = widths.values().copied().fold(0i64, i64::saturating_add);
+    widths
+        .into_iter()
+        .map(|(id, width)| (id, width as f64 / covered as f64))
+        .collect()
+}
+
+/// Wilson–Hilferty approximation of the upper chi-squared quantile.
+pub(crate) fn chi_squared_critical_value(degrees_of_freedom: usize, z: f64) -> f64 {
+    let k = degrees_of_freedom as f64;
+    let a = 2.0 / (9.0 * k);
+    k * (1.0 - a + z * a.sqrt()).powi(3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::admin::v1::flag::rule::{
+        assignment, Assignment, AssignmentSpec, BucketRange, MaterializationSpec,
+    };
+    use crate::proto::confidence::flags::admin::v1::flag_resolve_info::AssignmentResolveInfo;
+    use crate::proto::confidence::flags::admin::v1::{Flag, FlagResolveInfo};
+    use std::sync::Arc;
+
+    fn state_with_split(lower: i32, upper: i32) -> ResolverState {
+        let assignment = |id: &str, lower: i32, upper: i32| Assignment {
+            assignment_id: id.to_string(),
+            assignment: Some(assignment::Assignment::Fallthrough(Default::default())),
+            bucket_ranges: vec![BucketRange { lower, upper }],
+        };
+        let flag = Flag {
+            name: "flags/drift".to_string(),
+            rules: vec![Rule {
+                name: "flags/drift/rules/r1".to_string(),
+                assignment_spec: Some(AssignmentSpec {
+                    bucket_count: 100,
+                    assignments: vec![assignment("a", lower, upper), assignment("b", upper, 100)],
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        ResolverState {
+            secrets: HashMap::new(),
+            flags: Arc::new(HashMap::from([(flag.name.clone(), flag)])),
+            segments: Default::default(),
+            bitsets: Default::default(),
+            external_segments: Default::default(),
+            encryption_keys: Default::default(),
+            config: Default::default(),
+            kill_switches: Vec::new(),
+            fingerprint: String::new(),
+            decrypt_breaker: None,
+            derived: Default::default(),
+        }
+    }
+
+    fn logs(a: i64, b: i64) -> WriteFlagLogsRequest {
+        WriteFlagLogsRequest {
+            flag_resolve_info: vec![FlagResolveInfo {
+                flag: "flags/drift".to_string(),
+                rule_resolve_info: vec![RuleResolveInfo {
+                    rule: "flags/drift/rules/r1".to_string(),
+                    count: a + b,
+                    assignment_resolve_info: vec![
+                        AssignmentResolveInfo {
+                            assignment_id: "a".to_string(),
+                            count: a,
+                        },
+                        AssignmentResolveInfo {
+                            assignment_id: "b".to_string(),
+                            count: b,
+                        },
+                    ],
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn balanced_counts_do_not_drift() {
+        let state = state_with_split(0, 50);
+        let report = detect_drift(&state, &logs(5020, 4980), &DriftConfig::default());
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].degrees_of_freedom, 1);
+        assert!(!report[0].drifted);
+    }
+
+    #[test]
+    fn skewed_counts_drift() {
+        let state = state_with_split(0, 50);
+        let report = detect_drift(&state, &logs(6000, 4000), &DriftConfig::default());
+        assert_eq!(report.len(), 1);
+        assert!(report[0].drifted);
+    }
+
+    #[test]
+    fn uneven_split_uses_bucket_widths() {
+        let state = state_with_split(0, 10);
+        let report = detect_drift(&state, &logs(1000, 9000), &DriftConfig::default());
+        assert!(!report[0].drifted);
+    }
+
+    #[test]
+    fn drifted_rules_are_reported_in_telemetry() {
+        let mut state = state_with_split(0, 50);
+        // the check is off by default
+        let mut skewed = logs(6000, 4000);
+        report_drift(&state, &mut skewed);
+        assert!(skewed.telemetry_data.is_none());
+
+        state.config.drift = Some(DriftConfig::default());
+        let mut balanced = logs(5020, 4980);
+        report_drift(&state, &mut balanced);
+        assert!(balanced.telemetry_data.is_none());
+
+        report_drift(&state, &mut skewed);
+        let drift = &skewed.telemetry_data.unwrap().rule_drift;
+        assert_eq!(drift.len(), 1);
+        assert_eq!(drift[0].rule, "flags/drift/rules/r1");
+        assert_eq!(drift[0].samples, 10000);
+        assert_eq!(drift[0].degrees_of_freedom, 1);
+        assert!(drift[0].chi_squared > drift[0].critical_value);
+    }
+
+    #[test]
+    fn rules_reading_materializations_are_skipped() {
+        let mut state = state_with_split(0, 50);
+        Arc::make_mut(&mut state.flags)
+            .get_mut("flags/drift")
+            .unwrap()
+            .rules[0]
+            .materialization_spec = Some(MaterializationSpec {
+            read_materialization: "materializations/sticky".to_string(),
+            ..Default::default()
+        });
+        let report = detect_drift(&state, &logs(6000, 4000), &DriftConfig::default());
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn too_few_samples_are_skipped() {
+        let state = state_with_split(0, 50);
+        let report = detect_drift(&state, &logs(90, 10), &DriftConfig::default());
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn critical_value_is_close_to_table() {
+        // chi-squared with 1 dof at p=0.001 is 10.83
+        let v = chi_squared_critical_value(1, 3.09);
+        assert!((v - 10.83).abs() < 0.5, "{}", v);
+    }
+}
@@ -0,0 +1,359 @@
+//! Pluggable encryption of resolve tokens. Without a provider, tokens are encrypted with
+//! AES-CBC by [`Host::encrypt_resolve_token`]. A host that returns a provider from
+//! [`Host::encryption_provider`] has new tokens encrypted by it instead.
+//!
+//! Every token records the [`EncryptionProvider::key_id`] it was sealed with, so tokens of an
+//! earlier provider still open after the host switches to another one, as long as that
+//! provider is built in:
+//!
+//! - [`AesGcm`], AES-GCM with a 16 or 32 byte key, with the `aes-gcm` feature.
+//! - [`ChaCha20Poly1305`], with a 32 byte key, with the `chacha20poly1305` feature.
+//!
+//! Both are pure Rust and work without `std`, so WASM and embedded hosts can encrypt tokens
+//! too. Their nonces come from [`Host::random_bytes`], which no_std hosts must back with a
+//! cryptographically secure random number generator.
+//!
+//! - [`NullEncryption`], which leaves tokens in plaintext, for no_std hosts without a cipher.
+//! - [`HmacSha256`], which leaves tokens in plaintext but signs them, with the `std` feature.
+//!   Resolvers choose it per resolver with
+//!   [`AccountResolver::with_signed_tokens`](crate::AccountResolver::with_signed_tokens).
+//!
+//! [`Host::encrypt_resolve_token`]: crate::Host::encrypt_resolve_token
+//! [`Host::encryption_provider`]: crate::Host::encryption_provider
+//! [`Host::random_bytes`]: crate::Host::random_bytes
+
+use crate::EncryptionKey;
+
+/// Tokens in plaintext, see [`NullEncryption`].
+pub const KEY_ID_PLAINTEXT: u8 = 0;
+/// Tokens encrypted by [`Host::encrypt_resolve_token`](crate::Host::encrypt_resolve_token).
+pub const KEY_ID_HOST: u8 = 1;
+pub const KEY_ID_AES_GCM: u8 = 2;
+pub const KEY_ID_CHACHA20_POLY1305: u8 = 3;
+pub const KEY_ID_HMAC_SHA256: u8 = 4;
+/// Key ids below this are reserved for the built-in providers.
+pub const FIRST_CUSTOM_KEY_ID: u8 = 128;
+
+/// Encrypts and decrypts resolve tokens with the key of the client that resolved them.
+pub trait EncryptionProvider: Send + Sync {
+    /// Identifies the provider in the header of the tokens it encrypts. Custom providers use
+    /// ids from [`FIRST_CUSTOM_KEY_ID`] up.
+    fn key_id(&self) -> u8;
+
+    fn encrypt(&self, token: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String>;
+
+    /// Like [`EncryptionProvider::encrypt`], for providers that need a nonce, with random bytes
+    /// from [`Host::random_bytes`](crate::Host::random_bytes). Resolvers encrypt tokens with
+    /// this. The default ignores the nonce.
+    fn encrypt_with_nonce(
+        &self,
+        token: &[u8],
+        key: &EncryptionKey,
+        nonce: &[u8; NONCE_LEN],
+    ) -> Result<Vec<u8>, String> {
+        let _ = nonce;
+        self.encrypt(token, key)
+    }
+
+    fn decrypt(&self, encrypted: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String>;
+
+    /// Whether decrypting authenticates tokens, so that a forged token fails to decrypt. While
+    /// the host's provider is one, tokens of the host's own encryption are rejected, see
+    /// [`ResolverConfig::legacy_resolve_tokens_until`](crate::ResolverConfig).
+    fn is_aead(&self) -> bool {
+        false
+    }
+}
+
+/// Doesn't encrypt at all. Tokens are written as plaintext tokens and, while the host uses this
+/// provider, accepted even though the resolver has an encryption key.
+pub struct NullEncryption;
+
+impl EncryptionProvider for NullEncryption {
+    fn key_id(&self) -> u8 {
+        KEY_ID_PLAINTEXT
+    }
+
+    fn encrypt(&self, token: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>, String> {
+        Ok(token.to_vec())
+    }
+
+    fn decrypt(&self, encrypted: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>, String> {
+        Ok(encrypted.to_vec())
+    }
+}
+
+/// The length of the random nonce that starts tokens encrypted by the AEAD providers.
+pub const NONCE_LEN: usize = 12;
+
+#[cfg(all(
+    feature = "std",
+    any(feature = "aes-gcm", feature = "chacha20poly1305")
+))]
+fn random_nonce() -> Result<[u8; NONCE_LEN], String> {
+    use rand::RngCore;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    Ok(nonce)
+}
+
+#[cfg(all(
+    not(feature = "std"),
+    any(feature = "aes-gcm", feature = "chacha20poly1305")
+))]
+fn random_nonce() -> Result<[u8; NONCE_LEN], String> {
+    Err("without the std feature tokens are encrypted with encrypt_with_nonce".to_string())
+}
+
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+fn split_nonce(encrypted: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    if encrypted.len() < NONCE_LEN {
+        return Err("encrypted resolve token is truncated".to_string());
+    }
+    Ok(encrypted.split_at(NONCE_LEN))
+}
+
+/// AES-GCM with a random 96 bit nonce per token, AES-128 or AES-256 depending on the key.
+#[cfg(feature = "aes-gcm")]
+pub struct AesGcm;
+
+#[cfg(feature = "aes-gcm")]
+impl EncryptionProvider for AesGcm {
+    fn key_id(&self) -> u8 {
+        KEY_ID_AES_GCM
+    }
+
+    fn is_aead(&self) -> bool {
+        true
+    }
+
+    fn encrypt(&self, token: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String> {
+        self.encrypt_with_nonce(token, key, &random_nonce()?)
+    }
+
+    fn encrypt_with_nonce(
+        &self,
+        token: &[u8],
+        key: &EncryptionKey,
+        nonce: &[u8; NONCE_LEN],
+    ) -> Result<Vec<u8>, String> {
+        use aes_gcm::aead::{Aead, KeyInit};
+        use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+
+        let key = key.as_bytes();
+        let encrypted = if key.len() == 16 {
+            Aes128Gcm::new_from_slice(key).map(|c| c.encrypt(Nonce::from_slice(nonce), token))
+        } else {
+            Aes256Gcm::new_from_slice(key).map(|c| c.encrypt(Nonce::from_slice(nonce), token))
+        };
+        let encrypted = encrypted
+            .map_err(|_| "invalid AES-GCM key".to_string())?
+            .map_err(|_| "failed to encrypt resolve token".to_string())?;
+        Ok([nonce.as_slice(), &encrypted].concat())
+    }
+
+    fn decrypt(&self, encrypted: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String> {
+        use aes_gcm::aead::{Aead, KeyInit};
+        use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+
+        let (nonce, encrypted) = split_nonce(encrypted)?;
+        let key = key.as_bytes();
+        let token = if key.len() == 16 {
+            Aes128Gcm::new_from_slice(key).map(|c| c.decrypt(Nonce::from_slice(nonce), encrypted))
+        } else {
+            Aes256Gcm::new_from_slice(key).map(|c| c.decrypt(Nonce::from_slice(nonce), encrypted))
+        };
+        token
+            .map_err(|_| "invalid AES-GCM key".to_string())?
+            .map_err(|_| "failed to decrypt resolve token".to_string())
+    }
+}
+
+/// ChaCha20-Poly1305 with a random 96 bit nonce per token. Needs a 32 byte key.
+#[cfg(feature = "chacha20poly1305")]
+pub struct ChaCha20Poly1305;
+
+#[cfg(feature = "chacha20poly1305")]
+impl EncryptionProvider for ChaCha20Poly1305 {
+    fn key_id(&self) -> u8 {
+        KEY_ID_CHACHA20_POLY1305
+    }
+
+    fn is_aead(&self) -> bool {
+        true
+    }
+
+    fn encrypt(&self, token: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String> {
+        self.encrypt_with_nonce(token, key, &random_nonce()?)
+    }
+
+    fn encrypt_with_nonce(
+        &self,
+        token: &[u8],
+        key: &EncryptionKey,
+        nonce: &[u8; NONCE_LEN],
+    ) -> Result<Vec<u8>, String> {
+        use chacha20poly1305::aead::{Aead, KeyInit};
+        use chacha20poly1305::Nonce;
+
+        let cipher = chacha20poly1305::ChaCha20Poly1305::new_from_slice(key.as_bytes())
+            .map_err(|_| "ChaCha20-Poly1305 needs a 32 byte key".to_string())?;
+        let encrypted = cipher
+            .encrypt(Nonce::from_slice(nonce), token)
+            .map_err(|_| "failed to encrypt resolve token".to_string())?;
+        Ok([nonce.as_slice(), &encrypted].concat())
+    }
+
+    fn decrypt(&self, encrypted: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String> {
+        use chacha20poly1305::aead::{Aead, KeyInit};
+        use chacha20poly1305::Nonce;
+
+        let cipher = chacha20poly1305::ChaCha20Poly1305::new_from_slice(key.as_bytes())
+            .map_err(|_| "ChaCha20-Poly1305 needs a 32 byte key".to_string())?;
+        let (nonce, encrypted) = split_nonce(encrypted)?;
+        cipher
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .map_err(|_| "failed to decrypt resolve token".to_string())
+    }
+}
+
+/// The length of the signature that ends tokens signed by [`HmacSha256`].
+#[cfg(feature = "std")]
+const SIGNATURE_LEN: usize = 32;
+
+/// Doesn't encrypt, but appends an HMAC-SHA256 signature of the token keyed with the resolver's
+/// key, for deployments that need tokens to be tamper-evident but not confidential. The zero
+/// key is rejected, as anyone could sign with it.
+#[cfg(feature = "std")]
+pub struct HmacSha256;
+
+#[cfg(feature = "std")]
+impl HmacSha256 {
+    fn mac(token: &[u8], key: &EncryptionKey) -> Result<hmac::Hmac<sha2::Sha256>, String> {
+        use hmac::Mac;
+
+        if key.is_zero() {
+            return Err("signed resolve tokens need a non-zero key".to_string());
+        }
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key.as_bytes())
+            .map_err(|_| "invalid HMAC key".to_string())?;
+        mac.update(token);
+        Ok(mac)
+    }
+}
+
+#[cfg(feature = "std")]
+impl EncryptionProvider for HmacSha256 {
+    fn key_id(&self) -> u8 {
+        KEY_ID_HMAC_SHA256
+    }
+
+    fn encrypt(&self, token: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String> {
+        use hmac::Mac;
+
+        let signature = HmacSha256::mac(token, key)?.finalize().into_bytes();
+        Ok([token, signature.as_slice()].concat())
+    }
+
+    fn decrypt(&self, signed: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String> {
+        use hmac::Mac;
+
+        let Some(split) = signed.len().checked_sub(SIGNATURE_LEN) else {
+            return Err("signed resolve token is truncated".to_string());
+        };
+        let (token, signature) = signed.split_at(split);
+        // compares in constant time
+        HmacSha256::mac(token, key)?
+            .verify_slice(signature)
+            .map_err(|_| "resolve token signature is invalid".to_string())?;
+        Ok(token.to_vec())
+    }
+}
+
+/// The built-in provider with `key_id`, if its feature is enabled. Used to open tokens that
+/// were encrypted before the host switched providers, and signed tokens.
+pub fn builtin(key_id: u8) -> Option<&'static dyn EncryptionProvider> {
+    match key_id {
+        #[cfg(feature = "aes-gcm")]
+        KEY_ID_AES_GCM => Some(&AesGcm),
+        #[cfg(feature = "chacha20poly1305")]
+        KEY_ID_CHACHA20_POLY1305 => Some(&ChaCha20Poly1305),
+        #[cfg(feature = "std")]
+        KEY_ID_HMAC_SHA256 => Some(&HmacSha256),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+    fn assert_round_trips(provider: &dyn EncryptionProvider, key: &EncryptionKey) {
+        let encrypted = provider
+            .encrypt_with_nonce(b"token", key, &[3; NONCE_LEN])
+            .unwrap();
+        assert_eq!(encrypted.get(..NONCE_LEN), Some([3; NONCE_LEN].as_slice()));
+        assert_ne!(encrypted.get(NONCE_LEN..), Some(b"token".as_slice()));
+        assert_eq!(provider.decrypt(&encrypted, key).unwrap(), b"token");
+
+        let encrypted = provider.encrypt(b"token", key).unwrap();
+        assert_ne!(encrypted.get(NONCE_LEN..), Some(b"token".as_slice()));
+        assert_eq!(provider.decrypt(&encrypted, key).unwrap(), b"token");
+
+        // tampered tokens and other keys fail authentication
+        let mut tampered = encrypted.clone();
+        if let Some(last) = tampered.last_mut() {
+            *last ^= 1;
+        }
+        assert!(provider.decrypt(&tampered, key).is_err());
+        let other = EncryptionKey::try_from(vec![9; key.as_bytes().len()].as_slice()).unwrap();
+        assert!(provider.decrypt(&encrypted, &other).is_err());
+        assert!(provider.decrypt(&encrypted[..4], key).is_err());
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[test]
+    fn aes_gcm_round_trips() {
+        assert_round_trips(&AesGcm, &EncryptionKey::aes128([7; 16]));
+        assert_round_trips(&AesGcm, &EncryptionKey::aes256([7; 32]));
+    }
+
+    #[cfg(feature = "chacha20poly1305")]
+    #[test]
+    fn chacha20_poly1305_round_trips() {
+        assert_round_trips(&ChaCha20Poly1305, &EncryptionKey::aes256([7; 32]));
+        let err = ChaCha20Poly1305
+            .encrypt(b"token", &EncryptionKey::aes128([7; 16]))
+            .unwrap_err();
+        assert!(err.contains("32 byte key"), "{}", err);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn hmac_sha256_signs_tokens() {
+        let key = EncryptionKey::aes128([7; 16]);
+        let signed = HmacSha256.encrypt(b"token", &key).unwrap();
+        assert_eq!(signed.len(), 5 + SIGNATURE_LEN);
+        assert_eq!(signed.get(..5), Some(b"token".as_slice()));
+        assert_eq!(HmacSha256.decrypt(&signed, &key).unwrap(), b"token");
+
+        let mut tampered = signed.clone();
+        tampered[0] ^= 1;
+        let err = HmacSha256.decrypt(&tampered, &key).unwrap_err();
+        assert!(err.contains("signature is invalid"), "{}", err);
+        let other = EncryptionKey::aes128([9; 16]);
+        assert!(HmacSha256.decrypt(&signed, &other).is_err());
+        assert!(HmacSha256.decrypt(&signed[..4], &key).is_err());
+        assert!(HmacSha256.encrypt(b"token", &EncryptionKey::ZERO).is_err());
+    }
+
+    #[test]
+    fn null_encryption_keeps_tokens() {
+        let key = EncryptionKey::aes128([7; 16]);
+        assert_eq!(NullEncryption.encrypt(b"token", &key).unwrap(), b"token");
+        assert_eq!(NullEncryption.decrypt(b"token", &key).unwrap(), b"token");
+        assert!(builtin(KEY_ID_PLAINTEXT).is_none());
+    }
+}
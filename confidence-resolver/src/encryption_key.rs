@@ -0,0 +1,157 @@
+//! Resolve token encryption keys. Keys are validated when they are created, so a key of the
+//! wrong length fails where it enters the resolver rather than inside the crypto code, and
+//! they are wiped from memory when dropped.
+//!
+//! Keys can be given an id, which is written into the tokens they encrypt, so that a resolver
+//! holding the keys of a rotation knows which one decrypts a token.
+
+use core::fmt;
+
+use zeroize::Zeroize;
+
+/// An AES-128 or AES-256 key for resolve tokens. The all-zero key, [`EncryptionKey::ZERO`],
+/// means the resolver doesn't encrypt resolve tokens.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    key: Key,
+    id: Option<u32>,
+}
+
+#[derive(Clone)]
+enum Key {
+    Aes128([u8; 16]),
+    Aes256([u8; 32]),
+}
+
+impl EncryptionKey {
+    /// The all-zero 16 byte key.
+    pub const ZERO: EncryptionKey = EncryptionKey::aes128([0; 16]);
+
+    pub const fn aes128(key: [u8; 16]) -> Self {
+        EncryptionKey {
+            key: Key::Aes128(key),
+            id: None,
+        }
+    }
+
+    pub const fn aes256(key: [u8; 32]) -> Self {
+        EncryptionKey {
+            key: Key::Aes256(key),
+            id: None,
+        }
+    }
+
+    /// The key with `id`, see [`EncryptionKey::id`].
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Identifies the key in the resolve tokens it encrypts, see
+    /// [`AccountResolver::with_secondary_keys`](crate::AccountResolver::with_secondary_keys).
+    pub fn id(&self) -> Option<u32> {
+        self.id
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match &self.key {
+            Key::Aes128(key) => key,
+            Key::Aes256(key) => key,
+        }
+    }
+
+    /// Whether this is an all-zero key, i.e. resolve tokens aren't encrypted.
+    pub fn is_zero(&self) -> bool {
+        self.as_bytes().iter().all(|&b| b == 0)
+    }
+}
+
+impl TryFrom<&[u8]> for EncryptionKey {
+    type Error = String;
+
+    fn try_from(key: &[u8]) -> Result<Self, String> {
+        if let Ok(key) = <[u8; 16]>::try_from(key) {
+            Ok(EncryptionKey::aes128(key))
+        } else if let Ok(key) = <[u8; 32]>::try_from(key) {
+            Ok(EncryptionKey::aes256(key))
+        } else {
+            Err(format!(
+                "encryption key must be 16 or 32 bytes, got {}",
+                key.len()
+            ))
+        }
+    }
+}
+
+impl PartialEq for EncryptionKey {
+    /// Compares in time independent of where the keys differ.
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b) = (self.as_bytes(), other.as_bytes());
+        self.id == other.id
+            && a.len() == b.len()
+            && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+}
+
+impl Eq for EncryptionKey {}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id {
+            Some(id) => write!(
+                f,
+                "EncryptionKey({} bytes, id {}, redacted)",
+                self.as_bytes().len(),
+                id
+            ),
+            None => write!(
+                f,
+                "EncryptionKey({} bytes, redacted)",
+                self.as_bytes().len()
+            ),
+        }
+    }
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        match &mut self.key {
+            Key::Aes128(key) => key.zeroize(),
+            Key::Aes256(key) => key.zeroize(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_length() {
+        assert_eq!(
+            EncryptionKey::try_from(&[7u8; 16][..]),
+            Ok(EncryptionKey::aes128([7; 16]))
+        );
+        assert_eq!(
+            EncryptionKey::try_from(&[7u8; 32][..]),
+            Ok(EncryptionKey::aes256([7; 32]))
+        );
+        let err = EncryptionKey::try_from(&[7u8; 8][..]).unwrap_err();
+        assert!(err.contains("got 8"), "{}", err);
+    }
+
+    #[test]
+    fn zero_key_and_redacted_debug() {
+        assert!(EncryptionKey::ZERO.is_zero());
+        assert!(!EncryptionKey::aes128([7; 16]).is_zero());
+        assert_ne!(EncryptionKey::ZERO, EncryptionKey::aes256([0; 32]));
+        let debug = format!("{:?}", EncryptionKey::aes128([7; 16]));
+        assert_eq!(debug, "EncryptionKey(16 bytes, redacted)");
+        let with_id = EncryptionKey::aes128([7; 16]).with_id(3);
+        assert_eq!(
+            format!("{:?}", with_id),
+            "EncryptionKey(16 bytes, id 3, redacted)"
+        );
+        assert_ne!(with_id, EncryptionKey::aes128([7; 16]));
+    }
+}
@@ -0,0 +1,360 @@
+//! A structured trace of how a flag resolves for the evaluation context of a resolver, for
+//! debugging targeting without reverse-engineering the resolve logic. See
+//! [`AccountResolver::explain_flag`].
+//!
+//! The rules are walked the way a resolve walks them, but each step records what it found
+//! rather than only the outcome: the unit, whether the targeting and bitset of the segment
+//! matched, each criterion of the targeting on its own, and the bucket of the unit. Nothing
+//! is logged and no materializations are read or written.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::err::{Fallible, OrFailExt};
+use crate::proto::confidence::flags::admin::v1::flag::{rule, Rule};
+use crate::proto::confidence::flags::admin::v1::{Flag, Segment};
+use crate::{flags_admin, hashing, AccountResolver, Host, ResolveMemo, ResolveReason};
+
+/// How a flag resolved, rule by rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlagExplanation {
+    pub flag: String,
+    /// [`ResolveReason::Match`] if a rule assigned a variant or the client default,
+    /// [`ResolveReason::FlagKilled`] and [`ResolveReason::FlagArchived`] if no rule was
+    /// evaluated, and [`ResolveReason::NoSegmentMatch`] otherwise.
+    pub reason: ResolveReason,
+    /// The rules evaluated, in order. The last one assigned the flag if `reason` is a match.
+    pub rules: Vec<RuleExplanation>,
+}
+
+impl FlagExplanation {
+    /// The rule that assigned the flag.
+    pub fn matched_rule(&self) -> Option<&RuleExplanation> {
+        self.rules
+            .last()
+            .filter(|_| self.reason == ResolveReason::Match)
+    }
+}
+
+/// What happened when a rule was evaluated. The fields are set as far as evaluation got, e.g.
+/// a rule whose segment didn't match has no bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleExplanation {
+    pub rule: String,
+    pub outcome: RuleOutcome,
+    /// The unit, read from the context with the targeting key selector of the rule.
+    pub targeting_key: Option<String>,
+    pub segment: Option<SegmentExplanation>,
+    /// The bucket of the unit among the buckets of the assignment spec of the rule.
+    pub bucket: Option<i32>,
+    /// The assignment covering `bucket`.
+    pub assignment_id: Option<String>,
+    /// The variant assigned, for [`RuleOutcome::Variant`].
+    pub variant: Option<String>,
+}
+
+/// Why a rule did or didn't assign the flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleOutcome {
+    /// The rule is disabled.
+    Disabled,
+    /// The segment of the rule is not in the resolver state.
+    SegmentNotFound,
+    /// The context has no targeting key for the rule.
+    MissingTargetingKey,
+    /// The targeting key is neither a string nor an integer, which fails the resolve.
+    InvalidTargetingKey,
+    /// The rule reads a materialization, which isn't available when explaining.
+    ReadsMaterialization,
+    /// The rule has no assignment spec.
+    MissingAssignmentSpec,
+    /// The unit isn't in the segment, see [`RuleExplanation::segment`] for why.
+    SegmentMismatch,
+    /// No assignment of the rule covers the bucket of the unit.
+    BucketNotAssigned,
+    /// The assignment covering the bucket assigns nothing.
+    EmptyAssignment,
+    /// The unit was bucketed into a fallthrough assignment, evaluation went on.
+    Fallthrough,
+    /// The flag resolved to the client default.
+    ClientDefault,
+    /// The flag resolved to [`RuleExplanation::variant`].
+    Variant,
+}
+
+/// How the unit matched the segment of a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentExplanation {
+    pub segment: String,
+    /// Whether the targeting of the segment, including the segments it refers to, matched.
+    pub targeting_matched: bool,
+    /// Each criterion of the targeting by id, and whether it matched on its own.
+    pub criteria: BTreeMap<String, bool>,
+    /// The membership of the unit in the bitset of the segment, `None` if the segment has no
+    /// bitset, which contains every unit.
+    pub bitset: Option<BitsetExplanation>,
+    /// The answer of [`Host::segment_membership`] for an external segment, `None` for other
+    /// segments.
+    pub external_member: Option<bool>,
+}
+
+impl SegmentExplanation {
+    /// Whether the unit is in the segment, as evaluated by a resolve.
+    pub fn matched(&self) -> bool {
+        self.targeting_matched
+            && self.bitset.as_ref().is_none_or(|bitset| bitset.member)
+            && self.external_member.unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitsetExplanation {
+    /// The bucket of the unit, out of [`hashing::SEGMENT_BUCKETS`].
+    pub bucket: usize,
+    /// Whether the bit of the bucket is set.
+    pub member: bool,
+}
+
+pub(crate) fn explain_flag<H: Host>(
+    resolver: &AccountResolver<'_, H>,
+    flag: &Flag,
+) -> Fallible<FlagExplanation> {
+    let mut explanation = FlagExplanation {
+        flag: flag.name.clone(),
+        reason: ResolveReason::NoSegmentMatch,
+        rules: vec![],
+    };
+    if resolver
+        .state
+        .kill_switch(&flag.name, &resolver.client.client_name)
+        .is_some()
+    {
+        explanation.reason = ResolveReason::FlagKilled;
+        return Ok(explanation);
+    }
+    if flag.state == flags_admin::flag::State::Archived as i32 {
+        explanation.reason = ResolveReason::FlagArchived;
+        return Ok(explanation);
+    }
+
+    let mut memo = ResolveMemo::default();
+    for rule in &flag.rules {
+        let rule_explanation = explain_rule(resolver, flag, rule, &mut memo)?;
+        let outcome = rule_explanation.outcome;
+        explanation.rules.push(rule_explanation);
+        match outcome {
+            RuleOutcome::ClientDefault | RuleOutcome::Variant => {
+                explanation.reason = ResolveReason::Match;
+                break;
+            }
+            RuleOutcome::InvalidTargetingKey => {
+                explanation.reason = ResolveReason::TargetingKeyError;
+                break;
+            }
+            _ => {}
+        }
+    }
+    Ok(explanation)
+}
+
+fn explain_rule<H: Host>(
+    resolver: &AccountResolver<'_, H>,
+    flag: &Flag,
+    rule: &Rule,
+    memo: &mut ResolveMemo,
+) -> Fallible<RuleExplanation> {
+    let mut explanation = RuleExplanation {
+        rule: rule.name.clone(),
+        outcome: RuleOutcome::Disabled,
+        targeting_key: None,
+        segment: None,
+        bucket: None,
+        assignment_id: None,
+        variant: None,
+    };
+    if !rule.enabled {
+        return Ok(explanation);
+    }
+    let Some(segment) = resolver.state.segments.get(&rule.segment) else {
+        explanation.outcome = RuleOutcome::SegmentNotFound;
+        return Ok(explanation);
+    };
+    let selector = resolver.state.targeting_key_selector(flag, rule);
+    let unit = match resolver.get_targeting_key(selector) {
+        Ok(Some(unit)) => unit,
+        Ok(None) => {
+            explanation.outcome = RuleOutcome::MissingTargetingKey;
+            return Ok(explanation);
+        }
+        Err(_) => {
+            explanation.outcome = RuleOutcome::InvalidTargetingKey;
+            return Ok(explanation);
+        }
+    };
+    explanation.targeting_key = Some(unit.clone());
+    let Some(spec) = &rule.assignment_spec else {
+        explanation.outcome = RuleOutcome::MissingAssignmentSpec;
+        return Ok(explanation);
+    };
+    if rule
+        .materialization_spec
+        .as_ref()
+        .is_some_and(|spec| !spec.read_materialization.is_empty())
+    {
+        explanation.outcome = RuleOutcome::ReadsMaterialization;
+        return Ok(explanation);
+    }
+
+    let segment_explanation = explain_segment(resolver, segment, &unit, memo)?;
+    let matched = segment_explanation.matched();
+    explanation.segment = Some(segment_explanation);
+    if !matched {
+        explanation.outcome = RuleOutcome::SegmentMismatch;
+        return Ok(explanation);
+    }
+
+    let bucket_count = u64::try_from(spec.bucket_count).unwrap_or_default();
+    let bucket = hashing::assignment_bucket(&segment.name, &unit, bucket_count).or_fail()?;
+    let bucket = i32::try_from(bucket).unwrap_or(i32::MAX);
+    explanation.bucket = Some(bucket);
+    let Some(assignment) = spec.assignments.iter().find(|assignment| {
+        assignment
+            .bucket_ranges
+            .iter()
+            .any(|range| range.lower <= bucket && bucket < range.upper)
+    }) else {
+        explanation.outcome = RuleOutcome::BucketNotAssigned;
+        return Ok(explanation);
+    };
+    explanation.assignment_id = Some(assignment.assignment_id.clone());
+    explanation.outcome = match &assignment.assignment {
+        None => RuleOutcome::EmptyAssignment,
+        Some(rule::assignment::Assignment::Fallthrough(_)) => RuleOutcome::Fallthrough,
+        Some(rule::assignment::Assignment::ClientDefault(_)) => RuleOutcome::ClientDefault,
+        Some(rule::assignment::Assignment::Variant(variant)) => {
+            explanation.variant = Some(variant.variant.clone());
+            RuleOutcome::Variant
+        }
+    };
+    Ok(explanation)
+}
+
+fn explain_segment<H: Host>(
+    resolver: &AccountResolver<'_, H>,
+    segment: &Segment,
+    unit: &str,
+    memo: &mut ResolveMemo,
+) -> Fallible<SegmentExplanation> {
+    let targeting_matched = resolver.targeting_match(segment, unit, &mut HashSet::new(), memo)?;
+    let mut criteria = BTreeMap::new();
+    if let Some(targeting) = &segment.targeting {
+        for (id, criterion) in &targeting.criteria {
+            let matched =
+                resolver.criterion_match(Some(criterion), unit, &mut HashSet::new(), memo)?;
+            criteria.insert(id.clone(), matched);
+        }
+    }
+    let bitset = resolver
+        .bitset_membership(segment, unit, memo)?
+        .map(|(bucket, member)| BitsetExplanation { bucket, member });
+    let external_member = resolver
+        .state
+        .external_segments
+        .contains(&segment.name)
+        .then(|| resolver.external_membership(segment, unit));
+    Ok(SegmentExplanation {
+        segment: segment.name.clone(),
+        targeting_matched,
+        criteria,
+        bitset,
+        external_member,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestHost;
+    use crate::{EncryptionKey, ResolverState};
+    use std::sync::Arc;
+
+    const SECRET: &str = "mkjJruAATQWjeY7foFIWfVAcBWnci2YF";
+
+    fn state() -> ResolverState {
+        ResolverState::from_proto(
+            include_bytes!("../test-payloads/resolver_state.pb")
+                .to_vec()
+                .try_into()
+                .unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap()
+    }
+
+    fn explain(state: &ResolverState, context_json: &str, flag: &str) -> FlagExplanation {
+        state
+            .get_resolver_with_json_context::<TestHost>(SECRET, context_json, &EncryptionKey::ZERO)
+            .unwrap()
+            .explain_flag(flag)
+            .unwrap()
+    }
+
+    #[test]
+    fn explains_fallthrough_and_match() {
+        let state = state();
+        let flag = "flags/fallthrough-test-2";
+        let explanation = explain(&state, r#"{"visitor_id": "26"}"#, flag);
+
+        assert_eq!(explanation.reason, ResolveReason::Match);
+        assert_eq!(explanation.rules.len(), 2);
+        let fallthrough = &explanation.rules[0];
+        assert_eq!(fallthrough.outcome, RuleOutcome::Fallthrough);
+        assert_eq!(fallthrough.targeting_key.as_deref(), Some("26"));
+        assert_eq!(fallthrough.assignment_id.as_deref(), Some("control"));
+        assert!(fallthrough.segment.as_ref().unwrap().matched());
+
+        // the explanation agrees with the resolve
+        let resolver = state
+            .get_resolver_with_json_context::<TestHost>(
+                SECRET,
+                r#"{"visitor_id": "26"}"#,
+                &EncryptionKey::ZERO,
+            )
+            .unwrap();
+        let resolved = resolver
+            .resolve_flag(&state.flags[flag], BTreeMap::new())
+            .unwrap()
+            .resolved_value;
+        let assignment = resolved.assignment_match.unwrap();
+        let matched = explanation.matched_rule().unwrap();
+        assert_eq!(matched.outcome, RuleOutcome::Variant);
+        assert_eq!(matched.rule, assignment.rule.name);
+        assert_eq!(
+            matched.variant.as_deref(),
+            assignment.variant.map(|v| v.name.as_str())
+        );
+    }
+
+    #[test]
+    fn explains_skipped_rules() {
+        let mut state = state();
+        let explanation = explain(&state, "{}", "flags/fallthrough-test-1");
+        assert_eq!(explanation.reason, ResolveReason::NoSegmentMatch);
+        assert_eq!(
+            explanation.rules[0].outcome,
+            RuleOutcome::MissingTargetingKey
+        );
+        assert!(explanation.matched_rule().is_none());
+
+        let flag = Arc::make_mut(&mut state.flags)
+            .get_mut("flags/fallthrough-test-1")
+            .unwrap();
+        flag.rules[0].enabled = false;
+        let explanation = explain(
+            &state,
+            r#"{"visitor_id": "57"}"#,
+            "flags/fallthrough-test-1",
+        );
+        assert_eq!(explanation.rules[0].outcome, RuleOutcome::Disabled);
+        assert_eq!(explanation.rules[0].targeting_key, None);
+    }
+}
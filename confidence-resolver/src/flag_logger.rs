@@ -1,24 +1,90 @@
+use crate::assign_logger::merge_skew;
 use crate::proto::confidence::flags::admin::v1::client_resolve_info::EvaluationContextSchemaInstance;
 use crate::proto::confidence::flags::admin::v1::flag_resolve_info::{
     AssignmentResolveInfo, RuleResolveInfo, VariantResolveInfo,
 };
 use crate::proto::confidence::flags::admin::v1::{ClientResolveInfo, FlagResolveInfo};
 use crate::proto::confidence::flags::resolver::v1::events::FlagAssigned;
-use crate::proto::confidence::flags::resolver::v1::{TelemetryData, WriteFlagLogsRequest};
+use crate::proto::confidence::flags::resolver::v1::telemetry_data::{
+    ApplySkew, InstanceResolveCount,
+};
+use crate::proto::confidence::flags::resolver::v1::{Sdk, TelemetryData, WriteFlagLogsRequest};
+use crate::proto::google::{value::Kind, Struct};
+use crate::resolve_logger::MAX_CONTEXT_SAMPLES;
 use std::collections::{HashMap, HashSet};
 
+/// Evaluation context attribute that marks synthetic traffic, such as health checks and smoke
+/// tests. Its resolves and assigns are logged like any other, but tagged so that analytics can
+/// filter them out: assigns in `ClientInfo::synthetic` and resolves in
+/// `VariantResolveInfo::synthetic_count`.
+pub const SYNTHETIC_ATTRIBUTE: &str = "confidence_synthetic";
+
+/// Whether `evaluation_context` sets [`SYNTHETIC_ATTRIBUTE`] to `true`.
+pub fn is_synthetic(evaluation_context: &Struct) -> bool {
+    matches!(
+        evaluation_context
+            .fields
+            .get(SYNTHETIC_ATTRIBUTE)
+            .and_then(|value| value.kind.as_ref()),
+        Some(Kind::BoolValue(true))
+    )
+}
+
+/// Logs waiting for the next checkpoint of a logger, so hosts can flush early or shed work
+/// when logging falls behind rather than finding out through memory growth.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogBacklog {
+    /// Resolves or assign events logged since the last checkpoint.
+    pub events: usize,
+    /// Estimated encoded size of the logs in bytes.
+    pub bytes: usize,
+}
+
+/// Merges checkpoints into one request. Counts are deltas, so they are summed. The resolve
+/// count and sequence number of every checkpoint are kept apart in `instance_resolve_counts`,
+/// so a gap in the sequence numbers of a logger still shows a lost checkpoint; a single
+/// checkpoint keeps them in `client_instance_id` and `sequence_number`. Every distinct SDK and
+/// the drifted rules of every checkpoint are retained.
 pub fn aggregate_batch(message_batch: Vec<WriteFlagLogsRequest>) -> WriteFlagLogsRequest {
     // map of client credential to derived schema
     let mut schema_map: HashMap<String, SchemaItem> = HashMap::new();
     // map of flag to flag resolve info
     let mut flag_resolve_map: HashMap<String, VariantRuleResolveInfo> = HashMap::new();
     let mut flag_assigned: Vec<FlagAssigned> = vec![];
-    let mut first_sdk: Option<crate::proto::confidence::flags::resolver::v1::Sdk> = None;
+    let mut flag_exposed = vec![];
+    let mut sdks: Vec<Sdk> = vec![];
+    let mut apply_skew: Vec<ApplySkew> = vec![];
+    let mut instances: Vec<InstanceResolveCount> = vec![];
+    let mut dropped_assign_events = 0i64;
+    let mut rule_drift = vec![];
 
     for flag_logs_message in message_batch {
         if let Some(td) = &flag_logs_message.telemetry_data {
-            if first_sdk.is_none() && td.sdk.is_some() {
-                first_sdk = td.sdk.clone();
+            for sdk in td.sdk.iter().chain(&td.other_sdks) {
+                if !sdks.contains(sdk) {
+                    sdks.push(sdk.clone());
+                }
+            }
+            if td.instance_resolve_counts.is_empty() {
+                add_instance_count(
+                    &mut instances,
+                    &InstanceResolveCount {
+                        client_instance_id: td.client_instance_id.clone(),
+                        resolve_count: td.resolve_count,
+                        sequence_number: td.sequence_number,
+                    },
+                );
+            }
+            for count in &td.instance_resolve_counts {
+                add_instance_count(&mut instances, count);
+            }
+            dropped_assign_events = dropped_assign_events.saturating_add(td.dropped_assign_events);
+            rule_drift.extend(td.rule_drift.iter().cloned());
+            for skew in &td.apply_skew {
+                match apply_skew.iter_mut().find(|s| s.sdk == skew.sdk) {
+                    Some(existing) => merge_skew(existing, skew),
+                    None => apply_skew.push(skew.clone()),
+                }
             }
         }
 
@@ -27,18 +93,19 @@ pub fn aggregate_batch(message_batch: Vec<WriteFlagLogsRequest>) -> WriteFlagLog
                 for schema in &c.schema {
                     set.schemas.insert(schema.clone());
                 }
+                set.add_samples(&c.context_samples);
             } else {
                 let mut set = HashSet::new();
                 for schema in &c.schema {
                     set.insert(schema.clone());
                 }
-                schema_map.insert(
-                    c.client_credential.clone(),
-                    SchemaItem {
-                        client: c.client.clone(),
-                        schemas: set.clone(),
-                    },
-                );
+                let mut item = SchemaItem {
+                    client: c.client.clone(),
+                    schemas: set.clone(),
+                    context_samples: vec![],
+                };
+                item.add_samples(&c.context_samples);
+                schema_map.insert(c.client_credential.clone(), item);
             }
         }
 
@@ -51,6 +118,7 @@ pub fn aggregate_batch(message_batch: Vec<WriteFlagLogsRequest>) -> WriteFlagLog
         for fa in &flag_logs_message.flag_assigned {
             flag_assigned.push(fa.clone());
         }
+        flag_exposed.extend(flag_logs_message.flag_exposed);
     }
 
     let mut client_resolve_info: Vec<ClientResolveInfo> = vec![];
@@ -59,6 +127,7 @@ pub fn aggregate_batch(message_batch: Vec<WriteFlagLogsRequest>) -> WriteFlagLog
             client_credential: client_credentials,
             client: schema_item.client,
             schema: schema_item.schemas.into_iter().collect(),
+            context_samples: schema_item.context_samples,
         })
     }
 
@@ -68,9 +137,14 @@ pub fn aggregate_batch(message_batch: Vec<WriteFlagLogsRequest>) -> WriteFlagLog
         let variant_resolve_info = resolve_info
             .variant_resolve_info
             .iter()
-            .map(|r| VariantResolveInfo {
-                variant: r.0.clone(),
-                count: *r.1,
+            .map(|(variant, count)| VariantResolveInfo {
+                variant: variant.clone(),
+                count: *count,
+                synthetic_count: resolve_info
+                    .synthetic_variant_resolve_info
+                    .get(variant)
+                    .copied()
+                    .unwrap_or_default(),
             })
             .collect();
 
@@ -98,19 +172,71 @@ pub fn aggregate_batch(message_batch: Vec<WriteFlagLogsRequest>) -> WriteFlagLog
         })
     }
 
-    let telemetry_data = first_sdk.map(|sdk| TelemetryData { sdk: Some(sdk) });
+    let telemetry_data = if !sdks.is_empty()
+        || !apply_skew.is_empty()
+        || !instances.is_empty()
+        || dropped_assign_events > 0
+        || !rule_drift.is_empty()
+    {
+        let mut sdks = sdks.into_iter();
+        let resolve_count = instances
+            .iter()
+            .fold(0i64, |sum, c| sum.saturating_add(c.resolve_count));
+        let (client_instance_id, sequence_number, instance_resolve_counts) =
+            if let [single] = instances.as_slice() {
+                (
+                    single.client_instance_id.clone(),
+                    single.sequence_number,
+                    vec![],
+                )
+            } else {
+                (String::new(), 0, instances)
+            };
+        Some(TelemetryData {
+            sdk: sdks.next(),
+            apply_skew,
+            resolve_count,
+            client_instance_id,
+            sequence_number,
+            other_sdks: sdks.collect(),
+            instance_resolve_counts,
+            dropped_assign_events,
+            rule_drift,
+        })
+    } else {
+        None
+    };
 
     WriteFlagLogsRequest {
         telemetry_data,
         flag_assigned,
         flag_resolve_info,
         client_resolve_info,
+        flag_exposed,
     }
 }
 
+fn add_instance_count(instances: &mut Vec<InstanceResolveCount>, count: &InstanceResolveCount) {
+    if count.client_instance_id.is_empty() && count.resolve_count == 0 {
+        // telemetry without resolves, e.g. from an assign logger
+        return;
+    }
+    instances.push(count.clone());
+}
+
 struct SchemaItem {
     pub client: String,
     pub schemas: HashSet<EvaluationContextSchemaInstance>,
+    pub context_samples: Vec<Struct>,
+}
+
+impl SchemaItem {
+    /// Keeps the first [`MAX_CONTEXT_SAMPLES`] samples, like a single logger does.
+    fn add_samples(&mut self, samples: &[Struct]) {
+        let room = MAX_CONTEXT_SAMPLES.saturating_sub(self.context_samples.len());
+        self.context_samples
+            .extend(samples.iter().take(room).cloned());
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -126,6 +252,8 @@ struct VariantRuleResolveInfo {
     rule_resolve_info: HashMap<String, RuleResolveInfoCount>,
     // variant to count
     variant_resolve_info: HashMap<String, i64>,
+    // variant to count of synthetic resolves
+    synthetic_variant_resolve_info: HashMap<String, i64>,
 }
 
 impl VariantRuleResolveInfo {
@@ -133,6 +261,7 @@ impl VariantRuleResolveInfo {
         VariantRuleResolveInfo {
             rule_resolve_info: HashMap::new(),
             variant_resolve_info: HashMap::new(),
+            synthetic_variant_resolve_info: HashMap::new(),
         }
     }
 }
@@ -155,8 +284,8 @@ fn update_rule_variant_info(
                 None => &HashMap::new(),
             };
 
-        // assignment id to count
-        let mut new_assignment_count: HashMap<String, i64> = HashMap::new();
+        // assignment id to count, keeping assignments only counted by earlier requests
+        let mut new_assignment_count: HashMap<String, i64> = current_assignments.clone();
         for aa in &rule_info.assignment_resolve_info {
             let count = match current_assignments.get(&aa.assignment_id) {
                 None => 0,
@@ -183,5 +312,135 @@ fn update_rule_variant_info(
         flag_info
             .variant_resolve_info
             .insert(variant_info.variant.clone(), count);
+        if variant_info.synthetic_count != 0 {
+            let synthetic_count = flag_info
+                .synthetic_variant_resolve_info
+                .entry(variant_info.variant.clone())
+                .or_default();
+            *synthetic_count = synthetic_count.saturating_add(variant_info.synthetic_count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::resolver::v1::sdk;
+
+    fn sdk(name: &str) -> Sdk {
+        Sdk {
+            sdk: Some(sdk::Sdk::CustomId(name.to_string())),
+            version: "1.0.0".to_string(),
+        }
+    }
+
+    fn checkpoint(
+        instance: &str,
+        resolve_count: i64,
+        sequence_number: i64,
+    ) -> WriteFlagLogsRequest {
+        WriteFlagLogsRequest {
+            telemetry_data: Some(TelemetryData {
+                sdk: Some(sdk(instance)),
+                resolve_count,
+                client_instance_id: instance.to_string(),
+                sequence_number,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keeps_resolve_counts_per_instance() {
+        let merged = aggregate_batch(vec![
+            checkpoint("a", 3, 1),
+            checkpoint("b", 5, 7),
+            checkpoint("a", 2, 2),
+        ]);
+        let td = merged.telemetry_data.unwrap();
+        assert_eq!(td.resolve_count, 10);
+        assert_eq!(td.client_instance_id, "");
+        let counts: Vec<(&str, i64, i64)> = td
+            .instance_resolve_counts
+            .iter()
+            .map(|c| {
+                (
+                    c.client_instance_id.as_str(),
+                    c.resolve_count,
+                    c.sequence_number,
+                )
+            })
+            .collect();
+        assert_eq!(counts, vec![("a", 3, 1), ("b", 5, 7), ("a", 2, 2)]);
+        assert_eq!(td.sdk, Some(sdk("a")));
+        assert_eq!(td.other_sdks, vec![sdk("b")]);
+
+        // aggregating again, e.g. a later queue batch, doesn't lose the split
+        let again = aggregate_batch(vec![
+            WriteFlagLogsRequest {
+                telemetry_data: Some(td),
+                ..Default::default()
+            },
+            checkpoint("b", 1, 8),
+        ]);
+        let td = again.telemetry_data.unwrap();
+        assert_eq!(td.resolve_count, 11);
+        assert_eq!(td.instance_resolve_counts.len(), 4);
+        assert_eq!(td.instance_resolve_counts[3].sequence_number, 8);
+        assert_eq!(td.other_sdks, vec![sdk("b")]);
+    }
+
+    #[test]
+    fn sums_rule_counts_across_requests() {
+        let rule = |assignment: &str| WriteFlagLogsRequest {
+            flag_resolve_info: vec![FlagResolveInfo {
+                flag: "flags/a".to_string(),
+                rule_resolve_info: vec![RuleResolveInfo {
+                    rule: "flags/a/rules/r".to_string(),
+                    count: 1,
+                    assignment_resolve_info: vec![AssignmentResolveInfo {
+                        assignment_id: assignment.to_string(),
+                        count: 1,
+                    }],
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let merged = aggregate_batch(vec![rule("x"), rule("y"), rule("x")]);
+        let info = &merged.flag_resolve_info[0].rule_resolve_info[0];
+        assert_eq!(info.count, 3);
+        let mut counts: Vec<(&str, i64)> = info
+            .assignment_resolve_info
+            .iter()
+            .map(|a| (a.assignment_id.as_str(), a.count))
+            .collect();
+        counts.sort_unstable();
+        assert_eq!(counts, vec![("x", 2), ("y", 1)]);
+    }
+
+    #[test]
+    fn keeps_every_sequence_number() {
+        let single = aggregate_batch(vec![checkpoint("a", 3, 1)]);
+        let td = single.telemetry_data.unwrap();
+        assert_eq!(td.resolve_count, 3);
+        assert_eq!(td.client_instance_id, "a");
+        assert_eq!(td.sequence_number, 1);
+        assert!(td.instance_resolve_counts.is_empty());
+
+        // checkpoint 2 was lost, which a merge must not hide
+        let merged = aggregate_batch(vec![checkpoint("a", 3, 1), checkpoint("a", 2, 3)]);
+        let td = merged.telemetry_data.unwrap();
+        assert_eq!(td.resolve_count, 5);
+        assert_eq!(td.client_instance_id, "");
+        assert_eq!(td.sequence_number, 0);
+        let numbers: Vec<i64> = td
+            .instance_resolve_counts
+            .iter()
+            .map(|c| c.sequence_number)
+            .collect();
+        assert_eq!(numbers, vec![1, 3]);
+        assert!(td.other_sdks.is_empty());
     }
 }
@@ -0,0 +1,227 @@
+//! A [`ResolveLogger`] and an [`AssignLogger`] checkpointed together.
+//!
+//! Each logger is safe to checkpoint on its own, but a host checkpointing one and then the other
+//! can send a resolve's counters in one request and its assigns in the next, when the resolve
+//! is logged in between. [`FlagLogs`] checkpoints both while no resolve is being logged, and
+//! applies byte limits to the combined request.
+
+use std::sync::{RwLock, RwLockReadGuard};
+
+use crate::assign_logger::AssignLogger;
+use crate::proto::confidence::flags::resolver::v1::WriteFlagLogsRequest;
+use crate::resolve_logger::ResolveLogger;
+use crate::Host;
+
+#[derive(Debug)]
+pub struct FlagLogs<H> {
+    resolves: ResolveLogger<H>,
+    assigns: AssignLogger,
+    // held shared by log scopes and exclusively by checkpoints
+    checkpoints: RwLock<()>,
+}
+
+/// Returned by [`FlagLogs::log_scope`]; checkpoints wait until it is dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct LogScope<'a> {
+    _guard: RwLockReadGuard<'a, ()>,
+}
+
+impl<H: Host> Default for FlagLogs<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: Host> FlagLogs<H> {
+    pub fn new() -> FlagLogs<H> {
+        Self::with_loggers(ResolveLogger::new(), AssignLogger::new())
+    }
+
+    /// Checkpoints `resolves` and `assigns` together. An `assigns` created with
+    /// [`DropPolicy::Block`](crate::assign_logger::DropPolicy::Block) must not log within a
+    /// [`FlagLogs::log_scope`]: it waits for a checkpoint that waits for the scope.
+    pub fn with_loggers(resolves: ResolveLogger<H>, assigns: AssignLogger) -> FlagLogs<H> {
+        FlagLogs {
+            resolves,
+            assigns,
+            checkpoints: RwLock::new(()),
+        }
+    }
+
+    pub fn resolve_logger(&self) -> &ResolveLogger<H> {
+        &self.resolves
+    }
+
+    pub fn assign_logger(&self) -> &AssignLogger {
+        &self.assigns
+    }
+
+    /// Holds off checkpoints until the scope is dropped, so that everything logged for a
+    /// resolve within the scope, e.g. by `Host::log_assign` and `Host::log_resolve` of a
+    /// resolve with apply, ends up in the same checkpoint. Scopes don't block each other.
+    pub fn log_scope(&self) -> LogScope<'_> {
+        LogScope {
+            _guard: self
+                .checkpoints
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        }
+    }
+
+    /// The resolve and assign logs collected since the last checkpoint.
+    pub fn checkpoint(&self) -> WriteFlagLogsRequest {
+        let _checkpoint = self.lock();
+        let mut req = self.resolves.checkpoint();
+        self.assigns.checkpoint_fill(&mut req);
+        req
+    }
+
+    /// Like [`FlagLogs::checkpoint`], but the returned request encodes to at most
+    /// `limit_bytes`, and whether logs are left for another checkpoint. Resolve counters are
+    /// taken first, see [`ResolveLogger::checkpoint_with_limit`], and assigns fill up the
+    /// rest of the request. A limit can still hold back some of a resolve's counters or
+    /// assigns; those go with the next checkpoint. The one exception to the limit is a resolve
+    /// counter entry that can't be split, or an assign event, that is larger than it: it is
+    /// returned on its own, as it would otherwise never be sent. Hosts whose transport rejects
+    /// such a request should drop it.
+    pub fn checkpoint_with_limit(&self, limit_bytes: usize) -> (WriteFlagLogsRequest, bool) {
+        let _checkpoint = self.lock();
+        let mut req = self.resolves.checkpoint_with_limit(limit_bytes);
+        self.assigns
+            .checkpoint_fill_with_limit(&mut req, limit_bytes, false);
+        let more = self.resolves.has_overflow() || self.assigns.backlog().events > 0;
+        (req, more)
+    }
+
+    fn lock(&self) -> std::sync::RwLockWriteGuard<'_, ()> {
+        self.checkpoints
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::resolver::v1::resolve_token_v1::AssignedFlag;
+    use crate::proto::google::{Struct, Timestamp};
+    use crate::{Account, Client, FlagToApply, ResolvedValue};
+    use prost::Message;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct TestHost;
+    impl Host for TestHost {
+        #[cfg(not(feature = "std"))]
+        fn random_alphanumeric(_len: usize) -> String {
+            "random".to_string()
+        }
+
+        #[cfg(not(feature = "std"))]
+        fn current_time() -> Timestamp {
+            Timestamp::default()
+        }
+
+        fn log_resolve(
+            _resolve_id: &str,
+            _evaluation_context: &Struct,
+            _values: &[ResolvedValue<'_>],
+            _client: &Client,
+            _sdk: &Option<crate::flags_resolver::Sdk>,
+        ) {
+        }
+
+        fn log_assign(
+            _resolve_id: &str,
+            _evaluation_context: &Struct,
+            _assigned_flags: &[FlagToApply],
+            _client: &Client,
+            _sdk: &Option<crate::flags_resolver::Sdk>,
+        ) {
+        }
+    }
+
+    fn client() -> Client {
+        Client {
+            account: Account {
+                name: "accounts/test".to_string(),
+            },
+            client_name: "clients/test".to_string(),
+            client_credential_name: "clients/test/clientCredentials/test".to_string(),
+            context_logging: Default::default(),
+        }
+    }
+
+    fn log_resolve(logs: &FlagLogs<TestHost>, resolve_id: &str) {
+        let client = client();
+        let flag = FlagToApply {
+            assigned_flag: AssignedFlag {
+                flag: "flags/f".to_string(),
+                targeting_key: "user".to_string(),
+                ..Default::default()
+            },
+            skew_adjusted_applied_time: Timestamp::default(),
+            clock_skew_millis: None,
+            state_fingerprint: String::new(),
+        };
+        logs.assign_logger()
+            .log_assigns(resolve_id, &Struct::default(), &[flag], &client, &None);
+        logs.resolve_logger().log_resolve(
+            resolve_id,
+            &Struct::default(),
+            &client.client_credential_name,
+            &[],
+            &client,
+            &None,
+        );
+    }
+
+    fn resolve_count(req: &WriteFlagLogsRequest) -> i64 {
+        req.telemetry_data.as_ref().map_or(0, |t| t.resolve_count)
+    }
+
+    #[test]
+    fn checkpoints_wait_for_log_scopes() {
+        let logs = FlagLogs::<TestHost>::new();
+        std::thread::scope(|s| {
+            let (started, start) = mpsc::channel();
+            let (checkpointed, checkpoint) = mpsc::channel();
+            let scope = logs.log_scope();
+            let logs = &logs;
+            s.spawn(move || {
+                start.recv().unwrap();
+                checkpointed.send(logs.checkpoint()).unwrap();
+            });
+            started.send(()).unwrap();
+            // the checkpoint can't run until the scope is dropped
+            std::thread::sleep(Duration::from_millis(50));
+            log_resolve(logs, "r1");
+            drop(scope);
+            let req = checkpoint.recv().unwrap();
+            assert_eq!(req.flag_assigned.len(), 1);
+            assert_eq!(resolve_count(&req), 1);
+        });
+    }
+
+    #[test]
+    fn limits_apply_to_the_combined_request() {
+        let logs = FlagLogs::<TestHost>::new();
+        for i in 0..20 {
+            log_resolve(&logs, &format!("resolve-{}", i));
+        }
+        let (first, more) = logs.checkpoint_with_limit(400);
+        assert!(first.encoded_len() <= 400);
+        assert!(!first.flag_assigned.is_empty());
+        assert!(more);
+
+        let (rest, more) = logs.checkpoint_with_limit(usize::MAX);
+        assert!(!more);
+        assert_eq!(
+            first.flag_assigned.len() + rest.flag_assigned.len(),
+            20,
+            "every assign is sent once"
+        );
+        assert_eq!(resolve_count(&first) + resolve_count(&rest), 20);
+    }
+}
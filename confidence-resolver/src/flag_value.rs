@@ -0,0 +1,347 @@
+//! Typed access to the value of a resolved flag, so that consumers don't have to walk proto
+//! `Value` trees themselves.
+//!
+//! ```ignore
+//! let value = resolved_flag.typed_value();
+//! let enabled = value.get_bool("enabled").unwrap_or(false);
+//! let color = value.get_string("button.color");
+//! ```
+//!
+//! Fields are addressed by `.`-separated paths. A resolved flag carries every field of its
+//! schema, with null for the fields its variant leaves out, like the backend resolver returns
+//! it. Null and missing fields mean the same here: the field is unset. [`FlagValue::get`]
+//! returns `None` for an unset field, while the typed getters and
+//! [`FlagValue::with_defaults`] read it as the zero value of its schema type: `false`, `0`, an
+//! empty string, list or struct. Fields the schema doesn't declare and values of another type
+//! read as `None`.
+//!
+//! Both the null expansion of resolved flags and the zero defaults here fill in unset fields
+//! with `expand_struct`, so nested structs and structs inside lists are treated alike.
+
+use crate::proto::confidence::flags::resolver::v1::ResolvedFlag;
+use crate::proto::confidence::flags::types::v1::flag_schema::{SchemaType, StructFlagSchema};
+use crate::proto::confidence::flags::types::v1::FlagSchema;
+use crate::proto::google::{value::Kind, ListValue, Struct, Value};
+use crate::ResolvedValue;
+
+/// The value of a resolved flag together with its schema, see the [module docs](self).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlagValue<'a> {
+    value: Option<&'a Struct>,
+    schema: Option<&'a StructFlagSchema>,
+}
+
+impl<'a> FlagValue<'a> {
+    pub fn new(value: Option<&'a Struct>, schema: Option<&'a StructFlagSchema>) -> Self {
+        FlagValue { value, schema }
+    }
+
+    /// The value at `path` as set by the variant, without schema defaults. `None` if the field
+    /// is unset, that is missing or null.
+    pub fn get(&self, path: &str) -> Option<&'a Value> {
+        let (parent, field) = match path.rsplit_once('.') {
+            Some((parent, field)) => (self.get_struct(parent)?.value, field),
+            None => (self.value, path),
+        };
+        parent?
+            .fields
+            .get(field)
+            .filter(|v| !matches!(v.kind, None | Some(Kind::NullValue(_))))
+    }
+
+    pub fn get_bool(&self, path: &str) -> Option<bool> {
+        match self.lookup(path) {
+            (Some(Kind::BoolValue(b)), _) => Some(*b),
+            (None, Some(SchemaType::BoolSchema(_))) => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn get_string(&self, path: &str) -> Option<&'a str> {
+        match self.lookup(path) {
+            (Some(Kind::StringValue(s)), _) => Some(s.as_str()),
+            (None, Some(SchemaType::StringSchema(_))) => Some(""),
+            _ => None,
+        }
+    }
+
+    /// Numbers without a fractional part, whether the schema says int or double.
+    pub fn get_i64(&self, path: &str) -> Option<i64> {
+        match self.lookup(path) {
+            (Some(Kind::NumberValue(n)), _) if n.fract() == 0.0 => Some(*n as i64),
+            (None, Some(SchemaType::IntSchema(_) | SchemaType::DoubleSchema(_))) => Some(0),
+            _ => None,
+        }
+    }
+
+    pub fn get_f64(&self, path: &str) -> Option<f64> {
+        match self.lookup(path) {
+            (Some(Kind::NumberValue(n)), _) => Some(*n),
+            (None, Some(SchemaType::IntSchema(_) | SchemaType::DoubleSchema(_))) => Some(0.0),
+            _ => None,
+        }
+    }
+
+    pub fn get_list(&self, path: &str) -> Option<&'a [Value]> {
+        match self.lookup(path) {
+            (Some(Kind::ListValue(list)), _) => Some(list.values.as_slice()),
+            (None, Some(SchemaType::ListSchema(_))) => Some(&[]),
+            _ => None,
+        }
+    }
+
+    /// The struct at `path`, whose fields are read with the nested schema.
+    pub fn get_struct(&self, path: &str) -> Option<FlagValue<'a>> {
+        let (value, schema) = self.lookup(path);
+        let schema = match schema {
+            Some(SchemaType::StructSchema(schema)) => Some(schema),
+            _ => None,
+        };
+        match value {
+            Some(Kind::StructValue(s)) => Some(FlagValue::new(Some(s), schema)),
+            None if schema.is_some() => Some(FlagValue::new(None, schema)),
+            _ => None,
+        }
+    }
+
+    /// The whole value, with its unset fields set to the zero value of their schema type.
+    pub fn with_defaults(&self) -> Struct {
+        let mut value = self.value.cloned().unwrap_or_default();
+        if let Some(schema) = self.schema {
+            expand_struct(&mut value, schema, &zero_value);
+        }
+        value
+    }
+
+    /// Deserializes the whole value, with schema defaults, into `T`.
+    #[cfg(feature = "json")]
+    pub fn get_struct_as<T: serde::de::DeserializeOwned>(&self) -> Result<T, String> {
+        let json = serde_json::to_value(self.with_defaults()).map_err(|e| e.to_string())?;
+        serde_json::from_value(json).map_err(|e| e.to_string())
+    }
+
+    /// The value at `path`, `None` if it is missing or null, and its type in the schema.
+    fn lookup(&self, path: &str) -> (Option<&'a Kind>, Option<&'a SchemaType>) {
+        let mut value = self.value;
+        let mut schema = self.schema;
+        let mut fields = path.split('.').peekable();
+        while let Some(field) = fields.next() {
+            let field_value = value
+                .and_then(|s| s.fields.get(field))
+                .and_then(|v| v.kind.as_ref())
+                .filter(|kind| !matches!(kind, Kind::NullValue(_)));
+            let field_schema = schema
+                .and_then(|s| s.schema.get(field))
+                .and_then(|s| s.schema_type.as_ref());
+            if fields.peek().is_none() {
+                return (field_value, field_schema);
+            }
+            value = match field_value {
+                Some(Kind::StructValue(s)) => Some(s),
+                Some(_) => return (None, None),
+                None => None,
+            };
+            schema = match field_schema {
+                Some(SchemaType::StructSchema(s)) => Some(s),
+                _ => None,
+            };
+        }
+        (None, None)
+    }
+}
+
+/// Sets the unset fields of `value`, those missing or null, to `fill` of their schema type, and
+/// does the same inside the structs it sets, also those in lists. Fields without a schema type
+/// are set to null.
+pub(crate) fn expand_struct(
+    value: &mut Struct,
+    schema: &StructFlagSchema,
+    fill: &dyn Fn(&SchemaType) -> Kind,
+) {
+    for (field, field_schema) in &schema.schema {
+        let field_value = value.fields.entry(field.clone()).or_default();
+        if matches!(field_value.kind, None | Some(Kind::NullValue(_))) {
+            field_value.kind = Some(
+                field_schema
+                    .schema_type
+                    .as_ref()
+                    .map_or(Kind::NullValue(0), fill),
+            );
+        } else {
+            expand_value(field_value, field_schema, fill);
+        }
+    }
+}
+
+fn expand_value(value: &mut Value, schema: &FlagSchema, fill: &dyn Fn(&SchemaType) -> Kind) {
+    match (&mut value.kind, &schema.schema_type) {
+        (Some(Kind::StructValue(s)), Some(SchemaType::StructSchema(struct_schema))) => {
+            expand_struct(s, struct_schema, fill)
+        }
+        (Some(Kind::ListValue(list)), Some(SchemaType::ListSchema(list_schema))) => {
+            if let Some(element_schema) = &list_schema.element_schema {
+                for element in &mut list.values {
+                    expand_value(element, element_schema, fill);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn zero_value(schema_type: &SchemaType) -> Kind {
+    match schema_type {
+        SchemaType::StructSchema(schema) => {
+            let mut value = Struct::default();
+            expand_struct(&mut value, schema, &zero_value);
+            Kind::StructValue(value)
+        }
+        SchemaType::ListSchema(_) => Kind::ListValue(ListValue::default()),
+        SchemaType::IntSchema(_) | SchemaType::DoubleSchema(_) => Kind::NumberValue(0.0),
+        SchemaType::StringSchema(_) => Kind::StringValue(String::new()),
+        SchemaType::BoolSchema(_) => Kind::BoolValue(false),
+    }
+}
+
+impl<'a> ResolvedValue<'a> {
+    /// The value of the assigned or killed variant, empty if the flag resolved to no variant.
+    pub fn typed_value(&self) -> FlagValue<'a> {
+        let variant = self
+            .assignment_match
+            .as_ref()
+            .and_then(|m| m.variant)
+            .or(self.killed_variant);
+        match variant {
+            Some(variant) => FlagValue::new(variant.value.as_ref(), self.flag.schema.as_ref()),
+            None => FlagValue::default(),
+        }
+    }
+}
+
+impl ResolvedFlag {
+    pub fn typed_value(&self) -> FlagValue<'_> {
+        FlagValue::new(self.value.as_ref(), self.flag_schema.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::types::v1::flag_schema;
+    use std::collections::BTreeMap;
+
+    fn field(schema_type: SchemaType) -> FlagSchema {
+        FlagSchema {
+            schema_type: Some(schema_type),
+        }
+    }
+
+    fn schema() -> StructFlagSchema {
+        StructFlagSchema {
+            schema: BTreeMap::from([
+                (
+                    "enabled".to_string(),
+                    field(SchemaType::BoolSchema(Default::default())),
+                ),
+                (
+                    "title".to_string(),
+                    field(SchemaType::StringSchema(Default::default())),
+                ),
+                (
+                    "button".to_string(),
+                    field(SchemaType::StructSchema(StructFlagSchema {
+                        schema: BTreeMap::from([(
+                            "size".to_string(),
+                            field(SchemaType::IntSchema(flag_schema::IntFlagSchema {})),
+                        )]),
+                    })),
+                ),
+            ]),
+        }
+    }
+
+    fn value(fields: &[(&str, Kind)]) -> Struct {
+        Struct {
+            fields: fields
+                .iter()
+                .map(|(name, kind)| {
+                    (
+                        name.to_string(),
+                        Value {
+                            kind: Some(kind.clone()),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn reads_typed_fields() {
+        let button = value(&[("size", Kind::NumberValue(12.0))]);
+        let value = value(&[
+            ("enabled", Kind::BoolValue(true)),
+            ("title", Kind::StringValue("hi".to_string())),
+            ("button", Kind::StructValue(button)),
+        ]);
+        let schema = schema();
+        let flag = FlagValue::new(Some(&value), Some(&schema));
+
+        assert_eq!(flag.get_bool("enabled"), Some(true));
+        assert_eq!(flag.get_string("title"), Some("hi"));
+        assert_eq!(flag.get_i64("button.size"), Some(12));
+        assert_eq!(
+            flag.get_struct("button").unwrap().get_f64("size"),
+            Some(12.0)
+        );
+        // other types and undeclared fields
+        assert_eq!(flag.get_string("enabled"), None);
+        assert_eq!(flag.get_bool("missing"), None);
+        assert_eq!(flag.get_i64("title.size"), None);
+    }
+
+    #[test]
+    fn fills_in_schema_defaults() {
+        let value = value(&[("title", Kind::NullValue(0))]);
+        let schema = schema();
+        let flag = FlagValue::new(Some(&value), Some(&schema));
+
+        assert_eq!(flag.get_bool("enabled"), Some(false));
+        assert_eq!(flag.get_string("title"), Some(""));
+        assert_eq!(flag.get_i64("button.size"), Some(0));
+        assert_eq!(flag.get("enabled"), None);
+        assert_eq!(flag.get("title"), None);
+
+        let filled = flag.with_defaults();
+        assert_eq!(filled.fields.len(), 3);
+        let Some(Kind::StructValue(button)) = &filled.fields["button"].kind else {
+            panic!("expected a struct");
+        };
+        assert_eq!(button.fields["size"].kind, Some(Kind::NumberValue(0.0)));
+
+        // the null expansion of resolved flags reads the same
+        let mut expanded = value.clone();
+        expand_struct(&mut expanded, &schema, &|_| Kind::NullValue(0));
+        assert_eq!(expanded.fields["enabled"].kind, Some(Kind::NullValue(0)));
+        let expanded = FlagValue::new(Some(&expanded), Some(&schema));
+        assert_eq!(expanded.get("enabled"), None);
+        assert_eq!(expanded.get_bool("enabled"), Some(false));
+        assert_eq!(expanded.with_defaults(), filled);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn deserializes_the_value() {
+        use std::collections::HashMap;
+
+        let value = value(&[("enabled", Kind::BoolValue(true))]);
+        let schema = schema();
+        let parsed: HashMap<String, serde_json::Value> =
+            FlagValue::new(Some(&value), Some(&schema))
+                .get_struct_as()
+                .unwrap();
+        assert_eq!(parsed["enabled"], serde_json::json!(true));
+        assert_eq!(parsed["title"], serde_json::json!(""));
+        assert_eq!(parsed["button"], serde_json::json!({"size": 0.0}));
+    }
+}
@@ -1,3 +1,4 @@
+use miniz_oxide::deflate::compress_to_vec;
 use miniz_oxide::inflate::decompress_to_vec;
 
 use crate::err::{Fallible, OrFailExt};
@@ -8,6 +9,20 @@ const FEXTRA: u8 = 1 << 2;
 const FNAME: u8 = 1 << 3;
 const FCOMMENT: u8 = 1 << 4;
 const FRESERVED: u8 = 1 << 5 | 1 << 6 | 1 << 7;
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// Gzips `data` with a minimal header (no name, comment or extra fields), the format
+/// [`decompress_gz`] accepts.
+pub fn compress_gz(data: &[u8]) -> Vec<u8> {
+    let compressed = compress_to_vec(data, COMPRESSION_LEVEL);
+    let mut buffer = Vec::with_capacity(compressed.len().saturating_add(18));
+    // magic, deflate, no flags, no mtime, no extra flags, unknown OS
+    buffer.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+    buffer.extend_from_slice(&compressed);
+    buffer.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+    buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buffer
+}
 
 pub fn decompress_gz(buffer: &[u8]) -> Fallible<Vec<u8>> {
     let [m0, m1, cm, flags, ..] = *buffer else {
@@ -73,4 +88,12 @@ mod tests {
         let data = decompress_gz(&buffer).expect("Failed to decompress");
         println!("data len: {:?}", data.len());
     }
+
+    #[test]
+    fn test_compress_gz_roundtrip() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 7) as u8).collect();
+        let zipped = compress_gz(&data);
+        assert_eq!(decompress_gz(&zipped).unwrap(), data);
+        assert_eq!(decompress_gz(&compress_gz(&[])).unwrap(), Vec::<u8>::new());
+    }
 }
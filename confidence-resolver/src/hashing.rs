@@ -0,0 +1,120 @@
+//! The hashing that places units in buckets, for tools that need to reproduce assignments
+//! outside the resolver, e.g. to analyse an experiment offline.
+//!
+//! A unit is bucketed twice: once against the bitset of a segment, keyed by the account, and
+//! once against the bucket ranges of a rule's assignments, keyed by the rule's segment. Both
+//! hash a `salt|unit` key with the x64 128-bit variant of MurmurHash3 and map the hash to a
+//! bucket with [`bucket`]. Anything here changing output for the same input would reassign
+//! units, so it is covered by the crate's semver guarantees like any other API break.
+
+use core::fmt::Write;
+
+use fastmurmur3::murmur3_x64_128;
+
+use crate::resource_name::{ResourceKind, ResourceName};
+
+/// Number of buckets of a segment bitset.
+pub const SEGMENT_BUCKETS: u64 = 1_000_000;
+
+const ACCOUNT_SALT_PREFIX: &str = "MegaSalt-";
+
+/// MurmurHash3 (x64, 128 bit, seed 0) of `key`.
+pub fn hash(key: &str) -> u128 {
+    murmur3_x64_128(key.as_bytes(), 0)
+}
+
+/// The bucket out of `buckets` that `hash` falls into. Only the low 64 bits of the hash are
+/// used, matching the Java resolver.
+#[allow(clippy::arithmetic_side_effects)] // buckets != 0 checked above
+pub fn bucket(hash: u128, buckets: u64) -> Result<usize, String> {
+    if buckets == 0 {
+        return Err("bucket count must be positive".to_string());
+    }
+    // convert u128 to u64 to match what we do in the java resolver
+    let hash_long: u64 = hash as u64;
+
+    // don't ask me why
+    Ok(((hash_long >> 4) % buckets) as usize)
+}
+
+/// Key a unit is hashed with to look it up in segment bitsets of `account`, e.g.
+/// `MegaSalt-my-account|user-1` for `accounts/my-account`.
+pub fn segment_key(account: &str, unit: &str) -> Result<String, String> {
+    let account = parse_id(account, ResourceKind::Account)?;
+    let mut key = String::new();
+    write_segment_key(&mut key, account, unit).map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+/// Key a unit is hashed with to pick an assignment of a rule targeting `segment`, e.g.
+/// `my-segment|user-1` for `segments/my-segment`.
+pub fn assignment_key(segment: &str, unit: &str) -> Result<String, String> {
+    let segment = parse_id(segment, ResourceKind::Segment)?;
+    let mut key = String::new();
+    write_assignment_key(&mut key, segment, unit).map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+/// The bucket of `unit` in the bitsets of segments of `account`.
+pub fn segment_bucket(account: &str, unit: &str) -> Result<usize, String> {
+    bucket(hash(&segment_key(account, unit)?), SEGMENT_BUCKETS)
+}
+
+/// The bucket of `unit` among the `bucket_count` buckets of the assignment spec of a rule
+/// targeting `segment`.
+pub fn assignment_bucket(segment: &str, unit: &str, bucket_count: u64) -> Result<usize, String> {
+    bucket(hash(&assignment_key(segment, unit)?), bucket_count)
+}
+
+/// Writes the key of [`segment_key`] given the id of the account.
+pub(crate) fn write_segment_key(
+    out: &mut impl Write,
+    account_id: &str,
+    unit: &str,
+) -> core::fmt::Result {
+    write!(out, "{}{}|{}", ACCOUNT_SALT_PREFIX, account_id, unit)
+}
+
+/// Writes the key of [`assignment_key`] given the id of the segment.
+pub(crate) fn write_assignment_key(
+    out: &mut impl Write,
+    segment_id: &str,
+    unit: &str,
+) -> core::fmt::Result {
+    write!(out, "{}|{}", segment_id, unit)
+}
+
+fn parse_id(name: &str, kind: ResourceKind) -> Result<&str, String> {
+    Ok(ResourceName::parse_as(name, kind)?.id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_java_randomizer() {
+        assert_eq!(
+            segment_key("accounts/confidence-test", "roug").unwrap(),
+            "MegaSalt-confidence-test|roug"
+        );
+        assert_eq!(
+            segment_bucket("accounts/confidence-test", "roug").unwrap(),
+            567493
+        );
+    }
+
+    #[test]
+    fn builds_assignment_keys() {
+        assert_eq!(
+            assignment_key("segments/my-segment", "roug").unwrap(),
+            "my-segment|roug"
+        );
+        assert_eq!(
+            assignment_bucket("segments/my-segment", "roug", 100).unwrap(),
+            bucket(hash("my-segment|roug"), 100).unwrap()
+        );
+        assert!(assignment_key("flags/my-flag", "roug").is_err());
+        assert!(assignment_bucket("segments/my-segment", "roug", 0).is_err());
+    }
+}
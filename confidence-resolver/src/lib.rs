@@ -10,29 +10,68 @@
 )]
 
 use bitvec::prelude as bv;
+use bumpalo::collections::String as BumpString;
+use bumpalo::Bump;
 use core::marker::PhantomData;
 use fastmurmur3::murmur3_x64_128;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, OnceLock, RwLock};
 
-use bytes::Bytes;
-
-use chrono::{DateTime, Utc};
-
-const BUCKETS: u64 = 1_000_000;
 const TARGETING_KEY: &str = "targeting_key";
 const NULL: Value = Value { kind: None };
 
 const MAX_NO_OF_FLAGS_TO_BATCH_RESOLVE: usize = 200;
+const MAX_TARGETING_KEY_LENGTH: usize = 100;
 
 use err::Fallible;
 
+#[cfg(feature = "json")]
+pub mod api_json;
 pub mod assign_logger;
+#[cfg(feature = "async")]
+pub mod async_host;
+pub mod bucket_ranges;
+mod build_info;
+pub mod canonical;
+pub mod compat;
+pub mod coverage;
+pub mod decrypt_breaker;
+pub mod drift;
+pub mod encryption;
+pub mod encryption_key;
 mod err;
+pub mod explain;
 pub mod flag_logger;
+pub mod flag_logs;
+pub mod flag_value;
 mod gzip;
+pub mod hashing;
+pub mod manifest;
+#[cfg(feature = "sticky")]
+pub mod materialization;
+pub mod membership;
+pub mod metrics;
+pub mod openfeature;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod preview;
 pub mod proto;
+pub mod recent_resolves;
+pub mod request;
+pub mod requirements;
 pub mod resolve_logger;
+pub mod resolve_token;
+pub mod resource_name;
 mod schema_util;
+pub mod state_builder;
+#[cfg(feature = "std")]
+pub mod std_host;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+mod time;
+#[cfg(feature = "transcode")]
+pub mod transcode;
 mod value;
 
 use proto::confidence::flags::admin::v1 as flags_admin;
@@ -40,7 +79,7 @@ use proto::confidence::flags::resolver::v1 as flags_resolver;
 use proto::confidence::flags::resolver::v1::resolve_token_v1::AssignedFlag;
 use proto::confidence::flags::types::v1 as flags_types;
 use proto::confidence::iam::v1 as iam;
-use proto::google::{value::Kind, Struct, Timestamp, Value};
+use proto::google::{value::Kind, ListValue, Struct, Timestamp, Value};
 use proto::Message;
 
 use flags_admin::flag::rule;
@@ -53,35 +92,156 @@ use flags_types::targeting;
 use flags_types::targeting::criterion;
 use flags_types::targeting::Criterion;
 use flags_types::Expression;
-use gzip::decompress_gz;
+use gzip::{compress_gz, decompress_gz};
+use resource_name::{ResourceKind, ResourceName};
 
+pub use build_info::{build_info, BuildInfo};
+pub use encryption_key::EncryptionKey;
+pub use hashing::{bucket, hash};
+
+use crate::decrypt_breaker::{DecryptBreaker, HeldApply};
 use crate::err::{ErrorCode, OrFailExt};
+use crate::metrics::MetricTimer;
 use crate::proto::confidence::flags::resolver::v1::resolve_with_sticky_response::{
-    MaterializationUpdate, ResolveResult,
+    resolve_warning, MaterializationUpdate, ResolveResult, ResolveWarning,
 };
 use crate::proto::confidence::flags::resolver::v1::{
     resolve_with_sticky_response, MaterializationMap, ResolveFlagsRequest, ResolveFlagsResponse,
     ResolveWithStickyRequest, ResolveWithStickyResponse,
 };
 
-impl TryFrom<Vec<u8>> for ResolverStatePb {
-    type Error = ErrorCode;
-    fn try_from(s: Vec<u8>) -> Fallible<Self> {
-        ResolverStatePb::decode(&s[..]).or_fail()
+/// A segment allocation bitset. Bitsets arrive gzipped in the resolver state and are only
+/// decompressed the first time they are needed, since many segments in a state typically
+/// belong to flags that are never resolved. Clones share the bytes and the decompressed bits,
+/// so a bitset can be carried over to a new state, see [`state_builder`].
+#[derive(Debug, Clone)]
+pub struct Bitset {
+    gzipped: Arc<Vec<u8>>,
+    bits: Arc<OnceLock<Fallible<bv::BitVec<u8, bv::Lsb0>>>>,
+}
+
+impl Bitset {
+    pub fn gzipped(bytes: Vec<u8>) -> Self {
+        Bitset {
+            gzipped: Arc::new(bytes),
+            bits: Arc::new(OnceLock::new()),
+        }
+    }
+
+    pub fn from_bits(bits: bv::BitVec<u8, bv::Lsb0>) -> Self {
+        Bitset {
+            gzipped: Arc::new(Vec::new()),
+            bits: Arc::new(OnceLock::from(Ok(bits))),
+        }
+    }
+
+    /// Whether both bitsets were loaded from the same gzipped bytes.
+    fn same_gzipped(&self, other: &Bitset) -> bool {
+        !self.gzipped.is_empty() && self.gzipped == other.gzipped
+    }
+
+    /// Whether this is a clone of `other`, sharing its memory.
+    pub fn is_shared_with(&self, other: &Bitset) -> bool {
+        Arc::ptr_eq(&self.bits, &other.bits)
+    }
+
+    /// Like [`Bitset::bits`], reporting the time to decompress the bitset of `segment` to
+    /// [`Host::on_metric`] on its first use.
+    fn bits_with_host<H: Host>(&self, segment: &str) -> Fallible<&bv::BitVec<u8, bv::Lsb0>> {
+        if self.is_loaded() {
+            return self.bits();
+        }
+        let timer = MetricTimer::<H>::start();
+        let bits = self.bits();
+        timer.finish(metrics::BITSET_DECOMPRESS_DURATION, &[("segment", segment)]);
+        bits
+    }
+
+    /// The decompressed bitset, decompressing it if this is the first use.
+    pub fn bits(&self) -> Fallible<&bv::BitVec<u8, bv::Lsb0>> {
+        self.bits
+            .get_or_init(|| decompress_gz(&self.gzipped).map(bv::BitVec::from_vec))
+            .as_ref()
+            .map_err(|e| *e)
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.bits.get().is_some()
+    }
+
+    /// Number of buckets in the bitset.
+    pub fn population(&self) -> Fallible<usize> {
+        Ok(self.bits()?.count_ones())
+    }
+
+    /// Up to `size` buckets in the bitset, spread evenly over its members.
+    pub fn sample(&self, size: usize) -> Fallible<Vec<usize>> {
+        let bits = self.bits()?;
+        if size == 0 {
+            return Ok(vec![]);
+        }
+        let step = bits.count_ones().div_ceil(size).max(1);
+        Ok(bits.iter_ones().step_by(step).take(size).collect())
+    }
+
+    /// Counts the buckets that are in `to` but not in this bitset and the other way around.
+    /// Bitsets of different lengths are compared as if the shorter one was padded with zeros.
+    pub fn diff(&self, to: &Bitset) -> Fallible<BitsetDiff> {
+        let (from, to) = (self.bits()?, to.bits()?);
+        let mut diff = BitsetDiff::default();
+        for bucket in 0..from.len().max(to.len()) {
+            let in_from = from.get(bucket).is_some_and(|bit| *bit);
+            let in_to = to.get(bucket).is_some_and(|bit| *bit);
+            if in_to && !in_from {
+                diff.added = diff.added.saturating_add(1);
+            } else if in_from && !in_to {
+                diff.removed = diff.removed.saturating_add(1);
+            }
+        }
+        Ok(diff)
+    }
+
+    /// The bitset as it's shipped in the resolver state, reusing the original gzipped bytes
+    /// when there are any.
+    fn packed(&self) -> Fallible<flags_admin::resolver_state::packed_bitset::Bitset> {
+        let gzipped = if self.gzipped.is_empty() {
+            compress_gz(self.bits()?.as_raw_slice())
+        } else {
+            self.gzipped.to_vec()
+        };
+        Ok(flags_admin::resolver_state::packed_bitset::Bitset::GzippedBitset(gzipped))
+    }
+
+    fn heap_size(&self) -> usize {
+        let bits = match self.bits.get() {
+            Some(Ok(bits)) => bits.capacity().div_ceil(8),
+            _ => 0,
+        };
+        self.gzipped.capacity().saturating_add(bits)
     }
 }
 
-fn timestamp_to_datetime(ts: &Timestamp) -> Fallible<DateTime<Utc>> {
-    DateTime::from_timestamp(ts.seconds, ts.nanos as u32).or_fail()
+/// Bytes held by a map with string keys: its slots plus what keys and values own on the heap.
+fn map_size<V>(map: &HashMap<String, V>, value_heap_size: impl Fn(&V) -> usize) -> usize {
+    let slot = core::mem::size_of::<(String, V)>().saturating_add(1);
+    map.iter().fold(
+        map.capacity().saturating_mul(slot),
+        |total, (key, value)| {
+            total
+                .saturating_add(key.capacity())
+                .saturating_add(value_heap_size(value))
+        },
+    )
 }
-fn datetime_to_timestamp(dt: &DateTime<Utc>) -> Timestamp {
-    Timestamp {
-        seconds: dt.timestamp(),
-        nanos: dt.timestamp_subsec_nanos() as i32,
+
+impl TryFrom<Vec<u8>> for ResolverStatePb {
+    type Error = ErrorCode;
+    fn try_from(s: Vec<u8>) -> Fallible<Self> {
+        ResolverStatePb::decode(&s[..]).or_fail()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Account {
     pub name: String,
 }
@@ -92,1620 +252,5319 @@ impl Account {
         }
     }
 
-    fn salt(&self) -> Fallible<String> {
-        let id = self.name.split("/").nth(1).or_fail()?;
-        Ok(format!("MegaSalt-{}", id))
-    }
-
-    fn salt_unit(&self, unit: &str) -> Fallible<String> {
-        let salt = self.salt()?;
-        Ok(format!("{}|{}", salt, unit))
+    /// See [`hashing::segment_key`].
+    fn salt_unit<'b>(&self, unit: &str, arena: &'b Bump) -> Fallible<BumpString<'b>> {
+        let id = ResourceName::parse_as(&self.name, ResourceKind::Account)
+            .or_fail()?
+            .id();
+        let mut salted = BumpString::new_in(arena);
+        hashing::write_segment_key(&mut salted, id, unit).or_fail()?;
+        Ok(salted)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Client {
     pub account: Account,
     pub client_name: String,
     pub client_credential_name: String,
+    /// From [`ResolverConfig::credential_context_logging`].
+    pub context_logging: ContextLogging,
 }
 
-#[derive(Debug)]
-pub struct ResolverState {
-    pub secrets: HashMap<String, Client>,
-    pub flags: HashMap<String, Flag>,
-    pub segments: HashMap<String, Segment>,
-    pub bitsets: HashMap<String, bv::BitVec<u8, bv::Lsb0>>,
+/// What [`ResolveLogger`](resolve_logger::ResolveLogger) logs of the evaluation context of the
+/// resolves of a client credential.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ContextLogging {
+    /// Nothing, the credential isn't reported in the logs.
+    None,
+    /// The schema of the context: the kind and semantic type of each field.
+    #[default]
+    Schema,
+    /// The schema, and the full context of one in `one_in` resolves with the `redacted_fields`
+    /// removed, none for a `one_in` of 0. At most [`resolve_logger::MAX_CONTEXT_SAMPLES`] are
+    /// kept per checkpoint.
+    Sampled {
+        one_in: u32,
+        redacted_fields: Vec<String>,
+    },
 }
-impl ResolverState {
-    pub fn from_proto(state_pb: ResolverStatePb, account_id: &str) -> Fallible<Self> {
-        let mut secrets = HashMap::new();
-        let mut flags = HashMap::new();
-        let mut segments = HashMap::new();
-        let mut bitsets = HashMap::new();
 
-        for flag in state_pb.flags {
-            flags.insert(flag.name.clone(), flag);
-        }
-        for segment in state_pb.segments_no_bitsets {
-            segments.insert(segment.name.clone(), segment);
-        }
-        for bitset in state_pb.bitsets {
-            let Some(b) = bitset.bitset else { continue };
-            match b {
-                flags_admin::resolver_state::packed_bitset::Bitset::GzippedBitset(zipped_bytes) => {
-                    // unzip bytes
-                    let buffer = decompress_gz(&zipped_bytes[..])?;
-                    let bitvec = bv::BitVec::from_slice(&buffer);
-                    bitsets.insert(bitset.segment.clone(), bitvec);
-                }
-                // missing bitset treated as full
-                flags_admin::resolver_state::packed_bitset::Bitset::FullBitset(true) => (),
-                _ => fail!(),
-            }
+impl From<&flags_resolver::resolver_settings::ContextLogging> for ContextLogging {
+    fn from(logging: &flags_resolver::resolver_settings::ContextLogging) -> Self {
+        use flags_resolver::resolver_settings::context_logging::Mode;
+        match logging.mode() {
+            Mode::Schema => ContextLogging::Schema,
+            Mode::None => ContextLogging::None,
+            // without a sample rate only the schema is logged, rather than every context
+            Mode::Sampled if logging.sample_one_in <= 0 => ContextLogging::Schema,
+            Mode::Sampled => ContextLogging::Sampled {
+                one_in: u32::try_from(logging.sample_one_in).unwrap_or(u32::MAX),
+                redacted_fields: logging.redacted_fields.clone(),
+            },
         }
-        for client in state_pb.clients {
-            for credential in &state_pb.client_credentials {
-                if !credential.name.starts_with(client.name.as_str()) {
-                    continue;
-                }
-                let Some(iam::client_credential::Credential::ClientSecret(client_secret)) =
-                    &credential.credential
-                else {
-                    continue;
-                };
+    }
+}
 
-                secrets.insert(
-                    client_secret.secret.clone(),
-                    Client {
-                        account: Account::new(&format!("accounts/{}", account_id)),
-                        client_name: client.name.clone(),
-                        client_credential_name: credential.name.clone(),
-                    },
-                );
+impl From<&ContextLogging> for flags_resolver::resolver_settings::ContextLogging {
+    fn from(logging: &ContextLogging) -> Self {
+        use flags_resolver::resolver_settings::context_logging::Mode;
+        let mut pb = flags_resolver::resolver_settings::ContextLogging::default();
+        match logging {
+            ContextLogging::None => pb.set_mode(Mode::None),
+            ContextLogging::Schema => pb.set_mode(Mode::Schema),
+            ContextLogging::Sampled {
+                one_in,
+                redacted_fields,
+            } => {
+                pb.set_mode(Mode::Sampled);
+                pb.sample_one_in = i32::try_from(*one_in).unwrap_or(i32::MAX);
+                pb.redacted_fields = redacted_fields.clone();
             }
         }
-
-        Ok(ResolverState {
-            secrets,
-            flags,
-            segments,
-            bitsets,
-        })
+        pb
     }
+}
 
-    #[cfg(feature = "json")]
-    pub fn get_resolver_with_json_context<'a, H: Host>(
-        &'a self,
-        client_secret: &str,
-        evaluation_context: &str,
-        encryption_key: &Bytes,
-    ) -> Result<AccountResolver<'a, H>, String> {
-        self.get_resolver(
-            client_secret,
-            // allow this unwrap cause it only happens in std
-            #[allow(clippy::unwrap_used)]
-            serde_json::from_str(evaluation_context)
-                .map_err(|_| "failed to parse evaluation context".to_string())?,
-            encryption_key,
-        )
-    }
+/// How a unit whose bucket is beyond the end of a segment bitset is treated. Bitsets cover every
+/// bucket, so this only happens with a truncated one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TruncatedBitset {
+    #[default]
+    NotMember,
+    Member,
+    /// Fail the resolve.
+    Fail,
+}
 
-    pub fn get_resolver<'a, H: Host>(
-        &'a self,
-        client_secret: &str,
-        evaluation_context: Struct,
-        encryption_key: &Bytes,
-    ) -> Result<AccountResolver<'a, H>, String> {
-        self.secrets
-            .get(client_secret)
-            .ok_or("client secret not found".to_string())
-            .map(|client| {
-                AccountResolver::new(
-                    client,
-                    self,
-                    EvaluationContext {
-                        context: evaluation_context,
-                    },
-                    encryption_key,
-                )
-            })
+impl From<flags_resolver::resolver_settings::TruncatedBitset> for TruncatedBitset {
+    fn from(value: flags_resolver::resolver_settings::TruncatedBitset) -> Self {
+        use flags_resolver::resolver_settings::TruncatedBitset as Pb;
+        match value {
+            Pb::NotMember => TruncatedBitset::NotMember,
+            Pb::Member => TruncatedBitset::Member,
+            Pb::Fail => TruncatedBitset::Fail,
+        }
     }
 }
 
-pub struct EvaluationContext {
-    pub context: Struct,
+impl From<TruncatedBitset> for flags_resolver::resolver_settings::TruncatedBitset {
+    fn from(value: TruncatedBitset) -> Self {
+        match value {
+            TruncatedBitset::NotMember => Self::NotMember,
+            TruncatedBitset::Member => Self::Member,
+            TruncatedBitset::Fail => Self::Fail,
+        }
+    }
 }
-pub struct FlagToApply {
-    pub assigned_flag: AssignedFlag,
-    pub skew_adjusted_applied_time: Timestamp,
+
+/// Settings that change how the resolver behaves, loaded with a state through
+/// [`LoadOptions::config`]. Hosts that are configured with an encoded resolver-local
+/// `ResolverSettings` proto convert it with `ResolverConfig::from`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolverConfig {
+    pub max_flags_per_resolve: usize,
+    pub max_targeting_key_length: usize,
+    /// Evaluation context field used as unit by rules without a targeting key selector.
+    pub default_targeting_key: String,
+    /// Targeting key selector per flag name for rules without one, overriding the flag's own
+    /// `targeting_key_selector`.
+    pub flag_targeting_key_selectors: BTreeMap<String, String>,
+    pub log_resolves: bool,
+    pub log_assigns: bool,
+    /// Accept unencrypted resolve tokens even though the resolver has an encryption key,
+    /// while clients move from an unencrypted to an encrypting resolver.
+    pub allow_plaintext_resolve_tokens: bool,
+    /// Accept tokens encrypted by [`Host::encrypt_resolve_token`], or issued before tokens had
+    /// a header, until this time in seconds since the epoch although the host's provider is
+    /// [AEAD](encryption::EncryptionProvider::is_aead). Those tokens aren't authenticated and
+    /// are rejected otherwise, so set this only while tokens issued before moving to the
+    /// provider are in flight.
+    pub legacy_resolve_tokens_until: Option<i64>,
+    /// Fallthrough rules recorded per resolved flag, unbounded by default. Further ones aren't
+    /// recorded in the resolve token and logs, so their exposures are lost, and a
+    /// [`resolve_warning::Kind::FallthroughRulesTruncated`] warning marks the flag.
+    pub max_fallthrough_rules: usize,
+    /// What is logged of the evaluation context per client credential name. Credentials without
+    /// an entry log [`ContextLogging::Schema`].
+    pub credential_context_logging: BTreeMap<String, ContextLogging>,
+    pub truncated_bitset: TruncatedBitset,
+    /// Thresholds of the drift check of flag logs, off when `None`, see
+    /// [`drift::report_drift`].
+    pub drift: Option<drift::DriftConfig>,
 }
 
-pub trait Host {
-    #[cfg(not(feature = "std"))]
-    fn random_alphanumeric(len: usize) -> String;
-    #[cfg(feature = "std")]
-    fn random_alphanumeric(len: usize) -> String {
-        use rand::distr::{Alphanumeric, SampleString};
-        Alphanumeric.sample_string(&mut rand::rng(), len)
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            max_flags_per_resolve: MAX_NO_OF_FLAGS_TO_BATCH_RESOLVE,
+            max_targeting_key_length: MAX_TARGETING_KEY_LENGTH,
+            default_targeting_key: TARGETING_KEY.to_string(),
+            flag_targeting_key_selectors: BTreeMap::new(),
+            log_resolves: true,
+            log_assigns: true,
+            allow_plaintext_resolve_tokens: false,
+            legacy_resolve_tokens_until: None,
+            max_fallthrough_rules: usize::MAX,
+            credential_context_logging: BTreeMap::new(),
+            truncated_bitset: TruncatedBitset::default(),
+            drift: None,
+        }
     }
+}
 
-    fn log(_: &str) {
-        // noop
+impl From<&flags_resolver::ResolverSettings> for ResolverConfig {
+    fn from(settings: &flags_resolver::ResolverSettings) -> Self {
+        let defaults = ResolverConfig::default();
+        let limit = |value: i32, default: usize| match usize::try_from(value) {
+            Ok(value) if value > 0 => value,
+            _ => default,
+        };
+        ResolverConfig {
+            max_flags_per_resolve: limit(
+                settings.max_flags_per_resolve,
+                defaults.max_flags_per_resolve,
+            ),
+            max_targeting_key_length: limit(
+                settings.max_targeting_key_length,
+                defaults.max_targeting_key_length,
+            ),
+            default_targeting_key: if settings.default_targeting_key.is_empty() {
+                defaults.default_targeting_key
+            } else {
+                settings.default_targeting_key.clone()
+            },
+            flag_targeting_key_selectors: settings.flag_targeting_key_selectors.clone(),
+            log_resolves: !settings.disable_resolve_logging,
+            log_assigns: !settings.disable_assign_logging,
+            allow_plaintext_resolve_tokens: settings.allow_plaintext_resolve_tokens,
+            legacy_resolve_tokens_until: Some(settings.legacy_resolve_tokens_until)
+                .filter(|until| *until > 0),
+            max_fallthrough_rules: limit(
+                settings.max_fallthrough_rules,
+                defaults.max_fallthrough_rules,
+            ),
+            credential_context_logging: settings
+                .credential_context_logging
+                .iter()
+                .map(|(credential, logging)| (credential.clone(), logging.into()))
+                .collect(),
+            truncated_bitset: settings.truncated_bitset().into(),
+            drift: settings.drift_detection.as_ref().map(|detection| {
+                let defaults = drift::DriftConfig::default();
+                drift::DriftConfig {
+                    min_samples: if detection.min_samples > 0 {
+                        detection.min_samples
+                    } else {
+                        defaults.min_samples
+                    },
+                    z_threshold: if detection.z_threshold > 0.0 {
+                        detection.z_threshold
+                    } else {
+                        defaults.z_threshold
+                    },
+                }
+            }),
+        }
     }
+}
 
-    #[cfg(not(feature = "std"))]
-    fn current_time() -> Timestamp;
-    #[cfg(feature = "std")]
-    fn current_time() -> Timestamp {
-        let now = chrono::Utc::now();
-        Timestamp {
-            seconds: now.timestamp(),
-            nanos: now.timestamp_subsec_nanos() as i32,
+impl From<&ResolverConfig> for flags_resolver::ResolverSettings {
+    fn from(config: &ResolverConfig) -> Self {
+        let defaults = ResolverConfig::default();
+        let limit = |value: usize, default: usize| {
+            if value == default {
+                0
+            } else {
+                i32::try_from(value).unwrap_or(i32::MAX)
+            }
+        };
+        flags_resolver::ResolverSettings {
+            max_flags_per_resolve: limit(
+                config.max_flags_per_resolve,
+                defaults.max_flags_per_resolve,
+            ),
+            max_targeting_key_length: limit(
+                config.max_targeting_key_length,
+                defaults.max_targeting_key_length,
+            ),
+            default_targeting_key: if config.default_targeting_key == defaults.default_targeting_key
+            {
+                String::new()
+            } else {
+                config.default_targeting_key.clone()
+            },
+            flag_targeting_key_selectors: config.flag_targeting_key_selectors.clone(),
+            disable_resolve_logging: !config.log_resolves,
+            disable_assign_logging: !config.log_assigns,
+            allow_plaintext_resolve_tokens: config.allow_plaintext_resolve_tokens,
+            legacy_resolve_tokens_until: config.legacy_resolve_tokens_until.unwrap_or_default(),
+            max_fallthrough_rules: limit(
+                config.max_fallthrough_rules,
+                defaults.max_fallthrough_rules,
+            ),
+            credential_context_logging: config
+                .credential_context_logging
+                .iter()
+                .map(|(credential, logging)| (credential.clone(), logging.into()))
+                .collect(),
+            truncated_bitset: flags_resolver::resolver_settings::TruncatedBitset::from(
+                config.truncated_bitset,
+            ) as i32,
+            drift_detection: config.drift.map(|drift| {
+                flags_resolver::resolver_settings::DriftDetection {
+                    min_samples: drift.min_samples,
+                    z_threshold: drift.z_threshold,
+                }
+            }),
         }
     }
+}
 
-    fn log_resolve(
-        resolve_id: &str,
-        evaluation_context: &Struct,
-        values: &[ResolvedValue<'_>],
-        client: &Client,
-        sdk: &Option<flags_resolver::Sdk>,
-    );
-
-    fn log_assign(
-        resolve_id: &str,
-        evaluation_context: &Struct,
-        assigned_flags: &[FlagToApply],
-        client: &Client,
-        sdk: &Option<flags_resolver::Sdk>,
-    );
+/// Estimated heap usage of a [`ResolverState`] in bytes, see [`ResolverState::memory_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub flags: usize,
+    pub segments: usize,
+    pub bitsets: usize,
+    pub secrets: usize,
+}
 
-    fn encrypt_resolve_token(token_data: &[u8], encryption_key: &[u8]) -> Result<Vec<u8>, String> {
-        #[cfg(feature = "std")]
-        {
-            const ENCRYPTION_WRITE_BUFFER_SIZE: usize = 4096;
+impl MemoryStats {
+    pub fn total(&self) -> usize {
+        self.flags
+            .saturating_add(self.segments)
+            .saturating_add(self.bitsets)
+            .saturating_add(self.secrets)
+    }
+}
 
-            use std::io::Write;
+/// Members of a segment bitset, see [`ResolverState::segment_population`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentPopulation {
+    /// Number of buckets in the segment.
+    pub population: usize,
+    /// Number of buckets the bitset covers.
+    pub bucket_count: usize,
+    /// Buckets in the segment, spread evenly over its members.
+    pub sample: Vec<usize>,
+}
 
-            use crypto::{aes, blockmodes, buffer};
-            use rand::RngCore;
+/// Buckets added and removed between two bitsets, see [`Bitset::diff`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BitsetDiff {
+    pub added: usize,
+    pub removed: usize,
+}
 
-            let mut iv = [0u8; 16];
-            rand::rng().fill_bytes(&mut iv);
+/// Forces a flag to resolve to `variant`, or to no value, before any of its rules are
+/// evaluated. Resolves report [`ResolveReason::FlagKilled`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KillSwitch {
+    pub flag: String,
+    /// Only applies to resolves by this client, all clients when `None`.
+    pub client: Option<String>,
+    /// Name of the variant to resolve to, no value when `None`.
+    pub variant: Option<String>,
+}
 
-            let mut final_encrypted_token = Vec::<u8>::new();
-            final_encrypted_token
-                .write(&iv)
-                .map_err(|_| "Failed to write iv to encrypted resolve token buffer".to_string())?;
+impl From<flags_admin::resolver_state::KillSwitch> for KillSwitch {
+    fn from(kill_switch: flags_admin::resolver_state::KillSwitch) -> Self {
+        let non_empty = |s: String| (!s.is_empty()).then_some(s);
+        KillSwitch {
+            flag: kill_switch.flag,
+            client: non_empty(kill_switch.client),
+            variant: non_empty(kill_switch.variant),
+        }
+    }
+}
 
-            let mut encryptor = aes::cbc_encryptor(
-                aes::KeySize::KeySize128,
-                &iv,
-                encryption_key,
-                blockmodes::PkcsPadding,
-            );
+impl From<&KillSwitch> for flags_admin::resolver_state::KillSwitch {
+    fn from(kill_switch: &KillSwitch) -> Self {
+        flags_admin::resolver_state::KillSwitch {
+            flag: kill_switch.flag.clone(),
+            client: kill_switch.client.clone().unwrap_or_default(),
+            variant: kill_switch.variant.clone().unwrap_or_default(),
+        }
+    }
+}
 
-            let token_read_buffer = &mut buffer::RefReadBuffer::new(token_data);
-            let mut write_buffer = [0; ENCRYPTION_WRITE_BUFFER_SIZE];
-            let token_write_buffer = &mut buffer::RefWriteBuffer::new(&mut write_buffer);
+/// Options for [`ResolverState::from_proto_with_options`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadOptions {
+    /// Drop segments and bitsets that no active flag can reach, see
+    /// [`ResolverState::prune_unreferenced`].
+    pub prune_unreferenced: bool,
+    /// The [`ResolverState::config`] of the loaded state. It isn't part of the state proto,
+    /// which the Confidence backend owns.
+    pub config: ResolverConfig,
+}
 
-            loop {
-                use crypto::buffer::{BufferResult, ReadBuffer, WriteBuffer};
+/// Segments and bitsets removed by [`ResolverState::prune_unreferenced`], sorted by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub segments: Vec<String>,
+    pub bitsets: Vec<String>,
+}
 
-                let result = encryptor
-                    .encrypt(token_read_buffer, token_write_buffer, true)
-                    .map_err(|_| "Failed to encrypt resolve token".to_string())?;
+/// What [`ResolverState::from_proto_with_options`] found and changed while loading a state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    pub pruned: PruneReport,
+    /// Entries of the state proto that another entry replaced, in the order they were found.
+    pub duplicates: Vec<StateDuplicate>,
+}
 
-                final_encrypted_token.extend(
-                    token_write_buffer
-                        .take_read_buffer()
-                        .take_remaining()
-                        .iter()
-                        .copied(),
-                );
+/// An entry of a state proto with the same key as an earlier one. The entry listed last in
+/// the state is kept, as it was before duplicates were reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateDuplicate {
+    /// A flag listed more than once.
+    Flag { name: String },
+    /// A client secret of more than one credential. Resolves with the secret are attributed to
+    /// the `kept` credential and its client.
+    Secret { kept: String, replaced: String },
+}
 
-                match result {
-                    BufferResult::BufferUnderflow => break,
-                    BufferResult::BufferOverflow => {}
-                }
-            }
+impl StateDuplicate {
+    /// Reports `duplicates` to [`Host::log`] and as [`metrics::STATE_DUPLICATE`], for hosts that
+    /// load states with [`ResolverState::from_proto_with_options`] or a
+    /// [`state_builder::ResolverStateBuilder`], which only return them.
+    pub fn report<H: Host>(duplicates: &[StateDuplicate]) {
+        for duplicate in duplicates {
+            H::log(&format!("WARN: {}", duplicate));
+            H::on_metric(metrics::STATE_DUPLICATE, 1.0, &[("kind", duplicate.kind())]);
+        }
+    }
 
-            Ok(final_encrypted_token)
+    /// Tags the [`metrics::STATE_DUPLICATE`] metric with.
+    fn kind(&self) -> &'static str {
+        match self {
+            StateDuplicate::Flag { .. } => "flag",
+            StateDuplicate::Secret { .. } => "secret",
         }
+    }
+}
 
-        #[cfg(not(feature = "std"))]
-        {
-            // Null encryption for no_std when key is all zeros
-            if encryption_key.iter().all(|&b| b == 0) {
-                Ok(token_data.to_vec())
-            } else {
-                Err("Encryption not available in no_std mode".to_string())
+impl core::fmt::Display for StateDuplicate {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StateDuplicate::Flag { name } => {
+                write!(f, "flag {} is listed more than once in the state", name)
             }
+            StateDuplicate::Secret { kept, replaced } => write!(
+                f,
+                "{} and {} have the same client secret, resolves with it are attributed to {}",
+                replaced, kept, kept
+            ),
         }
     }
+}
 
-    fn decrypt_resolve_token(
-        encrypted_data: &[u8],
-        encryption_key: &[u8],
-    ) -> Result<Vec<u8>, String> {
-        #[cfg(feature = "std")]
-        {
-            {
-                const ENCRYPTION_WRITE_BUFFER_SIZE: usize = 4096;
+#[derive(Debug)]
+pub struct ResolverState {
+    pub secrets: HashMap<String, Client>,
+    // shared with the states that ResolverState::apply_delta derives while unchanged
+    pub flags: Arc<HashMap<String, Flag>>,
+    pub segments: Arc<HashMap<String, Segment>>,
+    pub bitsets: Arc<HashMap<String, Bitset>>,
+    /// Segments without a bitset whose membership is answered by
+    /// [`Host::segment_membership`], see [`membership`].
+    pub external_segments: HashSet<String>,
+    /// Keys obtained from [`Host::get_encryption_key`], per client credential.
+    pub encryption_keys: RwLock<HashMap<String, EncryptionKey>>,
+    pub config: ResolverConfig,
+    /// Checked before the rules of every resolved flag, see [`ResolverState::kill_switch`].
+    pub kill_switches: Vec<KillSwitch>,
+    /// Identifies the state in resolve tokens and assign logs. A hash of the encoded state proto
+    /// for states loaded with [`ResolverState::from_bytes`] or a fingerprinted
+    /// [`state_builder::ResolverStateBuilder`], empty for states loaded from a decoded proto
+    /// unless the host sets its own.
+    pub fingerprint: String,
+    /// Watches applies for resolve tokens that fail to decrypt, see [`decrypt_breaker`].
+    pub decrypt_breaker: Option<Arc<DecryptBreaker>>,
+    derived: OnceLock<Derived>,
+}
 
-                use crypto::{aes, blockmodes, buffer};
+/// What a packed bitset of the resolver state proto says about the units in its segment.
+enum PackedMembership {
+    Bitset(Bitset),
+    /// Every unit.
+    Full,
+    /// Answered by [`Host::segment_membership`].
+    External,
+}
 
-                let mut iv = [0u8; 16];
-                iv.copy_from_slice(encrypted_data.get(0..16).or_fail()?);
+impl PackedMembership {
+    fn unpack(bitset: flags_admin::resolver_state::packed_bitset::Bitset) -> Fallible<Self> {
+        use flags_admin::resolver_state::packed_bitset::Bitset as Packed;
+        Ok(match bitset {
+            Packed::GzippedBitset(zipped_bytes) => {
+                PackedMembership::Bitset(Bitset::gzipped(zipped_bytes))
+            }
+            Packed::FullBitset(true) => PackedMembership::Full,
+            Packed::External(true) => PackedMembership::External,
+            _ => fail!(),
+        })
+    }
+}
 
-                let mut decryptor = aes::cbc_decryptor(
-                    aes::KeySize::KeySize128,
-                    &iv,
-                    encryption_key,
-                    blockmodes::PkcsPadding,
-                );
+/// The secret of a client secret `credential` and the client it resolves for, `None` for
+/// other kinds of credentials.
+fn credential_client(
+    credential: &iam::ClientCredential,
+    account_id: &str,
+    config: &ResolverConfig,
+) -> Option<(String, Client)> {
+    let client_name = ResourceName::parse_as(&credential.name, ResourceKind::ClientCredential)
+        .ok()?
+        .parent()?;
+    let Some(iam::client_credential::Credential::ClientSecret(client_secret)) =
+        &credential.credential
+    else {
+        return None;
+    };
+    Some((
+        client_secret.secret.clone(),
+        Client {
+            account: Account::new(&format!("accounts/{}", account_id)),
+            client_name: client_name.as_str().to_string(),
+            client_credential_name: credential.name.clone(),
+            context_logging: config
+                .credential_context_logging
+                .get(&credential.name)
+                .cloned()
+                .unwrap_or_default(),
+        },
+    ))
+}
 
-                let encrypted_token_read_buffer =
-                    &mut buffer::RefReadBuffer::new(encrypted_data.get(16..).or_fail()?);
-                let mut write_buffer = [0; ENCRYPTION_WRITE_BUFFER_SIZE];
-                let encrypted_token_write_buffer =
-                    &mut buffer::RefWriteBuffer::new(&mut write_buffer);
+/// Lookup structures derived from the flags and segments of a state, built when the state is
+/// loaded, or on first use for states that are assembled by hand. Pruning only removes
+/// segments, so it leaves these valid.
+#[derive(Debug, Default)]
+struct Derived {
+    /// Names of the active flags of each client, in the iteration order of the flags.
+    client_flags: HashMap<String, Vec<String>>,
+    /// The versions bounding range rules, parsed.
+    versions: value::Versions,
+}
 
-                let mut final_decrypted_token = Vec::<u8>::new();
-                loop {
-                    use crypto::buffer::{BufferResult, ReadBuffer, WriteBuffer};
+impl Derived {
+    fn new(flags: &HashMap<String, Flag>, segments: &HashMap<String, Segment>) -> Self {
+        let mut derived = Derived::default();
+        for flag in flags
+            .values()
+            .filter(|flag| flag.state() == flags_admin::flag::State::Active)
+        {
+            for client in &flag.clients {
+                derived
+                    .client_flags
+                    .entry(client.clone())
+                    .or_default()
+                    .push(flag.name.clone());
+            }
+        }
+        let criteria = segments
+            .values()
+            .filter_map(|segment| segment.targeting.as_ref())
+            .flat_map(|targeting| targeting.criteria.values());
+        for criterion in criteria {
+            if let Some(criterion::Criterion::Attribute(attribute_criterion)) = &criterion.criterion
+            {
+                value::collect_versions(attribute_criterion, &mut derived.versions);
+            }
+        }
+        derived
+    }
+}
 
-                    let result = decryptor
-                        .decrypt(
-                            encrypted_token_read_buffer,
-                            encrypted_token_write_buffer,
-                            true,
-                        )
-                        .or_fail()?;
+impl ResolverState {
+    /// Loads a state proto. Duplicate flags and client secrets replace the earlier ones, see
+    /// [`ResolverState::from_proto_with_options`] to have them reported.
+    pub fn from_proto(state_pb: ResolverStatePb, account_id: &str) -> Fallible<Self> {
+        Ok(ResolverState::load(
+            state_pb,
+            account_id,
+            String::new(),
+            ResolverConfig::default(),
+        )?
+        .0)
+    }
 
-                    final_decrypted_token.extend(
-                        encrypted_token_write_buffer
-                            .take_read_buffer()
-                            .take_remaining()
-                            .iter()
-                            .copied(),
-                    );
+    /// Like [`ResolverState::from_proto`] for an encoded state proto, which also sets the
+    /// [`fingerprint`](ResolverState::fingerprint) of the state from `encoded`.
+    pub fn from_bytes(encoded: &[u8], account_id: &str) -> Fallible<Self> {
+        let state_pb = ResolverStatePb::decode(encoded).or_fail()?;
+        let fingerprint = ResolverState::fingerprint_of(encoded);
+        Ok(ResolverState::load(state_pb, account_id, fingerprint, ResolverConfig::default())?.0)
+    }
 
-                    match result {
-                        BufferResult::BufferUnderflow => break,
-                        BufferResult::BufferOverflow => {}
-                    }
-                }
+    /// The [`fingerprint`](ResolverState::fingerprint) of the state encoded in `encoded`.
+    pub fn fingerprint_of(encoded: &[u8]) -> String {
+        format!("{:032x}", murmur3_x64_128(encoded, 0))
+    }
 
-                Ok(final_decrypted_token)
+    fn load(
+        state_pb: ResolverStatePb,
+        account_id: &str,
+        fingerprint: String,
+        config: ResolverConfig,
+    ) -> Fallible<(Self, Vec<StateDuplicate>)> {
+        let mut secrets = HashMap::new();
+        let mut flags = HashMap::new();
+        let mut segments = HashMap::new();
+        let mut bitsets = HashMap::new();
+        let mut external_segments = HashSet::new();
+        let mut duplicates = Vec::new();
+
+        for flag in state_pb.flags {
+            let name = flag.name.clone();
+            if flags.insert(name.clone(), flag).is_some() {
+                duplicates.push(StateDuplicate::Flag { name });
             }
-            .map_err(|e: ErrorCode| format!("failed to decrypt resolve token [{}]", e.b64_str()))
         }
-
-        #[cfg(not(feature = "std"))]
-        {
-            // Null decryption for no_std when key is all zeros
-            if encryption_key.iter().all(|&b| b == 0) {
-                Ok(encrypted_data.to_vec())
-            } else {
-                Err("decryption not available in no_std mode".into())
+        for segment in state_pb.segments_no_bitsets {
+            segments.insert(segment.name.clone(), segment);
+        }
+        for bitset in state_pb.bitsets {
+            let Some(b) = bitset.bitset else { continue };
+            match PackedMembership::unpack(b)? {
+                PackedMembership::Bitset(b) => {
+                    bitsets.insert(bitset.segment, b);
+                }
+                // missing bitset treated as full
+                PackedMembership::Full => (),
+                PackedMembership::External => {
+                    external_segments.insert(bitset.segment);
+                }
+            }
+        }
+        for client in state_pb.clients {
+            for credential in &state_pb.client_credentials {
+                let Some((secret, credential_client)) =
+                    credential_client(credential, account_id, &config)
+                else {
+                    continue;
+                };
+                if credential_client.client_name != client.name {
+                    continue;
+                }
+                let kept = credential_client.client_credential_name.clone();
+                if let Some(replaced) = secrets.insert(secret, credential_client) {
+                    if replaced.client_credential_name != kept {
+                        duplicates.push(StateDuplicate::Secret {
+                            kept,
+                            replaced: replaced.client_credential_name,
+                        });
+                    }
+                }
             }
         }
+
+        let derived = Derived::new(&flags, &segments);
+        let state = ResolverState {
+            secrets,
+            flags: Arc::new(flags),
+            segments: Arc::new(segments),
+            bitsets: Arc::new(bitsets),
+            external_segments,
+            encryption_keys: RwLock::new(HashMap::new()),
+            config,
+            kill_switches: state_pb
+                .kill_switches
+                .into_iter()
+                .map(KillSwitch::from)
+                .collect(),
+            fingerprint,
+            decrypt_breaker: None,
+            derived: OnceLock::from(derived),
+        };
+        Ok((state, duplicates))
     }
-}
 
-pub struct AccountResolver<'a, H: Host> {
-    pub client: &'a Client,
-    pub state: &'a ResolverState,
-    pub evaluation_context: EvaluationContext,
-    pub encryption_key: Bytes,
-    host: PhantomData<H>,
-}
+    /// Like [`ResolverState::apply_delta`] for an encoded delta proto. The fingerprint of the
+    /// result chains the fingerprint of this state with `encoded`, so hosts applying the same
+    /// deltas to the same snapshot agree on it.
+    pub fn apply_delta_bytes(&self, encoded: &[u8], account_id: &str) -> Fallible<ResolverState> {
+        let delta = flags_admin::ResolverStateDelta::decode(encoded).or_fail()?;
+        let mut state = self.apply_delta(delta, account_id)?;
+        state.fingerprint =
+            ResolverState::fingerprint_of(&[self.fingerprint.as_bytes(), encoded].concat());
+        Ok(state)
+    }
 
-#[derive(Debug)]
-pub enum ResolveFlagError {
-    Message(String),
-    MissingMaterializations(),
-}
+    /// Builds the state that results from applying `delta` to this one, resolving the same as
+    /// a state loaded from a full snapshot with the changes made. This state is left as is, so
+    /// hosts sharing it can swap in the result, and the flags, segments and bitsets that the
+    /// delta doesn't change are shared with it. Kill switches of removed flags are removed too.
+    /// Fails if a bitset of the delta is invalid or a credential belongs to a client that no
+    /// flag or credential of the state refers to. Like states loaded from a decoded proto, the
+    /// result has no fingerprint, see [`ResolverState::apply_delta_bytes`].
+    pub fn apply_delta(
+        &self,
+        delta: flags_admin::ResolverStateDelta,
+        account_id: &str,
+    ) -> Fallible<ResolverState> {
+        let bitsets = delta
+            .bitsets
+            .into_iter()
+            .filter_map(|bitset| Some((bitset.segment, bitset.bitset?)))
+            .map(|(segment, b)| Ok((segment, PackedMembership::unpack(b)?)))
+            .collect::<Fallible<Vec<_>>>()?;
+        let known_clients: HashSet<&str> = self
+            .secrets
+            .values()
+            .map(|client| client.client_name.as_str())
+            .chain(
+                self.flags
+                    .values()
+                    .chain(&delta.flags)
+                    .flat_map(|flag| flag.clients.iter().map(String::as_str)),
+            )
+            .collect();
+        let mut added_secrets = Vec::with_capacity(delta.client_credentials.len());
+        for credential in &delta.client_credentials {
+            if let Some((secret, client)) = credential_client(credential, account_id, &self.config)
+            {
+                if !known_clients.contains(client.client_name.as_str()) {
+                    fail!();
+                }
+                added_secrets.push((secret, client));
+            }
+        }
 
-impl ResolveFlagError {
-    fn message(&self) -> String {
-        match self {
-            ResolveFlagError::Message(msg) => msg.clone(),
-            ResolveFlagError::MissingMaterializations() => "Missing materializations".to_string(),
+        let removed_credentials: HashSet<&str> = delta
+            .removed_client_credentials
+            .iter()
+            .chain(delta.client_credentials.iter().map(|c| &c.name))
+            .map(String::as_str)
+            .collect();
+        let mut secrets: HashMap<String, Client> = self
+            .secrets
+            .iter()
+            .filter(|(_, client)| {
+                !removed_credentials.contains(client.client_credential_name.as_str())
+            })
+            .map(|(secret, client)| (secret.clone(), client.clone()))
+            .collect();
+        secrets.extend(added_secrets);
+        let encryption_keys = self
+            .encryption_keys
+            .read()
+            .map(|keys| {
+                keys.iter()
+                    .filter(|(credential, _)| !removed_credentials.contains(credential.as_str()))
+                    .map(|(credential, key)| (credential.clone(), key.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut flags = Arc::clone(&self.flags);
+        if !delta.removed_flags.is_empty() || !delta.flags.is_empty() {
+            let flags = Arc::make_mut(&mut flags);
+            for name in &delta.removed_flags {
+                flags.remove(name);
+            }
+            for flag in delta.flags {
+                flags.insert(flag.name.clone(), flag);
+            }
+        }
+        let mut segments = Arc::clone(&self.segments);
+        if !delta.removed_segments.is_empty() || !delta.segments_no_bitsets.is_empty() {
+            let segments = Arc::make_mut(&mut segments);
+            for name in &delta.removed_segments {
+                segments.remove(name);
+            }
+            for segment in delta.segments_no_bitsets {
+                segments.insert(segment.name.clone(), segment);
+            }
+        }
+        let mut segment_bitsets = Arc::clone(&self.bitsets);
+        let mut external_segments = self.external_segments.clone();
+        if !delta.removed_segments.is_empty() || !bitsets.is_empty() {
+            let segment_bitsets = Arc::make_mut(&mut segment_bitsets);
+            for name in &delta.removed_segments {
+                segment_bitsets.remove(name);
+                external_segments.remove(name);
+            }
+            for (segment, membership) in bitsets {
+                segment_bitsets.remove(&segment);
+                external_segments.remove(&segment);
+                match membership {
+                    PackedMembership::Bitset(b) => {
+                        segment_bitsets.insert(segment, b);
+                    }
+                    PackedMembership::Full => (),
+                    PackedMembership::External => {
+                        external_segments.insert(segment);
+                    }
+                }
+            }
         }
-    }
 
-    pub fn err(message: &str) -> ResolveFlagError {
-        ResolveFlagError::Message(message.to_string())
+        let added_kill_switches: Vec<KillSwitch> = delta
+            .kill_switches
+            .into_iter()
+            .map(KillSwitch::from)
+            .collect();
+        let mut kill_switches: Vec<KillSwitch> = self
+            .kill_switches
+            .iter()
+            .filter(|kill_switch| {
+                !delta.removed_kill_switches.contains(&kill_switch.flag)
+                    && !(delta.removed_flags.contains(&kill_switch.flag)
+                        && !flags.contains_key(&kill_switch.flag))
+                    && !added_kill_switches.iter().any(|added| {
+                        added.flag == kill_switch.flag && added.client == kill_switch.client
+                    })
+            })
+            .cloned()
+            .collect();
+        kill_switches.extend(added_kill_switches);
+
+        let derived = Derived::new(&flags, &segments);
+        Ok(ResolverState {
+            secrets,
+            flags,
+            segments,
+            bitsets: segment_bitsets,
+            external_segments,
+            encryption_keys: RwLock::new(encryption_keys),
+            config: self.config.clone(),
+            kill_switches,
+            fingerprint: String::new(),
+            decrypt_breaker: self.decrypt_breaker.clone(),
+            derived: OnceLock::from(derived),
+        })
     }
 
-    pub fn missing_materializations() -> ResolveFlagError {
-        ResolveFlagError::MissingMaterializations()
+    fn derived(&self) -> &Derived {
+        self.derived
+            .get_or_init(|| Derived::new(&self.flags, &self.segments))
     }
-}
 
-impl From<ResolveFlagError> for String {
-    fn from(value: ResolveFlagError) -> Self {
-        value.message().to_string()
+    /// Does the one-time work of resolving up front instead of in the first resolves:
+    /// decompresses every bitset and builds the flag index and parsed versions if the state
+    /// wasn't loaded with [`ResolverState::from_proto`]. Fails if a bitset can't be
+    /// decompressed.
+    pub fn warm_up(&self) -> Result<(), String> {
+        self.derived();
+        for (segment, bitset) in self.bitsets.iter() {
+            bitset
+                .bits()
+                .map_err(|e| format!("failed to decompress bitset of {}: {}", segment, e))?;
+        }
+        Ok(())
     }
-}
 
-impl From<ErrorCode> for ResolveFlagError {
-    fn from(value: ErrorCode) -> Self {
-        ResolveFlagError::err(format!("error code {}", &value.to_string()).as_str())
+    /// The active flags of `client_name`.
+    pub fn client_flags<'a>(&'a self, client_name: &str) -> impl Iterator<Item = &'a Flag> {
+        self.derived()
+            .client_flags
+            .get(client_name)
+            .into_iter()
+            .flatten()
+            .filter_map(|name| self.flags.get(name))
     }
-}
 
-impl ResolveWithStickyResponse {
-    fn with_success(response: ResolveFlagsResponse, updates: Vec<MaterializationUpdate>) -> Self {
-        ResolveWithStickyResponse {
-            resolve_result: Some(ResolveResult::Success(
-                resolve_with_sticky_response::Success {
-                    response: Some(response),
-                    updates,
-                },
-            )),
-        }
+    /// Like [`ResolverState::from_proto`], reporting the time it took to [`Host::on_metric`] and
+    /// the duplicates it found with [`StateDuplicate::report`].
+    pub fn from_proto_with_host<H: Host>(
+        state_pb: ResolverStatePb,
+        account_id: &str,
+    ) -> Fallible<Self> {
+        let timer = MetricTimer::<H>::start();
+        let loaded = ResolverState::load(
+            state_pb,
+            account_id,
+            String::new(),
+            ResolverConfig::default(),
+        );
+        timer.finish(metrics::STATE_LOAD_DURATION, &[]);
+        let (state, duplicates) = loaded?;
+        StateDuplicate::report::<H>(&duplicates);
+        Ok(state)
     }
 
-    fn with_missing_materializations(
-        items: Vec<resolve_with_sticky_response::MissingMaterializationItem>,
-    ) -> Self {
-        ResolveWithStickyResponse {
-            resolve_result: Some(ResolveResult::MissingMaterializations(
-                resolve_with_sticky_response::MissingMaterializations { items },
-            )),
-        }
+    pub fn from_proto_with_options(
+        state_pb: ResolverStatePb,
+        account_id: &str,
+        options: &LoadOptions,
+    ) -> Fallible<(Self, LoadReport)> {
+        let (mut state, duplicates) =
+            ResolverState::load(state_pb, account_id, String::new(), options.config.clone())?;
+        let pruned = if options.prune_unreferenced {
+            state.prune_unreferenced()
+        } else {
+            PruneReport::default()
+        };
+        Ok((state, LoadReport { pruned, duplicates }))
     }
-}
 
-impl ResolveWithStickyRequest {
-    fn without_sticky(resolve_request: ResolveFlagsRequest) -> ResolveWithStickyRequest {
-        ResolveWithStickyRequest {
-            resolve_request: Some(resolve_request),
-            fail_fast_on_sticky: false,
-            not_process_sticky: true,
-            materializations_per_unit: BTreeMap::new(),
-        }
+    /// Removes segments and bitsets not reachable from the rules of any active flag. Only
+    /// active flags are ever resolved, so this doesn't change any resolve result.
+    pub fn prune_unreferenced(&mut self) -> PruneReport {
+        let referenced: HashSet<String> = self
+            .referenced_segments()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let mut report = PruneReport::default();
+        Arc::make_mut(&mut self.segments).retain(|name, _| {
+            let keep = referenced.contains(name);
+            if !keep {
+                report.segments.push(name.clone());
+            }
+            keep
+        });
+        Arc::make_mut(&mut self.bitsets).retain(|name, _| {
+            let keep = referenced.contains(name);
+            if !keep {
+                report.bitsets.push(name.clone());
+            }
+            keep
+        });
+        self.external_segments
+            .retain(|name| referenced.contains(name.as_str()));
+        report.segments.sort();
+        report.bitsets.sort();
+        report
     }
-}
 
-impl<'a, H: Host> AccountResolver<'a, H> {
-    pub fn new(
-        client: &'a Client,
-        state: &'a ResolverState,
-        evaluation_context: EvaluationContext,
-        encryption_key: &Bytes,
-    ) -> AccountResolver<'a, H> {
-        AccountResolver {
-            client,
-            state,
-            evaluation_context,
-            encryption_key: encryption_key.clone(),
-            host: PhantomData,
+    /// Estimates the memory held by this state. Map entries and bitsets are counted by
+    /// capacity; flags and segments by their in-memory size plus their encoded length, which
+    /// approximates the strings and collections they own.
+    pub fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            flags: map_size(&self.flags, |flag| flag.encoded_len()),
+            segments: map_size(&self.segments, |segment| segment.encoded_len()),
+            bitsets: map_size(&self.bitsets, Bitset::heap_size),
+            secrets: map_size(&self.secrets, |client| {
+                client
+                    .account
+                    .name
+                    .capacity()
+                    .saturating_add(client.client_name.capacity())
+                    .saturating_add(client.client_credential_name.capacity())
+            }),
         }
     }
 
-    pub fn resolve_flags_sticky(
+    /// The population of the bitset of `segment` with a sample of up to `sample_size` of its
+    /// buckets, to debug segments matching fewer or more units than expected.
+    pub fn segment_population(
         &self,
-        request: &flags_resolver::ResolveWithStickyRequest,
-    ) -> Result<ResolveWithStickyResponse, String> {
-        let timestamp = H::current_time();
+        segment: &str,
+        sample_size: usize,
+    ) -> Result<SegmentPopulation, String> {
+        let bitset = self.segment_bitset(segment)?;
+        let invalid = |e: ErrorCode| format!("invalid bitset for {} [{}]", segment, e.b64_str());
+        Ok(SegmentPopulation {
+            population: bitset.population().map_err(invalid)?,
+            bucket_count: bitset.bits().map_err(invalid)?.len(),
+            sample: bitset.sample(sample_size).map_err(invalid)?,
+        })
+    }
 
-        let resolve_request = &request.resolve_request.clone().or_fail()?;
-        let flag_names = resolve_request.flags.clone();
-        let flags_to_resolve = self
-            .state
-            .flags
-            .values()
-            .filter(|flag| flag.state() == flags_admin::flag::State::Active)
-            .filter(|flag| flag.clients.contains(&self.client.client_name))
-            .filter(|flag| flag_names.is_empty() || flag_names.contains(&flag.name))
-            .collect::<Vec<&Flag>>();
+    /// The buckets of `segment` that are in its bitset in state `to` but not in state `from`,
+    /// and the other way around, to see how a segment changed between two states.
+    pub fn diff_segments(
+        from: &ResolverState,
+        to: &ResolverState,
+        segment: &str,
+    ) -> Result<BitsetDiff, String> {
+        from.segment_bitset(segment)?
+            .diff(to.segment_bitset(segment)?)
+            .map_err(|e| format!("invalid bitset for {} [{}]", segment, e.b64_str()))
+    }
 
-        if flags_to_resolve.len() > MAX_NO_OF_FLAGS_TO_BATCH_RESOLVE {
-            return Err(format!(
-                "max {} flags allowed in a single resolve request, this request would return {} flags.",
-                MAX_NO_OF_FLAGS_TO_BATCH_RESOLVE,
-                flags_to_resolve.len()));
-        }
+    fn segment_bitset(&self, segment: &str) -> Result<&Bitset, String> {
+        self.bitsets
+            .get(segment)
+            .ok_or_else(|| format!("no bitset for segment {}", segment))
+    }
 
-        if let Ok(Some(unit)) = self.get_targeting_key(TARGETING_KEY) {
-            if unit.len() > 100 {
-                return Err("Targeting key is too larger, max 100 characters.".to_string());
+    /// Decompresses the bitsets of all segments that active flags can reach, so that the
+    /// first resolves don't pay for it and corrupt bitsets are reported up front. Returns the
+    /// number of bitsets that were decompressed by this call.
+    pub fn prewarm_bitsets(&self) -> Result<usize, String> {
+        let mut decompressed = 0usize;
+        for segment in self.referenced_segments() {
+            let Some(bitset) = self.bitsets.get(segment) else {
+                continue;
+            };
+            if !bitset.is_loaded() {
+                decompressed = decompressed.saturating_add(1);
             }
+            bitset
+                .bits()
+                .map_err(|e| format!("invalid bitset for {} [{}]", segment, e.b64_str()))?;
         }
+        Ok(decompressed)
+    }
 
-        let mut resolve_results = Vec::with_capacity(flags_to_resolve.len());
+    /// Names of the segments used by the rules of active flags, including segments referenced
+    /// from their targeting.
+    pub fn referenced_segments(&self) -> HashSet<&str> {
+        self.segments_used_by(
+            self.flags
+                .values()
+                .filter(|flag| flag.state() == flags_admin::flag::State::Active),
+        )
+    }
 
-        let mut has_missing_materializations = false;
+    fn segments_used_by<'s>(&'s self, flags: impl Iterator<Item = &'s Flag>) -> HashSet<&'s str> {
+        self.segments_reachable_from(
+            flags
+                .flat_map(|flag| flag.rules.iter())
+                .map(|rule| rule.segment.as_str()),
+        )
+    }
 
-        for flag in flags_to_resolve.clone() {
-            let resolve_result = self.resolve_flag(flag, request.materializations_per_unit.clone());
-            match resolve_result {
-                Ok(resolve_result) => resolve_results.push(resolve_result),
-                Err(err) => {
-                    return match err {
-                        ResolveFlagError::Message(msg) => Err(msg.to_string()),
-                        ResolveFlagError::MissingMaterializations() => {
-                            if request.not_process_sticky {
-                                continue;
-                            }
-                            // we want to fallback on online resolver, return early
-                            if request.fail_fast_on_sticky {
-                                Ok(ResolveWithStickyResponse::with_missing_materializations(
-                                    vec![],
-                                ))
-                            } else {
-                                has_missing_materializations = true;
-                                break;
-                            }
-                        }
-                    };
+    /// `segments` and the segments their targeting refers to, recursively.
+    fn segments_reachable_from<'s>(
+        &'s self,
+        segments: impl Iterator<Item = &'s str>,
+    ) -> HashSet<&'s str> {
+        let mut referenced = HashSet::new();
+        let mut pending: Vec<&str> = segments.collect();
+        while let Some(name) = pending.pop() {
+            if !referenced.insert(name) {
+                continue;
+            }
+            let Some(targeting) = self.segments.get(name).and_then(|s| s.targeting.as_ref()) else {
+                continue;
+            };
+            for criterion in targeting.criteria.values() {
+                if let Some(criterion::Criterion::Segment(segment_criterion)) = &criterion.criterion
+                {
+                    pending.push(segment_criterion.segment.as_str());
                 }
             }
         }
+        referenced
+    }
 
-        if has_missing_materializations {
-            let result = self.collect_missing_materializations(flags_to_resolve);
-            if let Ok(missing) = result {
-                return Ok(ResolveWithStickyResponse::with_missing_materializations(
-                    missing,
-                ));
-            } else {
-                return Err("Could not collect missing materializations".to_string());
+    /// The kill switch for `flag` when resolved by `client_name`. A switch for that client
+    /// takes precedence over one for all clients.
+    pub fn kill_switch(&self, flag: &str, client_name: &str) -> Option<&KillSwitch> {
+        let mut for_flag = self.kill_switches.iter().filter(|k| k.flag == flag);
+        let mut global = None;
+        for kill_switch in &mut for_flag {
+            match &kill_switch.client {
+                Some(client) if client == client_name => return Some(kill_switch),
+                Some(_) => {}
+                None => global = global.or(Some(kill_switch)),
             }
         }
+        global
+    }
 
-        let resolved_values: Vec<ResolvedValue> = resolve_results
-            .iter()
-            .map(|r| r.resolved_value.clone())
-            .collect();
-
-        let resolve_id = H::random_alphanumeric(32);
-        let mut response = flags_resolver::ResolveFlagsResponse {
-            resolve_id: resolve_id.clone(),
-            ..Default::default()
-        };
-        let mut updates: Vec<MaterializationUpdate> = vec![];
-        for resolved_value in &resolved_values {
-            response.resolved_flags.push(resolved_value.into());
+    /// The evaluation context field `rule` of `flag` takes its unit from: the rule's own
+    /// selector, else the selector configured for the flag in
+    /// [`ResolverConfig::flag_targeting_key_selectors`], else the flag's selector, else the
+    /// account default.
+    pub fn targeting_key_selector<'s>(&'s self, flag: &'s Flag, rule: &'s Rule) -> &'s str {
+        if !rule.targeting_key_selector.is_empty() {
+            return &rule.targeting_key_selector;
         }
-
-        // Collect all materialization updates from all resolve results
-        for resolve_result in &resolve_results {
-            updates.extend(resolve_result.updates.clone());
+        if let Some(selector) = self.config.flag_targeting_key_selectors.get(&flag.name) {
+            return selector;
+        }
+        if !flag.targeting_key_selector.is_empty() {
+            return &flag.targeting_key_selector;
         }
+        &self.config.default_targeting_key
+    }
 
-        if resolve_request.apply {
-            let flags_to_apply: Vec<FlagToApply> = resolved_values
-                .iter()
-                .filter(|v| v.should_apply)
-                .map(|v| FlagToApply {
-                    assigned_flag: v.into(),
-                    skew_adjusted_applied_time: timestamp.clone(),
-                })
-                .collect();
+    /// Exports this state as a resolver state proto that [`ResolverState::from_proto`] loads
+    /// back to an equivalent state. Bitsets keep their original gzipped bytes, or are
+    /// compressed again if they were built from bits. The account and region are not part of
+    /// the state and are left unset, and neither is the [`ResolverState::config`].
+    pub fn to_proto(&self) -> Result<ResolverStatePb, String> {
+        let segment_names: HashSet<&str> = self
+            .segments
+            .keys()
+            .chain(self.bitsets.keys())
+            .chain(self.external_segments.iter())
+            .map(String::as_str)
+            .collect();
+        self.build_proto(
+            self.flags.values().cloned().collect(),
+            segment_names,
+            self.secrets.iter().collect(),
+            self.kill_switches.iter().collect(),
+        )
+    }
 
-            H::log_assign(
-                &resolve_id,
-                &self.evaluation_context.context,
-                flags_to_apply.as_slice(),
-                self.client,
-                &resolve_request.sdk.clone(),
-            );
-        } else {
-            // create resolve token
-            let mut resolve_token_v1 = flags_resolver::ResolveTokenV1 {
-                resolve_id: resolve_id.clone(),
-                evaluation_context: Some(self.evaluation_context.context.clone()),
-                ..Default::default()
-            };
-            for resolved_value in &resolved_values {
-                let assigned_flag: AssignedFlag = resolved_value.into();
-                resolve_token_v1
-                    .assignments
-                    .insert(assigned_flag.flag.clone(), assigned_flag);
+    /// Builds a resolver state holding only what `client_secret` can resolve: the active
+    /// flags of its client, the segments and bitsets those flags reach, and the client with
+    /// this one credential. Loading the result with [`ResolverState::from_proto`] resolves
+    /// the same as this state for that secret.
+    pub fn slice_for_client(&self, client_secret: &str) -> Result<ResolverStatePb, String> {
+        let (secret, client) = self
+            .secrets
+            .get_key_value(client_secret)
+            .ok_or("client secret not found".to_string())?;
+        let flags: Vec<&Flag> = self.client_flags(&client.client_name).collect();
+        let segment_names = self.segments_used_by(flags.iter().copied());
+        let kill_switches = self
+            .kill_switches
+            .iter()
+            .filter(|k| flags.iter().any(|flag| flag.name == k.flag))
+            .filter(|k| k.client.as_ref().is_none_or(|c| *c == client.client_name))
+            .collect();
+        self.build_proto(
+            flags.into_iter().cloned().collect(),
+            segment_names,
+            vec![(secret, client)],
+            kill_switches,
+        )
+    }
+
+    fn build_proto(
+        &self,
+        mut flags: Vec<Flag>,
+        segment_names: HashSet<&str>,
+        mut secrets: Vec<(&String, &Client)>,
+        kill_switches: Vec<&KillSwitch>,
+    ) -> Result<ResolverStatePb, String> {
+        flags.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut segment_names: Vec<&str> = segment_names.into_iter().collect();
+        segment_names.sort_unstable();
+        let mut segments = Vec::with_capacity(segment_names.len());
+        let mut bitsets = Vec::with_capacity(segment_names.len());
+        for name in segment_names {
+            let segment = self.segments.get(name);
+            if let Some(segment) = segment {
+                segments.push(segment.clone());
             }
+            let bitset = match self.bitsets.get(name) {
+                Some(bitset) => bitset
+                    .packed()
+                    .map_err(|e| format!("invalid bitset for {} [{}]", name, e.b64_str()))?,
+                None if self.external_segments.contains(name) => {
+                    flags_admin::resolver_state::packed_bitset::Bitset::External(true)
+                }
+                None if segment.is_some() => {
+                    flags_admin::resolver_state::packed_bitset::Bitset::FullBitset(true)
+                }
+                None => continue,
+            };
+            bitsets.push(flags_admin::resolver_state::PackedBitset {
+                segment: name.to_string(),
+                bitset: Some(bitset),
+            });
+        }
 
-            let resolve_token = flags_resolver::ResolveToken {
-                resolve_token: Some(flags_resolver::resolve_token::ResolveToken::TokenV1(
-                    resolve_token_v1,
+        secrets.sort_by(|a, b| a.1.client_credential_name.cmp(&b.1.client_credential_name));
+        let mut clients: Vec<iam::Client> = Vec::new();
+        let mut client_credentials = Vec::with_capacity(secrets.len());
+        for (secret, client) in secrets {
+            if !clients.iter().any(|c| c.name == client.client_name) {
+                clients.push(iam::Client {
+                    name: client.client_name.clone(),
+                    ..Default::default()
+                });
+            }
+            client_credentials.push(iam::ClientCredential {
+                name: client.client_credential_name.clone(),
+                credential: Some(iam::client_credential::Credential::ClientSecret(
+                    iam::client_credential::ClientSecret {
+                        secret: secret.clone(),
+                    },
                 )),
-            };
+                ..Default::default()
+            });
+        }
 
-            let encrypted_token = self
-                .encrypt_resolve_token(&resolve_token)
-                .map_err(|_| "Failed to encrypt resolve token".to_string())
-                .or_fail()?;
+        Ok(ResolverStatePb {
+            flags,
+            segments_no_bitsets: segments,
+            bitsets,
+            clients,
+            client_credentials,
+            kill_switches: kill_switches.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        })
+    }
 
-            response.resolve_token = encrypted_token;
-        }
+    #[cfg(feature = "json")]
+    pub fn get_resolver_with_json_context<'a, H: Host>(
+        &'a self,
+        client_secret: &str,
+        evaluation_context: &str,
+        encryption_key: &EncryptionKey,
+    ) -> Result<AccountResolver<'a, H>, GetResolverError> {
+        self.get_resolver(
+            client_secret,
+            // allow this unwrap cause it only happens in std
+            #[allow(clippy::unwrap_used)]
+            serde_json::from_str(evaluation_context)
+                .map_err(|_| GetResolverError::err("failed to parse evaluation context"))?,
+            encryption_key,
+        )
+    }
 
-        H::log_resolve(
-            &resolve_id,
-            &self.evaluation_context.context,
-            &resolved_values,
-            self.client,
-            &resolve_request.sdk.clone(),
-        );
+    pub fn get_resolver<'a, H: Host>(
+        &'a self,
+        client_secret: &str,
+        evaluation_context: Struct,
+        encryption_key: &EncryptionKey,
+    ) -> Result<AccountResolver<'a, H>, GetResolverError> {
+        self.secrets
+            .get(client_secret)
+            .ok_or(GetResolverError::UnknownClientSecret)
+            .map(|client| {
+                AccountResolver::new(
+                    client,
+                    self,
+                    EvaluationContext {
+                        context: evaluation_context,
+                    },
+                    encryption_key,
+                )
+            })
+    }
 
-        Ok(ResolveWithStickyResponse::with_success(response, updates))
+    /// Like [`ResolverState::get_resolver`], but with the resolve token encryption key of the
+    /// client's credential provided by [`Host::get_encryption_key`]. Keys are cached for the
+    /// lifetime of this state, so rotated keys are picked up with the next state update.
+    pub fn get_resolver_with_host_key<'a, H: Host>(
+        &'a self,
+        client_secret: &str,
+        evaluation_context: Struct,
+    ) -> Result<AccountResolver<'a, H>, GetResolverError> {
+        let client = self
+            .secrets
+            .get(client_secret)
+            .ok_or(GetResolverError::UnknownClientSecret)?;
+        let encryption_key = self.encryption_key::<H>(&client.client_credential_name)?;
+        Ok(AccountResolver::new(
+            client,
+            self,
+            EvaluationContext {
+                context: evaluation_context,
+            },
+            &encryption_key,
+        )
+        .with_secondary_keys(H::get_secondary_encryption_keys(
+            &client.client_credential_name,
+        )))
     }
 
-    pub fn resolve_flags(
+    /// Resolves `request` with a resolver for its client secret. Requests for a client secret
+    /// that isn't part of this state are handed to [`Host::resolve_unknown_secret`].
+    pub fn resolve_flags<H: Host>(
         &self,
-        request: &flags_resolver::ResolveFlagsRequest,
-    ) -> Result<flags_resolver::ResolveFlagsResponse, String> {
-        let response = self.resolve_flags_sticky(&ResolveWithStickyRequest::without_sticky(
-            flags_resolver::ResolveFlagsRequest {
-                flags: request.flags.clone(),
-                sdk: request.sdk.clone(),
-                evaluation_context: request.evaluation_context.clone(),
-                client_secret: request.client_secret.clone(),
-                apply: request.apply,
-            },
-        ));
+        request: &ResolveFlagsRequest,
+        encryption_key: &EncryptionKey,
+    ) -> Result<ResolveFlagsResponse, String> {
+        let evaluation_context = request.evaluation_context.clone().unwrap_or_default();
+        match self.get_resolver::<H>(&request.client_secret, evaluation_context, encryption_key) {
+            Ok(resolver) => resolver.resolve_flags(request),
+            Err(GetResolverError::UnknownClientSecret) => H::resolve_unknown_secret(request)
+                .unwrap_or_else(|| Err(GetResolverError::UnknownClientSecret.into())),
+            Err(e) => Err(e.into()),
+        }
+    }
 
-        match response {
-            Ok(v) => match v.resolve_result {
-                None => Err("failed to resolve flags".to_string()),
-                Some(r) => match r {
-                    ResolveResult::Success(flags_response) => match flags_response.response {
-                        Some(flags_response) => Ok(flags_response),
-                        None => Err("failed to resolve flags".to_string()),
-                    },
-                    ResolveResult::MissingMaterializations(_) => {
-                        Err("sticky assignments is not supported".to_string())
+    /// Resolves each request with a resolver for the client secret it is paired with, which
+    /// takes the place of the request's own `client_secret`, for proxies multiplexing the
+    /// requests of several clients. Every request is resolved and logged like
+    /// [`ResolverState::resolve_flags`] would; the batch only saves the per-client work of
+    /// building the resolvers: the client of a secret and its keys are looked up once per
+    /// secret of the batch, including a failed [`Host::get_encryption_key`], which the state
+    /// doesn't cache. Results are in the order of the requests; one failing doesn't fail the
+    /// others.
+    pub fn resolve_batch<H: Host>(
+        &self,
+        requests: Vec<(String, ResolveFlagsRequest)>,
+    ) -> Vec<Result<ResolveFlagsResponse, String>> {
+        type Keys = (EncryptionKey, Vec<EncryptionKey>);
+        let mut clients: HashMap<String, Result<(&Client, Keys), GetResolverError>> =
+            HashMap::new();
+        requests
+            .into_iter()
+            .map(|(client_secret, mut request)| {
+                let client = clients.entry(client_secret.clone()).or_insert_with(|| {
+                    let client = self
+                        .secrets
+                        .get(&client_secret)
+                        .ok_or(GetResolverError::UnknownClientSecret)?;
+                    let credential = &client.client_credential_name;
+                    let key = self.encryption_key::<H>(credential)?;
+                    Ok((client, (key, H::get_secondary_encryption_keys(credential))))
+                });
+                request.client_secret = client_secret;
+                match client {
+                    Ok((client, (key, secondary_keys))) => {
+                        let evaluation_context = EvaluationContext {
+                            context: request.evaluation_context.clone().unwrap_or_default(),
+                        };
+                        AccountResolver::<H>::new(client, self, evaluation_context, key)
+                            .with_secondary_keys(secondary_keys.clone())
+                            .resolve_flags(&request)
                     }
-                },
-            },
-            Err(e) => Err(e),
-        }
+                    Err(GetResolverError::UnknownClientSecret) => {
+                        H::resolve_unknown_secret(&request)
+                            .unwrap_or_else(|| Err(GetResolverError::UnknownClientSecret.into()))
+                    }
+                    Err(e) => Err(e.clone().into()),
+                }
+            })
+            .collect()
     }
 
-    pub fn apply_flags(&self, request: &flags_resolver::ApplyFlagsRequest) -> Result<(), String> {
-        let send_time_ts = request.send_time.as_ref().ok_or("send_time is required")?;
-        let send_time = to_date_time_utc(send_time_ts).ok_or("invalid send_time")?;
-        let receive_time: DateTime<Utc> = timestamp_to_datetime(&H::current_time())?;
+    fn encryption_key<H: Host>(&self, client_credential: &str) -> Result<EncryptionKey, String> {
+        let cached = self
+            .encryption_keys
+            .read()
+            .ok()
+            .and_then(|keys| keys.get(client_credential).cloned());
+        if let Some(key) = cached {
+            return Ok(key);
+        }
+        let key = H::get_encryption_key(client_credential).map_err(|e| {
+            format!(
+                "failed to get encryption key for {}: {}",
+                client_credential, e
+            )
+        })?;
+        if let Ok(mut keys) = self.encryption_keys.write() {
+            keys.insert(client_credential.to_string(), key.clone());
+        }
+        Ok(key)
+    }
+}
 
-        let resolve_token_outer = self.decrypt_resolve_token(&request.resolve_token)?;
-        let Some(flags_resolver::resolve_token::ResolveToken::TokenV1(resolve_token)) =
-            resolve_token_outer.resolve_token
-        else {
-            return Err("resolve token is not a V1 token".to_string());
-        };
+pub struct EvaluationContext {
+    pub context: Struct,
+}
+#[derive(Debug, Clone)]
+pub struct FlagToApply {
+    pub assigned_flag: AssignedFlag,
+    pub skew_adjusted_applied_time: Timestamp,
+    /// Receive time minus send time of the apply request, `None` for flags applied as part of
+    /// the resolve.
+    pub clock_skew_millis: Option<i64>,
+    /// [`ResolverState::fingerprint`] of the state the flag was resolved with.
+    pub state_fingerprint: String,
+}
 
-        let assignments = resolve_token.assignments;
-        let evaluation_context = resolve_token
-            .evaluation_context
-            .as_ref()
-            .ok_or("missing evaluation context")?;
+/// A page of [`AccountResolver::resolve_flags_paged`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResolveFlagsPage {
+    /// A resolve of the flags of the page, with its own resolve id and token that clients apply
+    /// the flags with.
+    pub response: flags_resolver::ResolveFlagsResponse,
+    /// Resolves the next page when passed back, empty on the last page.
+    pub next_page_token: String,
+}
 
-        // ensure that all flags are present before we start sending events
-        let mut assigned_flags: Vec<FlagToApply> = Vec::with_capacity(request.flags.len());
-        for applied_flag in &request.flags {
-            let Some(assigned_flag) = assignments.get(&applied_flag.flag) else {
-                return Err("Flag in resolve token does not match flag in request".to_string());
-            };
-            let Some(apply_time) = applied_flag.apply_time.as_ref() else {
-                return Err(format!("Missing apply time for flag {}", applied_flag.flag));
-            };
-            let apply_time = to_date_time_utc(apply_time).or_fail()?;
-            let skew = send_time.signed_duration_since(apply_time);
-            let adjusted_time = receive_time.checked_sub_signed(skew).or_fail()?;
-            let skew_adjusted_applied_time = datetime_to_timestamp(&adjusted_time);
-            assigned_flags.push(FlagToApply {
-                assigned_flag: assigned_flag.clone(),
-                skew_adjusted_applied_time,
-            });
-        }
+/// The time a flag was applied according to the resolver's clock: `receive` minus the time
+/// that passed between `apply` and `send` on the client's clock, so that client clocks that are
+/// off don't skew applied times. [`AccountResolver::apply_flags`] computes applied times this
+/// way, hosts that batch or forward applies can use it to compute the same times.
+///
+/// Apply times after the send time are not clamped and give times after `receive`. `None` if
+/// a timestamp has nanos out of range or the result doesn't fit in a `Timestamp`.
+pub fn compute_skew_adjusted_time(
+    send: &Timestamp,
+    apply: &Timestamp,
+    receive: &Timestamp,
+) -> Option<Timestamp> {
+    let skew = time::to_nanos(send)?.checked_sub(time::to_nanos(apply)?)?;
+    time::from_nanos(time::to_nanos(receive)?.checked_sub(skew)?)
+}
 
-        H::log_assign(
-            &resolve_token.resolve_id,
-            evaluation_context,
-            assigned_flags.as_slice(),
-            self.client,
-            &request.sdk,
-        );
+pub trait Host {
+    #[cfg(not(feature = "std"))]
+    fn random_alphanumeric(len: usize) -> String;
+    #[cfg(feature = "std")]
+    fn random_alphanumeric(len: usize) -> String {
+        use rand::distr::{Alphanumeric, SampleString};
+        Alphanumeric.sample_string(&mut rand::rng(), len)
+    }
 
+    /// Fills `buf` from a cryptographically secure random number generator, for the nonces of
+    /// resolve tokens encrypted by an [`encryption`] provider. A nonce that repeats or can be
+    /// predicted breaks AES-GCM and ChaCha20-Poly1305, so a host without such a generator
+    /// returns an error, which fails encrypting the token. With `std` the default uses the
+    /// operating system seeded generator of `rand`.
+    #[cfg(not(feature = "std"))]
+    fn random_bytes(buf: &mut [u8]) -> Result<(), String>;
+    #[cfg(feature = "std")]
+    fn random_bytes(buf: &mut [u8]) -> Result<(), String> {
+        use rand::RngCore;
+        rand::rng().fill_bytes(buf);
         Ok(())
     }
 
-    fn get_targeting_key(&self, targeting_key: &str) -> Result<Option<String>, String> {
-        let unit_value = self.get_attribute_value(targeting_key);
-        match &unit_value.kind {
-            None => Ok(None),
-            Some(Kind::NullValue(_)) => Ok(None),
-            Some(Kind::StringValue(string_unit)) => Ok(Some(string_unit.clone())),
-            Some(Kind::NumberValue(num_value)) => {
-                if num_value.is_finite() && num_value.fract() == 0.0 {
-                    Ok(Some(format!("{:.0}", num_value)))
-                } else {
-                    Err("TargetingKeyError".to_string())
-                }
-            }
-            _ => Err("TargetingKeyError".to_string()),
+    fn log(_: &str) {
+        // noop
+    }
+
+    /// Receives low-level measurements like resolve durations and token failures, see
+    /// [`metrics`] for the names reported.
+    fn on_metric(_name: &str, _value: f64, _tags: &[(&str, &str)]) {
+        // noop
+    }
+
+    /// A monotonic clock reading in nanoseconds, used to record evaluation times on the
+    /// `tracing` spans of flags and rules. Hosts without a monotonic clock return `None`.
+    fn monotonic_nanos() -> Option<u64> {
+        None
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn current_time() -> Timestamp;
+    #[cfg(feature = "std")]
+    fn current_time() -> Timestamp {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Timestamp {
+            seconds: i64::try_from(now.as_secs()).unwrap_or(i64::MAX),
+            nanos: now.subsec_nanos() as i32,
         }
     }
-    pub fn resolve_flag_name(
-        &'a self,
-        flag_name: &str,
-    ) -> Result<FlagResolveResult<'a>, ResolveFlagError> {
-        self.state
-            .flags
-            .get(flag_name)
-            .ok_or(ResolveFlagError::err("flag not found"))
-            .and_then(|flag| self.resolve_flag(flag, BTreeMap::new()))
+
+    /// Whether `unit` is a member of the external `segment`, see [`membership`]. Failures
+    /// are logged and the unit is treated as not in the segment. The default knows no
+    /// external segments.
+    fn segment_membership(segment: &str, _unit: &str) -> Result<bool, String> {
+        Err(format!("no membership provider for {}", segment))
     }
 
-    pub fn collect_missing_materializations(
-        &'a self,
-        flags: Vec<&'a Flag>,
-    ) -> Result<Vec<resolve_with_sticky_response::MissingMaterializationItem>, String> {
-        let mut missing_materializations: Vec<
-            resolve_with_sticky_response::MissingMaterializationItem,
-        > = Vec::new();
-        for flag in flags {
-            let result = self.collect_missing_materializations_for_flag(flag);
-            if let Ok(items) = result {
-                missing_materializations.extend(items);
-            } else {
-                return Err(format!(
-                    "Could not collect missing materializations for flag {}",
-                    flag.name
-                ));
-            }
-        }
-        Ok(missing_materializations)
+    fn log_resolve(
+        resolve_id: &str,
+        evaluation_context: &Struct,
+        values: &[ResolvedValue<'_>],
+        client: &Client,
+        sdk: &Option<flags_resolver::Sdk>,
+    );
+
+    fn log_assign(
+        resolve_id: &str,
+        evaluation_context: &Struct,
+        assigned_flags: &[FlagToApply],
+        client: &Client,
+        sdk: &Option<flags_resolver::Sdk>,
+    );
+
+    /// Answers a resolve for a client secret that isn't part of the resolver state, e.g. with
+    /// every flag at its default or by forwarding it to the hosted resolver. `None`, the
+    /// default, fails the resolve with [`GetResolverError::UnknownClientSecret`].
+    fn resolve_unknown_secret(
+        _request: &ResolveFlagsRequest,
+    ) -> Option<Result<ResolveFlagsResponse, String>> {
+        None
     }
 
-    fn collect_missing_materializations_for_flag(
-        &'a self,
-        flag: &'a Flag,
-    ) -> Result<Vec<resolve_with_sticky_response::MissingMaterializationItem>, String> {
-        let mut missing_materializations: Vec<
-            resolve_with_sticky_response::MissingMaterializationItem,
-        > = Vec::new();
+    /// Checks a resolve against host policies before any flag is evaluated, e.g. to deny
+    /// flags to some clients or to require consent attributes in the evaluation context.
+    /// `flags` are the flags the resolve would evaluate. The default allows every resolve.
+    fn authorize_resolve(
+        _client: &Client,
+        _flags: &[&Flag],
+        _evaluation_context: &Struct,
+    ) -> Result<(), DenyReason> {
+        Ok(())
+    }
 
-        if flag.state == flags_admin::flag::State::Archived as i32 {
-            return Ok(vec![]);
-        }
+    /// The resolve token encryption key for `client_credential`, used by
+    /// [`ResolverState::get_resolver_with_host_key`].
+    fn get_encryption_key(client_credential: &str) -> Result<EncryptionKey, String> {
+        Err(format!(
+            "no encryption key provided for {}",
+            client_credential
+        ))
+    }
 
-        for rule in &flag.rules {
-            if !rule.enabled {
-                continue;
-            }
+    /// Keys that resolve tokens of `client_credential` are decrypted with besides the one of
+    /// [`Host::get_encryption_key`], while rotating keys. Used by
+    /// [`ResolverState::get_resolver_with_host_key`], which asks for every resolver.
+    fn get_secondary_encryption_keys(_client_credential: &str) -> Vec<EncryptionKey> {
+        Vec::new()
+    }
 
-            if let Some(materialization_spec) = &rule.materialization_spec {
-                let rule_name = &rule.name.as_str();
-                let read_materialization = materialization_spec.read_materialization.as_str();
-                if !read_materialization.is_empty() {
-                    let targeting_key = if !rule.targeting_key_selector.is_empty() {
-                        rule.targeting_key_selector.as_str()
-                    } else {
-                        TARGETING_KEY
-                    };
-                    let unit: String = match self.get_targeting_key(targeting_key) {
-                        Ok(Some(u)) => u,
-                        Ok(None) => continue,
-                        Err(_) => return Err("Targeting key error".to_string()),
-                    };
-                    missing_materializations.push(
-                        resolve_with_sticky_response::MissingMaterializationItem {
-                            unit,
-                            rule: rule_name.to_string(),
-                            read_materialization: read_materialization.to_string(),
-                        },
-                    );
-                    continue;
-                }
-            }
-        }
-        Ok(missing_materializations)
+    /// Encrypts new resolve tokens instead of [`Host::encrypt_resolve_token`], see
+    /// [`encryption`]. The default encrypts with the host.
+    fn encryption_provider() -> Option<&'static dyn encryption::EncryptionProvider> {
+        None
     }
 
-    pub fn resolve_flag(
-        &'a self,
-        flag: &'a Flag,
-        sticky_context: BTreeMap<String, MaterializationMap>,
-    ) -> Result<FlagResolveResult<'a>, ResolveFlagError> {
-        let mut updates: Vec<MaterializationUpdate> = Vec::new();
-        let mut resolved_value = ResolvedValue::new(flag);
+    fn encrypt_resolve_token(
+        token_data: &[u8],
+        encryption_key: &EncryptionKey,
+    ) -> Result<Vec<u8>, String> {
+        #[cfg(feature = "std")]
+        {
+            const ENCRYPTION_WRITE_BUFFER_SIZE: usize = 4096;
 
-        if flag.state == flags_admin::flag::State::Archived as i32 {
-            return Ok(FlagResolveResult {
-                resolved_value: resolved_value.error(ResolveReason::FlagArchived),
-                updates: vec![],
-            });
-        }
+            use std::io::Write;
 
-        for rule in &flag.rules {
-            if !rule.enabled {
-                continue;
-            }
+            use crypto::{aes, blockmodes, buffer};
+            use rand::RngCore;
 
-            let segment_name = &rule.segment;
-            if !self.state.segments.contains_key(segment_name) {
-                // log something? ResolveReason::SEGMENT_NOT_FOUND
-                continue;
-            }
-            let segment = self.state.segments.get(segment_name).or_fail()?;
+            let mut iv = [0u8; 16];
+            rand::rng().fill_bytes(&mut iv);
 
-            let targeting_key = if !rule.targeting_key_selector.is_empty() {
-                rule.targeting_key_selector.as_str()
-            } else {
-                TARGETING_KEY
-            };
-            let unit: String = match self.get_targeting_key(targeting_key) {
-                Ok(Some(u)) => u,
-                Ok(None) => continue,
-                Err(_) => {
-                    return Ok(FlagResolveResult {
-                        resolved_value: resolved_value.error(ResolveReason::TargetingKeyError),
-                        updates: vec![],
-                    })
-                }
-            };
+            let mut final_encrypted_token = Vec::<u8>::new();
+            final_encrypted_token
+                .write(&iv)
+                .map_err(|_| "Failed to write iv to encrypted resolve token buffer".to_string())?;
 
-            let Some(spec) = &rule.assignment_spec else {
-                continue;
-            };
+            let mut encryptor = aes::cbc_encryptor(
+                aes_key_size(encryption_key),
+                &iv,
+                encryption_key.as_bytes(),
+                blockmodes::PkcsPadding,
+            );
 
-            let mut materialization_matched = false;
-            if let Some(materialization_spec) = &rule.materialization_spec {
-                let read_materialization = &materialization_spec.read_materialization;
-                if !read_materialization.is_empty() {
-                    if let Some(info) = sticky_context.get(&unit) {
-                        let info_from_context = info.info_map.get(read_materialization);
+            let token_read_buffer = &mut buffer::RefReadBuffer::new(token_data);
+            let mut write_buffer = [0; ENCRYPTION_WRITE_BUFFER_SIZE];
+            let token_write_buffer = &mut buffer::RefWriteBuffer::new(&mut write_buffer);
 
-                        if let Some(info_data) = info_from_context {
-                            if !info_data.unit_in_info {
-                                if materialization_spec
-                                    .mode
-                                    .as_ref()
-                                    .map(|mode| mode.materialization_must_match)
-                                    .unwrap_or(false)
-                                {
-                                    // Materialization must match but unit is not in materialization
-                                    continue;
-                                }
-                                materialization_matched = false;
-                            } else if materialization_spec
-                                .mode
-                                .as_ref()
-                                .map(|mode| mode.segment_targeting_can_be_ignored)
-                                .unwrap_or(false)
-                            {
-                                materialization_matched = true;
-                            } else {
-                                materialization_matched = self.segment_match(segment, &unit)?;
-                            }
-                        } else {
-                            return Err(ResolveFlagError::missing_materializations());
-                        }
+            loop {
+                use crypto::buffer::{BufferResult, ReadBuffer, WriteBuffer};
 
-                        if materialization_matched {
-                            if let Some(variant_name) = info_from_context
-                                .as_ref()
-                                .and_then(|info| info.rule_to_variant.get(&rule.name))
-                            {
-                                if let Some(assignment) =
-                                    spec.assignments.iter().find(|assignment| {
-                                        if let Some(rule::assignment::Assignment::Variant(
-                                            ref variant_assignment,
-                                        )) = &assignment.assignment
-                                        {
-                                            variant_assignment.variant == *variant_name
-                                        } else {
-                                            false
-                                        }
-                                    })
-                                {
-                                    let variant = flag
-                                        .variants
-                                        .iter()
-                                        .find(|v| v.name == *variant_name)
-                                        .or_fail()?;
-                                    return Ok(FlagResolveResult {
-                                        resolved_value: resolved_value.with_variant_match(
-                                            rule,
-                                            segment,
-                                            variant,
-                                            &assignment.assignment_id,
-                                            &unit,
-                                        ),
-                                        updates: vec![],
-                                    });
-                                }
-                            }
-                        }
-                    } else {
-                        return Err(ResolveFlagError::missing_materializations());
-                    };
+                let result = encryptor
+                    .encrypt(token_read_buffer, token_write_buffer, true)
+                    .map_err(|_| "Failed to encrypt resolve token".to_string())?;
+
+                final_encrypted_token.extend(
+                    token_write_buffer
+                        .take_read_buffer()
+                        .take_remaining()
+                        .iter()
+                        .copied(),
+                );
+
+                match result {
+                    BufferResult::BufferUnderflow => break,
+                    BufferResult::BufferOverflow => {}
                 }
             }
 
-            if !materialization_matched && !self.segment_match(segment, &unit)? {
-                // ResolveReason::SEGMENT_NOT_MATCH
-                continue;
-            }
-            let bucket_count = spec.bucket_count;
-            let variant_salt = segment_name.split("/").nth(1).or_fail()?;
-            let key = format!("{}|{}", variant_salt, unit);
-            let bucket = bucket(hash(&key), bucket_count as u64)? as i32;
+            Ok(final_encrypted_token)
+        }
 
-            let matched_assignment = spec.assignments.iter().find(|assignment| {
-                assignment
-                    .bucket_ranges
-                    .iter()
-                    .any(|range| range.lower <= bucket && bucket < range.upper)
-            });
+        #[cfg(not(feature = "std"))]
+        {
+            // Null encryption for no_std when key is all zeros
+            if encryption_key.is_zero() {
+                Ok(token_data.to_vec())
+            } else {
+                Err("Encryption not available in no_std mode".to_string())
+            }
+        }
+    }
 
-            let has_write_spec = rule
-                .materialization_spec
-                .as_ref()
-                .map(|materialization_spec| &materialization_spec.write_materialization);
+    fn decrypt_resolve_token(
+        encrypted_data: &[u8],
+        encryption_key: &EncryptionKey,
+    ) -> Result<Vec<u8>, String> {
+        #[cfg(feature = "std")]
+        {
+            {
+                const ENCRYPTION_WRITE_BUFFER_SIZE: usize = 4096;
 
-            if let Some(assignment) = matched_assignment {
-                let Some(a) = &assignment.assignment else {
-                    continue;
-                };
+                use crypto::{aes, blockmodes, buffer};
 
-                // Extract variant name from assignment if it's a variant assignment
-                let variant_name = match a {
-                    rule::assignment::Assignment::Variant(ref variant_assignment) => {
-                        variant_assignment.variant.clone()
-                    }
-                    _ => "".to_string(),
-                };
+                let mut iv = [0u8; 16];
+                iv.copy_from_slice(encrypted_data.get(0..16).or_fail()?);
 
-                // write the materialization info if write spec exists
-                if let Some(write_spec) = has_write_spec {
-                    updates.push(MaterializationUpdate {
-                        write_materialization: write_spec.to_string(),
-                        unit: unit.to_string(),
-                        rule: rule.clone().name,
-                        variant: variant_name,
-                    })
-                }
+                let mut decryptor = aes::cbc_decryptor(
+                    aes_key_size(encryption_key),
+                    &iv,
+                    encryption_key.as_bytes(),
+                    blockmodes::PkcsPadding,
+                );
 
-                match a {
-                    rule::assignment::Assignment::Fallthrough(_) => {
-                        resolved_value.attribute_fallthrough_rule(
-                            rule,
-                            &assignment.assignment_id,
-                            &unit,
-                        );
-                        continue;
-                    }
-                    rule::assignment::Assignment::ClientDefault(_) => {
-                        return Ok(FlagResolveResult {
-                            resolved_value: resolved_value.with_client_default_match(
-                                rule,
-                                segment,
-                                &assignment.assignment_id,
-                                &unit,
-                            ),
-                            updates,
-                        })
-                    }
-                    rule::assignment::Assignment::Variant(
-                        rule::assignment::VariantAssignment {
-                            variant: variant_name,
-                        },
-                    ) => {
-                        let variant = flag
-                            .variants
+                let encrypted_token_read_buffer =
+                    &mut buffer::RefReadBuffer::new(encrypted_data.get(16..).or_fail()?);
+                let mut write_buffer = [0; ENCRYPTION_WRITE_BUFFER_SIZE];
+                let encrypted_token_write_buffer =
+                    &mut buffer::RefWriteBuffer::new(&mut write_buffer);
+
+                let mut final_decrypted_token = Vec::<u8>::new();
+                loop {
+                    use crypto::buffer::{BufferResult, ReadBuffer, WriteBuffer};
+
+                    let result = decryptor
+                        .decrypt(
+                            encrypted_token_read_buffer,
+                            encrypted_token_write_buffer,
+                            true,
+                        )
+                        .or_fail()?;
+
+                    final_decrypted_token.extend(
+                        encrypted_token_write_buffer
+                            .take_read_buffer()
+                            .take_remaining()
                             .iter()
-                            .find(|variant| variant.name == *variant_name)
-                            .or_fail()?;
+                            .copied(),
+                    );
 
-                        return Ok(FlagResolveResult {
-                            resolved_value: resolved_value.with_variant_match(
-                                rule,
-                                segment,
-                                variant,
-                                &assignment.assignment_id,
-                                &unit,
-                            ),
-                            updates,
-                        });
+                    match result {
+                        BufferResult::BufferUnderflow => break,
+                        BufferResult::BufferOverflow => {}
                     }
-                };
+                }
+
+                Ok(final_decrypted_token)
             }
+            .map_err(|e: ErrorCode| format!("failed to decrypt resolve token [{}]", e.b64_str()))
         }
 
-        if resolved_value.reason == ResolveReason::Match {
-            resolved_value.should_apply = true;
-        } else {
-            resolved_value.should_apply = !resolved_value.fallthrough_rules.is_empty();
+        #[cfg(not(feature = "std"))]
+        {
+            // Null decryption for no_std when key is all zeros
+            if encryption_key.is_zero() {
+                Ok(encrypted_data.to_vec())
+            } else {
+                Err("decryption not available in no_std mode".into())
+            }
         }
+    }
+}
 
-        Ok(FlagResolveResult {
-            resolved_value,
-            updates,
+#[cfg(feature = "std")]
+fn aes_key_size(encryption_key: &EncryptionKey) -> crypto::aes::KeySize {
+    if encryption_key.as_bytes().len() == 32 {
+        crypto::aes::KeySize::KeySize256
+    } else {
+        crypto::aes::KeySize::KeySize128
+    }
+}
+
+/// Share of the buckets of a rule that `assignment` covers, in percent.
+#[cfg(feature = "tracing")]
+fn allocation_percent(assignment: &rule::Assignment, bucket_count: i32) -> f64 {
+    if bucket_count <= 0 {
+        return 0.0;
+    }
+    let buckets = assignment
+        .bucket_ranges
+        .iter()
+        .map(|range| {
+            i64::from(range.upper)
+                .saturating_sub(i64::from(range.lower))
+                .max(0)
         })
+        .fold(0i64, i64::saturating_add);
+    buckets as f64 * 100.0 / f64::from(bucket_count)
+}
+
+/// Records the time between its creation and drop as `elapsed_us` on a span.
+#[cfg(feature = "tracing")]
+struct SpanTimer<H: Host> {
+    span: tracing::Span,
+    start: Option<u64>,
+    host: PhantomData<H>,
+}
+
+#[cfg(feature = "tracing")]
+impl<H: Host> SpanTimer<H> {
+    fn start(span: tracing::Span) -> Self {
+        SpanTimer {
+            start: if span.is_disabled() {
+                None
+            } else {
+                H::monotonic_nanos()
+            },
+            span,
+            host: PhantomData,
+        }
     }
+}
 
-    /// Get an attribute value from the [EvaluationContext] struct, addressed by a path specification.
-    /// If the struct is `{user:{name:"roug",id:42}}`, then getting the `"user.name"` field will return
-    /// the value `"roug"`.
-    pub fn get_attribute_value(&self, field_path: &str) -> &Value {
-        let mut path_parts = field_path.split('.').peekable();
-        let mut s = &self.evaluation_context.context;
-
-        while let Some(field) = path_parts.next() {
-            match s.fields.get(field) {
-                Some(value) => {
-                    if path_parts.peek().is_none() {
-                        // we are at the end of the path, return the value
-                        return value;
-                    } else if let Some(Kind::StructValue(struct_value)) = &value.kind {
-                        // if we are not at the end of the path, and the value is a struct, continue
-                        s = struct_value;
-                    } else {
-                        // if we are not at the end of the path, but the value is not a struct, return null
-                        return &NULL;
-                    }
-                }
-                None => {
-                    // non-struct value addressed with .-operator
-                    return &NULL;
-                }
-            }
+#[cfg(feature = "tracing")]
+impl<H: Host> Drop for SpanTimer<H> {
+    fn drop(&mut self) {
+        if let (Some(start), Some(end)) = (self.start, H::monotonic_nanos()) {
+            self.span
+                .record("elapsed_us", end.saturating_sub(start).checked_div(1000));
+        }
+    }
+}
+
+pub struct AccountResolver<'a, H: Host> {
+    pub client: &'a Client,
+    pub state: &'a ResolverState,
+    pub evaluation_context: EvaluationContext,
+    pub encryption_key: EncryptionKey,
+    /// Keys that resolve tokens are decrypted with besides `encryption_key`, see
+    /// [`AccountResolver::with_secondary_keys`].
+    pub secondary_keys: Vec<EncryptionKey>,
+    /// Used instead of [`Host::current_time`] as the time of resolves, see
+    /// [`AccountResolver::with_resolution_time`].
+    pub resolution_time: Option<Timestamp>,
+    /// Whether resolves and applies skip [`Host::log_resolve`] and [`Host::log_assign`], see
+    /// [`AccountResolver::without_logging`].
+    pub suppress_logging: bool,
+    /// Whether resolve tokens are signed instead of encrypted, see
+    /// [`AccountResolver::with_signed_tokens`].
+    pub signed_tokens: bool,
+    host: PhantomData<H>,
+}
+
+/// Why [`ResolverState::get_resolver`] couldn't create a resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetResolverError {
+    /// The client secret isn't part of the resolver state.
+    UnknownClientSecret,
+    Message(String),
+}
+
+impl GetResolverError {
+    pub fn err(message: &str) -> GetResolverError {
+        GetResolverError::Message(message.to_string())
+    }
+}
+
+impl From<String> for GetResolverError {
+    fn from(value: String) -> Self {
+        GetResolverError::Message(value)
+    }
+}
+
+impl From<GetResolverError> for String {
+    fn from(value: GetResolverError) -> Self {
+        match value {
+            GetResolverError::UnknownClientSecret => "client secret not found".to_string(),
+            GetResolverError::Message(msg) => msg,
         }
+    }
+}
+
+/// Why [`Host::authorize_resolve`] denied a resolve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DenyReason {
+    /// The client may not resolve the named flag.
+    Flag(String),
+    Message(String),
+}
 
-        &NULL
+impl From<DenyReason> for String {
+    fn from(value: DenyReason) -> Self {
+        match value {
+            DenyReason::Flag(flag) => format!("resolve denied: {} is not allowed", flag),
+            DenyReason::Message(msg) => format!("resolve denied: {}", msg),
+        }
     }
+}
 
-    pub fn segment_match(&self, segment: &Segment, unit: &str) -> Fallible<bool> {
-        self.segment_match_internal(segment, unit, &mut HashSet::new())
+#[derive(Debug)]
+pub enum ResolveFlagError {
+    Message(String),
+    MissingMaterializations(),
+}
+
+impl ResolveFlagError {
+    fn message(&self) -> String {
+        match self {
+            ResolveFlagError::Message(msg) => msg.clone(),
+            ResolveFlagError::MissingMaterializations() => "Missing materializations".to_string(),
+        }
     }
 
-    fn segment_match_internal(
-        &self,
-        segment: &Segment,
-        unit: &str,
-        visited: &mut HashSet<String>,
-    ) -> Fallible<bool> {
-        if visited.contains(&segment.name) {
-            fail!("circular segment dependency found");
+    pub fn err(message: &str) -> ResolveFlagError {
+        ResolveFlagError::Message(message.to_string())
+    }
+
+    pub fn missing_materializations() -> ResolveFlagError {
+        ResolveFlagError::MissingMaterializations()
+    }
+}
+
+impl From<ResolveFlagError> for String {
+    fn from(value: ResolveFlagError) -> Self {
+        value.message().to_string()
+    }
+}
+
+impl From<ErrorCode> for ResolveFlagError {
+    fn from(value: ErrorCode) -> Self {
+        ResolveFlagError::err(format!("error code {}", &value.to_string()).as_str())
+    }
+}
+
+impl ResolveWithStickyResponse {
+    fn with_success(
+        response: ResolveFlagsResponse,
+        updates: Vec<MaterializationUpdate>,
+        warnings: Vec<ResolveWarning>,
+    ) -> Self {
+        ResolveWithStickyResponse {
+            resolve_result: Some(ResolveResult::Success(
+                resolve_with_sticky_response::Success {
+                    response: Some(response),
+                    updates,
+                    warnings,
+                },
+            )),
         }
-        visited.insert(segment.name.clone());
+    }
 
-        if !self.targeting_match(segment, unit, visited)? {
-            return Ok(false);
+    #[cfg(feature = "sticky")]
+    fn with_missing_materializations(
+        items: Vec<resolve_with_sticky_response::MissingMaterializationItem>,
+    ) -> Self {
+        ResolveWithStickyResponse {
+            resolve_result: Some(ResolveResult::MissingMaterializations(
+                resolve_with_sticky_response::MissingMaterializations { items },
+            )),
         }
+    }
+}
 
-        // check bitset
-        let Some(bitset) = self.state.bitsets.get(&segment.name) else {
-            return Ok(true);
-        }; // todo: would this match or not?
-        let salted_unit = self.client.account.salt_unit(unit)?;
-        let unit_hash = bucket(hash(&salted_unit), BUCKETS)?;
-        if unit_hash >= bitset.len() {
-            return Ok(false);
+impl ResolveWithStickyRequest {
+    fn without_sticky(resolve_request: ResolveFlagsRequest) -> ResolveWithStickyRequest {
+        ResolveWithStickyRequest {
+            resolve_request: Some(resolve_request),
+            fail_fast_on_sticky: false,
+            not_process_sticky: true,
+            materializations_per_unit: BTreeMap::new(),
+            suppress_logging: false,
+        }
+    }
+}
+
+impl<'a, H: Host> AccountResolver<'a, H> {
+    pub fn new(
+        client: &'a Client,
+        state: &'a ResolverState,
+        evaluation_context: EvaluationContext,
+        encryption_key: &EncryptionKey,
+    ) -> AccountResolver<'a, H> {
+        AccountResolver {
+            client,
+            state,
+            evaluation_context,
+            encryption_key: encryption_key.clone(),
+            secondary_keys: Vec::new(),
+            resolution_time: None,
+            suppress_logging: false,
+            signed_tokens: false,
+            host: PhantomData,
+        }
+    }
+
+    /// Resolves as of `time` rather than the current time of the host, to reproduce what a
+    /// resolve against an archived state gave at the time, e.g. for backtesting. Every
+    /// time-dependent part of a resolve uses it, such as the applied time of assignments. Like
+    /// with [`AccountResolver::without_logging`], nothing is logged, as a resolve of the past
+    /// must not show up in flag analytics.
+    pub fn with_resolution_time(mut self, time: Timestamp) -> Self {
+        self.resolution_time = Some(time);
+        self.without_logging()
+    }
+
+    /// Also decrypts resolve tokens with `keys`, for rotating keys without failing the applies
+    /// of tokens encrypted with the previous key. Tokens are still encrypted with
+    /// `encryption_key`. Tokens of keys with an [id](EncryptionKey::id) are decrypted with the
+    /// key of that id; the others are tried with every key without id. A key that gets an id
+    /// when rotating should therefore stay around without id until its old tokens expire.
+    pub fn with_secondary_keys(mut self, keys: Vec<EncryptionKey>) -> Self {
+        self.secondary_keys = keys;
+        self
+    }
+
+    /// Writes resolve tokens as plaintext with an HMAC-SHA256 signature keyed with
+    /// `encryption_key`, see [`HmacSha256`](encryption::HmacSha256), for deployments that need
+    /// tokens to be tamper-evident but not confidential. Applies reject tokens whose signature
+    /// doesn't match. Fails without the `std` feature, and for the zero key, with which tokens
+    /// would be written unsigned.
+    pub fn with_signed_tokens(mut self) -> Result<Self, String> {
+        if encryption::builtin(encryption::KEY_ID_HMAC_SHA256).is_none() {
+            return Err("signed resolve tokens need the std feature".to_string());
+        }
+        if self.encryption_key.is_zero() {
+            return Err("signed resolve tokens need a non-zero encryption key".to_string());
+        }
+        self.signed_tokens = true;
+        Ok(self)
+    }
+
+    /// Doesn't log the resolves and applies of this resolver, for synthetic traffic such as
+    /// health checks and smoke tests that shouldn't show up in flag analytics. A single resolve
+    /// can be left unlogged with [`AccountResolver::resolve_flags_without_logging`] or
+    /// `ResolveWithStickyRequest::suppress_logging`. Resolve tokens of unlogged resolves say
+    /// so, and applies of them aren't logged by any resolver.
+    pub fn without_logging(mut self) -> Self {
+        self.suppress_logging = true;
+        self
+    }
+
+    /// Tags the resolves and applies of this resolver as synthetic traffic by setting
+    /// [`flag_logger::SYNTHETIC_ATTRIBUTE`] in the evaluation context. Unlike
+    /// [`AccountResolver::without_logging`] they are still logged, so analytics can filter them
+    /// rather than never seeing them. The attribute is part of resolve tokens, so later applies
+    /// of the flags are tagged as well.
+    pub fn as_synthetic(mut self) -> Self {
+        self.evaluation_context.context.fields.insert(
+            flag_logger::SYNTHETIC_ATTRIBUTE.to_string(),
+            Value {
+                kind: Some(Kind::BoolValue(true)),
+            },
+        );
+        self
+    }
+
+    fn now(&self) -> Timestamp {
+        self.resolution_time.clone().unwrap_or_else(H::current_time)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(client = %self.client.client_name, resolve_id = tracing::field::Empty)
+        )
+    )]
+    fn resolve_timed(
+        &self,
+        request: &flags_resolver::ResolveWithStickyRequest,
+    ) -> Result<ResolveWithStickyResponse, String> {
+        let timer = MetricTimer::<H>::start();
+        let response = self.resolve_untimed(request);
+        timer.finish(
+            metrics::RESOLVE_DURATION,
+            &[("client", &self.client.client_name)],
+        );
+        response
+    }
+
+    /// Resolves with the `materializations_per_unit` of `request`, reporting entries that
+    /// [`AccountResolver::validate_materializations`] finds with [`MaterializationIssue::report`].
+    #[cfg(feature = "sticky")]
+    pub fn resolve_flags_sticky(
+        &self,
+        request: &flags_resolver::ResolveWithStickyRequest,
+    ) -> Result<ResolveWithStickyResponse, String> {
+        if !request.materializations_per_unit.is_empty() {
+            MaterializationIssue::report::<H>(&self.validate_materializations(request));
+        }
+        self.resolve_timed(request)
+    }
+
+    fn resolve_untimed(
+        &self,
+        request: &flags_resolver::ResolveWithStickyRequest,
+    ) -> Result<ResolveWithStickyResponse, String> {
+        let timestamp = self.now();
+
+        let resolve_request = &request.resolve_request.clone().or_fail()?;
+        let flags_to_resolve = self.checked_flags_to_resolve(resolve_request)?;
+
+        let mut resolve_results = Vec::with_capacity(flags_to_resolve.len());
+        let mut memo = ResolveMemo::default();
+
+        for flag in flags_to_resolve.clone() {
+            let resolve_result = self.resolve_flag_with_memo(
+                flag,
+                request.materializations_per_unit.clone(),
+                &mut memo,
+            );
+            match resolve_result {
+                Ok(resolve_result) => resolve_results.push(resolve_result),
+                Err(ResolveFlagError::Message(msg)) => return Err(msg.to_string()),
+                Err(ResolveFlagError::MissingMaterializations()) => {
+                    match self
+                        .missing_materializations_response(request, flags_to_resolve.clone())?
+                    {
+                        Some(response) => return Ok(response),
+                        None => continue,
+                    }
+                }
+            }
+        }
+
+        self.complete_resolve(
+            resolve_request,
+            resolve_results,
+            timestamp,
+            request.suppress_logging,
+        )
+    }
+
+    /// Starts a resolve that is carried out in steps with [`AccountResolver::resolve_step`]
+    /// and completed with [`AccountResolver::resolve_finish`], so that hosts with a single
+    /// event loop can interleave other work with large batch resolves. Every call may use a
+    /// new resolver as long as it is created from the same state, client and context.
+    pub fn resolve_begin(
+        &self,
+        request: flags_resolver::ResolveWithStickyRequest,
+    ) -> Result<ResolveProgress, String> {
+        let timestamp = self.now();
+        let resolve_request = request.resolve_request.as_ref().or_fail()?;
+        let flags = self
+            .checked_flags_to_resolve(resolve_request)?
+            .iter()
+            .map(|flag| flag.name.clone())
+            .collect();
+        Ok(ResolveProgress {
+            request,
+            timestamp,
+            flags,
+            next: 0,
+            results: Vec::new(),
+            early_response: None,
+            memo: ResolveMemo::default(),
+        })
+    }
+
+    /// Resolves up to `max_flags` more flags of `progress` and returns whether all flags
+    /// are done. A `max_flags` of 0 resolves one flag, so that stepping always progresses.
+    pub fn resolve_step(
+        &self,
+        progress: &mut ResolveProgress,
+        max_flags: usize,
+    ) -> Result<bool, String> {
+        let max_flags = max_flags.max(1);
+        let mut stepped = 0usize;
+        while !progress.is_done() && stepped < max_flags {
+            let name = progress.flags.get(progress.next).or_fail()?;
+            let flag = self
+                .state
+                .flags
+                .get(name)
+                .ok_or_else(|| format!("flag {} is no longer in the resolver state", name))?;
+            progress.next = progress.next.saturating_add(1);
+            stepped = stepped.saturating_add(1);
+            match self.resolve_flag_with_memo(
+                flag,
+                progress.request.materializations_per_unit.clone(),
+                &mut progress.memo,
+            ) {
+                Ok(result) => progress.results.push(DetachedResult::detach(&result)?),
+                Err(ResolveFlagError::Message(msg)) => return Err(msg.to_string()),
+                Err(ResolveFlagError::MissingMaterializations()) => {
+                    let flags = progress
+                        .flags
+                        .iter()
+                        .filter_map(|name| self.state.flags.get(name))
+                        .collect();
+                    progress.early_response =
+                        self.missing_materializations_response(&progress.request, flags)?;
+                }
+            }
+        }
+        Ok(progress.is_done())
+    }
+
+    /// Resolves any flags left in `progress`, then logs the resolve and builds the response
+    /// exactly like [`AccountResolver::resolve_flags_sticky`].
+    pub fn resolve_finish(
+        &self,
+        mut progress: ResolveProgress,
+    ) -> Result<ResolveWithStickyResponse, String> {
+        self.resolve_step(&mut progress, usize::MAX)?;
+        if let Some(response) = progress.early_response {
+            return Ok(response);
+        }
+        let resolve_results = progress
+            .results
+            .iter()
+            .map(|result| result.attach(self.state))
+            .collect::<Result<Vec<_>, String>>()?;
+        let resolve_request = progress.request.resolve_request.as_ref().or_fail()?;
+        self.complete_resolve(
+            resolve_request,
+            resolve_results,
+            progress.timestamp,
+            progress.request.suppress_logging,
+        )
+    }
+
+    fn checked_flags_to_resolve(
+        &self,
+        resolve_request: &flags_resolver::ResolveFlagsRequest,
+    ) -> Result<Vec<&'a Flag>, String> {
+        let flags_to_resolve = self.flags_to_resolve(&resolve_request.flags);
+
+        let config = &self.state.config;
+        if flags_to_resolve.len() > config.max_flags_per_resolve {
+            return Err(format!(
+                "max {} flags allowed in a single resolve request, this request would return {} flags.",
+                config.max_flags_per_resolve,
+                flags_to_resolve.len()));
+        }
+
+        if let Ok(Some(unit)) = self.get_targeting_key(&config.default_targeting_key) {
+            if unit.len() > config.max_targeting_key_length {
+                return Err(format!(
+                    "Targeting key is too larger, max {} characters.",
+                    config.max_targeting_key_length
+                ));
+            }
+        }
+
+        H::authorize_resolve(
+            self.client,
+            &flags_to_resolve,
+            &self.evaluation_context.context,
+        )?;
+        Ok(flags_to_resolve)
+    }
+
+    /// The response for a resolve that hit a flag with missing materializations, or `None`
+    /// if that flag should be skipped.
+    #[cfg(feature = "sticky")]
+    fn missing_materializations_response(
+        &self,
+        request: &flags_resolver::ResolveWithStickyRequest,
+        flags_to_resolve: Vec<&'a Flag>,
+    ) -> Result<Option<ResolveWithStickyResponse>, String> {
+        if request.not_process_sticky {
+            return Ok(None);
+        }
+        // we want to fallback on online resolver, return early
+        if request.fail_fast_on_sticky {
+            return Ok(Some(
+                ResolveWithStickyResponse::with_missing_materializations(vec![]),
+            ));
+        }
+        match self.collect_missing_materializations(flags_to_resolve) {
+            Ok(missing) => Ok(Some(
+                ResolveWithStickyResponse::with_missing_materializations(missing),
+            )),
+            Err(_) => Err("Could not collect missing materializations".to_string()),
+        }
+    }
+
+    /// Without the sticky feature no materializations are read, so flags reading them are
+    /// skipped in stateless resolves and fail sticky ones.
+    #[cfg(not(feature = "sticky"))]
+    fn missing_materializations_response(
+        &self,
+        request: &flags_resolver::ResolveWithStickyRequest,
+        _flags_to_resolve: Vec<&'a Flag>,
+    ) -> Result<Option<ResolveWithStickyResponse>, String> {
+        if request.not_process_sticky {
+            return Ok(None);
+        }
+        Err("sticky assignments is not supported".to_string())
+    }
+
+    fn complete_resolve(
+        &self,
+        resolve_request: &flags_resolver::ResolveFlagsRequest,
+        resolve_results: Vec<FlagResolveResult<'_>>,
+        timestamp: Timestamp,
+        suppress_logging: bool,
+    ) -> Result<ResolveWithStickyResponse, String> {
+        let config = &self.state.config;
+        let suppress_logging = suppress_logging || self.suppress_logging;
+        let resolved_values: Vec<ResolvedValue> = resolve_results
+            .iter()
+            .map(|r| r.resolved_value.clone())
+            .collect();
+
+        let resolve_id = H::random_alphanumeric(32);
+        #[cfg(feature = "tracing")]
+        {
+            tracing::Span::current().record("resolve_id", resolve_id.as_str());
+            for resolved_value in &resolved_values {
+                tracing::debug!(
+                    flag = %resolved_value.flag.name,
+                    rule = resolved_value
+                        .assignment_match
+                        .as_ref()
+                        .map(|m| m.rule.name.as_str())
+                        .unwrap_or_default(),
+                    reason = ?resolved_value.reason,
+                    "resolved flag"
+                );
+            }
+        }
+        let mut response = flags_resolver::ResolveFlagsResponse {
+            resolve_id: resolve_id.clone(),
+            ..Default::default()
+        };
+        let mut updates: Vec<MaterializationUpdate> = vec![];
+        for resolved_value in &resolved_values {
+            response.resolved_flags.push(resolved_value.into());
+        }
+
+        // Collect all materialization updates and warnings from all resolve results
+        let mut warnings: Vec<ResolveWarning> = vec![];
+        for resolve_result in &resolve_results {
+            updates.extend(resolve_result.updates.clone());
+            warnings.extend(resolve_result.warnings.iter().cloned());
+        }
+        for warning in &warnings {
+            H::log(&format!("{}: {}", warning.flag, warning.message));
+        }
+
+        if resolve_request.apply {
+            let flags_to_apply: Vec<FlagToApply> = resolved_values
+                .iter()
+                .filter(|v| v.should_apply)
+                .map(|v| FlagToApply {
+                    assigned_flag: v.to_assigned_flag(self.state),
+                    skew_adjusted_applied_time: timestamp.clone(),
+                    clock_skew_millis: None,
+                    state_fingerprint: self.state.fingerprint.clone(),
+                })
+                .collect();
+
+            if config.log_assigns && !suppress_logging {
+                H::log_assign(
+                    &resolve_id,
+                    &self.evaluation_context.context,
+                    flags_to_apply.as_slice(),
+                    self.client,
+                    &resolve_request.sdk.clone(),
+                );
+            }
+        } else {
+            // create resolve token
+            let mut resolve_token_v1 = flags_resolver::ResolveTokenV1 {
+                resolve_id: resolve_id.clone(),
+                evaluation_context: Some(self.evaluation_context.context.clone()),
+                state_fingerprint: self.state.fingerprint.clone(),
+                suppress_logging,
+                ..Default::default()
+            };
+            for resolved_value in &resolved_values {
+                let assigned_flag = resolved_value.to_assigned_flag(self.state);
+                resolve_token_v1
+                    .assignments
+                    .insert(assigned_flag.flag.clone(), assigned_flag);
+            }
+
+            let resolve_token = flags_resolver::ResolveToken {
+                resolve_token: Some(flags_resolver::resolve_token::ResolveToken::TokenV1(
+                    resolve_token_v1,
+                )),
+            };
+
+            let encrypted_token = self
+                .encrypt_resolve_token(&resolve_token)
+                .map_err(|_| "Failed to encrypt resolve token".to_string())
+                .or_fail()?;
+
+            response.resolve_token = encrypted_token;
+        }
+
+        if config.log_resolves && !suppress_logging {
+            H::log_resolve(
+                &resolve_id,
+                &self.evaluation_context.context,
+                &resolved_values,
+                self.client,
+                &resolve_request.sdk.clone(),
+            );
+        }
+
+        Ok(ResolveWithStickyResponse::with_success(
+            response, updates, warnings,
+        ))
+    }
+
+    pub fn resolve_flags(
+        &self,
+        request: &flags_resolver::ResolveFlagsRequest,
+    ) -> Result<flags_resolver::ResolveFlagsResponse, String> {
+        self.resolve_without_sticky(request, false)
+            .map(|(response, _)| response)
+    }
+
+    /// Same as [`AccountResolver::resolve_flags`], but neither the resolve nor applies of its
+    /// flags are logged, like a resolve of a resolver made with
+    /// [`AccountResolver::without_logging`].
+    pub fn resolve_flags_without_logging(
+        &self,
+        request: &flags_resolver::ResolveFlagsRequest,
+    ) -> Result<flags_resolver::ResolveFlagsResponse, String> {
+        self.resolve_without_sticky(request, true)
+            .map(|(response, _)| response)
+    }
+
+    /// Resolves the flags of `request` a page of at most `page_size` flags at a time, so that
+    /// hosts can resolve every flag of clients with more than
+    /// [`ResolverConfig::max_flags_per_resolve`] flags, which caps `page_size`. Flags are paged
+    /// in name order, starting after the flag named by `page_token`, so pages stay consistent
+    /// when flags are added or removed between them. An empty `page_token` starts from the
+    /// first flag, and a token past the last flag gives an empty page.
+    pub fn resolve_flags_paged(
+        &self,
+        request: &flags_resolver::ResolveFlagsRequest,
+        page_size: usize,
+        page_token: &str,
+    ) -> Result<ResolveFlagsPage, String> {
+        let page_size = page_size
+            .min(self.state.config.max_flags_per_resolve)
+            .max(1);
+        let mut names: Vec<&str> = self
+            .flags_to_resolve(&request.flags)
+            .into_iter()
+            .map(|flag| flag.name.as_str())
+            .filter(|name| *name > page_token)
+            .collect();
+        if names.is_empty() {
+            // an empty list of flags would resolve all of them
+            return Ok(ResolveFlagsPage::default());
+        }
+        names.sort_unstable();
+        let more = names.len() > page_size;
+        names.truncate(page_size);
+        let next_page_token = if more {
+            names.last().or_fail()?.to_string()
+        } else {
+            String::new()
+        };
+        let response = self.resolve_flags(&flags_resolver::ResolveFlagsRequest {
+            flags: names.into_iter().map(str::to_string).collect(),
+            ..request.clone()
+        })?;
+        Ok(ResolveFlagsPage {
+            response,
+            next_page_token,
+        })
+    }
+
+    /// Same as [`AccountResolver::resolve_flags`], but also returns the materialization updates
+    /// produced by rules with a `write_materialization`. Hosts can persist these to build up
+    /// materializations before they start serving sticky assignments.
+    #[cfg(feature = "sticky")]
+    pub fn resolve_flags_with_updates(
+        &self,
+        request: &flags_resolver::ResolveFlagsRequest,
+    ) -> Result<
+        (
+            flags_resolver::ResolveFlagsResponse,
+            Vec<MaterializationUpdate>,
+        ),
+        String,
+    > {
+        self.resolve_without_sticky(request, false)
+    }
+
+    fn resolve_without_sticky(
+        &self,
+        request: &flags_resolver::ResolveFlagsRequest,
+        suppress_logging: bool,
+    ) -> Result<
+        (
+            flags_resolver::ResolveFlagsResponse,
+            Vec<MaterializationUpdate>,
+        ),
+        String,
+    > {
+        let response = self.resolve_timed(&ResolveWithStickyRequest {
+            suppress_logging,
+            ..ResolveWithStickyRequest::without_sticky(flags_resolver::ResolveFlagsRequest {
+                flags: request.flags.clone(),
+                sdk: request.sdk.clone(),
+                evaluation_context: request.evaluation_context.clone(),
+                client_secret: request.client_secret.clone(),
+                apply: request.apply,
+            })
+        });
+
+        match response {
+            Ok(v) => match v.resolve_result {
+                None => Err("failed to resolve flags".to_string()),
+                Some(r) => match r {
+                    ResolveResult::Success(success) => match success.response {
+                        Some(flags_response) => Ok((flags_response, success.updates)),
+                        None => Err("failed to resolve flags".to_string()),
+                    },
+                    ResolveResult::MissingMaterializations(_) => {
+                        Err("sticky assignments is not supported".to_string())
+                    }
+                },
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn apply_flags(&self, request: &flags_resolver::ApplyFlagsRequest) -> Result<(), String> {
+        self.apply_flags_received_at(
+            request,
+            &H::current_time(),
+            self.state.decrypt_breaker.as_deref(),
+        )
+    }
+
+    /// Applies an apply held by an open [`DecryptBreaker`], as if it was applied when it was
+    /// received. A token that still fails to decrypt fails the replay rather than being held
+    /// again.
+    pub fn replay_held_apply(&self, held: &HeldApply) -> Result<(), String> {
+        self.apply_flags_received_at(&held.request, &held.receive_time, None)
+    }
+
+    fn apply_flags_received_at(
+        &self,
+        request: &flags_resolver::ApplyFlagsRequest,
+        receive_time: &Timestamp,
+        breaker: Option<&DecryptBreaker>,
+    ) -> Result<(), String> {
+        let send_time = request.send_time.as_ref().ok_or("send_time is required")?;
+        time::to_nanos(send_time).ok_or("invalid send_time")?;
+        let clock_skew_millis = time::millis_between(send_time, receive_time).or_fail()?;
+
+        let resolve_token_outer = match self.decrypt_resolve_token(&request.resolve_token) {
+            Ok(token) => {
+                if let Some(breaker) = breaker {
+                    breaker.record_success::<H>();
+                }
+                token
+            }
+            Err(e) => {
+                if let Some(breaker) = breaker {
+                    breaker.record_failure::<H>();
+                    if breaker.hold::<H>(request, receive_time) {
+                        return Ok(());
+                    }
+                }
+                return Err(e);
+            }
+        };
+        let Some(flags_resolver::resolve_token::ResolveToken::TokenV1(resolve_token)) =
+            resolve_token_outer.resolve_token
+        else {
+            return Err("resolve token is not a V1 token".to_string());
+        };
+
+        let assignments = resolve_token.assignments;
+        let evaluation_context = resolve_token
+            .evaluation_context
+            .as_ref()
+            .ok_or("missing evaluation context")?;
+
+        // ensure that all flags are present before we start sending events
+        let mut assigned_flags: Vec<FlagToApply> = Vec::with_capacity(request.flags.len());
+        for applied_flag in &request.flags {
+            let Some(assigned_flag) = assignments.get(&applied_flag.flag) else {
+                return Err("Flag in resolve token does not match flag in request".to_string());
+            };
+            let Some(apply_time) = applied_flag.apply_time.as_ref() else {
+                return Err(format!("Missing apply time for flag {}", applied_flag.flag));
+            };
+            let skew_adjusted_applied_time =
+                compute_skew_adjusted_time(send_time, apply_time, receive_time).or_fail()?;
+            assigned_flags.push(FlagToApply {
+                assigned_flag: assigned_flag.clone(),
+                skew_adjusted_applied_time,
+                clock_skew_millis: Some(clock_skew_millis),
+                state_fingerprint: resolve_token.state_fingerprint.clone(),
+            });
+        }
+
+        if self.state.config.log_assigns
+            && !self.suppress_logging
+            && !resolve_token.suppress_logging
+        {
+            H::log_assign(
+                &resolve_token.resolve_id,
+                evaluation_context,
+                assigned_flags.as_slice(),
+                self.client,
+                &request.sdk,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Applies flags from several resolve tokens in one call, e.g. when an SDK batches applies
+    /// across earlier resolves. Every request is validated and logged independently, so the
+    /// returned outcomes line up with `requests` and a bad token only fails its own group.
+    pub fn apply_flags_batch(
+        &self,
+        requests: &[flags_resolver::ApplyFlagsRequest],
+    ) -> Vec<Result<(), String>> {
+        requests
+            .iter()
+            .map(|request| self.apply_flags(request))
+            .collect()
+    }
+
+    fn flags_to_resolve(&self, flag_names: &[String]) -> Vec<&'a Flag> {
+        self.state
+            .client_flags(&self.client.client_name)
+            .filter(|flag| flag_names.is_empty() || flag_names.contains(&flag.name))
+            .collect()
+    }
+
+    /// Checks the `materializations_per_unit` of a sticky request against the flags it
+    /// resolves and reports entries that can never be used: units that are not a targeting
+    /// key of any rule reading a materialization, materializations no such rule reads, and
+    /// rule-to-variant entries pointing at rules or variants that don't exist.
+    #[cfg(feature = "sticky")]
+    pub fn validate_materializations(
+        &self,
+        request: &flags_resolver::ResolveWithStickyRequest,
+    ) -> Vec<MaterializationIssue> {
+        let flag_names = request
+            .resolve_request
+            .as_ref()
+            .map(|r| r.flags.as_slice())
+            .unwrap_or_default();
+        // unit -> materialization -> rules reading it for that unit
+        type RulesByMaterialization<'r> = BTreeMap<&'r str, Vec<(&'r Flag, &'r Rule)>>;
+        let mut expected: BTreeMap<String, RulesByMaterialization> = BTreeMap::new();
+        for flag in self.flags_to_resolve(flag_names) {
+            for rule in flag.rules.iter().filter(|rule| rule.enabled) {
+                let Some(spec) = &rule.materialization_spec else {
+                    continue;
+                };
+                if spec.read_materialization.is_empty() {
+                    continue;
+                }
+                let targeting_key = self.state.targeting_key_selector(flag, rule);
+                let Ok(Some(unit)) = self.get_targeting_key(targeting_key) else {
+                    continue;
+                };
+                expected
+                    .entry(unit)
+                    .or_default()
+                    .entry(spec.read_materialization.as_str())
+                    .or_default()
+                    .push((flag, rule));
+            }
+        }
+
+        let mut issues = Vec::new();
+        for (unit, materializations) in &request.materializations_per_unit {
+            let Some(expected_materializations) = expected.get(unit) else {
+                issues.push(MaterializationIssue::UnknownUnit { unit: unit.clone() });
+                continue;
+            };
+            for (materialization, info) in &materializations.info_map {
+                let Some(rules) = expected_materializations.get(materialization.as_str()) else {
+                    issues.push(MaterializationIssue::UnknownMaterialization {
+                        unit: unit.clone(),
+                        materialization: materialization.clone(),
+                    });
+                    continue;
+                };
+                for (rule_name, variant_name) in &info.rule_to_variant {
+                    let Some((flag, _)) = rules.iter().find(|(_, rule)| rule.name == *rule_name)
+                    else {
+                        issues.push(MaterializationIssue::UnknownRule {
+                            unit: unit.clone(),
+                            materialization: materialization.clone(),
+                            rule: rule_name.clone(),
+                        });
+                        continue;
+                    };
+                    if !flag.variants.iter().any(|v| v.name == *variant_name) {
+                        issues.push(MaterializationIssue::UnknownVariant {
+                            unit: unit.clone(),
+                            materialization: materialization.clone(),
+                            rule: rule_name.clone(),
+                            variant: variant_name.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        issues
+    }
+
+    fn get_targeting_key(&self, targeting_key: &str) -> Result<Option<String>, String> {
+        let unit_value = self.get_attribute_value(targeting_key);
+        match &unit_value.kind {
+            None => Ok(None),
+            Some(Kind::NullValue(_)) => Ok(None),
+            Some(Kind::StringValue(string_unit)) => Ok(Some(string_unit.clone())),
+            Some(Kind::NumberValue(num_value)) => {
+                if num_value.is_finite() && num_value.fract() == 0.0 {
+                    Ok(Some(format!("{:.0}", num_value)))
+                } else {
+                    Err("TargetingKeyError".to_string())
+                }
+            }
+            _ => Err("TargetingKeyError".to_string()),
+        }
+    }
+    pub fn resolve_flag_name(
+        &'a self,
+        flag_name: &str,
+    ) -> Result<FlagResolveResult<'a>, ResolveFlagError> {
+        self.state
+            .flags
+            .get(flag_name)
+            .ok_or(ResolveFlagError::err("flag not found"))
+            .and_then(|flag| self.resolve_flag(flag, BTreeMap::new()))
+    }
+
+    /// Traces how `flag_name` resolves for the evaluation context of this resolver: the rules
+    /// evaluated, why the unit did or didn't match their segments, and the bucket and
+    /// assignment it got. See [`explain`].
+    pub fn explain_flag(&self, flag_name: &str) -> Result<explain::FlagExplanation, String> {
+        let flag = self.state.flags.get(flag_name).ok_or("flag not found")?;
+        Ok(explain::explain_flag(self, flag)?)
+    }
+
+    /// Resolves `flag_name` once for each of `units`, as if the unit were the value of every
+    /// targeting key selector of the flag in the evaluation context, and returns the name of
+    /// the variant each unit gets, `None` for units served the default value. Meant for batch
+    /// jobs assigning many units at once: segment matches are shared between units, and
+    /// nothing is logged or applied.
+    pub fn resolve_flag_for_units(
+        &self,
+        flag_name: &str,
+        units: &[&str],
+    ) -> Result<BTreeMap<String, Option<String>>, String> {
+        let flag = self
+            .state
+            .client_flags(&self.client.client_name)
+            .find(|flag| flag.name == flag_name)
+            .ok_or_else(|| format!("flag {} not found", flag_name))?;
+        H::authorize_resolve(self.client, &[flag], &self.evaluation_context.context)?;
+        let selectors: BTreeSet<&str> = flag
+            .rules
+            .iter()
+            .filter(|rule| rule.enabled)
+            .map(|rule| self.state.targeting_key_selector(flag, rule))
+            .collect();
+
+        // Contexts only differ in the targeting keys, so a segment match only depends on the
+        // unit and the memo can be shared between units; the targeting keys themselves can't.
+        let mut memo = ResolveMemo::default();
+        let mut variants = BTreeMap::new();
+        for &unit in units {
+            if unit.len() > self.state.config.max_targeting_key_length {
+                return Err(format!(
+                    "Targeting key is too larger, max {} characters.",
+                    self.state.config.max_targeting_key_length
+                ));
+            }
+            let mut context = self.evaluation_context.context.clone();
+            for selector in &selectors {
+                set_attribute_value(&mut context, selector, unit);
+            }
+            let resolver: AccountResolver<'_, H> = AccountResolver::new(
+                self.client,
+                self.state,
+                EvaluationContext { context },
+                &self.encryption_key,
+            );
+            memo.targeting_keys.clear();
+            memo.arena.reset();
+            let resolved_value = resolver
+                .resolve_flag_with_memo(flag, BTreeMap::new(), &mut memo)
+                .map_err(String::from)?
+                .resolved_value;
+            let variant = resolved_value
+                .assignment_match
+                .and_then(|assignment_match| assignment_match.variant)
+                .or(resolved_value.killed_variant)
+                .map(|variant| variant.name.clone());
+            variants.insert(unit.to_string(), variant);
+        }
+        Ok(variants)
+    }
+
+    #[cfg(feature = "sticky")]
+    pub fn collect_missing_materializations(
+        &'a self,
+        flags: Vec<&'a Flag>,
+    ) -> Result<Vec<resolve_with_sticky_response::MissingMaterializationItem>, String> {
+        let mut missing_materializations: Vec<
+            resolve_with_sticky_response::MissingMaterializationItem,
+        > = Vec::new();
+        for flag in flags {
+            let result = self.collect_missing_materializations_for_flag(flag);
+            if let Ok(items) = result {
+                missing_materializations.extend(items);
+            } else {
+                return Err(format!(
+                    "Could not collect missing materializations for flag {}",
+                    flag.name
+                ));
+            }
+        }
+        Ok(missing_materializations)
+    }
+
+    #[cfg(feature = "sticky")]
+    fn collect_missing_materializations_for_flag(
+        &'a self,
+        flag: &'a Flag,
+    ) -> Result<Vec<resolve_with_sticky_response::MissingMaterializationItem>, String> {
+        let mut missing_materializations: Vec<
+            resolve_with_sticky_response::MissingMaterializationItem,
+        > = Vec::new();
+
+        if flag.state == flags_admin::flag::State::Archived as i32 {
+            return Ok(vec![]);
+        }
+
+        for rule in &flag.rules {
+            if !rule.enabled {
+                continue;
+            }
+
+            if let Some(materialization_spec) = &rule.materialization_spec {
+                let rule_name = &rule.name.as_str();
+                let read_materialization = materialization_spec.read_materialization.as_str();
+                if !read_materialization.is_empty() {
+                    let targeting_key = self.state.targeting_key_selector(flag, rule);
+                    let unit: String = match self.get_targeting_key(targeting_key) {
+                        Ok(Some(u)) => u,
+                        Ok(None) => continue,
+                        Err(_) => return Err("Targeting key error".to_string()),
+                    };
+                    missing_materializations.push(
+                        resolve_with_sticky_response::MissingMaterializationItem {
+                            unit,
+                            rule: rule_name.to_string(),
+                            read_materialization: read_materialization.to_string(),
+                        },
+                    );
+                    continue;
+                }
+            }
+        }
+        Ok(missing_materializations)
+    }
+
+    pub fn resolve_flag(
+        &'a self,
+        flag: &'a Flag,
+        sticky_context: BTreeMap<String, MaterializationMap>,
+    ) -> Result<FlagResolveResult<'a>, ResolveFlagError> {
+        self.resolve_flag_with_memo(flag, sticky_context, &mut ResolveMemo::default())
+    }
+
+    /// Resolves `flag` reusing the segment matches and targeting keys in `memo`, which must
+    /// only be shared between flags resolved with this resolver.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(flag = %flag.name, elapsed_us = tracing::field::Empty)
+        )
+    )]
+    #[cfg_attr(not(feature = "sticky"), allow(unused_variables))]
+    fn resolve_flag_with_memo(
+        &'a self,
+        flag: &'a Flag,
+        sticky_context: BTreeMap<String, MaterializationMap>,
+        memo: &mut ResolveMemo,
+    ) -> Result<FlagResolveResult<'a>, ResolveFlagError> {
+        #[cfg(feature = "tracing")]
+        let _timer = SpanTimer::<H>::start(tracing::Span::current());
+        #[cfg_attr(not(feature = "sticky"), allow(unused_mut))]
+        let mut updates: Vec<MaterializationUpdate> = Vec::new();
+        let mut warnings: Vec<ResolveWarning> = Vec::new();
+        let mut resolved_value = ResolvedValue::new(flag);
+
+        if let Some(kill_switch) = self.state.kill_switch(&flag.name, &self.client.client_name) {
+            let variant = kill_switch
+                .variant
+                .as_ref()
+                .and_then(|name| flag.variants.iter().find(|v| v.name == *name));
+            return Ok(FlagResolveResult {
+                resolved_value: resolved_value.killed(variant),
+                updates: vec![],
+                warnings: vec![],
+            });
+        }
+
+        if flag.state == flags_admin::flag::State::Archived as i32 {
+            return Ok(FlagResolveResult {
+                resolved_value: resolved_value.error(ResolveReason::FlagArchived),
+                updates: vec![],
+                warnings: vec![],
+            });
+        }
+
+        let max_fallthrough_rules = self.state.config.max_fallthrough_rules;
+        for rule in &flag.rules {
+            if !rule.enabled {
+                warnings.extend(resolved_value.record_fallthrough(
+                    rule,
+                    FallthroughReason::RuleDisabled,
+                    "",
+                    "",
+                    max_fallthrough_rules,
+                ));
+                continue;
+            }
+            #[cfg(feature = "tracing")]
+            let (_rule_span, _rule_timer) = {
+                let span = tracing::trace_span!(
+                    "rule",
+                    rule = %rule.name,
+                    labels = tracing::field::Empty,
+                    assignment = tracing::field::Empty,
+                    allocation_percent = tracing::field::Empty,
+                    elapsed_us = tracing::field::Empty
+                );
+                if !rule.labels.is_empty() {
+                    let labels: Vec<String> = rule
+                        .labels
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect();
+                    span.record("labels", labels.join(","));
+                }
+                (span.clone().entered(), SpanTimer::<H>::start(span))
+            };
+
+            let segment_name = &rule.segment;
+            let Some(segment) = self.state.segments.get(segment_name) else {
+                warnings.push(resolve_warning(
+                    flag,
+                    rule,
+                    resolve_warning::Kind::SegmentNotFound,
+                    format!("segment {} of rule {} not found", segment_name, rule.name),
+                ));
+                warnings.extend(resolved_value.record_fallthrough(
+                    rule,
+                    FallthroughReason::SegmentNotFound,
+                    "",
+                    "",
+                    max_fallthrough_rules,
+                ));
+                continue;
+            };
+
+            let targeting_key = self.state.targeting_key_selector(flag, rule);
+            let unit: String = match memo.targeting_key(self, targeting_key) {
+                Ok(Some(u)) => u,
+                Ok(None) => {
+                    warnings.extend(resolved_value.record_fallthrough(
+                        rule,
+                        FallthroughReason::MissingTargetingKey,
+                        "",
+                        "",
+                        max_fallthrough_rules,
+                    ));
+                    continue;
+                }
+                Err(_) => {
+                    return Ok(FlagResolveResult {
+                        resolved_value: resolved_value.error(ResolveReason::TargetingKeyError),
+                        updates: vec![],
+                        warnings,
+                    })
+                }
+            };
+
+            let Some(spec) = &rule.assignment_spec else {
+                warnings.push(resolve_warning(
+                    flag,
+                    rule,
+                    resolve_warning::Kind::MissingAssignmentSpec,
+                    format!("rule {} has no assignment spec", rule.name),
+                ));
+                continue;
+            };
+
+            #[cfg(not(feature = "sticky"))]
+            if rule
+                .materialization_spec
+                .as_ref()
+                .is_some_and(|spec| !spec.read_materialization.is_empty())
+            {
+                return Err(ResolveFlagError::missing_materializations());
+            }
+            #[cfg(not(feature = "sticky"))]
+            let materialization_matched = false;
+
+            #[cfg(feature = "sticky")]
+            let mut materialization_matched = false;
+            #[cfg(feature = "sticky")]
+            if let Some(materialization_spec) = &rule.materialization_spec {
+                let read_materialization = &materialization_spec.read_materialization;
+                if !read_materialization.is_empty() {
+                    if let Some(info) = sticky_context.get(&unit) {
+                        let info_from_context = info.info_map.get(read_materialization);
+
+                        if let Some(info_data) = info_from_context {
+                            if !info_data.unit_in_info {
+                                if materialization_spec
+                                    .mode
+                                    .as_ref()
+                                    .map(|mode| mode.materialization_must_match)
+                                    .unwrap_or(false)
+                                {
+                                    // Materialization must match but unit is not in materialization
+                                    continue;
+                                }
+                                materialization_matched = false;
+                            } else if materialization_spec
+                                .mode
+                                .as_ref()
+                                .map(|mode| mode.segment_targeting_can_be_ignored)
+                                .unwrap_or(false)
+                            {
+                                materialization_matched = true;
+                            } else {
+                                materialization_matched =
+                                    self.segment_match_with_memo(segment, &unit, memo)?;
+                            }
+                        } else {
+                            return Err(ResolveFlagError::missing_materializations());
+                        }
+
+                        if materialization_matched {
+                            if let Some(variant_name) = info_from_context
+                                .as_ref()
+                                .and_then(|info| info.rule_to_variant.get(&rule.name))
+                            {
+                                if let Some(assignment) =
+                                    spec.assignments.iter().find(|assignment| {
+                                        if let Some(rule::assignment::Assignment::Variant(
+                                            ref variant_assignment,
+                                        )) = &assignment.assignment
+                                        {
+                                            variant_assignment.variant == *variant_name
+                                        } else {
+                                            false
+                                        }
+                                    })
+                                {
+                                    let variant = flag
+                                        .variants
+                                        .iter()
+                                        .find(|v| v.name == *variant_name)
+                                        .or_fail()?;
+                                    return Ok(FlagResolveResult {
+                                        resolved_value: resolved_value.with_variant_match(
+                                            rule,
+                                            segment,
+                                            variant,
+                                            &assignment.assignment_id,
+                                            &unit,
+                                        ),
+                                        updates: vec![],
+                                        warnings,
+                                    });
+                                }
+                            }
+                        }
+                    } else {
+                        return Err(ResolveFlagError::missing_materializations());
+                    };
+                }
+            }
+
+            if !materialization_matched && !self.segment_match_with_memo(segment, &unit, memo)? {
+                // ResolveReason::SEGMENT_NOT_MATCH
+                continue;
+            }
+            let bucket_count = spec.bucket_count;
+            let variant_salt = ResourceName::parse_as(segment_name, ResourceKind::Segment)
+                .or_fail()?
+                .id();
+            let mut key = BumpString::new_in(&memo.arena);
+            hashing::write_assignment_key(&mut key, variant_salt, &unit).or_fail()?;
+            let bucket = bucket(hash(&key), bucket_count as u64).or_fail()? as i32;
+
+            let matched_assignment = spec.assignments.iter().find(|assignment| {
+                assignment
+                    .bucket_ranges
+                    .iter()
+                    .any(|range| range.lower <= bucket && bucket < range.upper)
+            });
+
+            #[cfg(feature = "tracing")]
+            if let Some(assignment) = matched_assignment {
+                let span = tracing::Span::current();
+                span.record("assignment", assignment.assignment_id.as_str());
+                span.record(
+                    "allocation_percent",
+                    allocation_percent(assignment, bucket_count),
+                );
+            }
+
+            #[cfg(feature = "sticky")]
+            let has_write_spec = rule
+                .materialization_spec
+                .as_ref()
+                .map(|materialization_spec| &materialization_spec.write_materialization);
+
+            if let Some(assignment) = matched_assignment {
+                let Some(a) = &assignment.assignment else {
+                    warnings.push(resolve_warning(
+                        flag,
+                        rule,
+                        resolve_warning::Kind::EmptyAssignment,
+                        format!(
+                            "assignment {} of rule {} assigns nothing",
+                            assignment.assignment_id, rule.name
+                        ),
+                    ));
+                    continue;
+                };
+
+                // write the materialization info if write spec exists
+                #[cfg(feature = "sticky")]
+                if let Some(write_spec) = has_write_spec {
+                    // Extract variant name from assignment if it's a variant assignment
+                    let variant_name = match a {
+                        rule::assignment::Assignment::Variant(ref variant_assignment) => {
+                            variant_assignment.variant.clone()
+                        }
+                        _ => "".to_string(),
+                    };
+                    updates.push(MaterializationUpdate {
+                        write_materialization: write_spec.to_string(),
+                        unit: unit.to_string(),
+                        rule: rule.clone().name,
+                        variant: variant_name,
+                    })
+                }
+
+                match a {
+                    rule::assignment::Assignment::Fallthrough(_) => {
+                        warnings.extend(resolved_value.record_fallthrough(
+                            rule,
+                            FallthroughReason::Assignment,
+                            &assignment.assignment_id,
+                            &unit,
+                            max_fallthrough_rules,
+                        ));
+                        continue;
+                    }
+                    rule::assignment::Assignment::ClientDefault(_) => {
+                        return Ok(FlagResolveResult {
+                            resolved_value: resolved_value.with_client_default_match(
+                                rule,
+                                segment,
+                                &assignment.assignment_id,
+                                &unit,
+                            ),
+                            updates,
+                            warnings,
+                        })
+                    }
+                    rule::assignment::Assignment::Variant(
+                        rule::assignment::VariantAssignment {
+                            variant: variant_name,
+                        },
+                    ) => {
+                        let variant = flag
+                            .variants
+                            .iter()
+                            .find(|variant| variant.name == *variant_name)
+                            .or_fail()?;
+
+                        return Ok(FlagResolveResult {
+                            resolved_value: resolved_value.with_variant_match(
+                                rule,
+                                segment,
+                                variant,
+                                &assignment.assignment_id,
+                                &unit,
+                            ),
+                            updates,
+                            warnings,
+                        });
+                    }
+                };
+            } else {
+                // expected for rules that allocate part of the buckets, so counted rather than
+                // warned about
+                H::on_metric(metrics::BUCKET_NOT_ASSIGNED, 1.0, &[("rule", &rule.name)]);
+                warnings.extend(resolved_value.record_fallthrough(
+                    rule,
+                    FallthroughReason::BucketNotAssigned,
+                    "",
+                    &unit,
+                    max_fallthrough_rules,
+                ));
+            }
+        }
+
+        if resolved_value.reason == ResolveReason::Match {
+            resolved_value.should_apply = true;
+        } else {
+            resolved_value.should_apply = resolved_value.has_fallthrough_assignment();
+        }
+
+        Ok(FlagResolveResult {
+            resolved_value,
+            updates,
+            warnings,
+        })
+    }
+
+    /// Get an attribute value from the [EvaluationContext] struct, addressed by a path specification.
+    /// If the struct is `{user:{name:"roug",id:42}}`, then getting the `"user.name"` field will return
+    /// the value `"roug"`. A list of structs on the path gives the list of the values in its
+    /// elements, so `"devices.os"` of `{devices:[{os:"ios"},{os:"android"}]}` is
+    /// `["ios","android"]`.
+    pub fn get_attribute_value(&self, field_path: &str) -> Cow<'_, Value> {
+        attribute_value(&self.evaluation_context.context, field_path)
+    }
+
+    pub fn segment_match(&self, segment: &Segment, unit: &str) -> Fallible<bool> {
+        self.segment_match_with_memo(segment, unit, &mut ResolveMemo::default())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(segment = %segment.name))
+    )]
+    fn segment_match_with_memo(
+        &self,
+        segment: &Segment,
+        unit: &str,
+        memo: &mut ResolveMemo,
+    ) -> Fallible<bool> {
+        self.segment_match_internal(segment, unit, &mut HashSet::new(), memo)
+    }
+
+    fn segment_match_internal(
+        &self,
+        segment: &Segment,
+        unit: &str,
+        visited: &mut HashSet<String>,
+        memo: &mut ResolveMemo,
+    ) -> Fallible<bool> {
+        if let Some(matched) = memo.segment_match(&segment.name, unit) {
+            return Ok(matched);
+        }
+        if visited.contains(&segment.name) {
+            fail!("circular segment dependency found");
+        }
+        visited.insert(segment.name.clone());
+
+        let matched = self.segment_match_uncached(segment, unit, visited, memo)?;
+        memo.insert_segment_match(&segment.name, unit, matched);
+        Ok(matched)
+    }
+
+    fn segment_match_uncached(
+        &self,
+        segment: &Segment,
+        unit: &str,
+        visited: &mut HashSet<String>,
+        memo: &mut ResolveMemo,
+    ) -> Fallible<bool> {
+        if !self.targeting_match(segment, unit, visited, memo)? {
+            return Ok(false);
+        }
+
+        if self.state.external_segments.contains(&segment.name) {
+            return Ok(self.external_membership(segment, unit));
+        }
+
+        // check bitset
+        Ok(self
+            .bitset_membership(segment, unit, memo)?
+            .is_none_or(|(_, member)| member))
+    }
+
+    /// The bucket of `unit` in the bitset of `segment` and whether it is set, `None` if the
+    /// segment has no bitset.
+    fn bitset_membership(
+        &self,
+        segment: &Segment,
+        unit: &str,
+        memo: &ResolveMemo,
+    ) -> Fallible<Option<(usize, bool)>> {
+        let Some(bitset) = self.state.bitsets.get(&segment.name) else {
+            return Ok(None);
+        }; // todo: would this match or not?
+        let bitset = bitset.bits_with_host::<H>(&segment.name)?;
+        let salted_unit = self.client.account.salt_unit(unit, &memo.arena)?;
+        let unit_hash = bucket(hash(&salted_unit), hashing::SEGMENT_BUCKETS).or_fail()?;
+        if let Some(member) = bitset.get(unit_hash) {
+            return Ok(Some((unit_hash, *member)));
+        }
+        H::on_metric(
+            metrics::BITSET_OUT_OF_RANGE,
+            1.0,
+            &[("segment", &segment.name)],
+        );
+        match self.state.config.truncated_bitset {
+            TruncatedBitset::NotMember => Ok(Some((unit_hash, false))),
+            TruncatedBitset::Member => Ok(Some((unit_hash, true))),
+            TruncatedBitset::Fail => fail!(":bitset.out_of_range"),
+        }
+    }
+
+    /// Whether `unit` is a member of the external `segment` according to the host, `false`
+    /// if the host fails to tell.
+    fn external_membership(&self, segment: &Segment, unit: &str) -> bool {
+        H::segment_membership(&segment.name, unit).unwrap_or_else(|e| {
+            H::log(&format!(
+                "failed to look up membership of external segment {}: {}",
+                segment.name, e
+            ));
+            false
+        })
+    }
+
+    fn targeting_match(
+        &self,
+        segment: &Segment,
+        unit: &str,
+        visited: &mut HashSet<String>,
+        memo: &mut ResolveMemo,
+    ) -> Fallible<bool> {
+        let Some(targeting) = &segment.targeting else {
+            return Ok(true);
+        };
+        let mut criterion_evaluator =
+            |id: &String| self.criterion_match(targeting.criteria.get(id), unit, visited, memo);
+
+        let Some(expression) = &targeting.expression else {
+            return Ok(true);
+        };
+        evaluate_expression(expression, &mut criterion_evaluator)
+    }
+
+    fn criterion_match(
+        &self,
+        criterion: Option<&Criterion>,
+        unit: &str,
+        visited: &mut HashSet<String>,
+        memo: &mut ResolveMemo,
+    ) -> Fallible<bool> {
+        let Some(Criterion {
+            criterion: Some(criterion),
+        }) = criterion
+        else {
+            return Ok(false);
+        };
+        match &criterion {
+            criterion::Criterion::Attribute(attribute_criterion) => {
+                let expected_value_type = value::expected_value_type(attribute_criterion);
+                let attribute_value = self.get_attribute_value(&attribute_criterion.attribute_name);
+                let converted =
+                    value::convert_to_targeting_value(&attribute_value, expected_value_type)?;
+                let wrapped = list_wrapper(&converted);
+
+                Ok(value::evaluate_criterion(
+                    attribute_criterion,
+                    &wrapped,
+                    &self.state.derived().versions,
+                ))
+            }
+            criterion::Criterion::Segment(segment_criterion) => {
+                let Some(ref_segment) = self.state.segments.get(&segment_criterion.segment) else {
+                    return Ok(false);
+                };
+
+                self.segment_match_internal(ref_segment, unit, visited, memo)
+            }
+        }
+    }
+
+    fn encrypt_resolve_token(
+        &self,
+        resolve_token: &flags_resolver::ResolveToken,
+    ) -> Result<Vec<u8>, String> {
+        let mut token_buf = Vec::with_capacity(resolve_token.encoded_len());
+        resolve_token.encode(&mut token_buf).or_fail()?;
+
+        if self.signed_tokens {
+            let signer = encryption::builtin(encryption::KEY_ID_HMAC_SHA256)
+                .ok_or("signed resolve tokens need the std feature")?;
+            return resolve_token::seal_with::<H>(&token_buf, &self.encryption_key, Some(signer));
+        }
+        resolve_token::seal::<H>(&token_buf, &self.encryption_key)
+    }
+
+    fn decrypt_resolve_token(
+        &self,
+        encrypted_token: &[u8],
+    ) -> Result<flags_resolver::ResolveToken, String> {
+        let decrypted_data = resolve_token::open::<H>(
+            encrypted_token,
+            &self.encryption_key,
+            &self.secondary_keys,
+            self.state.config.allow_plaintext_resolve_tokens,
+            self.state.config.legacy_resolve_tokens_until,
+        )?;
+
+        let t = flags_resolver::ResolveToken::decode(&decrypted_data[..]).or_fail()?;
+        Ok(t)
+    }
+}
+
+fn evaluate_expression(
+    expression: &Expression,
+    criterion_evaluator: &mut dyn FnMut(&String) -> Fallible<bool>,
+) -> Fallible<bool> {
+    let Some(expression) = &expression.expression else {
+        return Ok(false);
+    };
+    match expression {
+        expression::Expression::Ref(ref_) => criterion_evaluator(ref_),
+        expression::Expression::Not(not) => Ok(!evaluate_expression(not, criterion_evaluator)?),
+        expression::Expression::And(and) => {
+            for op in &and.operands {
+                if !evaluate_expression(op, criterion_evaluator)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        expression::Expression::Or(or) => {
+            for op in &or.operands {
+                if evaluate_expression(op, criterion_evaluator)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// See [`AccountResolver::get_attribute_value`].
+fn attribute_value<'c>(context: &'c Struct, field_path: &str) -> Cow<'c, Value> {
+    let mut s = context;
+    let mut rest = field_path;
+
+    loop {
+        let (field, remaining) = match rest.split_once('.') {
+            Some((field, remaining)) => (field, Some(remaining)),
+            None => (rest, None),
+        };
+        let Some(value) = s.fields.get(field) else {
+            // non-struct value addressed with .-operator
+            return Cow::Borrowed(&NULL);
+        };
+        let Some(remaining) = remaining else {
+            // we are at the end of the path, return the value
+            return Cow::Borrowed(value);
+        };
+        match &value.kind {
+            // if we are not at the end of the path, and the value is a struct, continue
+            Some(Kind::StructValue(struct_value)) => {
+                s = struct_value;
+                rest = remaining;
+            }
+            Some(Kind::ListValue(list_value)) => {
+                return Cow::Owned(list_attribute_value(list_value, remaining));
+            }
+            // if we are not at the end of the path, but the value is not a struct, return null
+            _ => return Cow::Borrowed(&NULL),
+        }
+    }
+}
+
+/// The values at `field_path` of the structs in `list`, as a list the criterion is applied to
+/// each element of, like a list at the end of the path. Nested lists are flattened, and
+/// elements without a value are left out; null if none has one.
+fn list_attribute_value(list: &ListValue, field_path: &str) -> Value {
+    let mut values = Vec::new();
+    for element in &list.values {
+        let Some(Kind::StructValue(element)) = &element.kind else {
+            continue;
+        };
+        let value = attribute_value(element, field_path).into_owned();
+        match value.kind {
+            None | Some(Kind::NullValue(_)) => {}
+            Some(Kind::ListValue(nested)) => values.extend(nested.values),
+            kind => values.push(Value { kind }),
+        }
+    }
+    if values.is_empty() {
+        return NULL;
+    }
+    Value {
+        kind: Some(Kind::ListValue(ListValue { values })),
+    }
+}
+
+fn list_wrapper(value: &targeting::value::Value) -> targeting::ListValue {
+    match value {
+        targeting::value::Value::ListValue(list_value) => list_value.clone(),
+        _ => targeting::ListValue {
+            values: vec![targeting::Value {
+                value: Some(value.clone()),
+            }],
+        },
+    }
+}
+
+/// Sets the string at `field_path` of `context`, the inverse of
+/// [`AccountResolver::get_attribute_value`]. Fields on the way that aren't structs are replaced.
+fn set_attribute_value(context: &mut Struct, field_path: &str, value: &str) {
+    let mut s = context;
+    let mut path_parts = field_path.split('.').peekable();
+    while let Some(field) = path_parts.next() {
+        let entry = s.fields.entry(field.to_string()).or_default();
+        if path_parts.peek().is_none() {
+            entry.kind = Some(Kind::StringValue(value.to_string()));
+            return;
+        }
+        if !matches!(entry.kind, Some(Kind::StructValue(_))) {
+            entry.kind = Some(Kind::StructValue(Struct::default()));
+        }
+        let Some(Kind::StructValue(nested)) = &mut entry.kind else {
+            return;
+        };
+        s = nested;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolvedValue<'a> {
+    pub flag: &'a Flag,
+    pub reason: ResolveReason,
+    pub assignment_match: Option<AssignmentMatch<'a>>,
+    /// The variant forced by a kill switch, which is not assigned through any rule.
+    pub killed_variant: Option<&'a Variant>,
+    /// The fallthrough assignments the unit was bucketed into, which are exposures recorded in
+    /// the resolve token and logs. At most [`ResolverConfig::max_fallthrough_rules`].
+    pub fallthrough_rules: Vec<FallthroughRule<'a>>,
+    /// Whether the unit fell through rules beyond those in `fallthrough_rules`.
+    pub fallthrough_rules_truncated: bool,
+    /// Rules the unit was passed on by for another reason than a fallthrough assignment. They
+    /// are not exposures, but are logged with the assignment so that the backend can tell why
+    /// the rules were passed. Shares the limit of `fallthrough_rules`.
+    pub skipped_rules: Vec<FallthroughRule<'a>>,
+    pub should_apply: bool,
+}
+
+/// A resolve in progress, see [`AccountResolver::resolve_begin`].
+#[derive(Debug, Clone)]
+pub struct ResolveProgress {
+    request: ResolveWithStickyRequest,
+    timestamp: Timestamp,
+    flags: Vec<String>,
+    next: usize,
+    results: Vec<DetachedResult>,
+    early_response: Option<ResolveWithStickyResponse>,
+    memo: ResolveMemo,
+}
+
+/// Segment matches and targeting keys computed while resolving the flags of one request, so
+/// flags sharing segments or targeting keys don't evaluate them again. Only valid for the
+/// context and state of the resolver that filled it.
+#[derive(Debug, Default)]
+struct ResolveMemo {
+    /// segment -> unit -> matched
+    segment_matches: HashMap<String, HashMap<String, bool>>,
+    /// targeting key selector -> targeting key
+    targeting_keys: HashMap<String, Result<Option<String>, String>>,
+    /// Backs the strings hashed for bucketing, which are only needed while evaluating a rule,
+    /// so a resolve doesn't go through the allocator for each of them.
+    arena: Bump,
+}
+
+impl Clone for ResolveMemo {
+    fn clone(&self) -> Self {
+        ResolveMemo {
+            segment_matches: self.segment_matches.clone(),
+            targeting_keys: self.targeting_keys.clone(),
+            arena: Bump::new(),
+        }
+    }
+}
+
+impl ResolveMemo {
+    fn segment_match(&self, segment: &str, unit: &str) -> Option<bool> {
+        self.segment_matches.get(segment)?.get(unit).copied()
+    }
+
+    fn insert_segment_match(&mut self, segment: &str, unit: &str, matched: bool) {
+        self.segment_matches
+            .entry(segment.to_string())
+            .or_default()
+            .insert(unit.to_string(), matched);
+    }
+
+    fn targeting_key<H: Host>(
+        &mut self,
+        resolver: &AccountResolver<'_, H>,
+        selector: &str,
+    ) -> Result<Option<String>, String> {
+        if let Some(targeting_key) = self.targeting_keys.get(selector) {
+            return targeting_key.clone();
+        }
+        let targeting_key = resolver.get_targeting_key(selector);
+        self.targeting_keys
+            .insert(selector.to_string(), targeting_key.clone());
+        targeting_key
+    }
+}
+
+impl ResolveProgress {
+    pub fn is_done(&self) -> bool {
+        self.early_response.is_some() || self.next >= self.flags.len()
+    }
+
+    /// Number of flags not yet resolved.
+    pub fn remaining(&self) -> usize {
+        if self.early_response.is_some() {
+            0
+        } else {
+            self.flags.len().saturating_sub(self.next)
+        }
+    }
+}
+
+/// A [`FlagResolveResult`] with references into the resolver state replaced by names and
+/// positions, so it can be kept between the steps of a resolve.
+#[derive(Debug, Clone)]
+struct DetachedResult {
+    flag: String,
+    reason: ResolveReason,
+    assignment_match: Option<DetachedMatch>,
+    killed_variant: Option<usize>,
+    // (rule index, reason, assignment id, targeting key)
+    fallthrough_rules: Vec<(usize, FallthroughReason, String, String)>,
+    fallthrough_rules_truncated: bool,
+    skipped_rules: Vec<(usize, FallthroughReason, String, String)>,
+    should_apply: bool,
+    updates: Vec<MaterializationUpdate>,
+    warnings: Vec<ResolveWarning>,
+}
+
+#[derive(Debug, Clone)]
+struct DetachedMatch {
+    rule: usize,
+    segment: String,
+    assignment_id: String,
+    targeting_key: String,
+    variant: Option<usize>,
+}
+
+impl DetachedResult {
+    fn detach(result: &FlagResolveResult<'_>) -> Result<Self, String> {
+        let value = &result.resolved_value;
+        let flag = value.flag;
+        let variant_index = |variant: &Variant| {
+            flag.variants
+                .iter()
+                .position(|v| core::ptr::eq(v, variant))
+                .or_fail()
+        };
+        let rule_index = |rule: &Rule| {
+            flag.rules
+                .iter()
+                .position(|r| core::ptr::eq(r, rule))
+                .or_fail()
+        };
+        let assignment_match = match &value.assignment_match {
+            Some(m) => Some(DetachedMatch {
+                rule: rule_index(m.rule)?,
+                segment: m.segment.name.clone(),
+                assignment_id: m.assignment_id.clone(),
+                targeting_key: m.targeting_key.clone(),
+                variant: m.variant.map(variant_index).transpose()?,
+            }),
+            None => None,
+        };
+        let detach_rules = |rules: &[FallthroughRule<'_>]| {
+            rules
+                .iter()
+                .map(|f| {
+                    Ok((
+                        rule_index(f.rule)?,
+                        f.reason,
+                        f.assignment_id.clone(),
+                        f.targeting_key.clone(),
+                    ))
+                })
+                .collect::<Fallible<Vec<_>>>()
+        };
+        Ok(DetachedResult {
+            flag: flag.name.clone(),
+            reason: value.reason,
+            assignment_match,
+            killed_variant: value.killed_variant.map(variant_index).transpose()?,
+            fallthrough_rules: detach_rules(&value.fallthrough_rules)?,
+            fallthrough_rules_truncated: value.fallthrough_rules_truncated,
+            skipped_rules: detach_rules(&value.skipped_rules)?,
+            should_apply: value.should_apply,
+            updates: result.updates.clone(),
+            warnings: result.warnings.clone(),
+        })
+    }
+
+    fn attach<'a>(&self, state: &'a ResolverState) -> Result<FlagResolveResult<'a>, String> {
+        let flag = state
+            .flags
+            .get(&self.flag)
+            .ok_or_else(|| format!("flag {} is no longer in the resolver state", self.flag))?;
+        let assignment_match = match &self.assignment_match {
+            Some(m) => Some(AssignmentMatch {
+                rule: flag.rules.get(m.rule).or_fail()?,
+                segment: state.segments.get(&m.segment).or_fail()?,
+                assignment_id: m.assignment_id.clone(),
+                targeting_key: m.targeting_key.clone(),
+                variant: match m.variant {
+                    Some(index) => Some(flag.variants.get(index).or_fail()?),
+                    None => None,
+                },
+            }),
+            None => None,
+        };
+        let attach_rules = |rules: &[(usize, FallthroughReason, String, String)]| {
+            rules
+                .iter()
+                .map(|(rule, reason, assignment_id, targeting_key)| {
+                    Ok(FallthroughRule {
+                        rule: flag.rules.get(*rule).or_fail()?,
+                        reason: *reason,
+                        assignment_id: assignment_id.clone(),
+                        targeting_key: targeting_key.clone(),
+                    })
+                })
+                .collect::<Fallible<Vec<_>>>()
+        };
+        Ok(FlagResolveResult {
+            resolved_value: ResolvedValue {
+                flag,
+                reason: self.reason,
+                assignment_match,
+                killed_variant: match self.killed_variant {
+                    Some(index) => Some(flag.variants.get(index).or_fail()?),
+                    None => None,
+                },
+                fallthrough_rules: attach_rules(&self.fallthrough_rules)?,
+                fallthrough_rules_truncated: self.fallthrough_rules_truncated,
+                skipped_rules: attach_rules(&self.skipped_rules)?,
+                should_apply: self.should_apply,
+            },
+            updates: self.updates.clone(),
+            warnings: self.warnings.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct FlagResolveResult<'a> {
+    pub resolved_value: ResolvedValue<'a>,
+    pub updates: Vec<MaterializationUpdate>,
+    /// Configuration issues that made the resolver skip rules of the flag.
+    pub warnings: Vec<ResolveWarning>,
+}
+
+fn resolve_warning(
+    flag: &Flag,
+    rule: &Rule,
+    kind: resolve_warning::Kind,
+    message: String,
+) -> ResolveWarning {
+    ResolveWarning {
+        flag: flag.name.clone(),
+        rule: rule.name.clone(),
+        kind: kind as i32,
+        message,
+    }
+}
+
+impl<'a> ResolvedValue<'a> {
+    fn new(flag: &'a Flag) -> Self {
+        ResolvedValue {
+            flag,
+            reason: ResolveReason::NoSegmentMatch,
+            assignment_match: Option::None,
+            killed_variant: None,
+            fallthrough_rules: vec![],
+            fallthrough_rules_truncated: false,
+            skipped_rules: vec![],
+            should_apply: false,
+        }
+    }
+
+    // These take the value being resolved, so the fallthrough rules recorded so far move into
+    // the result rather than being copied.
+
+    fn error(self, reason: ResolveReason) -> Self {
+        ResolvedValue {
+            reason,
+            assignment_match: Option::None,
+            killed_variant: None,
+            should_apply: false,
+            ..self
+        }
+    }
+
+    fn killed(self, variant: Option<&'a Variant>) -> Self {
+        ResolvedValue {
+            killed_variant: variant,
+            ..self.error(ResolveReason::FlagKilled)
+        }
+    }
+
+    /// Records that the unit passed on `rule` for `reason`. Fallthrough assignments are
+    /// recorded unless `max` are recorded already, and the warning marking the truncation is
+    /// returned the first time one is dropped. Other reasons go to `skipped_rules`, which are
+    /// capped at `max` as well but without a warning, they don't lose exposures.
+    fn record_fallthrough(
+        &mut self,
+        rule: &'a Rule,
+        reason: FallthroughReason,
+        assignment_id: &str,
+        unit: &str,
+        max: usize,
+    ) -> Option<ResolveWarning> {
+        if reason != FallthroughReason::Assignment {
+            if self.skipped_rules.len() >= max {
+                return None;
+            }
+            self.skipped_rules.push(FallthroughRule {
+                rule,
+                reason,
+                assignment_id: assignment_id.to_string(),
+                targeting_key: unit.to_string(),
+            });
+            return None;
+        }
+        if self.fallthrough_rules.len() < max {
+            self.fallthrough_rules.push(FallthroughRule {
+                rule,
+                reason,
+                assignment_id: assignment_id.to_string(),
+                targeting_key: unit.to_string(),
+            });
+            return None;
+        }
+        if self.fallthrough_rules_truncated {
+            return None;
+        }
+        // only the first rule beyond the limit is reported
+        self.fallthrough_rules_truncated = true;
+        Some(resolve_warning(
+            self.flag,
+            rule,
+            resolve_warning::Kind::FallthroughRulesTruncated,
+            format!(
+                "rule {} and later fallthrough rules exceed the limit of {} and are not recorded",
+                rule.name, max
+            ),
+        ))
+    }
+
+    /// Whether the unit was bucketed into a fallthrough assignment, which is an exposure to
+    /// log like a match.
+    fn has_fallthrough_assignment(&self) -> bool {
+        !self.fallthrough_rules.is_empty()
+    }
+
+    fn with_client_default_match(
+        self,
+        rule: &'a Rule,
+        segment: &'a Segment,
+        assignment_id: &str,
+        unit: &str,
+    ) -> Self {
+        ResolvedValue {
+            reason: ResolveReason::Match,
+            assignment_match: Option::Some(AssignmentMatch {
+                rule,
+                segment,
+                assignment_id: assignment_id.to_string(),
+                targeting_key: unit.to_string(),
+                variant: Option::None,
+            }),
+            killed_variant: None,
+            should_apply: true,
+            ..self
+        }
+    }
+
+    fn with_variant_match(
+        self,
+        rule: &'a Rule,
+        segment: &'a Segment,
+        variant: &'a Variant,
+        assignment_id: &str,
+        unit: &str,
+    ) -> Self {
+        ResolvedValue {
+            reason: ResolveReason::Match,
+            assignment_match: Option::Some(AssignmentMatch {
+                rule,
+                segment,
+                assignment_id: assignment_id.to_string(),
+                targeting_key: unit.to_string(),
+                variant: Option::Some(variant),
+            }),
+            killed_variant: None,
+            should_apply: true,
+            ..self
+        }
+    }
+}
+
+impl<'a> From<&ResolvedValue<'a>> for flags_resolver::ResolvedFlag {
+    fn from(value: &ResolvedValue<'a>) -> Self {
+        let mut resolved_flag = flags_resolver::ResolvedFlag {
+            flag: value.flag.name.clone(),
+            reason: value.reason as i32,
+            should_apply: value.should_apply,
+            ..Default::default()
+        };
+
+        if let Some(assignment_match) = &value.assignment_match {
+            match assignment_match.variant {
+                Some(variant) => {
+                    resolved_flag.variant = variant.name.clone();
+                    resolved_flag.value = Some(expand_to_schema(variant, value.flag));
+                    resolved_flag.flag_schema = value.flag.schema.clone();
+                }
+                None => {
+                    resolved_flag.variant = "".to_string();
+                    resolved_flag.value = Some(Struct::default());
+                    resolved_flag.flag_schema =
+                        Some(flags_types::flag_schema::StructFlagSchema::default())
+                }
+            }
+        } else if let Some(variant) = value.killed_variant {
+            resolved_flag.variant = variant.name.clone();
+            resolved_flag.value = Some(expand_to_schema(variant, value.flag));
+            resolved_flag.flag_schema = value.flag.schema.clone();
+        }
+
+        resolved_flag
+    }
+}
+
+/// The value of `variant` with the fields of the flag schema it leaves out set to null, like
+/// the backend resolver returns it, see [`flag_value`] for how those nulls are read.
+fn expand_to_schema(variant: &Variant, flag: &Flag) -> Struct {
+    let mut value = variant.value.clone().unwrap_or_default();
+    if let Some(schema) = &flag.schema {
+        flag_value::expand_struct(&mut value, schema, &|_| Kind::NullValue(0));
+    }
+    value
+}
+
+impl<'a> ResolvedValue<'a> {
+    /// The assignment recorded in resolve tokens and assign logs. Rules without a selector of
+    /// their own record the one they took their unit from, see
+    /// [`ResolverState::targeting_key_selector`].
+    pub fn to_assigned_flag(&self, state: &ResolverState) -> AssignedFlag {
+        let selector = |rule: &Rule| state.targeting_key_selector(self.flag, rule).to_string();
+        let mut assigned_flag = AssignedFlag {
+            flag: self.flag.name.clone(),
+            reason: self.reason as i32,
+            fallthrough_assignments: self
+                .fallthrough_rules
+                .iter()
+                .map(
+                    |fallthrough_rule| flags_resolver::events::FallthroughAssignment {
+                        assignment_id: fallthrough_rule.assignment_id.clone(),
+                        rule: fallthrough_rule.rule.name.clone(),
+                        targeting_key: fallthrough_rule.targeting_key.clone(),
+                        targeting_key_selector: selector(fallthrough_rule.rule),
+                    },
+                )
+                .collect(),
+            skipped_rules: self
+                .skipped_rules
+                .iter()
+                .map(|skipped_rule| flags_resolver::events::SkippedRule {
+                    rule: skipped_rule.rule.name.clone(),
+                    reason: skipped_rule.reason as i32,
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        if let Some(assignment_match) = &self.assignment_match {
+            assigned_flag.assignment_id = assignment_match.assignment_id.clone();
+            assigned_flag.rule = assignment_match.rule.name.clone();
+            assigned_flag.segment = assignment_match.segment.name.clone();
+            assigned_flag.targeting_key = assignment_match.targeting_key.clone();
+            assigned_flag.targeting_key_selector = selector(assignment_match.rule);
+            if let Some(variant) = assignment_match.variant {
+                assigned_flag.variant = variant.name.clone();
+            }
+        } else if let Some(variant) = self.killed_variant {
+            assigned_flag.variant = variant.name.clone();
+        }
+
+        assigned_flag
+    }
+}
+
+/// A `materializations_per_unit` entry that doesn't correspond to anything being resolved.
+#[cfg(feature = "sticky")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MaterializationIssue {
+    UnknownUnit {
+        unit: String,
+    },
+    UnknownMaterialization {
+        unit: String,
+        materialization: String,
+    },
+    UnknownRule {
+        unit: String,
+        materialization: String,
+        rule: String,
+    },
+    UnknownVariant {
+        unit: String,
+        materialization: String,
+        rule: String,
+        variant: String,
+    },
+}
+
+#[cfg(feature = "sticky")]
+impl MaterializationIssue {
+    /// Reports `issues` to [`Host::log`] and as [`metrics::MATERIALIZATION_ISSUE`]. The units
+    /// are left out of the log, they identify users.
+    pub fn report<H: Host>(issues: &[MaterializationIssue]) {
+        for issue in issues {
+            H::log(&format!("WARN: {}", issue));
+            H::on_metric(
+                metrics::MATERIALIZATION_ISSUE,
+                1.0,
+                &[("kind", issue.kind())],
+            );
+        }
+    }
+
+    /// Tags the [`metrics::MATERIALIZATION_ISSUE`] metric with.
+    fn kind(&self) -> &'static str {
+        match self {
+            MaterializationIssue::UnknownUnit { .. } => "unit",
+            MaterializationIssue::UnknownMaterialization { .. } => "materialization",
+            MaterializationIssue::UnknownRule { .. } => "rule",
+            MaterializationIssue::UnknownVariant { .. } => "variant",
+        }
+    }
+}
+
+#[cfg(feature = "sticky")]
+impl core::fmt::Display for MaterializationIssue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MaterializationIssue::UnknownUnit { .. } => write!(
+                f,
+                "materializations were passed for a unit that no resolved rule reads them for"
+            ),
+            MaterializationIssue::UnknownMaterialization {
+                materialization, ..
+            } => write!(f, "{} isn't read by any resolved rule", materialization),
+            MaterializationIssue::UnknownRule {
+                materialization,
+                rule,
+                ..
+            } => write!(
+                f,
+                "{} in {} isn't a resolved rule reading it",
+                rule, materialization
+            ),
+            MaterializationIssue::UnknownVariant {
+                materialization,
+                rule,
+                variant,
+                ..
+            } => write!(
+                f,
+                "{} for {} in {} isn't a variant of its flag",
+                variant, rule, materialization
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AssignmentMatch<'a> {
+    pub rule: &'a Rule,
+    pub segment: &'a Segment,
+    pub assignment_id: String,
+    pub targeting_key: String,
+    pub variant: Option<&'a Variant>,
+}
+
+/// A rule the unit was passed on by. Only [`FallthroughReason::Assignment`] has an
+/// `assignment_id`, and rules skipped before the unit was known have no `targeting_key`.
+#[derive(Debug, Clone)]
+pub struct FallthroughRule<'a> {
+    pub rule: &'a Rule,
+    pub reason: FallthroughReason,
+    pub assignment_id: String,
+    pub targeting_key: String,
+}
+
+/// Why the unit was passed on by a rule. Only fallthrough assignments are exposures, the other
+/// reasons are logged as skipped rules.
+// note that the ordinal values of the skip reasons match the protobuf `SkippedRule.SkipReason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallthroughReason {
+    // The unit was bucketed into a fallthrough assignment of the rule.
+    Assignment = 0,
+    // The rule is disabled.
+    RuleDisabled = 1,
+    // The segment of the rule is not in the resolver state.
+    SegmentNotFound = 2,
+    // The evaluation context has no targeting key for the rule.
+    MissingTargetingKey = 3,
+    // No assignment of the rule covers the bucket of the unit.
+    BucketNotAssigned = 4,
+}
+
+// note that the ordinal values are set to match the corresponding protobuf enum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveReason {
+    // The flag was successfully resolved because one rule matched.
+    Match = 1,
+    // The flag could not be resolved because no rule matched.
+    NoSegmentMatch = 2,
+    // The flag could not be resolved because the matching rule had no variant that could be
+    // assigned. Deprecated in the protocol, kept to represent reasons reported by older resolvers.
+    NoTreatmentMatch = 3,
+    // The flag could not be resolved because it was archived.
+    FlagArchived = 4,
+    // The flag could not be resolved because the targeting key field was invalid
+    TargetingKeyError = 5,
+    // Unknown error occurred during the resolve.
+    Error = 6,
+    // The flag resolved to its kill switch variant, or to no value, without evaluating rules.
+    FlagKilled = 7,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::resolver::v1::{ResolveFlagsResponse, Sdk};
+
+    const EXAMPLE_STATE: &[u8] = include_bytes!("../test-payloads/resolver_state.pb");
+    const SECRET: &str = "mkjJruAATQWjeY7foFIWfVAcBWnci2YF";
+
+    const ENCRYPTION_KEY: EncryptionKey = EncryptionKey::ZERO;
+
+    struct L;
+
+    impl Host for L {
+        fn log_resolve(
+            _resolve_id: &str,
+            _evaluation_context: &Struct,
+            _values: &[ResolvedValue<'_>],
+            _client: &Client,
+            _sdk: &Option<Sdk>,
+        ) {
+            // In tests, we don't need to print anything
+        }
+
+        fn log_assign(
+            _resolve_id: &str,
+            _evaluation_context: &Struct,
+            _assigned_flag: &[FlagToApply],
+            _client: &Client,
+            _sdk: &Option<Sdk>,
+        ) {
+            // In tests, we don't need to print anything
+        }
+    }
+
+    #[test]
+    fn test_random_alphanumeric() {
+        let rnd = L::random_alphanumeric(32);
+        let re = regex::Regex::new(r"^[a-zA-Z0-9]{32}$").unwrap();
+        assert!(re.is_match(&rnd));
+    }
+
+    #[test]
+    fn test_expand_to_schema() {
+        use flags_types::flag_schema::{ListFlagSchema, SchemaType, StructFlagSchema};
+        use flags_types::FlagSchema;
+
+        let schema = |schema_type| FlagSchema {
+            schema_type: Some(schema_type),
+        };
+        let item_schema = StructFlagSchema {
+            schema: BTreeMap::from([
+                (
+                    "id".to_string(),
+                    schema(SchemaType::IntSchema(Default::default())),
+                ),
+                (
+                    "label".to_string(),
+                    schema(SchemaType::StringSchema(Default::default())),
+                ),
+            ]),
+        };
+        let button_schema = StructFlagSchema {
+            schema: BTreeMap::from([
+                (
+                    "color".to_string(),
+                    schema(SchemaType::StringSchema(Default::default())),
+                ),
+                (
+                    "size".to_string(),
+                    schema(SchemaType::IntSchema(Default::default())),
+                ),
+            ]),
+        };
+        let flag = Flag {
+            name: "flags/partial".to_string(),
+            schema: Some(StructFlagSchema {
+                schema: BTreeMap::from([
+                    (
+                        "enabled".to_string(),
+                        schema(SchemaType::BoolSchema(Default::default())),
+                    ),
+                    (
+                        "button".to_string(),
+                        schema(SchemaType::StructSchema(button_schema.clone())),
+                    ),
+                    (
+                        "footer".to_string(),
+                        schema(SchemaType::StructSchema(button_schema)),
+                    ),
+                    (
+                        "items".to_string(),
+                        schema(SchemaType::ListSchema(
+                            ListFlagSchema {
+                                element_schema: Some(
+                                    schema(SchemaType::StructSchema(item_schema)).into(),
+                                ),
+                            }
+                            .into(),
+                        )),
+                    ),
+                ]),
+            }),
+            ..Default::default()
+        };
+        let json = |value: serde_json::Value| -> Struct { serde_json::from_value(value).unwrap() };
+        let variant = Variant {
+            name: "flags/partial/variants/v".to_string(),
+            value: Some(json(serde_json::json!({
+                "button": {"color": "red"},
+                "items": [{"id": 1}, {"label": "two"}],
+            }))),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            expand_to_schema(&variant, &flag),
+            json(serde_json::json!({
+                "enabled": null,
+                "button": {"color": "red", "size": null},
+                "footer": null,
+                "items": [{"id": 1, "label": null}, {"id": null, "label": "two"}],
+            }))
+        );
+
+        let mut resolved = ResolvedValue::new(&flag);
+        resolved.killed_variant = Some(&variant);
+        let resolved_flag = flags_resolver::ResolvedFlag::from(&resolved);
+        assert_eq!(resolved_flag.value, Some(expand_to_schema(&variant, &flag)));
+    }
+
+    #[test]
+    fn test_compute_skew_adjusted_time() {
+        let ts = |seconds, nanos| Timestamp { seconds, nanos };
+        // applied 2.5s before sending, on a client clock 100s behind
+        assert_eq!(
+            compute_skew_adjusted_time(&ts(1000, 0), &ts(997, 500_000_000), &ts(1100, 0)),
+            Some(ts(1097, 500_000_000))
+        );
+        // applied after sending
+        assert_eq!(
+            compute_skew_adjusted_time(&ts(1000, 0), &ts(1001, 0), &ts(1000, 0)),
+            Some(ts(1001, 0))
+        );
+        assert_eq!(
+            compute_skew_adjusted_time(&ts(1000, 0), &ts(1000, -1), &ts(1000, 0)),
+            None
+        );
+        assert_eq!(
+            compute_skew_adjusted_time(&ts(i64::MAX, 0), &ts(i64::MIN, 0), &ts(0, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_state_bitsets() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+
+        let bitset = state.bitsets.get("segments/qnbpewfufewyn5rpsylm").unwrap();
+        assert!(!bitset.is_loaded());
+        let bitvec = bitset.bits().unwrap();
+        assert!(bitset.is_loaded());
+        let bitvec2 = state
+            .bitsets
+            .get("segments/h2f3kemn2nqbnc7k5lk2")
+            .unwrap()
+            .bits()
+            .unwrap();
+
+        assert_eq!(bitvec.count_ones(), 555600);
+        assert_eq!(bitvec2.count_ones(), 555600);
+
+        // assert that we read the bytes in LSB order
+        let first_bits: Vec<bool> = (0..16).map(|i| bitvec[i]).collect();
+        let expected_first_bits = vec![
+            false, false, false, true, false, false, true, false, true, true, false, true, true,
+            true, true, true,
+        ];
+        assert_eq!(first_bits, expected_first_bits);
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        assert!(state.bitsets.values().all(|bitset| !bitset.is_loaded()));
+        // the flag index is built with the state
+        assert!(state.derived.get().is_some());
+
+        state.warm_up().unwrap();
+        assert!(state.bitsets.values().all(Bitset::is_loaded));
+
+        let client = &state.secrets.get(SECRET).unwrap().client_name;
+        let expected: Vec<&str> = state
+            .flags
+            .values()
+            .filter(|flag| flag.state() == flags_admin::flag::State::Active)
+            .filter(|flag| flag.clients.contains(client))
+            .map(|flag| flag.name.as_str())
+            .collect();
+        let actual: Vec<&str> = state
+            .client_flags(client)
+            .map(|flag| flag.name.as_str())
+            .collect();
+        assert!(!actual.is_empty());
+        assert_eq!(actual, expected);
+        assert_eq!(state.client_flags("clients/unknown").count(), 0);
+    }
+
+    #[test]
+    fn test_parse_state_secrets() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+
+        let account_client = state
+            .secrets
+            .get("mkjJruAATQWjeY7foFIWfVAcBWnci2YF")
+            .unwrap();
+        assert_eq!(account_client.client_name, "clients/cqzy4juldrvnz0z1uedj");
+        assert_eq!(
+            account_client.client_credential_name,
+            "clients/cqzy4juldrvnz0z1uedj/clientCredentials/yejholwrnjfewftakun8"
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        let account = Account {
+            name: "accounts/confidence-test".to_string(),
+        };
+        let arena = Bump::new();
+        let bucket = bucket(
+            hash(&account.salt_unit("roug", &arena).unwrap()),
+            hashing::SEGMENT_BUCKETS,
+        )
+        .unwrap();
+        assert_eq!(bucket, 567493); // test matching bucketing result from the java randomizer
+    }
+
+    #[test]
+    fn test_bucket_zero() {
+        let account = Account {
+            name: "accounts/confidence-test".to_string(),
+        };
+        let result = bucket(hash(&account.salt_unit("roug", &Bump::new()).unwrap()), 0);
+        assert!(result.is_err()); // bucket count of 0 should return error
+    }
+
+    #[test]
+    fn test_account_salt() {
+        let account = Account {
+            name: "accounts/test".to_string(),
+        };
+
+        assert_eq!(
+            account.salt_unit("roug", &Bump::new()).unwrap(),
+            "MegaSalt-test|roug"
+        );
+    }
+
+    #[test]
+    fn test_resolve_flag() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+
+        {
+            let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+            let flag = resolver.state.flags.get("flags/tutorial-feature").unwrap();
+            let resolve_result = resolver.resolve_flag(flag, BTreeMap::new()).unwrap();
+            let resolved_value = &resolve_result.resolved_value;
+            let assignment_match = resolved_value.assignment_match.as_ref().unwrap();
+
+            assert_eq!(
+                assignment_match.rule.name,
+                "flags/tutorial-feature/rules/tutorial-visitor-override"
+            );
+            assert_eq!(
+                assignment_match.variant.unwrap().name,
+                "flags/tutorial-feature/variants/exciting-welcome"
+            );
+            assert_eq!(resolved_value.should_apply, true);
+        }
+
+        {
+            let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+            let flag = resolver.state.flags.get("flags/tutorial-feature").unwrap();
+            let assignment_match = resolver
+                .resolve_flag(flag, BTreeMap::new())
+                .unwrap()
+                .resolved_value
+                .assignment_match
+                .unwrap();
+
+            assert_eq!(
+                assignment_match.rule.name,
+                "flags/tutorial-feature/rules/tutorial-visitor-override"
+            );
+            assert_eq!(
+                assignment_match.variant.unwrap().name,
+                "flags/tutorial-feature/variants/exciting-welcome"
+            );
+        }
+    }
+    #[test]
+    fn test_resolve_flags() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+
+        {
+            let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+
+            let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
+                evaluation_context: Some(Struct::default()),
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/tutorial-feature".to_string()],
+                apply: false,
+                sdk: Some(Sdk {
+                    sdk: None,
+                    version: "0.1.0".to_string(),
+                }),
+            };
+
+            let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
+            assert_eq!(response.resolved_flags.len(), 1);
+            let flag = response.resolved_flags.get(0).unwrap();
+
+            let decrypted_token = resolver
+                .decrypt_resolve_token(&response.resolve_token)
+                .unwrap();
+            match decrypted_token.resolve_token {
+                Some(flags_resolver::resolve_token::ResolveToken::TokenV1(token)) => {
+                    assert_eq!(token.resolve_id, response.resolve_id);
+                    assert_eq!(token.assignments.len(), response.resolved_flags.len());
+
+                    let assignment = token.assignments.get("flags/tutorial-feature").unwrap();
+
+                    assert_eq!(assignment.flag, "flags/tutorial-feature");
+                    assert_eq!(
+                        assignment.assignment_id,
+                        "flags/tutorial-feature/variants/exciting-welcome"
+                    );
+                    assert_eq!(
+                        assignment.variant,
+                        "flags/tutorial-feature/variants/exciting-welcome"
+                    );
+                    assert_eq!(
+                        assignment.rule,
+                        "flags/tutorial-feature/rules/tutorial-visitor-override"
+                    );
+
+                    assert_eq!(assignment.flag, flag.flag);
+                    assert_eq!(assignment.variant, flag.variant);
+                }
+                _ => panic!("Unexpected resolve token type"),
+            }
+
+            assert!(resolver.state.flags.contains_key("flags/tutorial-feature"));
+            assert_eq!(true, flag.should_apply);
         }
-        Ok(bitset[unit_hash])
     }
 
-    fn targeting_match(
-        &self,
-        segment: &Segment,
-        unit: &str,
-        visited: &mut HashSet<String>,
-    ) -> Fallible<bool> {
-        let Some(targeting) = &segment.targeting else {
-            return Ok(true);
-        };
-        let mut criterion_evaluator = |id: &String| {
-            let Some(Criterion {
-                criterion: Some(criterion),
-            }) = targeting.criteria.get(id)
-            else {
-                return Ok(false);
+    #[test]
+    fn test_resolve_flags_fallthrough() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+
+        // Single rule
+        {
+            let context_json = r#"{"visitor_id": "57"}"#;
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+
+            let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
+                evaluation_context: Some(Struct::default()),
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/fallthrough-test-1".to_string()],
+                apply: false,
+                sdk: Some(Sdk {
+                    sdk: None,
+                    version: "0.1.0".to_string(),
+                }),
             };
-            match &criterion {
-                criterion::Criterion::Attribute(attribute_criterion) => {
-                    let expected_value_type = value::expected_value_type(attribute_criterion);
-                    let attribute_value =
-                        self.get_attribute_value(&attribute_criterion.attribute_name);
-                    let converted =
-                        value::convert_to_targeting_value(attribute_value, expected_value_type)?;
-                    let wrapped = list_wrapper(&converted);
-
-                    Ok(value::evaluate_criterion(attribute_criterion, &wrapped))
-                }
-                criterion::Criterion::Segment(segment_criterion) => {
-                    let Some(ref_segment) = self.state.segments.get(&segment_criterion.segment)
-                    else {
-                        return Ok(false);
+
+            let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
+            assert_eq!(response.resolved_flags.len(), 1);
+            let flag = response.resolved_flags.get(0).unwrap();
+
+            let decrypted_token = resolver
+                .decrypt_resolve_token(&response.resolve_token)
+                .unwrap();
+            match decrypted_token.resolve_token {
+                Some(flags_resolver::resolve_token::ResolveToken::TokenV1(token)) => {
+                    assert_eq!(token.resolve_id, response.resolve_id);
+                    assert_eq!(token.assignments.len(), response.resolved_flags.len());
+
+                    let assignment = token.assignments.get("flags/fallthrough-test-1").unwrap();
+                    assert_eq!(assignment.flag, "flags/fallthrough-test-1");
+                    assert_eq!(assignment.targeting_key, "");
+                    assert_eq!(assignment.targeting_key_selector, "");
+                    assert_eq!(assignment.segment, "");
+                    assert_eq!(assignment.variant, "");
+                    assert_eq!(assignment.rule, "");
+                    assert_eq!(ResolveReason::NoSegmentMatch as i32, flag.reason);
+                    assert_eq!(assignment.assignment_id, "");
+
+                    let expected_fallthrough = flags_resolver::events::FallthroughAssignment {
+                        rule: "flags/fallthrough-test-1/rules/gdbiknjycxvmc6wu7zzz".to_string(),
+                        assignment_id: "control".to_string(),
+                        targeting_key: "57".to_string(),
+                        targeting_key_selector: "visitor_id".to_string(),
                     };
 
-                    self.segment_match_internal(ref_segment, unit, visited)
+                    assert_eq!(assignment.fallthrough_assignments.len(), 1);
+                    assert_eq!(assignment.fallthrough_assignments[0], expected_fallthrough);
                 }
+                _ => panic!("Unexpected resolve token type"),
             }
-        };
 
-        let Some(expression) = &targeting.expression else {
-            return Ok(true);
-        };
-        evaluate_expression(expression, &mut criterion_evaluator)
-    }
+            assert_eq!(true, flag.should_apply);
+        }
 
-    fn encrypt_resolve_token(
-        &self,
-        resolve_token: &flags_resolver::ResolveToken,
-    ) -> Result<Vec<u8>, String> {
-        let mut token_buf = Vec::with_capacity(resolve_token.encoded_len());
-        resolve_token.encode(&mut token_buf).or_fail()?;
+        // Fallthrough to second rule
+        {
+            let context_json = r#"{"visitor_id": "26"}"#;
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
 
-        H::encrypt_resolve_token(&token_buf, &self.encryption_key)
-    }
+            let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
+                evaluation_context: Some(Struct::default()),
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/fallthrough-test-2".to_string()],
+                apply: false,
+                sdk: Some(Sdk {
+                    sdk: None,
+                    version: "0.1.0".to_string(),
+                }),
+            };
 
-    fn decrypt_resolve_token(
-        &self,
-        encrypted_token: &[u8],
-    ) -> Result<flags_resolver::ResolveToken, String> {
-        let decrypted_data = H::decrypt_resolve_token(encrypted_token, &self.encryption_key)?;
+            let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
+            assert_eq!(response.resolved_flags.len(), 1);
+            let flag = response.resolved_flags.get(0).unwrap();
 
-        let t = flags_resolver::ResolveToken::decode(&decrypted_data[..]).or_fail()?;
-        Ok(t)
-    }
-}
+            let decrypted_token = resolver
+                .decrypt_resolve_token(&response.resolve_token)
+                .unwrap();
+            match decrypted_token.resolve_token {
+                Some(flags_resolver::resolve_token::ResolveToken::TokenV1(token)) => {
+                    assert_eq!(token.resolve_id, response.resolve_id);
+                    assert_eq!(token.assignments.len(), response.resolved_flags.len());
 
-fn to_date_time_utc(timestamp: &Timestamp) -> Option<chrono::DateTime<chrono::Utc>> {
-    chrono::DateTime::from_timestamp(timestamp.seconds, timestamp.nanos as u32)
-}
+                    let assignment = token.assignments.get("flags/fallthrough-test-2").unwrap();
+                    assert_eq!(assignment.flag, "flags/fallthrough-test-2");
+                    assert_eq!(assignment.targeting_key, "26");
+                    assert_eq!(assignment.targeting_key_selector, "visitor_id");
+                    assert_eq!(assignment.segment, "segments/dvlllobhnpxcojqn6vfa");
+                    assert_eq!(
+                        assignment.variant,
+                        "flags/fallthrough-test-2/variants/enabled"
+                    );
+                    assert_eq!(
+                        assignment.rule,
+                        "flags/fallthrough-test-2/rules/oxl1yqqjj1aqyiuvf9al"
+                    );
+                    assert_eq!(ResolveReason::Match as i32, flag.reason);
+                    assert_eq!(assignment.assignment_id, "");
 
-fn evaluate_expression(
-    expression: &Expression,
-    criterion_evaluator: &mut dyn FnMut(&String) -> Fallible<bool>,
-) -> Fallible<bool> {
-    let Some(expression) = &expression.expression else {
-        return Ok(false);
-    };
-    match expression {
-        expression::Expression::Ref(ref_) => criterion_evaluator(ref_),
-        expression::Expression::Not(not) => Ok(!evaluate_expression(not, criterion_evaluator)?),
-        expression::Expression::And(and) => {
-            for op in &and.operands {
-                if !evaluate_expression(op, criterion_evaluator)? {
-                    return Ok(false);
-                }
-            }
-            Ok(true)
-        }
-        expression::Expression::Or(or) => {
-            for op in &or.operands {
-                if evaluate_expression(op, criterion_evaluator)? {
-                    return Ok(true);
+                    let expected_fallthrough = flags_resolver::events::FallthroughAssignment {
+                        rule: "flags/fallthrough-test-2/rules/wwzea3vq89gwtcufe9ou".to_string(),
+                        assignment_id: "control".to_string(),
+                        targeting_key: "26".to_string(),
+                        targeting_key_selector: "visitor_id".to_string(),
+                    };
+
+                    assert_eq!(assignment.fallthrough_assignments.len(), 1);
+                    assert_eq!(assignment.fallthrough_assignments[0], expected_fallthrough);
                 }
+                _ => panic!("Unexpected resolve token type"),
             }
-            Ok(false)
+
+            assert_eq!(true, flag.should_apply);
         }
     }
-}
 
-fn list_wrapper(value: &targeting::value::Value) -> targeting::ListValue {
-    match value {
-        targeting::value::Value::ListValue(list_value) => list_value.clone(),
-        _ => targeting::ListValue {
-            values: vec![targeting::Value {
-                value: Some(value.clone()),
-            }],
-        },
-    }
-}
+    #[test]
+    fn test_resolve_flags_no_match() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
 
-#[derive(Debug, Clone)]
-pub struct ResolvedValue<'a> {
-    pub flag: &'a Flag,
-    pub reason: ResolveReason,
-    pub assignment_match: Option<AssignmentMatch<'a>>,
-    pub fallthrough_rules: Vec<FallthroughRule<'a>>,
-    pub should_apply: bool,
-}
+        {
+            let context_json = r#"{}"#; // NO CONTEXT
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
 
-#[derive(Debug)]
-pub struct FlagResolveResult<'a> {
-    pub resolved_value: ResolvedValue<'a>,
-    pub updates: Vec<MaterializationUpdate>,
-}
+            let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
+                evaluation_context: Some(Struct::default()),
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/tutorial-feature".to_string()],
+                apply: false,
+                sdk: Some(Sdk {
+                    sdk: None,
+                    version: "0.1.0".to_string(),
+                }),
+            };
 
-impl<'a> ResolvedValue<'a> {
-    fn new(flag: &'a Flag) -> Self {
-        ResolvedValue {
-            flag,
-            reason: ResolveReason::NoSegmentMatch,
-            assignment_match: Option::None,
-            fallthrough_rules: vec![],
-            should_apply: false,
-        }
-    }
+            let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
+            assert_eq!(response.resolved_flags.len(), 1);
+            assert!(resolver.state.flags.contains_key("flags/tutorial-feature"));
 
-    fn error(&self, reason: ResolveReason) -> Self {
-        ResolvedValue {
-            flag: self.flag,
-            reason,
-            assignment_match: Option::None,
-            fallthrough_rules: self.fallthrough_rules.clone(),
-            should_apply: false,
+            let flag = response.resolved_flags.get(0).unwrap();
+            assert_eq!(false, flag.should_apply);
+            assert_eq!(ResolveReason::NoSegmentMatch as i32, flag.reason);
         }
     }
 
-    fn attribute_fallthrough_rule(&mut self, rule: &'a Rule, assignment_id: &str, unit: &str) {
-        self.fallthrough_rules.push(FallthroughRule {
-            rule,
-            assignment_id: assignment_id.to_string(),
-            targeting_key: unit.to_string(),
-        });
-    }
-
-    fn with_client_default_match(
-        &self,
-        rule: &'a Rule,
-        segment: &'a Segment,
-        assignment_id: &str,
-        unit: &str,
-    ) -> Self {
-        ResolvedValue {
-            flag: self.flag,
-            reason: ResolveReason::Match,
-            assignment_match: Option::Some(AssignmentMatch {
-                rule,
-                segment,
-                assignment_id: assignment_id.to_string(),
-                targeting_key: unit.to_string(),
-                variant: Option::None,
-            }),
-            fallthrough_rules: self.fallthrough_rules.clone(),
-            should_apply: true,
-        }
+    #[test]
+    fn test_segment_population() {
+        let mut state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let population = state
+            .segment_population("segments/qnbpewfufewyn5rpsylm", 10)
+            .unwrap();
+        assert_eq!(population.population, 555600);
+        assert_eq!(population.sample.len(), 10);
+        assert!(population.sample.windows(2).all(|w| w[0] < w[1]));
+        assert!(state
+            .segment_population("segments/does-not-exist", 10)
+            .is_err());
+
+        let mut from = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        Arc::make_mut(&mut from.bitsets).insert(
+            "segments/changed".to_string(),
+            Bitset::from_bits(bv::BitVec::from_vec(vec![0b0000_1111])),
+        );
+        Arc::make_mut(&mut state.bitsets).insert(
+            "segments/changed".to_string(),
+            Bitset::from_bits(bv::BitVec::from_vec(vec![0b0011_1100, 0b1])),
+        );
+        assert_eq!(
+            ResolverState::diff_segments(&from, &state, "segments/changed").unwrap(),
+            BitsetDiff {
+                added: 3,
+                removed: 2
+            }
+        );
+        assert!(ResolverState::diff_segments(&from, &state, "segments/does-not-exist").is_err());
+        assert_eq!(
+            state
+                .segment_population("segments/changed", 2)
+                .unwrap()
+                .sample,
+            vec![2, 5]
+        );
     }
 
-    fn with_variant_match(
-        &self,
-        rule: &'a Rule,
-        segment: &'a Segment,
-        variant: &'a Variant,
-        assignment_id: &str,
-        unit: &str,
-    ) -> Self {
-        ResolvedValue {
-            flag: self.flag,
-            reason: ResolveReason::Match,
-            assignment_match: Option::Some(AssignmentMatch {
-                rule,
-                segment,
-                assignment_id: assignment_id.to_string(),
-                targeting_key: unit.to_string(),
-                variant: Option::Some(variant),
-            }),
-            fallthrough_rules: self.fallthrough_rules.clone(),
-            should_apply: true,
+    #[test]
+    fn test_prewarm_bitsets() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        assert!(state.bitsets.values().all(|b| !b.is_loaded()));
+
+        let referenced = state.referenced_segments();
+        let decompressed = state.prewarm_bitsets().unwrap();
+        assert!(decompressed > 0);
+        for (segment, bitset) in state.bitsets.iter() {
+            assert_eq!(bitset.is_loaded(), referenced.contains(segment.as_str()));
         }
+        assert_eq!(state.prewarm_bitsets().unwrap(), 0);
     }
-}
 
-impl<'a> From<&ResolvedValue<'a>> for flags_resolver::ResolvedFlag {
-    fn from(value: &ResolvedValue<'a>) -> Self {
-        let mut resolved_flag = flags_resolver::ResolvedFlag {
-            flag: value.flag.name.clone(),
-            reason: value.reason as i32,
-            should_apply: value.should_apply,
+    #[test]
+    fn test_apply_delta() {
+        let mut pb: ResolverStatePb = EXAMPLE_STATE.to_owned().try_into().unwrap();
+        let kill_switch = |flag: &str, variant: &str| flags_admin::resolver_state::KillSwitch {
+            flag: flag.to_string(),
+            variant: variant.to_string(),
             ..Default::default()
         };
+        pb.kill_switches = vec![
+            kill_switch("flags/fallthrough-test-1", ""),
+            kill_switch("flags/fallthrough-test-2", ""),
+            kill_switch("flags/tutorial-feature", ""),
+        ];
+        let previous =
+            ResolverState::from_bytes(&pb.encode_to_vec(), "confidence-demo-june").unwrap();
+        let client = previous.secrets[SECRET].client_name.clone();
+        let credential = previous.secrets[SECRET].client_credential_name.clone();
+        let mut flag = previous.flags["flags/tutorial-feature"].clone();
+        flag.rules.clear();
+
+        let state = previous
+            .apply_delta(
+                flags_admin::ResolverStateDelta {
+                    flags: vec![flag],
+                    removed_flags: vec!["flags/fallthrough-test-1".to_string()],
+                    client_credentials: vec![iam::ClientCredential {
+                        name: credential.clone(),
+                        credential: Some(iam::client_credential::Credential::ClientSecret(
+                            iam::client_credential::ClientSecret {
+                                secret: "rotated".to_string(),
+                            },
+                        )),
+                        ..Default::default()
+                    }],
+                    kill_switches: vec![kill_switch(
+                        "flags/tutorial-feature",
+                        "flags/tutorial-feature/variants/off",
+                    )],
+                    removed_kill_switches: vec!["flags/fallthrough-test-2".to_string()],
+                    ..Default::default()
+                },
+                "confidence-demo-june",
+            )
+            .unwrap();
+        // the previous state is unchanged
+        assert!(previous.secrets.contains_key(SECRET));
+        assert!(previous.flags.contains_key("flags/fallthrough-test-1"));
+        assert!(!state.secrets.contains_key(SECRET));
+        assert_eq!(state.secrets["rotated"].client_credential_name, credential);
+        assert_eq!(state.secrets["rotated"].client_name, client);
+        assert!(state.flags["flags/tutorial-feature"].rules.is_empty());
+        assert!(!state.flags.contains_key("flags/fallthrough-test-1"));
+        assert!(state
+            .client_flags(&client)
+            .all(|flag| flag.name != "flags/fallthrough-test-1"));
+        // segments and bitsets are untouched, so they are shared
+        assert!(!Arc::ptr_eq(&state.flags, &previous.flags));
+        assert!(Arc::ptr_eq(&state.segments, &previous.segments));
+        assert!(Arc::ptr_eq(&state.bitsets, &previous.bitsets));
+        // switches of removed flags go with them, and switches are replaced per flag and client
+        assert_eq!(
+            state.kill_switches,
+            vec![KillSwitch {
+                flag: "flags/tutorial-feature".to_string(),
+                client: None,
+                variant: Some("flags/tutorial-feature/variants/off".to_string()),
+            }]
+        );
+        assert_eq!(previous.kill_switches.len(), 3);
+        assert!(state.fingerprint.is_empty());
 
-        if let Some(assignment_match) = &value.assignment_match {
-            match assignment_match.variant {
-                Some(variant) => {
-                    resolved_flag.variant = variant.name.clone();
-                    resolved_flag.value = variant.value.clone(); // todo: expand to schema
-                    resolved_flag.flag_schema = value.flag.schema.clone();
-                }
-                None => {
-                    resolved_flag.variant = "".to_string();
-                    resolved_flag.value = Some(Struct::default());
-                    resolved_flag.flag_schema =
-                        Some(flags_types::flag_schema::StructFlagSchema::default())
-                }
-            }
-        }
+        // the delta gives the same state as a full snapshot with the changes
+        let reloaded =
+            ResolverState::from_proto(state.to_proto().unwrap(), "confidence-demo-june").unwrap();
+        assert_eq!(reloaded.to_proto().unwrap(), state.to_proto().unwrap());
 
-        resolved_flag
-    }
-}
+        // encoded deltas chain the fingerprint of the state they are applied to
+        let encoded = flags_admin::ResolverStateDelta {
+            removed_flags: vec!["flags/fallthrough-test-1".to_string()],
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let chained = previous
+            .apply_delta_bytes(&encoded, "confidence-demo-june")
+            .unwrap();
+        assert_eq!(
+            chained.fingerprint,
+            ResolverState::fingerprint_of(&[previous.fingerprint.as_bytes(), &encoded].concat())
+        );
+        assert_ne!(chained.fingerprint, previous.fingerprint);
+        assert_eq!(
+            previous
+                .apply_delta_bytes(&encoded, "confidence-demo-june")
+                .unwrap()
+                .fingerprint,
+            chained.fingerprint
+        );
 
-impl<'a> From<&ResolvedValue<'a>> for flags_resolver::resolve_token_v1::AssignedFlag {
-    fn from(value: &ResolvedValue<'a>) -> Self {
-        let mut assigned_flag = flags_resolver::resolve_token_v1::AssignedFlag {
-            flag: value.flag.name.clone(),
-            reason: value.reason as i32,
-            fallthrough_assignments: value
-                .fallthrough_rules
-                .iter()
-                .map(
-                    |fallthrough_rule| flags_resolver::events::FallthroughAssignment {
-                        assignment_id: fallthrough_rule.assignment_id.clone(),
-                        rule: fallthrough_rule.rule.name.clone(),
-                        targeting_key: fallthrough_rule.targeting_key.clone(),
-                        targeting_key_selector: fallthrough_rule
-                            .rule
-                            .targeting_key_selector
-                            .clone(),
+        // credentials must belong to a client of the state
+        let stray = flags_admin::ResolverStateDelta {
+            client_credentials: vec![iam::ClientCredential {
+                name: "clients/unknown/clientCredentials/stray".to_string(),
+                credential: Some(iam::client_credential::Credential::ClientSecret(
+                    iam::client_credential::ClientSecret {
+                        secret: "stray".to_string(),
                     },
-                )
-                .collect(),
+                )),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(state.apply_delta(stray, "confidence-demo-june").is_err());
+
+        // an invalid bitset fails the delta without applying any of it
+        let invalid = flags_admin::ResolverStateDelta {
+            removed_flags: vec!["flags/tutorial-feature".to_string()],
+            bitsets: vec![flags_admin::resolver_state::PackedBitset {
+                segment: "segments/invalid".to_string(),
+                bitset: Some(flags_admin::resolver_state::packed_bitset::Bitset::FullBitset(false)),
+            }],
             ..Default::default()
         };
+        assert!(state.apply_delta(invalid, "confidence-demo-june").is_err());
+    }
 
-        if let Some(assignment_match) = &value.assignment_match {
-            assigned_flag.assignment_id = assignment_match.assignment_id.clone();
-            assigned_flag.rule = assignment_match.rule.name.clone();
-            assigned_flag.segment = assignment_match.segment.name.clone();
-            assigned_flag.targeting_key = assignment_match.targeting_key.clone();
-            assigned_flag.targeting_key_selector =
-                assignment_match.rule.targeting_key_selector.clone();
-            if let Some(variant) = assignment_match.variant {
-                assigned_flag.variant = variant.name.clone();
-            }
-        }
+    #[test]
+    fn test_duplicates_in_state() {
+        use crate::test_util::TestHost;
 
-        assigned_flag
-    }
-}
+        let mut pb: ResolverStatePb = EXAMPLE_STATE.to_owned().try_into().unwrap();
+        let mut flag = pb
+            .flags
+            .iter()
+            .find(|flag| flag.name == "flags/tutorial-feature")
+            .unwrap()
+            .clone();
+        flag.rules.clear();
+        pb.flags.push(flag);
+        pb.clients.push(iam::Client {
+            name: "clients/other".to_string(),
+            ..Default::default()
+        });
+        pb.client_credentials.push(iam::ClientCredential {
+            name: "clients/other/clientCredentials/copy".to_string(),
+            credential: Some(iam::client_credential::Credential::ClientSecret(
+                iam::client_credential::ClientSecret {
+                    secret: SECRET.to_string(),
+                },
+            )),
+            ..Default::default()
+        });
 
-#[derive(Debug, Clone)]
-pub struct AssignmentMatch<'a> {
-    pub rule: &'a Rule,
-    pub segment: &'a Segment,
-    pub assignment_id: String,
-    pub targeting_key: String,
-    pub variant: Option<&'a Variant>,
-}
+        let original = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let (state, report) = ResolverState::from_proto_with_options(
+            pb.clone(),
+            "confidence-demo-june",
+            &LoadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            report.duplicates,
+            vec![
+                StateDuplicate::Flag {
+                    name: "flags/tutorial-feature".to_string(),
+                },
+                StateDuplicate::Secret {
+                    kept: "clients/other/clientCredentials/copy".to_string(),
+                    replaced: original.secrets[SECRET].client_credential_name.clone(),
+                },
+            ]
+        );
+        // the entries listed last are kept
+        assert!(state.flags["flags/tutorial-feature"].rules.is_empty());
+        assert_eq!(state.secrets[SECRET].client_name, "clients/other");
+
+        TestHost::reset();
+        ResolverState::from_proto_with_host::<TestHost>(pb, "confidence-demo-june").unwrap();
+        let mut kinds: Vec<String> = TestHost::metrics()
+            .into_iter()
+            .filter(|m| m.name == metrics::STATE_DUPLICATE)
+            .flat_map(|m| m.tags)
+            .map(|(_, kind)| kind)
+            .collect();
+        kinds.sort();
+        assert_eq!(kinds, vec!["flag", "secret"]);
+    }
 
-#[derive(Debug, Clone)]
-pub struct FallthroughRule<'a> {
-    pub rule: &'a Rule,
-    pub assignment_id: String,
-    pub targeting_key: String,
-}
+    #[test]
+    fn test_prune_unreferenced() {
+        let mut pb: ResolverStatePb = EXAMPLE_STATE.to_owned().try_into().unwrap();
+        pb.segments_no_bitsets.push(Segment {
+            name: "segments/orphan".to_string(),
+            ..Default::default()
+        });
 
-// note that the ordinal values are set to match the corresponding protobuf enum
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ResolveReason {
-    // The flag was successfully resolved because one rule matched.
-    Match = 1,
-    // The flag could not be resolved because no rule matched.
-    NoSegmentMatch = 2,
-    // The flag could not be resolved because it was archived.
-    FlagArchived = 4,
-    // The flag could not be resolved because the targeting key field was invalid
-    TargetingKeyError = 5,
-}
+        let (state, report) = ResolverState::from_proto_with_options(
+            pb.clone(),
+            "confidence-demo-june",
+            &LoadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report, LoadReport::default());
+        assert!(state.segments.contains_key("segments/orphan"));
 
-pub fn hash(key: &str) -> u128 {
-    murmur3_x64_128(key.as_bytes(), 0)
-}
+        let (pruned, report) = ResolverState::from_proto_with_options(
+            pb,
+            "confidence-demo-june",
+            &LoadOptions {
+                prune_unreferenced: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let report = report.pruned;
+        assert!(report.segments.contains(&"segments/orphan".to_string()));
+        assert!(!pruned.segments.contains_key("segments/orphan"));
+        assert_eq!(
+            pruned.segments.len() + report.segments.len(),
+            state.segments.len()
+        );
+        assert_eq!(
+            pruned.bitsets.len() + report.bitsets.len(),
+            state.bitsets.len()
+        );
+        let referenced = state.referenced_segments();
+        assert!(report
+            .segments
+            .iter()
+            .all(|segment| !referenced.contains(segment.as_str())));
 
-#[allow(clippy::arithmetic_side_effects)] // buckets != 0 checked above
-pub fn bucket(hash: u128, buckets: u64) -> Fallible<usize> {
-    if buckets == 0 {
-        fail!(":bucket.zero_buckets");
+        // resolves are unaffected
+        let request = flags_resolver::ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            apply: false,
+            ..Default::default()
+        };
+        let resolve = |state: &ResolverState| {
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(
+                    SECRET,
+                    r#"{"visitor_id": "tutorial_visitor"}"#,
+                    &ENCRYPTION_KEY,
+                )
+                .unwrap();
+            let mut flags = resolver.resolve_flags(&request).unwrap().resolved_flags;
+            flags.sort_by(|a, b| a.flag.cmp(&b.flag));
+            flags
+        };
+        assert_eq!(resolve(&pruned), resolve(&state));
     }
-    // convert u128 to u64 to match what we do in the java resolver
-    let hash_long: u64 = hash as u64;
-
-    // don't ask me why
-    Ok(((hash_long >> 4) % buckets) as usize)
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::proto::confidence::flags::resolver::v1::{ResolveFlagsResponse, Sdk};
+    #[test]
+    fn test_slice_for_client() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        assert!(state.slice_for_client("not-a-secret").is_err());
 
-    const EXAMPLE_STATE: &[u8] = include_bytes!("../test-payloads/resolver_state.pb");
-    const SECRET: &str = "mkjJruAATQWjeY7foFIWfVAcBWnci2YF";
+        let slice = state.slice_for_client(SECRET).unwrap();
+        let client = &state.secrets[SECRET];
+        assert_eq!(slice.clients.len(), 1);
+        assert_eq!(slice.client_credentials.len(), 1);
+        assert!(slice
+            .flags
+            .iter()
+            .all(|flag| flag.clients.contains(&client.client_name)));
+        assert!(slice.segments_no_bitsets.len() <= state.segments.len());
+        assert_eq!(slice.bitsets.len(), slice.segments_no_bitsets.len());
 
-    const ENCRYPTION_KEY: Bytes = Bytes::from_static(&[0; 16]);
+        let sliced = ResolverState::from_proto(
+            ResolverStatePb::decode(slice.encode_to_vec().as_slice()).unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let request = flags_resolver::ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            apply: false,
+            ..Default::default()
+        };
+        let resolve = |state: &ResolverState| {
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(
+                    SECRET,
+                    r#"{"visitor_id": "tutorial_visitor"}"#,
+                    &ENCRYPTION_KEY,
+                )
+                .unwrap();
+            let mut flags = resolver.resolve_flags(&request).unwrap().resolved_flags;
+            flags.sort_by(|a, b| a.flag.cmp(&b.flag));
+            flags
+        };
+        assert!(!resolve(&state).is_empty());
+        assert_eq!(resolve(&sliced), resolve(&state));
+    }
 
-    struct L;
+    #[test]
+    fn test_to_proto_round_trip() {
+        let pb: ResolverStatePb = EXAMPLE_STATE.to_owned().try_into().unwrap();
+        let mut state = ResolverState::from_proto(pb.clone(), "confidence-demo-june").unwrap();
+        state.config.log_assigns = false;
+        Arc::make_mut(&mut state.bitsets).insert(
+            "segments/from-bits".to_string(),
+            Bitset::from_bits(bv::BitVec::from_vec(vec![0b0110_1001])),
+        );
 
-    impl Host for L {
-        fn log_resolve(
-            _resolve_id: &str,
-            _evaluation_context: &Struct,
-            _values: &[ResolvedValue<'_>],
-            _client: &Client,
-            _sdk: &Option<Sdk>,
-        ) {
-            // In tests, we don't need to print anything
-        }
+        let exported = state.to_proto().unwrap();
+        assert_eq!(exported.flags.len(), pb.flags.len());
+        assert_eq!(
+            exported.segments_no_bitsets.len(),
+            pb.segments_no_bitsets.len()
+        );
+        assert_eq!(exported.client_credentials.len(), state.secrets.len());
 
-        fn log_assign(
-            _resolve_id: &str,
-            _evaluation_context: &Struct,
-            _assigned_flag: &[FlagToApply],
-            _client: &Client,
-            _sdk: &Option<Sdk>,
-        ) {
-            // In tests, we don't need to print anything
+        let reloaded = ResolverState::from_proto(exported.clone(), "confidence-demo-june").unwrap();
+        assert_eq!(reloaded.flags, state.flags);
+        assert_eq!(reloaded.segments, state.segments);
+        assert_eq!(reloaded.config, state.config);
+        assert_eq!(
+            reloaded.secrets.keys().collect::<HashSet<_>>(),
+            state.secrets.keys().collect::<HashSet<_>>()
+        );
+        assert_eq!(reloaded.bitsets.len(), state.bitsets.len());
+        for (name, bitset) in state.bitsets.iter() {
+            assert_eq!(reloaded.bitsets[name].bits(), bitset.bits(), "{}", name);
         }
+        // exporting is deterministic
+        assert_eq!(reloaded.to_proto().unwrap(), exported);
     }
 
     #[test]
-    fn test_random_alphanumeric() {
-        let rnd = L::random_alphanumeric(32);
-        let re = regex::Regex::new(r"^[a-zA-Z0-9]{32}$").unwrap();
-        assert!(re.is_match(&rnd));
+    fn test_packed_bitset_from_bits() {
+        let bits = bv::BitVec::<u8, bv::Lsb0>::from_vec(vec![0b1010_0101, 0xff, 0]);
+        let flags_admin::resolver_state::packed_bitset::Bitset::GzippedBitset(gzipped) =
+            Bitset::from_bits(bits.clone()).packed().unwrap()
+        else {
+            panic!("expected a gzipped bitset");
+        };
+        assert_eq!(Bitset::gzipped(gzipped).bits().unwrap(), &bits);
     }
 
     #[test]
-    fn test_parse_state_bitsets() {
-        let state = ResolverState::from_proto(
-            EXAMPLE_STATE.to_owned().try_into().unwrap(),
-            "confidence-demo-june",
-        )
-        .unwrap();
-
-        let bitvec = state.bitsets.get("segments/qnbpewfufewyn5rpsylm").unwrap();
-        let bitvec2 = state.bitsets.get("segments/h2f3kemn2nqbnc7k5lk2").unwrap();
-
-        assert_eq!(bitvec.count_ones(), 555600);
-        assert_eq!(bitvec2.count_ones(), 555600);
-
-        // assert that we read the bytes in LSB order
-        let first_bits: Vec<bool> = (0..16).map(|i| bitvec[i]).collect();
-        let expected_first_bits = vec![
-            false, false, false, true, false, false, true, false, true, true, false, true, true,
-            true, true, true,
-        ];
-        assert_eq!(first_bits, expected_first_bits);
+    fn test_corrupt_bitset_fails_on_use() {
+        let bitset = Bitset::gzipped(vec![1, 2, 3]);
+        assert!(bitset.bits().is_err());
+        assert!(bitset.is_loaded());
     }
 
     #[test]
-    fn test_parse_state_secrets() {
+    fn test_memory_stats() {
         let state = ResolverState::from_proto(
             EXAMPLE_STATE.to_owned().try_into().unwrap(),
             "confidence-demo-june",
         )
         .unwrap();
-
-        let account_client = state
-            .secrets
-            .get("mkjJruAATQWjeY7foFIWfVAcBWnci2YF")
-            .unwrap();
-        assert_eq!(account_client.client_name, "clients/cqzy4juldrvnz0z1uedj");
+        let stats = state.memory_stats();
+        assert!(stats.flags > 0);
+        assert!(stats.segments > 0);
+        assert!(stats.secrets > 0);
         assert_eq!(
-            account_client.client_credential_name,
-            "clients/cqzy4juldrvnz0z1uedj/clientCredentials/yejholwrnjfewftakun8"
+            stats.total(),
+            stats.flags + stats.segments + stats.bitsets + stats.secrets
         );
+
+        let empty = sticky_state("", "");
+        assert!(empty.memory_stats().flags < stats.flags);
     }
 
+    #[cfg(feature = "sticky")]
     #[test]
-    fn test_hash() {
-        let account = Account {
-            name: "accounts/confidence-test".to_string(),
-        };
-        let bucket = bucket(hash(&account.salt_unit("roug").unwrap()), BUCKETS).unwrap();
-        assert_eq!(bucket, 567493); // test matching bucketing result from the java randomizer
+    fn test_stepped_resolve_matches_resolve_flags_sticky() {
+        use crate::test_util::TestHost;
+
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        let request =
+            ResolveWithStickyRequest::without_sticky(flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                apply: true,
+                ..Default::default()
+            });
+
+        TestHost::reset();
+        let expected = resolver.resolve_flags_sticky(&request).unwrap();
+        let expected_assigns = TestHost::assigned_flags();
+
+        TestHost::reset();
+        let mut progress = resolver.resolve_begin(request).unwrap();
+        let total = progress.remaining();
+        assert!(total > 1);
+        // a step of no flags still resolves one
+        assert!(!resolver.resolve_step(&mut progress, 0).unwrap());
+        assert_eq!(progress.remaining(), total - 1);
+        let mut steps = 1;
+        while !resolver.resolve_step(&mut progress, 1).unwrap() {
+            steps += 1;
+            assert_eq!(progress.remaining(), total - steps);
+        }
+        let stepped = resolver.resolve_finish(progress).unwrap();
+
+        assert_eq!(stepped, expected);
+        assert_eq!(TestHost::assigned_flags(), expected_assigns);
     }
 
+    #[cfg(feature = "sticky")]
     #[test]
-    fn test_bucket_zero() {
-        let account = Account {
-            name: "accounts/confidence-test".to_string(),
+    fn test_resolve_warnings() {
+        use crate::test_util::TestHost;
+
+        let mut state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let rule_name = "flags/tutorial-feature/rules/tutorial-visitor-override";
+        let segment_name = state.flags["flags/tutorial-feature"]
+            .rules
+            .iter()
+            .find(|rule| rule.name == rule_name)
+            .unwrap()
+            .segment
+            .clone();
+        Arc::make_mut(&mut state.segments).remove(&segment_name);
+
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        let request =
+            ResolveWithStickyRequest::without_sticky(flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/tutorial-feature".to_string()],
+                apply: false,
+                ..Default::default()
+            });
+
+        TestHost::reset();
+        let response = resolver.resolve_flags_sticky(&request).unwrap();
+        let Some(ResolveResult::Success(success)) = response.resolve_result else {
+            panic!("expected a successful resolve");
         };
-        let result = bucket(hash(&account.salt_unit("roug").unwrap()), 0);
-        assert!(result.is_err()); // bucket count of 0 should return error
+        assert_eq!(success.warnings.len(), 1);
+        let warning = &success.warnings[0];
+        assert_eq!(warning.flag, "flags/tutorial-feature");
+        assert_eq!(warning.rule, rule_name);
+        assert_eq!(warning.kind(), resolve_warning::Kind::SegmentNotFound);
+        assert!(TestHost::messages()
+            .iter()
+            .any(|message| message.contains(&segment_name)));
     }
 
+    #[cfg(feature = "tracing")]
     #[test]
-    fn test_account_salt() {
-        let account = Account {
-            name: "accounts/test".to_string(),
+    fn test_allocation_percent() {
+        let assignment = rule::Assignment {
+            bucket_ranges: vec![
+                rule::BucketRange {
+                    lower: 0,
+                    upper: 250,
+                },
+                rule::BucketRange {
+                    lower: 500,
+                    upper: 750,
+                },
+            ],
+            ..Default::default()
         };
-
-        assert_eq!(account.salt(), Ok("MegaSalt-test".into()));
+        assert_eq!(allocation_percent(&assignment, 1000), 50.0);
+        assert_eq!(allocation_percent(&assignment, 0), 0.0);
     }
 
     #[test]
-    fn test_resolve_flag() {
+    fn test_resolve_flag_for_units() {
         let state = ResolverState::from_proto(
             EXAMPLE_STATE.to_owned().try_into().unwrap(),
             "confidence-demo-june",
         )
         .unwrap();
-
-        {
-            let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
-            let resolver: AccountResolver<'_, L> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
-                .unwrap();
-            let flag = resolver.state.flags.get("flags/tutorial-feature").unwrap();
-            let resolve_result = resolver.resolve_flag(flag, BTreeMap::new()).unwrap();
-            let resolved_value = &resolve_result.resolved_value;
-            let assignment_match = resolved_value.assignment_match.as_ref().unwrap();
-
-            assert_eq!(
-                assignment_match.rule.name,
-                "flags/tutorial-feature/rules/tutorial-visitor-override"
-            );
-            assert_eq!(
-                assignment_match.variant.unwrap().name,
-                "flags/tutorial-feature/variants/exciting-welcome"
-            );
-            assert_eq!(resolved_value.should_apply, true);
-        }
-
-        {
-            let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
+        let variant_of = |context: &str| {
             let resolver: AccountResolver<'_, L> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .get_resolver_with_json_context(SECRET, context, &ENCRYPTION_KEY)
                 .unwrap();
             let flag = resolver.state.flags.get("flags/tutorial-feature").unwrap();
-            let assignment_match = resolver
+            resolver
                 .resolve_flag(flag, BTreeMap::new())
                 .unwrap()
                 .resolved_value
                 .assignment_match
-                .unwrap();
+                .and_then(|m| m.variant)
+                .map(|v| v.name.clone())
+        };
 
-            assert_eq!(
-                assignment_match.rule.name,
-                "flags/tutorial-feature/rules/tutorial-visitor-override"
-            );
-            assert_eq!(
-                assignment_match.variant.unwrap().name,
-                "flags/tutorial-feature/variants/exciting-welcome"
-            );
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "someone_else"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        let units = [
+            "tutorial_visitor",
+            "visitor-1",
+            "visitor-2",
+            "tutorial_visitor",
+        ];
+        let variants = resolver
+            .resolve_flag_for_units("flags/tutorial-feature", &units)
+            .unwrap();
+        assert_eq!(variants.len(), 3);
+        for unit in units {
+            let context = format!(r#"{{"visitor_id": "{}"}}"#, unit);
+            assert_eq!(variants[unit], variant_of(&context), "{}", unit);
         }
+        assert_eq!(
+            variants["tutorial_visitor"].as_deref(),
+            Some("flags/tutorial-feature/variants/exciting-welcome")
+        );
+
+        assert!(resolver
+            .resolve_flag_for_units("flags/does-not-exist", &units)
+            .is_err());
     }
+
     #[test]
-    fn test_resolve_flags() {
+    fn test_set_attribute_value() {
+        let mut context: Struct =
+            serde_json::from_str(r#"{"user": {"name": "roug"}, "id": 42}"#).unwrap();
+        set_attribute_value(&mut context, "user.id", "a");
+        set_attribute_value(&mut context, "id.nested", "b");
+        let expected: Struct =
+            serde_json::from_str(r#"{"user": {"name": "roug", "id": "a"}, "id": {"nested": "b"}}"#)
+                .unwrap();
+        assert_eq!(context, expected);
+    }
+
+    #[test]
+    fn test_attribute_value_through_lists() {
+        let context: Struct = serde_json::from_str(
+            r#"{"devices": [
+                {"os": "ios", "apps": [{"id": "a"}, {"id": "b"}]},
+                {"os": "android", "apps": [{"id": "c"}]},
+                {"apps": "none"},
+                "not-a-struct"
+            ]}"#,
+        )
+        .unwrap();
+        let expected = |json: &str| serde_json::from_str::<Value>(json).unwrap();
+
+        assert_eq!(
+            *attribute_value(&context, "devices.os"),
+            expected(r#"["ios", "android"]"#)
+        );
+        assert_eq!(
+            *attribute_value(&context, "devices.apps.id"),
+            expected(r#"["a", "b", "c"]"#)
+        );
+        assert_eq!(*attribute_value(&context, "devices.model"), NULL);
+    }
+
+    #[test]
+    fn test_resolve_memo() {
         let state = ResolverState::from_proto(
             EXAMPLE_STATE.to_owned().try_into().unwrap(),
             "confidence-demo-june",
         )
         .unwrap();
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        let flag = resolver.state.flags.get("flags/tutorial-feature").unwrap();
+        let rule_name = "flags/tutorial-feature/rules/tutorial-visitor-override";
+        let matched_rule = |memo: &mut ResolveMemo| {
+            resolver
+                .resolve_flag_with_memo(flag, BTreeMap::new(), memo)
+                .unwrap()
+                .resolved_value
+                .assignment_match
+                .map(|m| m.rule.name.clone())
+        };
 
-        {
-            let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
-            let resolver: AccountResolver<'_, L> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
-                .unwrap();
-
-            let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
-                evaluation_context: Some(Struct::default()),
-                client_secret: SECRET.to_string(),
-                flags: vec!["flags/tutorial-feature".to_string()],
-                apply: false,
-                sdk: Some(Sdk {
-                    sdk: None,
-                    version: "0.1.0".to_string(),
-                }),
-            };
-
-            let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
-            assert_eq!(response.resolved_flags.len(), 1);
-            let flag = response.resolved_flags.get(0).unwrap();
-
-            let decrypted_token = resolver
-                .decrypt_resolve_token(&response.resolve_token)
-                .unwrap();
-            match decrypted_token.resolve_token {
-                Some(flags_resolver::resolve_token::ResolveToken::TokenV1(token)) => {
-                    assert_eq!(token.resolve_id, response.resolve_id);
-                    assert_eq!(token.assignments.len(), response.resolved_flags.len());
+        let mut memo = ResolveMemo::default();
+        assert_eq!(matched_rule(&mut memo).as_deref(), Some(rule_name));
+        let segment = &flag
+            .rules
+            .iter()
+            .find(|rule| rule.name == rule_name)
+            .unwrap()
+            .segment;
+        assert_eq!(memo.segment_match(segment, "tutorial_visitor"), Some(true));
+        assert_eq!(
+            memo.targeting_keys.get("visitor_id"),
+            Some(&Ok(Some("tutorial_visitor".to_string())))
+        );
 
-                    let assignment = token.assignments.get("flags/tutorial-feature").unwrap();
+        // later flags trust the memo rather than evaluating the segment again
+        memo.insert_segment_match(segment, "tutorial_visitor", false);
+        assert_ne!(matched_rule(&mut memo).as_deref(), Some(rule_name));
+    }
 
-                    assert_eq!(assignment.flag, "flags/tutorial-feature");
-                    assert_eq!(
-                        assignment.assignment_id,
-                        "flags/tutorial-feature/variants/exciting-welcome"
-                    );
-                    assert_eq!(
-                        assignment.variant,
-                        "flags/tutorial-feature/variants/exciting-welcome"
-                    );
-                    assert_eq!(
-                        assignment.rule,
-                        "flags/tutorial-feature/rules/tutorial-visitor-override"
-                    );
+    #[test]
+    fn test_metrics() {
+        use crate::test_util::TestHost;
 
-                    assert_eq!(assignment.flag, flag.flag);
-                    assert_eq!(assignment.variant, flag.variant);
-                }
-                _ => panic!("Unexpected resolve token type"),
-            }
+        TestHost::reset();
+        let state = ResolverState::from_proto_with_host::<TestHost>(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        resolver
+            .resolve_flags(&flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
 
-            assert!(resolver.state.flags.contains_key("flags/tutorial-feature"));
-            assert_eq!(true, flag.should_apply);
-        }
+        let metrics = TestHost::metrics();
+        let names: HashSet<&str> = metrics.iter().map(|m| m.name.as_str()).collect();
+        assert!(names.contains(metrics::STATE_LOAD_DURATION));
+        assert!(names.contains(metrics::BITSET_DECOMPRESS_DURATION));
+        let resolve = metrics
+            .iter()
+            .find(|m| m.name == metrics::RESOLVE_DURATION)
+            .unwrap();
+        assert!(resolve.value > 0.0);
+        assert_eq!(
+            resolve.tags,
+            vec![("client".to_string(), resolver.client.client_name.clone())]
+        );
     }
 
     #[test]
-    fn test_resolve_flags_fallthrough() {
+    fn test_authorize_resolve() {
+        use crate::test_util::TestHost;
+
         let state = ResolverState::from_proto(
             EXAMPLE_STATE.to_owned().try_into().unwrap(),
             "confidence-demo-june",
         )
         .unwrap();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        let request = |flags: Vec<String>| flags_resolver::ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            flags,
+            apply: true,
+            ..Default::default()
+        };
 
-        // Single rule
-        {
-            let context_json = r#"{"visitor_id": "57"}"#;
-            let resolver: AccountResolver<'_, L> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
-                .unwrap();
-
-            let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
-                evaluation_context: Some(Struct::default()),
-                client_secret: SECRET.to_string(),
-                flags: vec!["flags/fallthrough-test-1".to_string()],
-                apply: false,
-                sdk: Some(Sdk {
-                    sdk: None,
-                    version: "0.1.0".to_string(),
-                }),
-            };
-
-            let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
-            assert_eq!(response.resolved_flags.len(), 1);
-            let flag = response.resolved_flags.get(0).unwrap();
-
-            let decrypted_token = resolver
-                .decrypt_resolve_token(&response.resolve_token)
-                .unwrap();
-            match decrypted_token.resolve_token {
-                Some(flags_resolver::resolve_token::ResolveToken::TokenV1(token)) => {
-                    assert_eq!(token.resolve_id, response.resolve_id);
-                    assert_eq!(token.assignments.len(), response.resolved_flags.len());
-
-                    let assignment = token.assignments.get("flags/fallthrough-test-1").unwrap();
-                    assert_eq!(assignment.flag, "flags/fallthrough-test-1");
-                    assert_eq!(assignment.targeting_key, "");
-                    assert_eq!(assignment.targeting_key_selector, "");
-                    assert_eq!(assignment.segment, "");
-                    assert_eq!(assignment.variant, "");
-                    assert_eq!(assignment.rule, "");
-                    assert_eq!(ResolveReason::NoSegmentMatch as i32, flag.reason);
-                    assert_eq!(assignment.assignment_id, "");
+        TestHost::reset();
+        TestHost::deny_flag("flags/tutorial-feature");
+        let err = resolver.resolve_flags(&request(vec![])).unwrap_err();
+        assert_eq!(err, "resolve denied: flags/tutorial-feature is not allowed");
+        assert!(TestHost::resolve_logs().is_empty());
+        assert!(TestHost::assigned_flags().is_empty());
 
-                    let expected_fallthrough = flags_resolver::events::FallthroughAssignment {
-                        rule: "flags/fallthrough-test-1/rules/gdbiknjycxvmc6wu7zzz".to_string(),
-                        assignment_id: "control".to_string(),
-                        targeting_key: "57".to_string(),
-                        targeting_key_selector: "visitor_id".to_string(),
-                    };
+        let other_flags: Vec<String> = resolver
+            .flags_to_resolve(&[])
+            .iter()
+            .map(|flag| flag.name.clone())
+            .filter(|name| name != "flags/tutorial-feature")
+            .collect();
+        assert!(!other_flags.is_empty());
+        let response = resolver
+            .resolve_flags(&request(other_flags.clone()))
+            .unwrap();
+        assert_eq!(response.resolved_flags.len(), other_flags.len());
+    }
 
-                    assert_eq!(assignment.fallthrough_assignments.len(), 1);
-                    assert_eq!(assignment.fallthrough_assignments[0], expected_fallthrough);
-                }
-                _ => panic!("Unexpected resolve token type"),
-            }
+    #[test]
+    fn test_resolve_flags_apply_logging() {
+        use crate::test_util::TestHost;
 
-            assert_eq!(true, flag.should_apply);
-        }
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
 
-        // Fallthrough to second rule
+        // Test 1: NO_MATCH case with apply=true should NOT log assignments
         {
-            let context_json = r#"{"visitor_id": "26"}"#;
-            let resolver: AccountResolver<'_, L> = state
+            TestHost::reset();
+            let context_json = r#"{}"#; // NO CONTEXT
+            let resolver: AccountResolver<'_, TestHost> = state
                 .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
                 .unwrap();
 
             let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
                 evaluation_context: Some(Struct::default()),
                 client_secret: SECRET.to_string(),
-                flags: vec!["flags/fallthrough-test-2".to_string()],
-                apply: false,
+                flags: vec!["flags/tutorial-feature".to_string()],
+                apply: true,
                 sdk: Some(Sdk {
                     sdk: None,
                     version: "0.1.0".to_string(),
@@ -1713,61 +5572,24 @@ mod tests {
             };
 
             let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
-            assert_eq!(response.resolved_flags.len(), 1);
             let flag = response.resolved_flags.get(0).unwrap();
+            assert_eq!(false, flag.should_apply);
+            assert_eq!(ResolveReason::NoSegmentMatch as i32, flag.reason);
 
-            let decrypted_token = resolver
-                .decrypt_resolve_token(&response.resolve_token)
-                .unwrap();
-            match decrypted_token.resolve_token {
-                Some(flags_resolver::resolve_token::ResolveToken::TokenV1(token)) => {
-                    assert_eq!(token.resolve_id, response.resolve_id);
-                    assert_eq!(token.assignments.len(), response.resolved_flags.len());
-
-                    let assignment = token.assignments.get("flags/fallthrough-test-2").unwrap();
-                    assert_eq!(assignment.flag, "flags/fallthrough-test-2");
-                    assert_eq!(assignment.targeting_key, "26");
-                    assert_eq!(assignment.targeting_key_selector, "visitor_id");
-                    assert_eq!(assignment.segment, "segments/dvlllobhnpxcojqn6vfa");
-                    assert_eq!(
-                        assignment.variant,
-                        "flags/fallthrough-test-2/variants/enabled"
-                    );
-                    assert_eq!(
-                        assignment.rule,
-                        "flags/fallthrough-test-2/rules/oxl1yqqjj1aqyiuvf9al"
-                    );
-                    assert_eq!(ResolveReason::Match as i32, flag.reason);
-                    assert_eq!(assignment.assignment_id, "");
-
-                    let expected_fallthrough = flags_resolver::events::FallthroughAssignment {
-                        rule: "flags/fallthrough-test-2/rules/wwzea3vq89gwtcufe9ou".to_string(),
-                        assignment_id: "control".to_string(),
-                        targeting_key: "26".to_string(),
-                        targeting_key_selector: "visitor_id".to_string(),
-                    };
-
-                    assert_eq!(assignment.fallthrough_assignments.len(), 1);
-                    assert_eq!(assignment.fallthrough_assignments[0], expected_fallthrough);
-                }
-                _ => panic!("Unexpected resolve token type"),
-            }
-
-            assert_eq!(true, flag.should_apply);
+            // Verify that no assignment was logged
+            TestHost::assert_not_assigned("flags/tutorial-feature");
+            TestHost::assert_resolved(
+                "flags/tutorial-feature",
+                ResolveReason::NoSegmentMatch,
+                None,
+            );
         }
-    }
-
-    #[test]
-    fn test_resolve_flags_no_match() {
-        let state = ResolverState::from_proto(
-            EXAMPLE_STATE.to_owned().try_into().unwrap(),
-            "confidence-demo-june",
-        )
-        .unwrap();
 
+        // Test 2: MATCH case with apply=true SHOULD log assignments
         {
-            let context_json = r#"{}"#; // NO CONTEXT
-            let resolver: AccountResolver<'_, L> = state
+            TestHost::reset();
+            let context_json = r#"{"visitor_id": "tutorial_visitor"}"#; // This should match
+            let resolver: AccountResolver<'_, TestHost> = state
                 .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
                 .unwrap();
 
@@ -1775,7 +5597,7 @@ mod tests {
                 evaluation_context: Some(Struct::default()),
                 client_secret: SECRET.to_string(),
                 flags: vec!["flags/tutorial-feature".to_string()],
-                apply: false,
+                apply: true,
                 sdk: Some(Sdk {
                     sdk: None,
                     version: "0.1.0".to_string(),
@@ -1783,149 +5605,473 @@ mod tests {
             };
 
             let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
-            assert_eq!(response.resolved_flags.len(), 1);
-            assert!(resolver.state.flags.contains_key("flags/tutorial-feature"));
-
             let flag = response.resolved_flags.get(0).unwrap();
-            assert_eq!(false, flag.should_apply);
-            assert_eq!(ResolveReason::NoSegmentMatch as i32, flag.reason);
+            assert_eq!(true, flag.should_apply);
+            assert_eq!(ResolveReason::Match as i32, flag.reason);
+
+            // Verify that assignment was logged
+            assert_eq!(
+                TestHost::assigned_flags(),
+                vec!["flags/tutorial-feature".to_string()],
+                "MATCH flags should be logged when apply=true"
+            );
+            let assigns = TestHost::assign_logs();
+            assert_eq!(assigns[0].resolve_id, response.resolve_id);
+            assert_eq!(
+                assigns[0].assigned_flags[0].skew_adjusted_applied_time,
+                TestHost::current_time()
+            );
         }
     }
 
     #[test]
-    fn test_resolve_flags_apply_logging() {
+    fn test_apply_flags_batch() {
         let state = ResolverState::from_proto(
             EXAMPLE_STATE.to_owned().try_into().unwrap(),
             "confidence-demo-june",
         )
         .unwrap();
+        let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+            .unwrap();
 
-        // Custom logger that tracks what gets logged
-        struct TestLogger {
-            assign_logs: std::sync::Mutex<Vec<String>>,
-        }
+        let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
+            evaluation_context: Some(Struct::default()),
+            client_secret: SECRET.to_string(),
+            flags: vec!["flags/tutorial-feature".to_string()],
+            apply: false,
+            sdk: None,
+        };
+        let response = resolver.resolve_flags(&resolve_flag_req).unwrap();
+
+        let now = L::current_time();
+        let apply_request =
+            |resolve_token: Vec<u8>, flag: &str| flags_resolver::ApplyFlagsRequest {
+                flags: vec![flags_resolver::AppliedFlag {
+                    flag: flag.to_string(),
+                    apply_time: Some(now.clone()),
+                }],
+                client_secret: SECRET.to_string(),
+                resolve_token,
+                send_time: Some(now.clone()),
+                sdk: None,
+            };
 
-        impl Host for TestLogger {
-            fn log_resolve(
-                _resolve_id: &str,
-                _evaluation_context: &Struct,
-                _values: &[ResolvedValue<'_>],
-                _client: &Client,
-                _sdk: &Option<Sdk>,
-            ) {
-                // Do nothing for resolve logs
-            }
+        let outcomes = resolver.apply_flags_batch(&[
+            apply_request(response.resolve_token.clone(), "flags/tutorial-feature"),
+            apply_request(vec![1, 2, 3], "flags/tutorial-feature"),
+            apply_request(response.resolve_token.clone(), "flags/not-in-token"),
+        ]);
 
-            fn log_assign(
-                resolve_id: &str,
-                _evaluation_context: &Struct,
-                assigned_flag: &[FlagToApply],
-                _client: &Client,
-                _sdk: &Option<Sdk>,
-            ) {
-                let mut logs = TestLogger::get_instance()
-                    .assign_logs
-                    .try_lock()
-                    .expect("mutex is locked or poisoned");
-                assigned_flag.iter().for_each(|f| {
-                    let log_entry = format!("{}:{}", resolve_id, f.assigned_flag.flag);
-                    logs.push(log_entry);
-                });
-            }
-        }
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes[0].is_ok());
+        assert!(outcomes[1].is_err());
+        assert!(outcomes[2].is_err());
+    }
 
-        impl TestLogger {
-            fn get_instance() -> &'static TestLogger {
-                static INSTANCE: std::sync::OnceLock<TestLogger> = std::sync::OnceLock::new();
-                INSTANCE.get_or_init(|| TestLogger {
-                    assign_logs: std::sync::Mutex::new(Vec::new()),
-                })
-            }
+    #[test]
+    fn test_apply_flags_decrypt_breaker() {
+        use crate::decrypt_breaker::DecryptBreakerConfig;
+        use crate::test_util::TestHost;
 
-            fn clear_logs() {
-                if let Ok(mut logs) = TestLogger::get_instance().assign_logs.lock() {
-                    logs.clear();
-                }
-            }
+        TestHost::reset();
+        let mut state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let breaker = Arc::new(DecryptBreaker::new(DecryptBreakerConfig {
+            max_failures: 2,
+            accept_applies: true,
+            ..Default::default()
+        }));
+        state.decrypt_breaker = Some(breaker.clone());
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        let now = TestHost::current_time();
+        let mut request = flags_resolver::ApplyFlagsRequest {
+            flags: vec![flags_resolver::AppliedFlag {
+                flag: "flags/tutorial-feature".to_string(),
+                apply_time: Some(now.clone()),
+            }],
+            client_secret: SECRET.to_string(),
+            resolve_token: vec![1, 2, 3],
+            send_time: Some(now),
+            sdk: None,
+        };
 
-            fn get_logs() -> Vec<String> {
-                TestLogger::get_instance()
-                    .assign_logs
-                    .lock()
-                    .unwrap()
-                    .clone()
-            }
-        }
+        assert!(resolver.apply_flags(&request).is_err());
+        // the second failure opens the breaker, which accepts and holds the apply
+        assert!(resolver.apply_flags(&request).is_ok());
+        assert!(breaker.is_open());
+        let held = breaker.take_held_applies();
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].request, request);
+        assert_eq!(held[0].receive_time, now);
+        assert!(TestHost::assign_logs().is_empty());
+
+        // once the key is fixed, the replay is applied as of when it was received
+        request.resolve_token = resolver
+            .resolve_flags(&flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/tutorial-feature".to_string()],
+                ..Default::default()
+            })
+            .unwrap()
+            .resolve_token;
+        TestHost::advance_time(3600);
+        resolver
+            .replay_held_apply(&HeldApply {
+                request,
+                receive_time: now.clone(),
+            })
+            .unwrap();
+        let assigns = TestHost::assign_logs();
+        assert_eq!(assigns[0].assigned_flags[0].skew_adjusted_applied_time, now);
+    }
 
-        // Test 1: NO_MATCH case with apply=true should NOT log assignments
-        {
-            TestLogger::clear_logs();
-            let context_json = r#"{}"#; // NO CONTEXT
-            let resolver: AccountResolver<'_, TestLogger> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
-                .unwrap();
+    #[test]
+    fn test_resolution_time() {
+        use crate::test_util::TestHost;
 
-            let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
-                evaluation_context: Some(Struct::default()),
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let last_tuesday = Timestamp {
+            seconds: 1_699_920_000,
+            nanos: 0,
+        };
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap()
+            .with_resolution_time(last_tuesday.clone());
+
+        TestHost::reset();
+        let response = resolver
+            .resolve_flags(&flags_resolver::ResolveFlagsRequest {
                 client_secret: SECRET.to_string(),
                 flags: vec!["flags/tutorial-feature".to_string()],
                 apply: true,
-                sdk: Some(Sdk {
-                    sdk: None,
-                    version: "0.1.0".to_string(),
-                }),
-            };
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            response.resolved_flags[0].variant,
+            "flags/tutorial-feature/variants/exciting-welcome"
+        );
+        assert_eq!(resolver.now(), last_tuesday);
+        assert_ne!(TestHost::current_time(), last_tuesday);
+        // resolves of the past are not analytics
+        assert!(TestHost::resolve_logs().is_empty());
+        assert!(TestHost::assign_logs().is_empty());
+    }
 
-            let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
-            let flag = response.resolved_flags.get(0).unwrap();
-            assert_eq!(false, flag.should_apply);
-            assert_eq!(ResolveReason::NoSegmentMatch as i32, flag.reason);
+    #[test]
+    fn test_without_logging() {
+        use crate::test_util::TestHost;
 
-            // Verify that no assignment was logged
-            let logs = TestLogger::get_logs();
-            assert_eq!(
-                logs.len(),
-                0,
-                "NO_MATCH flags should not be logged when apply=true"
-            );
-        }
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        let request = flags_resolver::ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            flags: vec!["flags/tutorial-feature".to_string()],
+            apply: true,
+            ..Default::default()
+        };
 
-        // Test 2: MATCH case with apply=true SHOULD log assignments
-        {
-            TestLogger::clear_logs();
-            let context_json = r#"{"visitor_id": "tutorial_visitor"}"#; // This should match
-            let resolver: AccountResolver<'_, TestLogger> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+        TestHost::reset();
+        let sticky_request = ResolveWithStickyRequest {
+            resolve_request: Some(request.clone()),
+            suppress_logging: true,
+            ..Default::default()
+        };
+        let progress = resolver.resolve_begin(sticky_request).unwrap();
+        resolver.resolve_finish(progress).unwrap();
+        assert!(TestHost::resolve_logs().is_empty());
+        assert!(TestHost::assign_logs().is_empty());
+
+        let unlogged = state
+            .get_resolver_with_json_context::<TestHost>(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap()
+            .without_logging();
+        let deferred = flags_resolver::ResolveFlagsRequest {
+            apply: false,
+            ..request.clone()
+        };
+        // the token remembers that the resolve wasn't logged, so any resolver applying it
+        // leaves the apply unlogged too
+        let apply = |resolve_token: Vec<u8>| {
+            let now = TestHost::current_time();
+            resolver
+                .apply_flags(&flags_resolver::ApplyFlagsRequest {
+                    flags: vec![flags_resolver::AppliedFlag {
+                        flag: "flags/tutorial-feature".to_string(),
+                        apply_time: Some(now.clone()),
+                    }],
+                    client_secret: SECRET.to_string(),
+                    resolve_token,
+                    send_time: Some(now),
+                    sdk: None,
+                })
                 .unwrap();
+        };
+        let response = unlogged.resolve_flags(&deferred).unwrap();
+        assert_eq!(
+            response.resolved_flags[0].variant,
+            "flags/tutorial-feature/variants/exciting-welcome"
+        );
+        apply(response.resolve_token);
+        apply(
+            resolver
+                .resolve_flags_without_logging(&deferred)
+                .unwrap()
+                .resolve_token,
+        );
+        assert!(TestHost::resolve_logs().is_empty());
+        assert!(TestHost::assign_logs().is_empty());
 
-            let resolve_flag_req = flags_resolver::ResolveFlagsRequest {
-                evaluation_context: Some(Struct::default()),
+        apply(resolver.resolve_flags(&deferred).unwrap().resolve_token);
+        assert_eq!(TestHost::resolve_logs().len(), 1);
+        assert_eq!(TestHost::assign_logs().len(), 1);
+    }
+
+    #[test]
+    fn test_as_synthetic() {
+        use crate::test_util::TestHost;
+
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap()
+            .as_synthetic();
+
+        TestHost::reset();
+        let response = resolver
+            .resolve_flags(&flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/tutorial-feature".to_string()],
+                apply: true,
+                ..Default::default()
+            })
+            .unwrap();
+        // tagging doesn't change what is resolved
+        assert_eq!(
+            response.resolved_flags[0].variant,
+            "flags/tutorial-feature/variants/exciting-welcome"
+        );
+        let resolves = TestHost::resolve_logs();
+        assert!(flag_logger::is_synthetic(&resolves[0].evaluation_context));
+        let assigns = TestHost::assign_logs();
+        assert!(flag_logger::is_synthetic(&assigns[0].evaluation_context));
+    }
+
+    #[test]
+    fn test_secondary_keys() {
+        use crate::test_util::TestHost;
+
+        TestHost::reset();
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
+        let old_key = EncryptionKey::aes128([1; 16]).with_id(1);
+        let new_key = EncryptionKey::aes256([2; 32]).with_id(2);
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(SECRET, context_json, &old_key)
+            .unwrap();
+        let response = resolver
+            .resolve_flags(&flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/tutorial-feature".to_string()],
+                apply: false,
+                ..Default::default()
+            })
+            .unwrap();
+        let now = TestHost::current_time();
+        let apply = flags_resolver::ApplyFlagsRequest {
+            flags: vec![flags_resolver::AppliedFlag {
+                flag: "flags/tutorial-feature".to_string(),
+                apply_time: Some(now.clone()),
+            }],
+            client_secret: SECRET.to_string(),
+            resolve_token: response.resolve_token,
+            send_time: Some(now),
+            sdk: None,
+        };
+
+        let rotated: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(SECRET, context_json, &new_key)
+            .unwrap();
+        let err = rotated.apply_flags(&apply).unwrap_err();
+        assert!(err.contains("unknown key 1"), "{}", err);
+        let rotated = rotated.with_secondary_keys(vec![old_key]);
+        rotated.apply_flags(&apply).unwrap();
+        TestHost::assert_assigned("flags/tutorial-feature");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_signed_tokens() {
+        use crate::test_util::TestHost;
+
+        TestHost::reset();
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &ENCRYPTION_KEY,
+            )
+            .unwrap()
+            .with_signed_tokens()
+            .unwrap();
+        let response = resolver
+            .resolve_flags(&flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec!["flags/tutorial-feature".to_string()],
+                apply: false,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            resolve_token::sniff(&response.resolve_token),
+            Ok(resolve_token::TokenFormat::Signed)
+        );
+        let now = TestHost::current_time();
+        let mut apply = flags_resolver::ApplyFlagsRequest {
+            flags: vec![flags_resolver::AppliedFlag {
+                flag: "flags/tutorial-feature".to_string(),
+                apply_time: Some(now.clone()),
+            }],
+            client_secret: SECRET.to_string(),
+            resolve_token: response.resolve_token,
+            send_time: Some(now),
+            sdk: None,
+        };
+
+        // a tampered token is rejected without logging the apply
+        let last = apply.resolve_token.len() - 1;
+        apply.resolve_token[last] ^= 1;
+        let err = resolver.apply_flags(&apply).unwrap_err();
+        assert!(err.contains("signature is invalid"), "{}", err);
+        assert!(TestHost::assign_logs().is_empty());
+
+        apply.resolve_token[last] ^= 1;
+        resolver.apply_flags(&apply).unwrap();
+        TestHost::assert_assigned("flags/tutorial-feature");
+
+        // the zero key would leave tokens unsigned
+        let err = state
+            .get_resolver_with_json_context::<TestHost>(SECRET, "{}", &EncryptionKey::ZERO)
+            .unwrap()
+            .with_signed_tokens()
+            .unwrap_err();
+        assert!(err.contains("non-zero"), "{}", err);
+    }
+
+    #[test]
+    fn test_state_fingerprint() {
+        use crate::test_util::TestHost;
+
+        let state = ResolverState::from_bytes(EXAMPLE_STATE, "confidence-demo-june").unwrap();
+        let again = ResolverState::from_bytes(EXAMPLE_STATE, "confidence-demo-june").unwrap();
+        assert_eq!(state.fingerprint.len(), 32);
+        assert_eq!(state.fingerprint, again.fingerprint);
+        // the bytes the host passed are hashed as they are
+        assert_eq!(
+            state.fingerprint,
+            format!("{:032x}", murmur3_x64_128(EXAMPLE_STATE, 0))
+        );
+        let decoded = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        assert!(decoded.fingerprint.is_empty());
+
+        let mut changed_pb: ResolverStatePb = EXAMPLE_STATE.to_owned().try_into().unwrap();
+        changed_pb.flags.pop();
+        let mut changed =
+            ResolverState::from_bytes(&changed_pb.encode_to_vec(), "confidence-demo-june").unwrap();
+        assert_ne!(changed.fingerprint, state.fingerprint);
+
+        TestHost::reset();
+        let context_json = r#"{"visitor_id": "tutorial_visitor"}"#;
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+            .unwrap();
+        let response = resolver
+            .resolve_flags(&flags_resolver::ResolveFlagsRequest {
                 client_secret: SECRET.to_string(),
                 flags: vec!["flags/tutorial-feature".to_string()],
-                apply: true,
-                sdk: Some(Sdk {
-                    sdk: None,
-                    version: "0.1.0".to_string(),
-                }),
-            };
-
-            let response: ResolveFlagsResponse = resolver.resolve_flags(&resolve_flag_req).unwrap();
-            let flag = response.resolved_flags.get(0).unwrap();
-            assert_eq!(true, flag.should_apply);
-            assert_eq!(ResolveReason::Match as i32, flag.reason);
+                apply: false,
+                ..Default::default()
+            })
+            .unwrap();
 
-            // Verify that assignment was logged
-            let logs = TestLogger::get_logs();
-            assert_eq!(
-                logs.len(),
-                1,
-                "MATCH flags should be logged when apply=true"
-            );
-            assert!(
-                logs[0].contains("flags/tutorial-feature"),
-                "Log should contain the flag name"
-            );
-        }
+        // a late apply is attributed to the state that resolved, not the current one
+        changed.fingerprint = "newer".to_string();
+        let resolver: AccountResolver<'_, TestHost> = changed
+            .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+            .unwrap();
+        let now = TestHost::current_time();
+        resolver
+            .apply_flags(&flags_resolver::ApplyFlagsRequest {
+                flags: vec![flags_resolver::AppliedFlag {
+                    flag: "flags/tutorial-feature".to_string(),
+                    apply_time: Some(now.clone()),
+                }],
+                client_secret: SECRET.to_string(),
+                resolve_token: response.resolve_token,
+                send_time: Some(now),
+                sdk: None,
+            })
+            .unwrap();
+        let assigns = TestHost::assign_logs();
+        assert_eq!(
+            assigns[0].assigned_flags[0].state_fingerprint,
+            state.fingerprint
+        );
     }
 
     #[test]
@@ -1955,6 +6101,144 @@ mod tests {
         assert_eq!(assignment_match.targeting_key, "26");
     }
 
+    #[test]
+    fn test_fallthrough_rules_are_bounded() {
+        let mut state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let flag = Arc::make_mut(&mut state.flags)
+            .get_mut("flags/fallthrough-test-2")
+            .unwrap();
+        let fallthrough = "flags/fallthrough-test-2/rules/wwzea3vq89gwtcufe9ou";
+        let position = flag
+            .rules
+            .iter()
+            .position(|rule| rule.name == fallthrough)
+            .unwrap();
+        for i in 1..=3 {
+            let mut copy = flag.rules[position].clone();
+            copy.name = format!("{}-{}", fallthrough, i);
+            flag.rules.insert(position + i, copy);
+        }
+
+        let context_json = r#"{"visitor_id": "26"}"#;
+        let resolve = |state: &ResolverState| {
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+            let flag = &state.flags["flags/fallthrough-test-2"];
+            let result = resolver.resolve_flag(flag, BTreeMap::new()).unwrap();
+            let recorded: Vec<_> = result
+                .resolved_value
+                .fallthrough_rules
+                .iter()
+                .map(|f| f.rule.name.clone())
+                .collect();
+            (
+                result.resolved_value.fallthrough_rules_truncated,
+                recorded,
+                result.warnings,
+            )
+        };
+
+        // every fallthrough is recorded by default
+        let (truncated, recorded, warnings) = resolve(&state);
+        assert!(!truncated);
+        assert!(recorded.starts_with(&[
+            fallthrough.to_string(),
+            format!("{}-1", fallthrough),
+            format!("{}-2", fallthrough),
+            format!("{}-3", fallthrough),
+        ]));
+        assert!(warnings.is_empty());
+
+        state.config.max_fallthrough_rules = 2;
+        let (truncated, recorded, warnings) = resolve(&state);
+        assert_eq!(
+            recorded,
+            vec![fallthrough.to_string(), format!("{}-1", fallthrough)]
+        );
+        assert!(truncated);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].kind(),
+            resolve_warning::Kind::FallthroughRulesTruncated
+        );
+        assert_eq!(warnings[0].rule, format!("{}-2", fallthrough));
+    }
+
+    #[test]
+    fn test_skipped_rules_record_reason() {
+        let mut state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let flag = Arc::make_mut(&mut state.flags)
+            .get_mut("flags/fallthrough-test-2")
+            .unwrap();
+        flag.rules[0].enabled = false;
+
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, r#"{"visitor_id": "26"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let flag = &state.flags["flags/fallthrough-test-2"];
+        let value = resolver
+            .resolve_flag(flag, BTreeMap::new())
+            .unwrap()
+            .resolved_value;
+        assert_eq!(value.reason, ResolveReason::Match);
+        assert!(value.fallthrough_rules.is_empty());
+        assert_eq!(value.skipped_rules.len(), 1);
+        assert_eq!(
+            value.skipped_rules[0].reason,
+            FallthroughReason::RuleDisabled
+        );
+        assert_eq!(value.skipped_rules[0].assignment_id, "");
+        // skipped rules are not exposures, but are logged with their reason
+        let assigned = value.to_assigned_flag(&state);
+        assert!(assigned.fallthrough_assignments.is_empty());
+        assert_eq!(
+            assigned.skipped_rules,
+            vec![flags_resolver::events::SkippedRule {
+                rule: value.skipped_rules[0].rule.name.clone(),
+                reason: flags_resolver::events::skipped_rule::SkipReason::RuleDisabled as i32,
+            }]
+        );
+
+        // rules skipped for lack of a targeting key don't make the flag applicable
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, r#"{}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let flag = &state.flags["flags/fallthrough-test-1"];
+        let value = resolver
+            .resolve_flag(flag, BTreeMap::new())
+            .unwrap()
+            .resolved_value;
+        assert_eq!(value.reason, ResolveReason::NoSegmentMatch);
+        assert!(value.fallthrough_rules.is_empty());
+        assert_eq!(value.skipped_rules.len(), 1);
+        assert_eq!(
+            value.skipped_rules[0].reason,
+            FallthroughReason::MissingTargetingKey
+        );
+        assert!(!value.should_apply);
+
+        // skipped rules share the fallthrough limit
+        state.config.max_fallthrough_rules = 0;
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, r#"{}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let flag = &state.flags["flags/fallthrough-test-1"];
+        let value = resolver
+            .resolve_flag(flag, BTreeMap::new())
+            .unwrap()
+            .resolved_value;
+        assert!(value.skipped_rules.is_empty());
+    }
+
     #[test]
     fn test_targeting_key_fractional_rejected() {
         let state = ResolverState::from_proto(
@@ -2030,6 +6314,29 @@ mod tests {
         assert!(!resolver.segment_match(&segment, "test").unwrap());
     }
 
+    #[test]
+    fn test_segment_match_eq_list_of_structs() {
+        let rule_json = r#"{
+            "attributeName": "devices.os",
+            "eqRule": {
+                "value": { "stringValue": "ios" }
+            }
+        }"#;
+        let (segment, state) = parse_segment(rule_json);
+        let matches = |context_json: &str| {
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+            resolver.segment_match(&segment, "test").unwrap()
+        };
+
+        assert!(matches(
+            r#"{"devices": [{"os": "android"}, {"os": "ios"}]}"#
+        ));
+        assert!(!matches(r#"{"devices": [{"os": "android"}, {"id": 1}]}"#));
+        assert!(!matches(r#"{"devices": []}"#));
+    }
+
     #[test]
     fn test_segment_match_eq_bool_l() {
         let rule_json = r#"{
@@ -2764,12 +7071,176 @@ mod tests {
     }
 
     #[test]
-    fn test_segment_match_range_number_se_ee() {
+    fn test_segment_match_range_number_se_ee() {
+        let rule_json = r#"{
+            "attributeName": "client.score",
+            "rangeRule": {
+                "startExclusive": { "numberValue": 42.1 },
+                "endExclusive": { "numberValue": 43.0 }
+            }
+        }"#;
+        let assert_case = |context_json: &str, expected: bool| {
+            let (segment, state) = parse_segment(rule_json);
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+            assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
+        };
+
+        assert_case(r#"{"client": { "score": 42.0 }, "user_id": "test"}"#, false);
+        assert_case(r#"{"client": { "score": 42.1 }, "user_id": "test"}"#, false);
+        assert_case(r#"{"client": { "score": 42.5 }, "user_id": "test"}"#, true);
+        assert_case(r#"{"client": { "score": 43.0 }, "user_id": "test"}"#, false);
+        assert_case(r#"{"client": { "score": 43.1 }, "user_id": "test"}"#, false);
+    }
+
+    #[test]
+    fn test_segment_match_range_number_l() {
+        let rule_json = r#"{
+            "attributeName": "client.score",
+            "rangeRule": {
+                "startInclusive": { "numberValue": 42.1 },
+                "endInclusive": { "numberValue": 43.0 }
+            }
+        }"#;
+        let context_json = r#"{
+            "user_id": "test",
+            "client": {
+                "score": [40.1, 42.5, 44.1]
+            }
+        }"#;
+        let (segment, state) = parse_segment(rule_json);
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+            .unwrap();
+
+        assert!(resolver.segment_match(&segment, "test").unwrap());
+    }
+
+    #[test]
+    fn test_segment_match_range_timestamp_si_ei() {
+        let rule_json = r#"{
+            "attributeName": "client.buildDate",
+            "rangeRule": {
+                "startInclusive": { "timestampValue": "2022-11-17T15:16:17.118Z" },
+                "endInclusive": { "timestampValue": "2022-11-18T00:00:00Z" }
+            }
+        }"#;
+        let assert_case = |context_json: &str, expected: bool| {
+            let (segment, state) = parse_segment(rule_json);
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+            assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
+        };
+
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:00.000Z" }, "user_id": "test"}"#,
+            false,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:17.118Z" }, "user_id": "test"}"#,
+            true,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:30.000Z" }, "user_id": "test"}"#,
+            true,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-18T00:00:00.000Z" }, "user_id": "test"}"#,
+            true,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-18T15:16:17.118Z" }, "user_id": "test"}"#,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_segment_match_range_timestamp_si_ee() {
+        let rule_json = r#"{
+            "attributeName": "client.buildDate",
+            "rangeRule": {
+                "startInclusive": { "timestampValue": "2022-11-17T15:16:17.118Z" },
+                "endExclusive": { "timestampValue": "2022-11-18T00:00:00Z" }
+            }
+        }"#;
+        let assert_case = |context_json: &str, expected: bool| {
+            let (segment, state) = parse_segment(rule_json);
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+            assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
+        };
+
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:00.000Z" }, "user_id": "test"}"#,
+            false,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:17.118Z" }, "user_id": "test"}"#,
+            true,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:30.000Z" }, "user_id": "test"}"#,
+            true,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-18T00:00:00.000Z" }, "user_id": "test"}"#,
+            false,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-18T15:16:17.118Z" }, "user_id": "test"}"#,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_segment_match_range_timestamp_se_ei() {
+        let rule_json = r#"{
+            "attributeName": "client.buildDate",
+            "rangeRule": {
+                "startExclusive": { "timestampValue": "2022-11-17T15:16:17.118Z" },
+                "endInclusive": { "timestampValue": "2022-11-18T00:00:00Z" }
+            }
+        }"#;
+        let assert_case = |context_json: &str, expected: bool| {
+            let (segment, state) = parse_segment(rule_json);
+            let resolver: AccountResolver<'_, L> = state
+                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .unwrap();
+            assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
+        };
+
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:00.000Z" }, "user_id": "test"}"#,
+            false,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:17.118Z" }, "user_id": "test"}"#,
+            false,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:30.000Z" }, "user_id": "test"}"#,
+            true,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-18T00:00:00.000Z" }, "user_id": "test"}"#,
+            true,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-18T15:16:17.118Z" }, "user_id": "test"}"#,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_segment_match_range_timestamp_se_ee() {
         let rule_json = r#"{
-            "attributeName": "client.score",
+            "attributeName": "client.buildDate",
             "rangeRule": {
-                "startExclusive": { "numberValue": 42.1 },
-                "endExclusive": { "numberValue": 43.0 }
+                "startExclusive": { "timestampValue": "2022-11-17T15:16:17.118Z" },
+                "endExclusive": { "timestampValue": "2022-11-18T00:00:00Z" }
             }
         }"#;
         let assert_case = |context_json: &str, expected: bool| {
@@ -2780,43 +7251,35 @@ mod tests {
             assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
         };
 
-        assert_case(r#"{"client": { "score": 42.0 }, "user_id": "test"}"#, false);
-        assert_case(r#"{"client": { "score": 42.1 }, "user_id": "test"}"#, false);
-        assert_case(r#"{"client": { "score": 42.5 }, "user_id": "test"}"#, true);
-        assert_case(r#"{"client": { "score": 43.0 }, "user_id": "test"}"#, false);
-        assert_case(r#"{"client": { "score": 43.1 }, "user_id": "test"}"#, false);
-    }
-
-    #[test]
-    fn test_segment_match_range_number_l() {
-        let rule_json = r#"{
-            "attributeName": "client.score",
-            "rangeRule": {
-                "startInclusive": { "numberValue": 42.1 },
-                "endInclusive": { "numberValue": 43.0 }
-            }
-        }"#;
-        let context_json = r#"{
-            "user_id": "test",
-            "client": {
-                "score": [40.1, 42.5, 44.1]
-            }
-        }"#;
-        let (segment, state) = parse_segment(rule_json);
-        let resolver: AccountResolver<'_, L> = state
-            .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
-            .unwrap();
-
-        assert!(resolver.segment_match(&segment, "test").unwrap());
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:00.000Z" }, "user_id": "test"}"#,
+            false,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:17.118Z" }, "user_id": "test"}"#,
+            false,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-17T15:16:30.000Z" }, "user_id": "test"}"#,
+            true,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-18T00:00:00.000Z" }, "user_id": "test"}"#,
+            false,
+        );
+        assert_case(
+            r#"{"client": { "buildDate": "2022-11-18T15:16:17.118Z" }, "user_id": "test"}"#,
+            false,
+        );
     }
 
     #[test]
-    fn test_segment_match_range_timestamp_si_ei() {
+    fn test_segment_match_range_version_si_ei() {
         let rule_json = r#"{
-            "attributeName": "client.buildDate",
+            "attributeName": "client.version",
             "rangeRule": {
-                "startInclusive": { "timestampValue": "2022-11-17T15:16:17.118Z" },
-                "endInclusive": { "timestampValue": "2022-11-18T00:00:00Z" }
+                "startInclusive": { "versionValue": { "version": "1.4.0" } },
+                "endInclusive": { "versionValue": { "version": "1.4.5" } }
             }
         }"#;
         let assert_case = |context_json: &str, expected: bool| {
@@ -2828,34 +7291,34 @@ mod tests {
         };
 
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:00.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.3.0" }, "user_id": "test"}"#,
             false,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:17.118Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.0" }, "user_id": "test"}"#,
             true,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:30.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.2" }, "user_id": "test"}"#,
             true,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-18T00:00:00.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.5" }, "user_id": "test"}"#,
             true,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-18T15:16:17.118Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.5.1" }, "user_id": "test"}"#,
             false,
         );
     }
 
     #[test]
-    fn test_segment_match_range_timestamp_si_ee() {
+    fn test_segment_match_range_version_si_ee() {
         let rule_json = r#"{
-            "attributeName": "client.buildDate",
+            "attributeName": "client.version",
             "rangeRule": {
-                "startInclusive": { "timestampValue": "2022-11-17T15:16:17.118Z" },
-                "endExclusive": { "timestampValue": "2022-11-18T00:00:00Z" }
+                "startInclusive": { "versionValue": { "version": "1.4.0" } },
+                "endExclusive": { "versionValue": { "version": "1.4.5" } }
             }
         }"#;
         let assert_case = |context_json: &str, expected: bool| {
@@ -2867,34 +7330,34 @@ mod tests {
         };
 
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:00.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.3.0" }, "user_id": "test"}"#,
             false,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:17.118Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.0" }, "user_id": "test"}"#,
             true,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:30.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.2" }, "user_id": "test"}"#,
             true,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-18T00:00:00.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.5" }, "user_id": "test"}"#,
             false,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-18T15:16:17.118Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.5.1" }, "user_id": "test"}"#,
             false,
         );
     }
 
     #[test]
-    fn test_segment_match_range_timestamp_se_ei() {
+    fn test_segment_match_range_version_se_ei() {
         let rule_json = r#"{
-            "attributeName": "client.buildDate",
+            "attributeName": "client.version",
             "rangeRule": {
-                "startExclusive": { "timestampValue": "2022-11-17T15:16:17.118Z" },
-                "endInclusive": { "timestampValue": "2022-11-18T00:00:00Z" }
+                "startExclusive": { "versionValue": { "version": "1.4.0" } },
+                "endInclusive": { "versionValue": { "version": "1.4.5" } }
             }
         }"#;
         let assert_case = |context_json: &str, expected: bool| {
@@ -2906,34 +7369,34 @@ mod tests {
         };
 
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:00.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.3.0" }, "user_id": "test"}"#,
             false,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:17.118Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.0" }, "user_id": "test"}"#,
             false,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:30.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.2" }, "user_id": "test"}"#,
             true,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-18T00:00:00.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.5" }, "user_id": "test"}"#,
             true,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-18T15:16:17.118Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.5.1" }, "user_id": "test"}"#,
             false,
         );
     }
 
     #[test]
-    fn test_segment_match_range_timestamp_se_ee() {
+    fn test_segment_match_range_version_se_ee() {
         let rule_json = r#"{
-            "attributeName": "client.buildDate",
+            "attributeName": "client.version",
             "rangeRule": {
-                "startExclusive": { "timestampValue": "2022-11-17T15:16:17.118Z" },
-                "endExclusive": { "timestampValue": "2022-11-18T00:00:00Z" }
+                "startExclusive": { "versionValue": { "version": "1.4.0" } },
+                "endExclusive": { "versionValue": { "version": "1.4.5" } }
             }
         }"#;
         let assert_case = |context_json: &str, expected: bool| {
@@ -2945,181 +7408,915 @@ mod tests {
         };
 
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:00.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.3.0" }, "user_id": "test"}"#,
             false,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:17.118Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.0" }, "user_id": "test"}"#,
             false,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-17T15:16:30.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.2" }, "user_id": "test"}"#,
             true,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-18T00:00:00.000Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.4.5" }, "user_id": "test"}"#,
             false,
         );
         assert_case(
-            r#"{"client": { "buildDate": "2022-11-18T15:16:17.118Z" }, "user_id": "test"}"#,
+            r#"{"client": { "version": "1.5.1" }, "user_id": "test"}"#,
             false,
         );
     }
 
+    const STICKY_FLAG: &str = "flags/sticky";
+    const STICKY_RULE: &str = "flags/sticky/rules/r1";
+    const STICKY_VARIANT: &str = "flags/sticky/variants/on";
+
+    /// A state with a single flag whose only rule writes to and, if `read_materialization`
+    /// is non-empty, reads from a materialization.
+    fn sticky_state(write_materialization: &str, read_materialization: &str) -> ResolverState {
+        let segment = Segment {
+            name: "segments/sticky".to_string(),
+            ..Default::default()
+        };
+        let flag = Flag {
+            name: STICKY_FLAG.to_string(),
+            state: flags_admin::flag::State::Active as i32,
+            clients: vec!["clients/test".to_string()],
+            variants: vec![Variant {
+                name: STICKY_VARIANT.to_string(),
+                value: Some(Struct::default()),
+                ..Default::default()
+            }],
+            rules: vec![Rule {
+                name: STICKY_RULE.to_string(),
+                segment: segment.name.clone(),
+                enabled: true,
+                assignment_spec: Some(rule::AssignmentSpec {
+                    bucket_count: 1,
+                    assignments: vec![rule::Assignment {
+                        assignment_id: "on".to_string(),
+                        assignment: Some(rule::assignment::Assignment::Variant(
+                            rule::assignment::VariantAssignment {
+                                variant: STICKY_VARIANT.to_string(),
+                            },
+                        )),
+                        bucket_ranges: vec![rule::BucketRange { lower: 0, upper: 1 }],
+                    }],
+                }),
+                materialization_spec: Some(rule::MaterializationSpec {
+                    write_materialization: write_materialization.to_string(),
+                    read_materialization: read_materialization.to_string(),
+                    mode: None,
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut secrets = HashMap::new();
+        secrets.insert(
+            SECRET.to_string(),
+            Client {
+                account: Account::new("accounts/test"),
+                client_name: "clients/test".to_string(),
+                client_credential_name: "clients/test/clientCredentials/abcdef".to_string(),
+                context_logging: ContextLogging::default(),
+            },
+        );
+
+        ResolverState {
+            secrets,
+            flags: Arc::new(HashMap::from([(flag.name.clone(), flag)])),
+            segments: Arc::new(HashMap::from([(segment.name.clone(), segment)])),
+            bitsets: Default::default(),
+            external_segments: HashSet::new(),
+            encryption_keys: Default::default(),
+            config: ResolverConfig::default(),
+            kill_switches: Vec::new(),
+            fingerprint: String::new(),
+            decrypt_breaker: None,
+            derived: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_truncated_bitset() {
+        use crate::test_util::{LoggedMetric, TestHost};
+
+        let mut state = sticky_state("", "");
+        // 8 buckets, all set
+        Arc::make_mut(&mut state.bitsets).insert(
+            "segments/sticky".to_string(),
+            Bitset::from_bits(bv::BitVec::from_vec(vec![0xff])),
+        );
+        let segment = state.segments["segments/sticky"].clone();
+        let account = &state.secrets[SECRET].account;
+        let unit = (0..100)
+            .map(|i| format!("u{}", i))
+            .find(|unit| {
+                let salted = account.salt_unit(unit, &Bump::new()).unwrap();
+                bucket(hash(&salted), hashing::SEGMENT_BUCKETS).unwrap() >= 8
+            })
+            .unwrap();
+
+        let matches = |state: &ResolverState| {
+            let resolver: AccountResolver<'_, TestHost> = state
+                .get_resolver_with_json_context(SECRET, "{}", &ENCRYPTION_KEY)
+                .unwrap();
+            resolver.segment_match(&segment, &unit)
+        };
+
+        TestHost::reset();
+        assert_eq!(matches(&state), Ok(false));
+        assert_eq!(
+            TestHost::metrics()
+                .into_iter()
+                .find(|m| m.name == metrics::BITSET_OUT_OF_RANGE),
+            Some(LoggedMetric {
+                name: metrics::BITSET_OUT_OF_RANGE.to_string(),
+                value: 1.0,
+                tags: vec![("segment".to_string(), "segments/sticky".to_string())],
+            })
+        );
+        state.config.truncated_bitset = TruncatedBitset::Member;
+        assert_eq!(matches(&state), Ok(true));
+        state.config.truncated_bitset = TruncatedBitset::Fail;
+        assert!(matches(&state).is_err());
+    }
+
+    #[test]
+    fn test_bucket_not_assigned_is_a_metric() {
+        use crate::test_util::{LoggedMetric, TestHost};
+
+        let mut state = sticky_state("", "");
+        let flag = Arc::make_mut(&mut state.flags)
+            .get_mut(STICKY_FLAG)
+            .unwrap();
+        let spec = flag.rules[0].assignment_spec.as_mut().unwrap();
+        spec.assignments[0].bucket_ranges.clear();
+
+        TestHost::reset();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(SECRET, r#"{"targeting_key": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let result = resolver
+            .resolve_flag(&state.flags[STICKY_FLAG], BTreeMap::new())
+            .unwrap();
+        assert_eq!(result.resolved_value.reason, ResolveReason::NoSegmentMatch);
+        assert!(result.warnings.is_empty());
+        assert_eq!(
+            TestHost::metrics()
+                .into_iter()
+                .find(|m| m.name == metrics::BUCKET_NOT_ASSIGNED),
+            Some(LoggedMetric {
+                name: metrics::BUCKET_NOT_ASSIGNED.to_string(),
+                value: 1.0,
+                tags: vec![("rule".to_string(), STICKY_RULE.to_string())],
+            })
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_resolve_flags_sticky_async() {
+        use crate::async_host::AsyncHost;
+        use flags_resolver::resolve_with_sticky_response::{
+            MaterializationUpdate, MissingMaterializationItem,
+        };
+        use std::cell::RefCell;
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        thread_local! {
+            static READS: RefCell<Vec<MissingMaterializationItem>> = const { RefCell::new(vec![]) };
+            static WRITES: RefCell<Vec<MaterializationUpdate>> = const { RefCell::new(vec![]) };
+        }
+
+        struct KvHost;
+
+        impl Host for KvHost {
+            fn log_resolve(
+                _resolve_id: &str,
+                _evaluation_context: &Struct,
+                _values: &[ResolvedValue<'_>],
+                _client: &Client,
+                _sdk: &Option<Sdk>,
+            ) {
+            }
+
+            fn log_assign(
+                _resolve_id: &str,
+                _evaluation_context: &Struct,
+                _assigned_flag: &[FlagToApply],
+                _client: &Client,
+                _sdk: &Option<Sdk>,
+            ) {
+            }
+        }
+
+        impl AsyncHost for KvHost {
+            async fn read_materializations(
+                items: &[MissingMaterializationItem],
+            ) -> Result<BTreeMap<String, MaterializationMap>, String> {
+                READS.with_borrow_mut(|reads| reads.extend_from_slice(items));
+                Ok(BTreeMap::new())
+            }
+
+            async fn write_materializations(
+                updates: &[MaterializationUpdate],
+            ) -> Result<(), String> {
+                WRITES.with_borrow_mut(|writes| writes.extend_from_slice(updates));
+                Ok(())
+            }
+        }
+
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let mut future = std::pin::pin!(future);
+            let mut cx = Context::from_waker(Waker::noop());
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+            }
+        }
+
+        let state = sticky_state("materializations/exp", "materializations/exp");
+        let resolver: AccountResolver<'_, KvHost> = state
+            .get_resolver_with_json_context(SECRET, r#"{"targeting_key": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let request = ResolveWithStickyRequest {
+            resolve_request: Some(flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec![STICKY_FLAG.to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // without the host the materialization is reported missing
+        let response = resolver.resolve_flags_sticky(&request).unwrap();
+        assert!(matches!(
+            response.resolve_result,
+            Some(ResolveResult::MissingMaterializations(_))
+        ));
+
+        let response = block_on(resolver.resolve_flags_sticky_async(&request)).unwrap();
+        let Some(ResolveResult::Success(success)) = response.resolve_result else {
+            panic!("expected a successful resolve");
+        };
+        assert_eq!(
+            success.response.unwrap().resolved_flags[0].variant,
+            STICKY_VARIANT
+        );
+        let reads = READS.with_borrow(|reads| reads.clone());
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].unit, "u1");
+        assert_eq!(reads[0].read_materialization, "materializations/exp");
+        assert_eq!(WRITES.with_borrow(|writes| writes.clone()), success.updates);
+        assert!(!success.updates.is_empty());
+    }
+
+    #[cfg(feature = "sticky")]
+    #[test]
+    fn test_resolve_flags_sticky_with_store() {
+        use crate::materialization::{InMemoryMaterializationStore, MaterializationStore};
+
+        let state = sticky_state("materializations/exp", "materializations/exp");
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, r#"{"targeting_key": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let request = ResolveWithStickyRequest {
+            resolve_request: Some(flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec![STICKY_FLAG.to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let store = InMemoryMaterializationStore::new();
+
+        // the empty store doesn't contain the unit, so the rule assigns and writes it
+        let response = resolver
+            .resolve_flags_sticky_with_store(&request, &store)
+            .unwrap();
+        let Some(ResolveResult::Success(success)) = response.resolve_result else {
+            panic!("expected a successful resolve");
+        };
+        assert!(!success.updates.is_empty());
+        let stored = store.get("u1", "materializations/exp").unwrap().unwrap();
+        assert!(stored.unit_in_info);
+        assert_eq!(stored.rule_to_variant[STICKY_RULE], STICKY_VARIANT);
+
+        // the next resolve reads the stored assignment
+        let response = resolver
+            .resolve_flags_sticky_with_store(&request, &store)
+            .unwrap();
+        let Some(ResolveResult::Success(success)) = response.resolve_result else {
+            panic!("expected a successful resolve");
+        };
+        assert_eq!(
+            success.response.unwrap().resolved_flags[0].variant,
+            STICKY_VARIANT
+        );
+        assert_eq!(store.len(), 1);
+    }
+
+    #[cfg(feature = "sticky")]
+    #[test]
+    fn test_resolve_flags_with_updates() {
+        let state = sticky_state("materializations/exp", "");
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, r#"{"targeting_key": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let request = flags_resolver::ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            flags: vec![STICKY_FLAG.to_string()],
+            ..Default::default()
+        };
+
+        let (response, updates) = resolver.resolve_flags_with_updates(&request).unwrap();
+        assert_eq!(response.resolved_flags[0].variant, STICKY_VARIANT);
+        assert_eq!(
+            updates,
+            vec![MaterializationUpdate {
+                unit: "u1".to_string(),
+                write_materialization: "materializations/exp".to_string(),
+                rule: STICKY_RULE.to_string(),
+                variant: STICKY_VARIANT.to_string(),
+            }]
+        );
+
+        // plain resolve_flags gives the same response without the updates
+        let plain = resolver.resolve_flags(&request).unwrap();
+        assert_eq!(plain.resolved_flags, response.resolved_flags);
+    }
+
     #[test]
-    fn test_segment_match_range_version_si_ei() {
-        let rule_json = r#"{
-            "attributeName": "client.version",
-            "rangeRule": {
-                "startInclusive": { "versionValue": { "version": "1.4.0" } },
-                "endInclusive": { "versionValue": { "version": "1.4.5" } }
-            }
-        }"#;
-        let assert_case = |context_json: &str, expected: bool| {
-            let (segment, state) = parse_segment(rule_json);
-            let resolver: AccountResolver<'_, L> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+    fn test_external_segment_membership() {
+        use crate::test_util::TestHost;
+
+        TestHost::reset();
+        let mut state = sticky_state("", "");
+        state
+            .external_segments
+            .insert("segments/sticky".to_string());
+        TestHost::set_segment_members("segments/sticky", &["u1"]);
+        let request = flags_resolver::ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            flags: vec![STICKY_FLAG.to_string()],
+            ..Default::default()
+        };
+        let resolve = |state: &ResolverState, unit: &str| {
+            let resolver: AccountResolver<'_, TestHost> = state
+                .get_resolver_with_json_context(
+                    SECRET,
+                    &format!(r#"{{"targeting_key": "{}"}}"#, unit),
+                    &ENCRYPTION_KEY,
+                )
                 .unwrap();
-            assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
+            resolver.resolve_flags(&request).unwrap().resolved_flags[0].reason
         };
 
-        assert_case(
-            r#"{"client": { "version": "1.3.0" }, "user_id": "test"}"#,
-            false,
+        assert_eq!(resolve(&state, "u1"), ResolveReason::Match as i32);
+        assert_eq!(resolve(&state, "u2"), ResolveReason::NoSegmentMatch as i32);
+        assert_eq!(
+            TestHost::membership_lookups(),
+            vec![
+                ("segments/sticky".to_string(), "u1".to_string()),
+                ("segments/sticky".to_string(), "u2".to_string()),
+            ]
         );
-        assert_case(
-            r#"{"client": { "version": "1.4.0" }, "user_id": "test"}"#,
-            true,
+
+        // the marker survives an export, and failed lookups don't match
+        let reloaded = ResolverState::from_proto(state.to_proto().unwrap(), "test").unwrap();
+        assert!(reloaded.external_segments.contains("segments/sticky"));
+        TestHost::reset();
+        assert_eq!(
+            resolve(&reloaded, "u1"),
+            ResolveReason::NoSegmentMatch as i32
         );
-        assert_case(
-            r#"{"client": { "version": "1.4.2" }, "user_id": "test"}"#,
-            true,
+        assert!(TestHost::messages()
+            .iter()
+            .any(|m| m.contains("external segment segments/sticky")));
+    }
+
+    #[test]
+    fn test_resolver_config_from_settings() {
+        use flags_resolver::ResolverSettings;
+
+        assert_eq!(
+            ResolverConfig::from(&ResolverSettings::default()),
+            ResolverConfig::default()
         );
-        assert_case(
-            r#"{"client": { "version": "1.4.5" }, "user_id": "test"}"#,
-            true,
+        let config = ResolverConfig::from(&ResolverSettings {
+            max_flags_per_resolve: 10,
+            max_targeting_key_length: -1,
+            default_targeting_key: "user_id".to_string(),
+            disable_resolve_logging: true,
+            disable_assign_logging: false,
+            allow_plaintext_resolve_tokens: true,
+            legacy_resolve_tokens_until: 1_700_000_000,
+            max_fallthrough_rules: 2,
+            flag_targeting_key_selectors: BTreeMap::from([(
+                STICKY_FLAG.to_string(),
+                "user_id".to_string(),
+            )]),
+            credential_context_logging: BTreeMap::from([(
+                "clients/test/clientCredentials/abcdef".to_string(),
+                flags_resolver::resolver_settings::ContextLogging {
+                    mode: 2,
+                    sample_one_in: 4,
+                    redacted_fields: vec!["user.email".to_string()],
+                },
+            )]),
+            truncated_bitset: 2,
+            drift_detection: Some(flags_resolver::resolver_settings::DriftDetection {
+                min_samples: 0,
+                z_threshold: 2.58,
+            }),
+        });
+        assert_eq!(config.max_flags_per_resolve, 10);
+        assert_eq!(config.max_targeting_key_length, MAX_TARGETING_KEY_LENGTH);
+        assert_eq!(config.default_targeting_key, "user_id");
+        assert!(!config.log_resolves);
+        assert!(config.log_assigns);
+        assert!(config.allow_plaintext_resolve_tokens);
+        assert_eq!(config.legacy_resolve_tokens_until, Some(1_700_000_000));
+        assert_eq!(config.max_fallthrough_rules, 2);
+        assert_eq!(config.flag_targeting_key_selectors[STICKY_FLAG], "user_id");
+        assert_eq!(
+            config.credential_context_logging["clients/test/clientCredentials/abcdef"],
+            ContextLogging::Sampled {
+                one_in: 4,
+                redacted_fields: vec!["user.email".to_string()],
+            }
         );
-        assert_case(
-            r#"{"client": { "version": "1.5.1" }, "user_id": "test"}"#,
-            false,
+        assert_eq!(config.truncated_bitset, TruncatedBitset::Fail);
+        assert_eq!(
+            config.drift,
+            Some(drift::DriftConfig {
+                z_threshold: 2.58,
+                ..Default::default()
+            })
+        );
+        let settings = ResolverSettings::from(&config);
+        assert_eq!(ResolverConfig::from(&settings), config);
+
+        // sampling without a rate logs the schema only
+        let unsampled = flags_resolver::resolver_settings::ContextLogging {
+            mode: 2,
+            sample_one_in: 0,
+            redacted_fields: vec![],
+        };
+        assert_eq!(ContextLogging::from(&unsampled), ContextLogging::Schema);
+
+        // the config is loaded with the state, not from it
+        let pb: ResolverStatePb = EXAMPLE_STATE.to_owned().try_into().unwrap();
+        let credential = ResolverState::from_proto(pb.clone(), "confidence-demo-june")
+            .unwrap()
+            .secrets[SECRET]
+            .client_credential_name
+            .clone();
+        let config = ResolverConfig {
+            credential_context_logging: BTreeMap::from([(credential, ContextLogging::None)]),
+            ..config
+        };
+        let (state, _) = ResolverState::from_proto_with_options(
+            pb,
+            "confidence-demo-june",
+            &LoadOptions {
+                config: config.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(state.config, config);
+        assert_eq!(state.secrets[SECRET].context_logging, ContextLogging::None);
+        let exported = state.to_proto().unwrap();
+        assert_eq!(
+            ResolverState::from_proto(exported, "confidence-demo-june")
+                .unwrap()
+                .config,
+            ResolverConfig::default()
         );
     }
 
     #[test]
-    fn test_segment_match_range_version_si_ee() {
-        let rule_json = r#"{
-            "attributeName": "client.version",
-            "rangeRule": {
-                "startInclusive": { "versionValue": { "version": "1.4.0" } },
-                "endExclusive": { "versionValue": { "version": "1.4.5" } }
-            }
-        }"#;
-        let assert_case = |context_json: &str, expected: bool| {
-            let (segment, state) = parse_segment(rule_json);
+    fn test_flag_targeting_key_selector() {
+        let mut state = sticky_state("", "");
+        let resolve = |state: &ResolverState| {
             let resolver: AccountResolver<'_, L> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+                .get_resolver_with_json_context(
+                    SECRET,
+                    r#"{"user_id": "u1", "device_id": "d1"}"#,
+                    &ENCRYPTION_KEY,
+                )
                 .unwrap();
-            assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
+            let request = flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec![STICKY_FLAG.to_string()],
+                apply: false,
+                ..Default::default()
+            };
+            let flag = &resolver.resolve_flags(&request).unwrap().resolved_flags[0];
+            flag.reason
         };
+        // no targeting_key in the context
+        assert_eq!(resolve(&state), ResolveReason::NoSegmentMatch as i32);
 
-        assert_case(
-            r#"{"client": { "version": "1.3.0" }, "user_id": "test"}"#,
-            false,
-        );
-        assert_case(
-            r#"{"client": { "version": "1.4.0" }, "user_id": "test"}"#,
-            true,
-        );
-        assert_case(
-            r#"{"client": { "version": "1.4.2" }, "user_id": "test"}"#,
-            true,
-        );
-        assert_case(
-            r#"{"client": { "version": "1.4.5" }, "user_id": "test"}"#,
-            false,
+        state
+            .flags
+            .get_mut(STICKY_FLAG)
+            .unwrap()
+            .targeting_key_selector = "user_id".to_string();
+        assert_eq!(resolve(&state), ResolveReason::Match as i32);
+        let flag = &state.flags[STICKY_FLAG];
+        assert_eq!(
+            state.targeting_key_selector(flag, &flag.rules[0]),
+            "user_id"
         );
-        assert_case(
-            r#"{"client": { "version": "1.5.1" }, "user_id": "test"}"#,
-            false,
+        // assignments record the selector the rule inherited
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, r#"{"user_id": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let value = resolver
+            .resolve_flag(flag, BTreeMap::new())
+            .unwrap()
+            .resolved_value;
+        assert_eq!(
+            value.to_assigned_flag(&state).targeting_key_selector,
+            "user_id"
         );
+
+        // configured selectors take precedence over the flag
+        state
+            .config
+            .flag_targeting_key_selectors
+            .insert(STICKY_FLAG.to_string(), "missing".to_string());
+        assert_eq!(resolve(&state), ResolveReason::NoSegmentMatch as i32);
+
+        // and a rule's own selector over both
+        Arc::make_mut(&mut state.flags)
+            .get_mut(STICKY_FLAG)
+            .unwrap()
+            .rules[0]
+            .targeting_key_selector = "device_id".to_string();
+        assert_eq!(resolve(&state), ResolveReason::Match as i32);
     }
 
     #[test]
-    fn test_segment_match_range_version_se_ei() {
-        let rule_json = r#"{
-            "attributeName": "client.version",
-            "rangeRule": {
-                "startExclusive": { "versionValue": { "version": "1.4.0" } },
-                "endInclusive": { "versionValue": { "version": "1.4.5" } }
-            }
-        }"#;
-        let assert_case = |context_json: &str, expected: bool| {
-            let (segment, state) = parse_segment(rule_json);
-            let resolver: AccountResolver<'_, L> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+    fn test_resolver_config_is_honored() {
+        use crate::test_util::TestHost;
+
+        TestHost::reset();
+        let mut state = sticky_state("", "");
+        state.config = ResolverConfig {
+            default_targeting_key: "user_id".to_string(),
+            log_resolves: false,
+            ..Default::default()
+        };
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(SECRET, r#"{"user_id": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let request = flags_resolver::ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            flags: vec![STICKY_FLAG.to_string()],
+            apply: true,
+            ..Default::default()
+        };
+
+        let response = resolver.resolve_flags(&request).unwrap();
+        assert_eq!(response.resolved_flags[0].variant, STICKY_VARIANT);
+        assert!(TestHost::resolve_logs().is_empty());
+        TestHost::assert_assigned(STICKY_FLAG);
+
+        state.config.max_flags_per_resolve = 0;
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(SECRET, r#"{"user_id": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        assert!(resolver.resolve_flags(&request).is_err());
+    }
+
+    #[test]
+    fn test_kill_switch() {
+        use crate::test_util::TestHost;
+
+        TestHost::reset();
+        let mut state = sticky_state("", "");
+        let client_name = state.secrets[SECRET].client_name.clone();
+        let request = flags_resolver::ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            flags: vec![STICKY_FLAG.to_string()],
+            apply: false,
+            ..Default::default()
+        };
+        let resolve = |state: &ResolverState| {
+            let resolver: AccountResolver<'_, TestHost> = state
+                .get_resolver_with_json_context(
+                    SECRET,
+                    r#"{"targeting_key": "u1"}"#,
+                    &ENCRYPTION_KEY,
+                )
                 .unwrap();
-            assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
+            resolver.resolve_flags(&request).unwrap().resolved_flags[0].clone()
         };
 
-        assert_case(
-            r#"{"client": { "version": "1.3.0" }, "user_id": "test"}"#,
-            false,
-        );
-        assert_case(
-            r#"{"client": { "version": "1.4.0" }, "user_id": "test"}"#,
-            false,
-        );
-        assert_case(
-            r#"{"client": { "version": "1.4.2" }, "user_id": "test"}"#,
-            true,
-        );
-        assert_case(
-            r#"{"client": { "version": "1.4.5" }, "user_id": "test"}"#,
-            true,
-        );
-        assert_case(
-            r#"{"client": { "version": "1.5.1" }, "user_id": "test"}"#,
-            false,
+        state.kill_switches = vec![
+            KillSwitch {
+                flag: STICKY_FLAG.to_string(),
+                client: Some("clients/other".to_string()),
+                variant: Some(STICKY_VARIANT.to_string()),
+            },
+            KillSwitch {
+                flag: STICKY_FLAG.to_string(),
+                client: None,
+                variant: None,
+            },
+        ];
+        let killed = resolve(&state);
+        assert_eq!(killed.reason, ResolveReason::FlagKilled as i32);
+        assert!(killed.variant.is_empty());
+        assert!(!killed.should_apply);
+
+        // a switch for the resolving client wins over the global one
+        state.kill_switches.push(KillSwitch {
+            flag: STICKY_FLAG.to_string(),
+            client: Some(client_name),
+            variant: Some(STICKY_VARIANT.to_string()),
+        });
+        let killed = resolve(&state);
+        assert_eq!(killed.reason, ResolveReason::FlagKilled as i32);
+        assert_eq!(killed.variant, STICKY_VARIANT);
+        assert!(killed.value.is_some());
+
+        let reloaded =
+            ResolverState::from_proto(state.to_proto().unwrap(), "confidence-demo-june").unwrap();
+        assert_eq!(reloaded.kill_switches.len(), state.kill_switches.len());
+        assert_eq!(resolve(&reloaded), killed);
+
+        state.kill_switches.clear();
+        assert_eq!(resolve(&state).reason, ResolveReason::Match as i32);
+    }
+
+    #[cfg(feature = "sticky")]
+    #[test]
+    fn test_validate_materializations() {
+        use crate::proto::confidence::flags::resolver::v1::MaterializationInfo;
+
+        let state = sticky_state("", "materializations/exp");
+        let resolver: AccountResolver<'_, L> = state
+            .get_resolver_with_json_context(SECRET, r#"{"targeting_key": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let info = |rule: &str, variant: &str| MaterializationInfo {
+            unit_in_info: true,
+            rule_to_variant: BTreeMap::from([(rule.to_string(), variant.to_string())]),
+        };
+        let request = ResolveWithStickyRequest {
+            resolve_request: Some(flags_resolver::ResolveFlagsRequest {
+                client_secret: SECRET.to_string(),
+                flags: vec![STICKY_FLAG.to_string()],
+                ..Default::default()
+            }),
+            materializations_per_unit: BTreeMap::from([
+                (
+                    "u1".to_string(),
+                    MaterializationMap {
+                        info_map: BTreeMap::from([
+                            (
+                                "materializations/exp".to_string(),
+                                info(STICKY_RULE, "flags/sticky/variants/gone"),
+                            ),
+                            (
+                                "materializations/other".to_string(),
+                                info(STICKY_RULE, STICKY_VARIANT),
+                            ),
+                        ]),
+                    },
+                ),
+                ("u2".to_string(), MaterializationMap::default()),
+            ]),
+            fail_fast_on_sticky: false,
+            not_process_sticky: false,
+        };
+
+        assert_eq!(
+            resolver.validate_materializations(&request),
+            vec![
+                MaterializationIssue::UnknownVariant {
+                    unit: "u1".to_string(),
+                    materialization: "materializations/exp".to_string(),
+                    rule: STICKY_RULE.to_string(),
+                    variant: "flags/sticky/variants/gone".to_string(),
+                },
+                MaterializationIssue::UnknownMaterialization {
+                    unit: "u1".to_string(),
+                    materialization: "materializations/other".to_string(),
+                },
+                MaterializationIssue::UnknownUnit {
+                    unit: "u2".to_string()
+                },
+            ]
         );
+
+        // sticky resolves report them without the units
+        use crate::test_util::TestHost;
+        TestHost::reset();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(SECRET, r#"{"targeting_key": "u1"}"#, &ENCRYPTION_KEY)
+            .unwrap();
+        let _ = resolver.resolve_flags_sticky(&request);
+        let kinds: Vec<String> = TestHost::metrics()
+            .into_iter()
+            .filter(|m| m.name == metrics::MATERIALIZATION_ISSUE)
+            .flat_map(|m| m.tags.into_iter().map(|(_, kind)| kind))
+            .collect();
+        assert_eq!(kinds, vec!["variant", "materialization", "unit"]);
+        let warnings: Vec<String> = TestHost::messages()
+            .into_iter()
+            .filter(|m| m.starts_with("WARN: ") && m.contains("materializations"))
+            .collect();
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings
+            .iter()
+            .all(|m| !m.contains("u1") && !m.contains("u2")));
     }
 
     #[test]
-    fn test_segment_match_range_version_se_ee() {
-        let rule_json = r#"{
-            "attributeName": "client.version",
-            "rangeRule": {
-                "startExclusive": { "versionValue": { "version": "1.4.0" } },
-                "endExclusive": { "versionValue": { "version": "1.4.5" } }
-            }
-        }"#;
-        let assert_case = |context_json: &str, expected: bool| {
-            let (segment, state) = parse_segment(rule_json);
-            let resolver: AccountResolver<'_, L> = state
-                .get_resolver_with_json_context(SECRET, context_json, &ENCRYPTION_KEY)
+    fn test_get_resolver_with_host_key() {
+        use crate::test_util::TestHost;
+
+        const CREDENTIAL: &str = "clients/test/clientCredentials/abcdef";
+        TestHost::reset();
+        let state = sticky_state("", "");
+
+        let err: String = state
+            .get_resolver_with_host_key::<TestHost>(SECRET, Struct::default())
+            .err()
+            .unwrap()
+            .into();
+        assert!(err.contains(CREDENTIAL), "{}", err);
+
+        TestHost::set_encryption_key(CREDENTIAL, EncryptionKey::aes256([7; 32]));
+        for _ in 0..2 {
+            let resolver = state
+                .get_resolver_with_host_key::<TestHost>(SECRET, Struct::default())
                 .unwrap();
-            assert_eq!(resolver.segment_match(&segment, "test"), Ok(expected));
+            assert_eq!(resolver.encryption_key, EncryptionKey::aes256([7; 32]));
+        }
+        // the successful lookup is cached, failed ones are retried
+        assert_eq!(TestHost::encryption_key_requests().len(), 2);
+    }
+
+    #[test]
+    fn test_resolve_batch() {
+        use crate::test_util::TestHost;
+
+        TestHost::reset();
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let credential = state.secrets[SECRET].client_credential_name.clone();
+        TestHost::set_encryption_key(&credential, ENCRYPTION_KEY);
+        let request = |visitor: &str| ResolveFlagsRequest {
+            flags: vec!["flags/tutorial-feature".to_string()],
+            evaluation_context: Some(
+                serde_json::from_str(&format!(r#"{{"visitor_id": "{}"}}"#, visitor)).unwrap(),
+            ),
+            ..Default::default()
         };
 
-        assert_case(
-            r#"{"client": { "version": "1.3.0" }, "user_id": "test"}"#,
-            false,
-        );
-        assert_case(
-            r#"{"client": { "version": "1.4.0" }, "user_id": "test"}"#,
-            false,
+        let results = state.resolve_batch::<TestHost>(vec![
+            (SECRET.to_string(), request("tutorial_visitor")),
+            ("unknown".to_string(), request("tutorial_visitor")),
+            (SECRET.to_string(), request("other_visitor")),
+        ]);
+        assert_eq!(results.len(), 3);
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(
+            first.resolved_flags[0].variant,
+            "flags/tutorial-feature/variants/exciting-welcome"
         );
-        assert_case(
-            r#"{"client": { "version": "1.4.2" }, "user_id": "test"}"#,
-            true,
+        assert_eq!(results[1], Err("client secret not found".to_string()));
+        assert!(results[2].is_ok());
+
+        // a failed key lookup is not retried for every request of the client
+        TestHost::reset();
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let results = state.resolve_batch::<TestHost>(vec![
+            (SECRET.to_string(), request("tutorial_visitor")),
+            (SECRET.to_string(), request("other_visitor")),
+        ]);
+        assert!(results.iter().all(Result::is_err));
+        assert_eq!(TestHost::encryption_key_requests(), vec![credential]);
+        // unlike building a resolver for each of them
+        for _ in 0..2 {
+            assert!(state
+                .get_resolver_with_host_key::<TestHost>(SECRET, Default::default())
+                .is_err());
+        }
+        assert_eq!(TestHost::encryption_key_requests().len(), 3);
+    }
+
+    #[test]
+    fn test_resolve_flags_paged() {
+        use crate::test_util::TestHost;
+
+        TestHost::reset();
+        let mut state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let client_name = state.secrets[SECRET].client_name.clone();
+        let mut all: Vec<String> = state
+            .client_flags(&client_name)
+            .map(|flag| flag.name.clone())
+            .collect();
+        all.sort();
+        assert!(all.len() > 1);
+        // fewer flags per resolve than the client has
+        state.config.max_flags_per_resolve = 1;
+        let resolver = state
+            .get_resolver::<TestHost>(
+                SECRET,
+                serde_json::from_str(r#"{"visitor_id": "tutorial_visitor"}"#).unwrap(),
+                &ENCRYPTION_KEY,
+            )
+            .unwrap();
+        let request = ResolveFlagsRequest::default();
+        assert!(resolver.resolve_flags(&request).is_err());
+
+        let mut resolved = Vec::new();
+        let mut page_token = String::new();
+        loop {
+            let page = resolver
+                .resolve_flags_paged(&request, 10, &page_token)
+                .unwrap();
+            assert_eq!(page.response.resolved_flags.len(), 1);
+            assert!(!page.response.resolve_token.is_empty());
+            resolved.extend(page.response.resolved_flags.into_iter().map(|f| f.flag));
+            if page.next_page_token.is_empty() {
+                break;
+            }
+            page_token = page.next_page_token;
+        }
+        assert_eq!(resolved, all);
+
+        let past_the_end = resolver
+            .resolve_flags_paged(&request, 10, all.last().unwrap())
+            .unwrap();
+        assert_eq!(past_the_end, ResolveFlagsPage::default());
+    }
+
+    #[test]
+    fn test_unknown_client_secret() {
+        use crate::test_util::TestHost;
+
+        TestHost::reset();
+        let state = sticky_state("", "");
+        assert_eq!(
+            state
+                .get_resolver::<TestHost>("unknown", Struct::default(), &ENCRYPTION_KEY)
+                .err(),
+            Some(GetResolverError::UnknownClientSecret)
         );
-        assert_case(
-            r#"{"client": { "version": "1.4.5" }, "user_id": "test"}"#,
-            false,
+
+        let request = ResolveFlagsRequest {
+            client_secret: "unknown".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            state.resolve_flags::<TestHost>(&request, &ENCRYPTION_KEY),
+            Err("client secret not found".to_string())
         );
-        assert_case(
-            r#"{"client": { "version": "1.5.1" }, "user_id": "test"}"#,
-            false,
+
+        let fallback = ResolveFlagsResponse {
+            resolve_id: "fallback".to_string(),
+            ..Default::default()
+        };
+        TestHost::set_unknown_secret_response(fallback.clone());
+        assert_eq!(
+            state.resolve_flags::<TestHost>(&request, &ENCRYPTION_KEY),
+            Ok(fallback)
         );
+
+        // known secrets are resolved as usual
+        let request = ResolveFlagsRequest {
+            client_secret: SECRET.to_string(),
+            ..Default::default()
+        };
+        let response = state
+            .resolve_flags::<TestHost>(&request, &ENCRYPTION_KEY)
+            .unwrap();
+        assert_ne!(response.resolve_id, "fallback");
     }
 
     fn parse_segment(rule_json: &str) -> (Segment, ResolverState) {
@@ -3157,14 +8354,22 @@ mod tests {
                 account: Account::new("accounts/test"),
                 client_name: "clients/test".to_string(),
                 client_credential_name: "clients/test/clientCredentials/abcdef".to_string(),
+                context_logging: ContextLogging::default(),
             },
         );
 
         let state = ResolverState {
             secrets,
-            flags: HashMap::new(),
-            segments,
-            bitsets: HashMap::new(),
+            flags: Default::default(),
+            segments: Arc::new(segments),
+            bitsets: Default::default(),
+            external_segments: HashSet::new(),
+            encryption_keys: Default::default(),
+            config: ResolverConfig::default(),
+            kill_switches: Vec::new(),
+            fingerprint: String::new(),
+            decrypt_breaker: None,
+            derived: Default::default(),
         };
 
         (segment, state)
@@ -0,0 +1,308 @@
+//! A manifest of the flags a client credential can resolve, to generate SDK documentation and
+//! typed flag accessors from. It lists every active flag of the credential's client with its
+//! schema, variants, a summary of its rules and the context attributes it reads.
+//!
+//! ```ignore
+//! let json = flag_manifest(&state, client_secret)?.to_json()?;
+//! ```
+
+use crate::proto::confidence::flags::admin::v1::flag::rule::assignment;
+use crate::proto::confidence::flags::admin::v1::flag::Rule;
+use crate::proto::confidence::flags::types::v1::flag_schema::StructFlagSchema;
+use crate::proto::google::Struct;
+use crate::requirements::{context_requirements, ContextRequirement};
+use crate::ResolverState;
+
+/// The flags of a client, sorted by name, see the [module docs](self).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlagManifest {
+    pub client: String,
+    pub flags: Vec<FlagEntry>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlagEntry {
+    pub name: String,
+    pub description: String,
+    pub schema: Option<StructFlagSchema>,
+    pub variants: Vec<VariantEntry>,
+    /// The enabled rules, in the order they are evaluated.
+    pub rules: Vec<RuleSummary>,
+    /// The context attributes the flag reads, sorted by path.
+    pub context: Vec<ContextRequirement>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VariantEntry {
+    pub name: String,
+    pub description: String,
+    pub value: Option<Struct>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleSummary {
+    pub name: String,
+    pub segment: String,
+    pub targeting_key_selector: String,
+    /// The variants the rule assigns, in the order of its assignments.
+    pub variants: Vec<String>,
+    /// Whether some units the rule matches fall through to the next rule.
+    pub falls_through: bool,
+}
+
+/// The manifest of the flags the client of `client_secret` can resolve.
+pub fn flag_manifest(state: &ResolverState, client_secret: &str) -> Result<FlagManifest, String> {
+    let client = state
+        .secrets
+        .get(client_secret)
+        .ok_or("client secret not found".to_string())?;
+    let mut flags: Vec<FlagEntry> = state
+        .client_flags(&client.client_name)
+        .map(|flag| FlagEntry {
+            name: flag.name.clone(),
+            description: flag.description.clone(),
+            schema: flag.schema.clone(),
+            variants: flag
+                .variants
+                .iter()
+                .map(|variant| VariantEntry {
+                    name: variant.name.clone(),
+                    description: variant.description.clone(),
+                    value: variant.value.clone(),
+                })
+                .collect(),
+            rules: flag
+                .rules
+                .iter()
+                .filter(|rule| rule.enabled)
+                .map(|rule| RuleSummary {
+                    name: rule.name.clone(),
+                    segment: rule.segment.clone(),
+                    targeting_key_selector: state.targeting_key_selector(flag, rule).to_string(),
+                    variants: rule_variants(rule),
+                    falls_through: rule_falls_through(rule),
+                })
+                .collect(),
+            context: context_requirements(state, &[flag.name.as_str()]),
+        })
+        .collect();
+    flags.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(FlagManifest {
+        client: client.client_name.clone(),
+        flags,
+    })
+}
+
+fn rule_variants(rule: &Rule) -> Vec<String> {
+    let mut variants: Vec<String> = Vec::new();
+    let assignments = rule
+        .assignment_spec
+        .iter()
+        .flat_map(|spec| &spec.assignments);
+    for assignment in assignments {
+        if let Some(assignment::Assignment::Variant(variant)) = &assignment.assignment {
+            if !variants.contains(&variant.variant) {
+                variants.push(variant.variant.clone());
+            }
+        }
+    }
+    variants
+}
+
+fn rule_falls_through(rule: &Rule) -> bool {
+    let Some(spec) = &rule.assignment_spec else {
+        return true;
+    };
+    let covered = spec
+        .assignments
+        .iter()
+        .flat_map(|assignment| &assignment.bucket_ranges)
+        .fold(0i64, |sum, range| {
+            sum.saturating_add(i64::from(range.upper.saturating_sub(range.lower).max(0)))
+        });
+    covered < i64::from(spec.bucket_count)
+        || spec.assignments.iter().any(|assignment| {
+            matches!(
+                assignment.assignment,
+                Some(assignment::Assignment::Fallthrough(_))
+            )
+        })
+}
+
+#[cfg(feature = "json")]
+impl FlagManifest {
+    /// The manifest as JSON. Schemas and values are written with the protobuf JSON mapping,
+    /// attribute types in lower case.
+    pub fn to_json(&self) -> Result<String, String> {
+        use serde_json::{json, to_value};
+
+        let mut flags = Vec::with_capacity(self.flags.len());
+        for flag in &self.flags {
+            let mut variants = Vec::with_capacity(flag.variants.len());
+            for variant in &flag.variants {
+                variants.push(json!({
+                    "name": variant.name,
+                    "description": variant.description,
+                    "value": to_value(&variant.value).map_err(|e| e.to_string())?,
+                }));
+            }
+            let rules: Vec<_> = flag
+                .rules
+                .iter()
+                .map(|rule| {
+                    json!({
+                        "name": rule.name,
+                        "segment": rule.segment,
+                        "targetingKeySelector": rule.targeting_key_selector,
+                        "variants": rule.variants,
+                        "fallsThrough": rule.falls_through,
+                    })
+                })
+                .collect();
+            let context: Vec<_> = flag
+                .context
+                .iter()
+                .map(|requirement| {
+                    let types: Vec<_> = requirement
+                        .types
+                        .iter()
+                        .map(|t| format!("{:?}", t).to_lowercase())
+                        .collect();
+                    json!({
+                        "attribute": requirement.attribute,
+                        "types": types,
+                        "targetingKey": requirement.targeting_key,
+                    })
+                })
+                .collect();
+            flags.push(json!({
+                "name": flag.name,
+                "description": flag.description,
+                "schema": to_value(&flag.schema).map_err(|e| e.to_string())?,
+                "variants": variants,
+                "rules": rules,
+                "context": context,
+            }));
+        }
+        serde_json::to_string_pretty(&json!({
+            "client": self.client,
+            "flags": flags,
+        }))
+        .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "mkjJruAATQWjeY7foFIWfVAcBWnci2YF";
+
+    fn example_state() -> ResolverState {
+        ResolverState::from_proto(
+            include_bytes!("../test-payloads/resolver_state.pb")
+                .to_vec()
+                .try_into()
+                .unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn lists_the_flags_of_the_client() {
+        let state = example_state();
+        let manifest = flag_manifest(&state, SECRET).unwrap();
+        let client = &state.secrets[SECRET].client_name;
+        assert_eq!(&manifest.client, client);
+        let names: Vec<_> = manifest.flags.iter().map(|f| f.name.as_str()).collect();
+        let mut expected: Vec<_> = state
+            .client_flags(client)
+            .map(|f| f.name.as_str())
+            .collect();
+        expected.sort();
+        assert_eq!(names, expected);
+
+        let tutorial = manifest
+            .flags
+            .iter()
+            .find(|f| f.name == "flags/tutorial-feature")
+            .unwrap();
+        assert!(tutorial.schema.is_some());
+        assert!(tutorial
+            .variants
+            .iter()
+            .any(|v| v.name == "flags/tutorial-feature/variants/exciting-welcome"));
+        assert!(tutorial.rules.iter().any(|rule| rule
+            .variants
+            .contains(&"flags/tutorial-feature/variants/exciting-welcome".to_string())));
+        assert!(tutorial
+            .context
+            .iter()
+            .any(|requirement| requirement.attribute == "visitor_id" && requirement.targeting_key));
+
+        assert!(flag_manifest(&state, "unknown").is_err());
+    }
+
+    #[test]
+    fn summarizes_rules() {
+        use crate::proto::confidence::flags::admin::v1::flag::rule::{
+            Assignment, AssignmentSpec, BucketRange,
+        };
+
+        let variant = |id: &str, variant: &str, lower: i32, upper: i32| Assignment {
+            assignment_id: id.to_string(),
+            assignment: Some(assignment::Assignment::Variant(
+                assignment::VariantAssignment {
+                    variant: variant.to_string(),
+                },
+            )),
+            bucket_ranges: vec![BucketRange { lower, upper }],
+        };
+        let mut rule = Rule {
+            assignment_spec: Some(AssignmentSpec {
+                bucket_count: 10,
+                assignments: vec![
+                    variant("a", "flags/f/variants/on", 0, 5),
+                    variant("b", "flags/f/variants/off", 5, 8),
+                    variant("c", "flags/f/variants/on", 8, 10),
+                ],
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            rule_variants(&rule),
+            vec!["flags/f/variants/on", "flags/f/variants/off"]
+        );
+        assert!(!rule_falls_through(&rule));
+
+        if let Some(spec) = &mut rule.assignment_spec {
+            spec.assignments.pop();
+        }
+        assert!(rule_falls_through(&rule));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn writes_json() {
+        let state = example_state();
+        let json = flag_manifest(&state, SECRET).unwrap().to_json().unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let flags = parsed["flags"].as_array().unwrap();
+        let tutorial = flags
+            .iter()
+            .find(|f| f["name"] == "flags/tutorial-feature")
+            .unwrap();
+        assert!(tutorial["schema"]["schema"].is_object());
+        assert!(tutorial["variants"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|v| v["value"].is_object()));
+        assert!(tutorial["context"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|c| c["targetingKey"] == true && c["types"][0] == "string"));
+    }
+}
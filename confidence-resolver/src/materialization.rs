@@ -0,0 +1,485 @@
+//! Helpers for hosts that keep materializations in their own storage.
+//!
+//! A sticky resolve returns the assignments it made as a flat list of
+//! [`MaterializationUpdate`]s, while the next sticky resolve expects them back as a
+//! `MaterializationMap` per unit. [`updates_to_map`] performs that conversion and
+//! [`merge`] folds newly written materializations into previously stored ones.
+//!
+//! Hosts whose storage can be read synchronously can instead implement
+//! [`MaterializationStore`] and resolve with
+//! [`AccountResolver::resolve_flags_sticky_with_store`], which reads what the request lacks from
+//! the store and writes the updates back with [`write_updates`]. [`InMemoryMaterializationStore`]
+//! keeps them in memory. Stores read asynchronously get the same with
+//! [`crate::async_host`].
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::proto::confidence::flags::resolver::v1::resolve_with_sticky_response::{
+    MaterializationUpdate, MissingMaterializationItem, ResolveResult,
+};
+use crate::proto::confidence::flags::resolver::v1::{
+    MaterializationInfo, MaterializationMap, ResolveWithStickyRequest, ResolveWithStickyResponse,
+};
+use crate::{AccountResolver, Host};
+
+/// How [`merge`] handles a rule that is assigned different variants on both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Keep the variant already stored. Sticky assignments never change once written.
+    #[default]
+    KeepExisting,
+    /// Replace the stored variant with the incoming one.
+    Overwrite,
+    /// Reject the merge, leaving the stored materializations untouched.
+    Fail,
+}
+
+/// Groups updates per unit and materialization, in the format expected by
+/// `ResolveWithStickyRequest::materializations_per_unit`. Later updates for the same rule
+/// replace earlier ones.
+pub fn updates_to_map(updates: &[MaterializationUpdate]) -> BTreeMap<String, MaterializationMap> {
+    let mut per_unit: BTreeMap<String, MaterializationMap> = BTreeMap::new();
+    for update in updates {
+        let info = per_unit
+            .entry(update.unit.clone())
+            .or_default()
+            .info_map
+            .entry(update.write_materialization.clone())
+            .or_default();
+        info.unit_in_info = true;
+        // non-variant assignments (fallthrough, client default) only record the unit
+        if !update.variant.is_empty() {
+            info.rule_to_variant
+                .insert(update.rule.clone(), update.variant.clone());
+        }
+    }
+    per_unit
+}
+
+/// Merges `incoming` into `existing`, resolving rules assigned on both sides according to
+/// `policy`. With [`ConflictPolicy::Fail`] nothing is modified if any rule conflicts.
+pub fn merge(
+    existing: &mut BTreeMap<String, MaterializationMap>,
+    incoming: BTreeMap<String, MaterializationMap>,
+    policy: ConflictPolicy,
+) -> Result<(), String> {
+    if policy == ConflictPolicy::Fail {
+        if let Some(conflict) = find_conflict(existing, &incoming) {
+            return Err(conflict);
+        }
+    }
+    for (unit, map) in incoming {
+        let stored = existing.entry(unit).or_default();
+        for (materialization, info) in map.info_map {
+            let stored_info = stored.info_map.entry(materialization).or_default();
+            merge_info(stored_info, info, policy);
+        }
+    }
+    Ok(())
+}
+
+/// Converts `updates` and merges them into `existing`, see [`updates_to_map`] and [`merge`].
+pub fn apply_updates(
+    existing: &mut BTreeMap<String, MaterializationMap>,
+    updates: &[MaterializationUpdate],
+    policy: ConflictPolicy,
+) -> Result<(), String> {
+    merge(existing, updates_to_map(updates), policy)
+}
+
+/// Materializations by unit, read and written by
+/// [`AccountResolver::resolve_flags_sticky_with_store`].
+pub trait MaterializationStore {
+    /// What `materialization` holds for `unit`, `None` if the unit isn't in it.
+    fn get(&self, unit: &str, materialization: &str)
+        -> Result<Option<MaterializationInfo>, String>;
+
+    /// Replaces what `materialization` holds for `unit`.
+    fn set(
+        &self,
+        unit: &str,
+        materialization: &str,
+        info: MaterializationInfo,
+    ) -> Result<(), String>;
+}
+
+/// A [`MaterializationStore`] that keeps materializations in memory, for tests and for hosts
+/// that serve a unit from a single process.
+#[derive(Debug, Default)]
+pub struct InMemoryMaterializationStore {
+    // (unit, materialization) -> info
+    entries: Mutex<BTreeMap<(String, String), MaterializationInfo>>,
+}
+
+impl InMemoryMaterializationStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of stored unit and materialization pairs.
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|entries| entries.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl MaterializationStore for InMemoryMaterializationStore {
+    fn get(
+        &self,
+        unit: &str,
+        materialization: &str,
+    ) -> Result<Option<MaterializationInfo>, String> {
+        let entries = self
+            .entries
+            .lock()
+            .map_err(|_| "materialization store is poisoned".to_string())?;
+        Ok(entries
+            .get(&(unit.to_string(), materialization.to_string()))
+            .cloned())
+    }
+
+    fn set(
+        &self,
+        unit: &str,
+        materialization: &str,
+        info: MaterializationInfo,
+    ) -> Result<(), String> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| "materialization store is poisoned".to_string())?;
+        entries.insert((unit.to_string(), materialization.to_string()), info);
+        Ok(())
+    }
+}
+
+impl<H: Host> AccountResolver<'_, H> {
+    /// Like [`AccountResolver::resolve_flags_sticky`], reading the materializations missing
+    /// from the request from `store` and writing the updates of the resolve back to it with
+    /// [`write_updates`]. Materializations in the request take precedence over stored ones, so
+    /// callers that provide them keep working as before. With `fail_fast_on_sticky` set the
+    /// response doesn't list what is missing, so the store is not read.
+    pub fn resolve_flags_sticky_with_store<S: MaterializationStore + ?Sized>(
+        &self,
+        request: &ResolveWithStickyRequest,
+        store: &S,
+    ) -> Result<ResolveWithStickyResponse, String> {
+        let mut response = self.resolve_flags_sticky(request)?;
+        if let Some(items) = missing_items(&response) {
+            let mut read: BTreeMap<String, MaterializationMap> = BTreeMap::new();
+            for item in items {
+                if let Some(info) = store.get(&item.unit, &item.read_materialization)? {
+                    read.entry(item.unit.clone())
+                        .or_default()
+                        .info_map
+                        .insert(item.read_materialization.clone(), info);
+                }
+            }
+            response =
+                self.resolve_flags_sticky(&with_read_materializations(request, items, read)?)?;
+        }
+        if let Some(updates) = written_updates(&response) {
+            write_updates(store, updates)?;
+        }
+        Ok(response)
+    }
+}
+
+/// Writes the `updates` of a sticky resolve to `store`, keeping variants already stored: sticky
+/// assignments never change once written. This is what
+/// [`AccountResolver::resolve_flags_sticky_with_store`] does with the updates, and what
+/// stores read asynchronously should do in
+/// [`AsyncHost::write_materializations`](crate::async_host::AsyncHost::write_materializations).
+pub fn write_updates<S: MaterializationStore + ?Sized>(
+    store: &S,
+    updates: &[MaterializationUpdate],
+) -> Result<(), String> {
+    for (unit, map) in updates_to_map(updates) {
+        for (materialization, info) in map.info_map {
+            let mut stored = store.get(&unit, &materialization)?.unwrap_or_default();
+            merge_info(&mut stored, info, ConflictPolicy::KeepExisting);
+            store.set(&unit, &materialization, stored)?;
+        }
+    }
+    Ok(())
+}
+
+/// The materializations `response` lists as missing, if it lists any.
+pub fn missing_items(
+    response: &ResolveWithStickyResponse,
+) -> Option<&[MissingMaterializationItem]> {
+    match &response.resolve_result {
+        Some(ResolveResult::MissingMaterializations(missing)) if !missing.items.is_empty() => {
+            Some(&missing.items)
+        }
+        _ => None,
+    }
+}
+
+/// The materializations a successful `response` writes, if it writes any.
+pub fn written_updates(response: &ResolveWithStickyResponse) -> Option<&[MaterializationUpdate]> {
+    match &response.resolve_result {
+        Some(ResolveResult::Success(success)) if !success.updates.is_empty() => {
+            Some(&success.updates)
+        }
+        _ => None,
+    }
+}
+
+/// `request` with the materializations `read` for the missing `items` added, to resolve again
+/// after a response listed them with [`missing_items`]. Items not in `read` are known not to
+/// contain the unit, and the materializations of `request` take precedence over read ones.
+pub fn with_read_materializations(
+    request: &ResolveWithStickyRequest,
+    items: &[MissingMaterializationItem],
+    mut read: BTreeMap<String, MaterializationMap>,
+) -> Result<ResolveWithStickyRequest, String> {
+    for item in items {
+        read.entry(item.unit.clone())
+            .or_default()
+            .info_map
+            .entry(item.read_materialization.clone())
+            .or_default();
+    }
+    merge(
+        &mut read,
+        request.materializations_per_unit.clone(),
+        ConflictPolicy::Overwrite,
+    )?;
+    let mut request = request.clone();
+    request.materializations_per_unit = read;
+    Ok(request)
+}
+
+fn merge_info(
+    stored: &mut MaterializationInfo,
+    incoming: MaterializationInfo,
+    policy: ConflictPolicy,
+) {
+    stored.unit_in_info |= incoming.unit_in_info;
+    for (rule, variant) in incoming.rule_to_variant {
+        match policy {
+            ConflictPolicy::KeepExisting | ConflictPolicy::Fail => {
+                stored.rule_to_variant.entry(rule).or_insert(variant);
+            }
+            ConflictPolicy::Overwrite => {
+                stored.rule_to_variant.insert(rule, variant);
+            }
+        }
+    }
+}
+
+fn find_conflict(
+    existing: &BTreeMap<String, MaterializationMap>,
+    incoming: &BTreeMap<String, MaterializationMap>,
+) -> Option<String> {
+    for (unit, map) in incoming {
+        let Some(stored) = existing.get(unit) else {
+            continue;
+        };
+        for (materialization, info) in &map.info_map {
+            let Some(stored_info) = stored.info_map.get(materialization) else {
+                continue;
+            };
+            for (rule, variant) in &info.rule_to_variant {
+                match stored_info.rule_to_variant.get(rule) {
+                    Some(stored_variant) if stored_variant != variant => {
+                        return Some(format!(
+                            "conflicting variants for unit {} in {} rule {}: {} and {}",
+                            unit, materialization, rule, stored_variant, variant
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(
+        unit: &str,
+        materialization: &str,
+        rule: &str,
+        variant: &str,
+    ) -> MaterializationUpdate {
+        MaterializationUpdate {
+            unit: unit.to_string(),
+            write_materialization: materialization.to_string(),
+            rule: rule.to_string(),
+            variant: variant.to_string(),
+        }
+    }
+
+    fn rule_to_variant<'a>(
+        map: &'a BTreeMap<String, MaterializationMap>,
+        unit: &str,
+        materialization: &str,
+    ) -> &'a BTreeMap<String, String> {
+        &map[unit].info_map[materialization].rule_to_variant
+    }
+
+    #[test]
+    fn groups_updates_per_unit() {
+        let map = updates_to_map(&[
+            update("u1", "materializations/a", "rules/1", "variants/on"),
+            update("u1", "materializations/a", "rules/2", "variants/off"),
+            update("u1", "materializations/b", "rules/3", ""),
+            update("u2", "materializations/a", "rules/1", "variants/off"),
+        ]);
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(
+            rule_to_variant(&map, "u1", "materializations/a"),
+            &BTreeMap::from([
+                ("rules/1".to_string(), "variants/on".to_string()),
+                ("rules/2".to_string(), "variants/off".to_string()),
+            ])
+        );
+        let fallthrough = &map["u1"].info_map["materializations/b"];
+        assert!(fallthrough.unit_in_info);
+        assert!(fallthrough.rule_to_variant.is_empty());
+        assert_eq!(
+            rule_to_variant(&map, "u2", "materializations/a")["rules/1"],
+            "variants/off"
+        );
+    }
+
+    #[test]
+    fn merge_policies() {
+        let stored =
+            updates_to_map(&[update("u1", "materializations/a", "rules/1", "variants/on")]);
+        let updates = [
+            update("u1", "materializations/a", "rules/1", "variants/off"),
+            update("u1", "materializations/a", "rules/2", "variants/on"),
+        ];
+
+        let mut keep = stored.clone();
+        apply_updates(&mut keep, &updates, ConflictPolicy::KeepExisting).unwrap();
+        assert_eq!(
+            rule_to_variant(&keep, "u1", "materializations/a")["rules/1"],
+            "variants/on"
+        );
+        assert_eq!(
+            rule_to_variant(&keep, "u1", "materializations/a")["rules/2"],
+            "variants/on"
+        );
+
+        let mut overwrite = stored.clone();
+        apply_updates(&mut overwrite, &updates, ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(
+            rule_to_variant(&overwrite, "u1", "materializations/a")["rules/1"],
+            "variants/off"
+        );
+
+        let mut fail = stored.clone();
+        assert!(apply_updates(&mut fail, &updates, ConflictPolicy::Fail).is_err());
+        assert_eq!(fail, stored);
+
+        let mut no_conflict = stored.clone();
+        apply_updates(&mut no_conflict, &updates[1..], ConflictPolicy::Fail).unwrap();
+        assert_eq!(
+            rule_to_variant(&no_conflict, "u1", "materializations/a").len(),
+            2
+        );
+    }
+
+    #[test]
+    fn writes_updates_keeping_stored_variants() {
+        let store = InMemoryMaterializationStore::new();
+        write_updates(
+            &store,
+            &[update("u1", "materializations/a", "rules/1", "variants/on")],
+        )
+        .unwrap();
+        write_updates(
+            &store,
+            &[
+                update("u1", "materializations/a", "rules/1", "variants/off"),
+                update("u1", "materializations/a", "rules/2", "variants/off"),
+            ],
+        )
+        .unwrap();
+        let stored = store.get("u1", "materializations/a").unwrap().unwrap();
+        assert_eq!(
+            stored.rule_to_variant,
+            BTreeMap::from([
+                ("rules/1".to_string(), "variants/on".to_string()),
+                ("rules/2".to_string(), "variants/off".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn adds_read_materializations_under_the_requested_ones() {
+        let item = |unit: &str, materialization: &str| MissingMaterializationItem {
+            unit: unit.to_string(),
+            read_materialization: materialization.to_string(),
+            ..Default::default()
+        };
+        let request = ResolveWithStickyRequest {
+            materializations_per_unit: updates_to_map(&[update(
+                "u1",
+                "materializations/a",
+                "rules/1",
+                "variants/off",
+            )]),
+            ..Default::default()
+        };
+        let read = updates_to_map(&[
+            update("u1", "materializations/a", "rules/1", "variants/on"),
+            update("u1", "materializations/a", "rules/2", "variants/on"),
+        ]);
+        let request = with_read_materializations(
+            &request,
+            &[
+                item("u1", "materializations/a"),
+                item("u2", "materializations/a"),
+            ],
+            read,
+        )
+        .unwrap();
+        let map = &request.materializations_per_unit;
+        assert_eq!(
+            rule_to_variant(map, "u1", "materializations/a")["rules/1"],
+            "variants/off"
+        );
+        assert_eq!(
+            rule_to_variant(map, "u1", "materializations/a")["rules/2"],
+            "variants/on"
+        );
+        // not read, so the unit isn't in it
+        assert_eq!(
+            map["u2"].info_map["materializations/a"],
+            MaterializationInfo::default()
+        );
+    }
+
+    #[test]
+    fn in_memory_store_keeps_materializations_per_unit() {
+        let store = InMemoryMaterializationStore::new();
+        assert!(store.is_empty());
+        assert_eq!(store.get("u1", "materializations/a").unwrap(), None);
+
+        let info = MaterializationInfo {
+            unit_in_info: true,
+            rule_to_variant: BTreeMap::from([("rules/1".to_string(), "variants/on".to_string())]),
+        };
+        store.set("u1", "materializations/a", info.clone()).unwrap();
+        assert_eq!(store.get("u1", "materializations/a").unwrap(), Some(info));
+        assert_eq!(store.get("u2", "materializations/a").unwrap(), None);
+        assert_eq!(store.get("u1", "materializations/b").unwrap(), None);
+        assert_eq!(store.len(), 1);
+    }
+}
@@ -0,0 +1,152 @@
+//! Segments whose membership is kept outside Confidence, e.g. in a Redis set or a CDP
+//! audience.
+//!
+//! Such a segment ships no bitset; the resolver state marks it external instead, see
+//! [`crate::ResolverState::external_segments`]. Its targeting is evaluated as usual, and a unit
+//! that passes it is then looked up through [`Host::segment_membership`]. Lookups are made at
+//! most once per segment and unit within a resolve. Hosts answer them with a
+//! [`SegmentMembershipProvider`], usually wrapped in a [`CachedMembership`] so that repeated
+//! resolves for the same unit don't each go to the external system.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use crate::Host;
+
+/// Answers whether a unit is a member of an external segment.
+pub trait SegmentMembershipProvider {
+    fn is_member(&self, segment: &str, unit: &str) -> Result<bool, String>;
+}
+
+/// A [`SegmentMembershipProvider`] that remembers the answers of `provider` for
+/// `ttl_seconds` of [`Host::current_time`]. Failed lookups aren't remembered. Once
+/// `max_entries` answers are held, expired ones are dropped to make room, and all of them if
+/// none have expired.
+pub struct CachedMembership<P, H> {
+    provider: P,
+    ttl_seconds: i64,
+    max_entries: usize,
+    /// The answer for each segment and unit, with the time it expires.
+    entries: Mutex<HashMap<(String, String), (bool, i64)>>,
+    _phantom: PhantomData<H>,
+}
+
+impl<P: SegmentMembershipProvider, H: Host> CachedMembership<P, H> {
+    pub fn new(provider: P, ttl_seconds: i64, max_entries: usize) -> Self {
+        CachedMembership {
+            provider,
+            ttl_seconds,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The number of answers currently held, including expired ones.
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|entries| entries.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every answer, e.g. after the external system reports a change.
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+}
+
+impl<P: SegmentMembershipProvider, H: Host> SegmentMembershipProvider for CachedMembership<P, H> {
+    fn is_member(&self, segment: &str, unit: &str) -> Result<bool, String> {
+        let now = H::current_time().seconds;
+        let key = (segment.to_string(), unit.to_string());
+        if let Ok(entries) = self.entries.lock() {
+            if let Some(&(member, expires_at)) = entries.get(&key) {
+                if now < expires_at {
+                    return Ok(member);
+                }
+            }
+        }
+        let member = self.provider.is_member(segment, unit)?;
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+                entries.retain(|_, &mut (_, expires_at)| now < expires_at);
+                if entries.len() >= self.max_entries {
+                    entries.clear();
+                }
+            }
+            if self.max_entries > 0 {
+                entries.insert(key, (member, now.saturating_add(self.ttl_seconds)));
+            }
+        }
+        Ok(member)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestHost;
+    use std::cell::Cell;
+    use std::collections::HashSet;
+
+    struct Audience {
+        members: HashSet<&'static str>,
+        lookups: Cell<usize>,
+    }
+
+    impl SegmentMembershipProvider for &Audience {
+        fn is_member(&self, _segment: &str, unit: &str) -> Result<bool, String> {
+            self.lookups.set(self.lookups.get() + 1);
+            if unit.is_empty() {
+                return Err("empty unit".to_string());
+            }
+            Ok(self.members.contains(unit))
+        }
+    }
+
+    fn audience() -> Audience {
+        Audience {
+            members: HashSet::from(["alice"]),
+            lookups: Cell::new(0),
+        }
+    }
+
+    #[test]
+    fn cached_membership_remembers_answers_until_they_expire() {
+        let audience = audience();
+        let cache = CachedMembership::<_, TestHost>::new(&audience, 60, 10);
+        TestHost::reset();
+
+        assert_eq!(cache.is_member("segments/s", "alice"), Ok(true));
+        assert_eq!(cache.is_member("segments/s", "alice"), Ok(true));
+        assert_eq!(cache.is_member("segments/s", "bob"), Ok(false));
+        assert_eq!(audience.lookups.get(), 2);
+
+        TestHost::advance_time(60);
+        assert_eq!(cache.is_member("segments/s", "alice"), Ok(true));
+        assert_eq!(audience.lookups.get(), 3);
+    }
+
+    #[test]
+    fn cached_membership_does_not_remember_failures_and_is_bounded() {
+        let audience = audience();
+        let cache = CachedMembership::<_, TestHost>::new(&audience, 60, 2);
+        TestHost::reset();
+
+        assert!(cache.is_member("segments/s", "").is_err());
+        assert!(cache.is_empty());
+
+        cache.is_member("segments/s", "a").unwrap();
+        cache.is_member("segments/s", "b").unwrap();
+        cache.is_member("segments/s", "c").unwrap();
+        assert!(cache.len() <= 2);
+    }
+}
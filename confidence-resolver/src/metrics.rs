@@ -0,0 +1,55 @@
+//! Names of the metrics reported to [`Host::on_metric`]. Durations are in milliseconds and
+//! only reported by hosts with a [`Host::monotonic_nanos`] clock.
+
+use core::marker::PhantomData;
+
+use crate::Host;
+
+/// Time to load a resolver state with [`crate::ResolverState::from_proto_with_host`].
+pub const STATE_LOAD_DURATION: &str = "confidence.resolver.state_load_duration";
+/// A flag or client secret listed more than once in a loaded state, with value 1, tagged with
+/// the `kind`: `flag` or `secret`. See [`crate::StateDuplicate::report`].
+pub const STATE_DUPLICATE: &str = "confidence.resolver.state_duplicate";
+/// Time of a resolve, tagged with the `client`.
+pub const RESOLVE_DURATION: &str = "confidence.resolver.resolve_duration";
+/// Time to decompress the bitset of a `segment` on its first use.
+pub const BITSET_DECOMPRESS_DURATION: &str = "confidence.resolver.bitset_decompress_duration";
+/// A unit whose bucket is beyond the end of the bitset of a `segment`, with value 1. The
+/// bitset is truncated and [`crate::ResolverConfig::truncated_bitset`] decides the membership.
+pub const BITSET_OUT_OF_RANGE: &str = "confidence.resolver.bitset_out_of_range";
+/// A unit whose bucket no assignment of a `rule` covers, with value 1. The unit falls
+/// through to the next rule.
+pub const BUCKET_NOT_ASSIGNED: &str = "confidence.resolver.bucket_not_assigned";
+/// A `materializations_per_unit` entry of a sticky resolve that no resolved rule reads, with
+/// value 1, tagged with the `kind`: `unit`, `materialization`, `rule` or `variant`. See
+/// [`crate::MaterializationIssue::report`].
+pub const MATERIALIZATION_ISSUE: &str = "confidence.resolver.materialization_issue";
+/// A resolve token the host failed to encrypt, with value 1.
+pub const TOKEN_ENCRYPT_FAILURE: &str = "confidence.resolver.token_encrypt_failure";
+/// A resolve token the host failed to decrypt, with value 1.
+pub const TOKEN_DECRYPT_FAILURE: &str = "confidence.resolver.token_decrypt_failure";
+/// A [`crate::decrypt_breaker::DecryptBreaker`] opening, with value 1.
+pub const DECRYPT_BREAKER_OPEN: &str = "confidence.resolver.decrypt_breaker_open";
+/// An apply held by an open [`crate::decrypt_breaker::DecryptBreaker`], with value 1.
+pub const APPLY_HELD: &str = "confidence.resolver.apply_held";
+
+/// Measures the time until [`MetricTimer::finish`].
+pub(crate) struct MetricTimer<H: Host> {
+    start: Option<u64>,
+    host: PhantomData<H>,
+}
+
+impl<H: Host> MetricTimer<H> {
+    pub(crate) fn start() -> Self {
+        MetricTimer {
+            start: H::monotonic_nanos(),
+            host: PhantomData,
+        }
+    }
+
+    pub(crate) fn finish(self, name: &str, tags: &[(&str, &str)]) {
+        if let (Some(start), Some(end)) = (self.start, H::monotonic_nanos()) {
+            H::on_metric(name, end.saturating_sub(start) as f64 / 1e6, tags);
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Mapping of resolve reasons to the standard OpenFeature resolution reasons and error codes,
+//! so that every provider built on top of the resolver reports them the same way.
+//!
+//! Rust services can evaluate flags the way an OpenFeature provider does with
+//! [`AccountResolver::resolve_boolean_value`] and its siblings. Flag keys are flag names
+//! without the `flags/` prefix, optionally followed by a `.`-separated path into the value,
+//! e.g. `my-flag.button.color`. Evaluations are applied, like those of the other providers.
+
+use crate::proto::confidence::flags::resolver::v1::{
+    ResolveFlagsRequest, ResolveReason as ResolveReasonPb,
+};
+use crate::proto::google::{value::Kind, Struct, Value};
+use crate::{AccountResolver, Host, ResolveReason};
+
+pub const TARGETING_MATCH: &str = "TARGETING_MATCH";
+pub const SPLIT: &str = "SPLIT";
+pub const STATIC: &str = "STATIC";
+pub const DEFAULT: &str = "DEFAULT";
+pub const DISABLED: &str = "DISABLED";
+pub const CACHED: &str = "CACHED";
+pub const ERROR: &str = "ERROR";
+pub const UNKNOWN: &str = "UNKNOWN";
+
+pub const ERROR_CODE_TARGETING_KEY_MISSING: &str = "TARGETING_KEY_MISSING";
+pub const ERROR_CODE_GENERAL: &str = "GENERAL";
+pub const ERROR_CODE_FLAG_NOT_FOUND: &str = "FLAG_NOT_FOUND";
+pub const ERROR_CODE_TYPE_MISMATCH: &str = "TYPE_MISMATCH";
+
+impl ResolveReason {
+    /// The OpenFeature resolution reason for this resolve reason.
+    pub fn openfeature_reason(self) -> &'static str {
+        match self {
+            ResolveReason::Match => TARGETING_MATCH,
+            ResolveReason::NoSegmentMatch | ResolveReason::NoTreatmentMatch => DEFAULT,
+            ResolveReason::FlagArchived | ResolveReason::FlagKilled => DISABLED,
+            ResolveReason::TargetingKeyError | ResolveReason::Error => ERROR,
+        }
+    }
+
+    /// The OpenFeature error code for this resolve reason, if it represents an error.
+    pub fn openfeature_error_code(self) -> Option<&'static str> {
+        match self {
+            ResolveReason::TargetingKeyError => Some(ERROR_CODE_TARGETING_KEY_MISSING),
+            ResolveReason::Error => Some(ERROR_CODE_GENERAL),
+            _ => None,
+        }
+    }
+}
+
+/// The OpenFeature resolution reason for a `reason` field of a resolved flag.
+/// Unrecognized values map to [`UNKNOWN`].
+pub fn reason_from_proto(reason: i32) -> &'static str {
+    match ResolveReasonPb::try_from(reason) {
+        Ok(ResolveReasonPb::Match) => TARGETING_MATCH,
+        Ok(ResolveReasonPb::NoSegmentMatch) | Ok(ResolveReasonPb::NoTreatmentMatch) => DEFAULT,
+        Ok(ResolveReasonPb::FlagArchived) | Ok(ResolveReasonPb::FlagKilled) => DISABLED,
+        Ok(ResolveReasonPb::TargetingKeyError) | Ok(ResolveReasonPb::Error) => ERROR,
+        Ok(ResolveReasonPb::Unspecified) | Err(_) => UNKNOWN,
+    }
+}
+
+/// The OpenFeature error code for a `reason` field of a resolved flag, if it represents an error.
+pub fn error_code_from_proto(reason: i32) -> Option<&'static str> {
+    match ResolveReasonPb::try_from(reason) {
+        Ok(ResolveReasonPb::TargetingKeyError) => Some(ERROR_CODE_TARGETING_KEY_MISSING),
+        Ok(ResolveReasonPb::Error) => Some(ERROR_CODE_GENERAL),
+        _ => None,
+    }
+}
+
+/// The outcome of evaluating a flag, as an OpenFeature provider reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvaluationResult<T> {
+    /// The evaluated value, or the default if the flag assigned no value or failed.
+    pub value: T,
+    pub variant: Option<String>,
+    /// One of the OpenFeature resolution reasons above.
+    pub reason: &'static str,
+    pub error_code: Option<&'static str>,
+    pub error_message: Option<String>,
+}
+
+impl<T> EvaluationResult<T> {
+    fn error(value: T, error_code: &'static str, error_message: String) -> Self {
+        EvaluationResult {
+            value,
+            variant: None,
+            reason: ERROR,
+            error_code: Some(error_code),
+            error_message: Some(error_message),
+        }
+    }
+}
+
+impl<H: Host> AccountResolver<'_, H> {
+    pub fn resolve_boolean_value(&self, flag_key: &str, default: bool) -> EvaluationResult<bool> {
+        self.evaluate(flag_key, default, |kind| match kind {
+            Kind::BoolValue(b) => Some(*b),
+            _ => None,
+        })
+    }
+
+    pub fn resolve_string_value(&self, flag_key: &str, default: &str) -> EvaluationResult<String> {
+        self.evaluate(flag_key, default.to_string(), |kind| match kind {
+            Kind::StringValue(s) => Some(s.clone()),
+            _ => None,
+        })
+    }
+
+    /// Like [`AccountResolver::resolve_float_value`], but only accepts whole numbers that fit in
+    /// an `i64`. Any other value is reported as a type mismatch.
+    pub fn resolve_integer_value(&self, flag_key: &str, default: i64) -> EvaluationResult<i64> {
+        self.evaluate(flag_key, default, |kind| match kind {
+            Kind::NumberValue(n) => integer(*n),
+            _ => None,
+        })
+    }
+
+    pub fn resolve_float_value(&self, flag_key: &str, default: f64) -> EvaluationResult<f64> {
+        self.evaluate(flag_key, default, |kind| match kind {
+            Kind::NumberValue(n) => Some(*n),
+            _ => None,
+        })
+    }
+
+    /// The whole value of the flag if the key has no path.
+    pub fn resolve_structure_value(
+        &self,
+        flag_key: &str,
+        default: Struct,
+    ) -> EvaluationResult<Struct> {
+        self.evaluate(flag_key, default, |kind| match kind {
+            Kind::StructValue(s) => Some(s.clone()),
+            _ => None,
+        })
+    }
+
+    fn evaluate<T>(
+        &self,
+        flag_key: &str,
+        default: T,
+        convert: impl Fn(&Kind) -> Option<T>,
+    ) -> EvaluationResult<T> {
+        let (flag, path) = match flag_key.split_once('.') {
+            Some((flag, path)) => (flag, Some(path)),
+            None => (flag_key, None),
+        };
+        let flag_name = format!("flags/{}", flag);
+        let request = ResolveFlagsRequest {
+            flags: vec![flag_name.clone()],
+            apply: true,
+            ..Default::default()
+        };
+        let response = match self.resolve_flags(&request) {
+            Ok(response) => response,
+            Err(e) => return EvaluationResult::error(default, ERROR_CODE_GENERAL, e),
+        };
+        let Some(resolved) = response
+            .resolved_flags
+            .into_iter()
+            .find(|resolved| resolved.flag == flag_name)
+        else {
+            return EvaluationResult::error(
+                default,
+                ERROR_CODE_FLAG_NOT_FOUND,
+                format!("flag '{}' not found", flag),
+            );
+        };
+        let reason = reason_from_proto(resolved.reason);
+        if let Some(error_code) = error_code_from_proto(resolved.reason) {
+            return EvaluationResult {
+                value: default,
+                variant: None,
+                reason,
+                error_code: Some(error_code),
+                error_message: None,
+            };
+        }
+        if resolved.variant.is_empty() {
+            return EvaluationResult {
+                value: default,
+                variant: None,
+                reason,
+                error_code: None,
+                error_message: None,
+            };
+        }
+
+        let root = Value {
+            kind: Some(Kind::StructValue(resolved.value.unwrap_or_default())),
+        };
+        let found =
+            path.into_iter()
+                .flat_map(|path| path.split('.'))
+                .try_fold(&root, |value, field| match &value.kind {
+                    Some(Kind::StructValue(s)) => s.fields.get(field),
+                    _ => None,
+                });
+        let Some(value) = found else {
+            return EvaluationResult::error(
+                default,
+                ERROR_CODE_FLAG_NOT_FOUND,
+                format!("path '{}' not found in flag '{}'", path.unwrap_or(""), flag),
+            );
+        };
+        let value = match &value.kind {
+            // a field without a value in the variant leaves the default
+            None | Some(Kind::NullValue(_)) => default,
+            Some(kind) => match convert(kind) {
+                Some(value) => value,
+                None => {
+                    return EvaluationResult::error(
+                        default,
+                        ERROR_CODE_TYPE_MISMATCH,
+                        format!("value of '{}' has another type", flag_key),
+                    )
+                }
+            },
+        };
+        EvaluationResult {
+            value,
+            variant: Some(resolved.variant),
+            reason,
+            error_code: None,
+            error_message: None,
+        }
+    }
+}
+
+/// `n` as an `i64` if it is one exactly.
+fn integer(n: f64) -> Option<i64> {
+    // i64::MAX as f64 rounds up to 2^63, which is out of range
+    let in_range = (i64::MIN as f64..i64::MAX as f64).contains(&n);
+    (in_range && n.fract() == 0.0).then_some(n as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestHost;
+    use crate::{EncryptionKey, ResolverState};
+
+    const EXAMPLE_STATE: &[u8] = include_bytes!("../test-payloads/resolver_state.pb");
+    const SECRET: &str = "mkjJruAATQWjeY7foFIWfVAcBWnci2YF";
+
+    #[test]
+    fn evaluates_flag_values() {
+        TestHost::reset();
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let resolver: AccountResolver<'_, TestHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &EncryptionKey::ZERO,
+            )
+            .unwrap();
+
+        let title = resolver.resolve_string_value("tutorial-feature.title", "default");
+        assert_eq!(title.value, "Welcome to Confidence!");
+        assert_eq!(
+            title.variant.as_deref(),
+            Some("flags/tutorial-feature/variants/exciting-welcome")
+        );
+        assert_eq!(title.reason, TARGETING_MATCH);
+        assert_eq!(title.error_code, None);
+
+        let value = resolver.resolve_structure_value("tutorial-feature", Struct::default());
+        assert!(value.value.fields.contains_key("title"));
+
+        let mismatch = resolver.resolve_boolean_value("tutorial-feature.title", true);
+        assert!(mismatch.value);
+        assert_eq!(mismatch.reason, ERROR);
+        assert_eq!(mismatch.error_code, Some(ERROR_CODE_TYPE_MISMATCH));
+
+        let missing_path = resolver.resolve_string_value("tutorial-feature.nope", "default");
+        assert_eq!(missing_path.value, "default");
+        assert_eq!(missing_path.error_code, Some(ERROR_CODE_FLAG_NOT_FOUND));
+
+        let missing_flag = resolver.resolve_float_value("no-such-flag", 1.5);
+        assert_eq!(missing_flag.value, 1.5);
+        assert_eq!(missing_flag.error_code, Some(ERROR_CODE_FLAG_NOT_FOUND));
+    }
+
+    #[test]
+    fn integers_are_whole_numbers_in_range() {
+        assert_eq!(integer(42.0), Some(42));
+        assert_eq!(integer(-3.0), Some(-3));
+        assert_eq!(integer(i64::MIN as f64), Some(i64::MIN));
+        assert_eq!(integer(1.5), None);
+        assert_eq!(integer(9.3e18), None);
+        assert_eq!(integer(i64::MAX as f64), None);
+        assert_eq!(integer(f64::NAN), None);
+        assert_eq!(integer(f64::INFINITY), None);
+    }
+
+    #[test]
+    fn enum_and_proto_mappings_agree() {
+        for reason in [
+            ResolveReason::Match,
+            ResolveReason::NoSegmentMatch,
+            ResolveReason::NoTreatmentMatch,
+            ResolveReason::FlagArchived,
+            ResolveReason::TargetingKeyError,
+            ResolveReason::Error,
+            ResolveReason::FlagKilled,
+        ] {
+            assert_eq!(
+                reason.openfeature_reason(),
+                reason_from_proto(reason as i32)
+            );
+            assert_eq!(
+                reason.openfeature_error_code(),
+                error_code_from_proto(reason as i32)
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_reasons() {
+        assert_eq!(reason_from_proto(0), UNKNOWN);
+        assert_eq!(reason_from_proto(42), UNKNOWN);
+        assert_eq!(error_code_from_proto(42), None);
+        assert_eq!(
+            error_code_from_proto(ResolveReasonPb::Error as i32),
+            Some(ERROR_CODE_GENERAL)
+        );
+    }
+}
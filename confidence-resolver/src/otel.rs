@@ -0,0 +1,183 @@
+//! Translation of resolver counters into OpenTelemetry instruments.
+//!
+//! The resolver does not own a meter provider; hosts create [`OtelMetrics`] from their own
+//! [`Meter`] and feed it the data they already have: each log checkpoint, errors returned from
+//! resolve/apply calls, and the time the current resolver state was fetched.
+
+use opentelemetry::metrics::{Counter, Gauge, Meter};
+use opentelemetry::KeyValue;
+
+use crate::proto::confidence::flags::resolver::v1::WriteFlagLogsRequest;
+use crate::proto::google::Timestamp;
+
+pub struct OtelMetrics {
+    resolves: Counter<u64>,
+    applies: Counter<u64>,
+    errors: Counter<u64>,
+    state_age: Gauge<f64>,
+}
+
+impl OtelMetrics {
+    pub fn new(meter: &Meter) -> Self {
+        OtelMetrics {
+            resolves: meter
+                .u64_counter("confidence.resolver.resolves")
+                .with_description("Number of flag resolves, by flag and variant")
+                .build(),
+            applies: meter
+                .u64_counter("confidence.resolver.applies")
+                .with_description("Number of applied flags, by flag")
+                .build(),
+            errors: meter
+                .u64_counter("confidence.resolver.errors")
+                .with_description("Number of failed resolver operations, by operation")
+                .build(),
+            state_age: meter
+                .f64_gauge("confidence.resolver.state_age")
+                .with_description("Age of the resolver state in use")
+                .with_unit("s")
+                .build(),
+        }
+    }
+
+    /// Records the resolve and apply counts contained in a log checkpoint. Call this with every
+    /// request obtained from the resolve/assign loggers before sending it on.
+    pub fn record_checkpoint(&self, checkpoint: &WriteFlagLogsRequest) {
+        for flag_info in &checkpoint.flag_resolve_info {
+            for variant_info in &flag_info.variant_resolve_info {
+                self.resolves.add(
+                    u64::try_from(variant_info.count).unwrap_or_default(),
+                    &[
+                        KeyValue::new("flag", flag_info.flag.clone()),
+                        KeyValue::new("variant", variant_info.variant.clone()),
+                    ],
+                );
+            }
+        }
+        for assigned in &checkpoint.flag_assigned {
+            for applied in &assigned.flags {
+                self.applies
+                    .add(1, &[KeyValue::new("flag", applied.flag.clone())]);
+            }
+        }
+    }
+
+    /// Records a failed resolver operation, e.g. `"resolve"` or `"apply"`.
+    pub fn record_error(&self, operation: &'static str) {
+        self.errors.add(1, &[KeyValue::new("operation", operation)]);
+    }
+
+    /// Records how long ago the resolver state in use was fetched.
+    pub fn record_state_age(&self, fetched_at: &Timestamp, now: &Timestamp) {
+        let seconds = now.seconds.saturating_sub(fetched_at.seconds) as f64;
+        let nanos = f64::from(now.nanos.saturating_sub(fetched_at.nanos)) / 1e9;
+        self.state_age.record((seconds + nanos).max(0.0), &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::admin::v1::flag_resolve_info::VariantResolveInfo;
+    use crate::proto::confidence::flags::admin::v1::FlagResolveInfo;
+    use crate::proto::confidence::flags::resolver::v1::events::{flag_assigned, FlagAssigned};
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, Metric, MetricData};
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+    use std::collections::BTreeSet;
+
+    fn sum_points(metrics: &[&Metric], name: &str) -> Vec<(u64, Vec<KeyValue>)> {
+        let metric = metrics.iter().find(|m| m.name() == name).unwrap();
+        let AggregatedMetrics::U64(MetricData::Sum(sum)) = metric.data() else {
+            panic!("{} is not a u64 sum", name);
+        };
+        sum.data_points()
+            .map(|point| (point.value(), point.attributes().cloned().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn records_instruments() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        let metrics = OtelMetrics::new(&provider.meter("confidence-test"));
+        metrics.record_checkpoint(&WriteFlagLogsRequest {
+            flag_resolve_info: vec![FlagResolveInfo {
+                flag: "flags/test".to_string(),
+                variant_resolve_info: vec![VariantResolveInfo {
+                    variant: "flags/test/variants/on".to_string(),
+                    count: 3,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            flag_assigned: vec![FlagAssigned {
+                flags: vec![flag_assigned::AppliedFlag {
+                    flag: "flags/test".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        metrics.record_error("resolve");
+        metrics.record_state_age(
+            &Timestamp {
+                seconds: 10,
+                nanos: 0,
+            },
+            &Timestamp {
+                seconds: 70,
+                nanos: 500,
+            },
+        );
+        provider.force_flush().unwrap();
+
+        let exported = exporter.get_finished_metrics().unwrap();
+        let instruments: Vec<&Metric> = exported
+            .iter()
+            .flat_map(|resource| resource.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .collect();
+        let names: BTreeSet<&str> = instruments.iter().map(|m| m.name()).collect();
+        assert_eq!(
+            names,
+            BTreeSet::from([
+                "confidence.resolver.applies",
+                "confidence.resolver.errors",
+                "confidence.resolver.resolves",
+                "confidence.resolver.state_age",
+            ])
+        );
+        assert_eq!(
+            sum_points(&instruments, "confidence.resolver.resolves"),
+            vec![(
+                3,
+                vec![
+                    KeyValue::new("flag", "flags/test"),
+                    KeyValue::new("variant", "flags/test/variants/on"),
+                ]
+            )]
+        );
+        assert_eq!(
+            sum_points(&instruments, "confidence.resolver.applies"),
+            vec![(1, vec![KeyValue::new("flag", "flags/test")])]
+        );
+        assert_eq!(
+            sum_points(&instruments, "confidence.resolver.errors"),
+            vec![(1, vec![KeyValue::new("operation", "resolve")])]
+        );
+        let state_age = instruments
+            .iter()
+            .find(|m| m.name() == "confidence.resolver.state_age")
+            .unwrap();
+        let AggregatedMetrics::F64(MetricData::Gauge(gauge)) = state_age.data() else {
+            panic!("state_age is not a f64 gauge");
+        };
+        let ages: Vec<f64> = gauge.data_points().map(|point| point.value()).collect();
+        assert_eq!(ages.len(), 1);
+        assert!((ages[0] - 60.0).abs() < 1e-3, "{}", ages[0]);
+    }
+}
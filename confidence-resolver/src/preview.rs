@@ -0,0 +1,372 @@
+//! Local preview of flag changes: resolve a proposed version of a flag against sample
+//! evaluation contexts and compare the outcome with the flag as it is in the state, or
+//! compare the assignments of two flags over the same contexts.
+
+use std::collections::BTreeMap;
+
+use crate::drift::chi_squared_critical_value;
+use crate::proto::confidence::flags::admin::v1::Flag;
+use crate::proto::google::Struct;
+use crate::{
+    openfeature, AccountResolver, Client, EncryptionKey, EvaluationContext, Host, ResolverState,
+};
+
+/// Outcome counts before and after a proposed flag change.
+///
+/// Outcomes are keyed by variant name, or by the OpenFeature reason when no variant was
+/// assigned (e.g. `DEFAULT` when no rule matched, `ERROR` when the flag couldn't be resolved).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhatIfReport {
+    pub samples: u64,
+    /// Empty if the flag doesn't exist in the state yet.
+    pub before: BTreeMap<String, u64>,
+    pub after: BTreeMap<String, u64>,
+    /// Number of contexts whose outcome differs between before and after.
+    pub changed: u64,
+}
+
+/// Resolves `proposed` and its current version in `state` for each of `contexts`.
+///
+/// Segments referenced by `proposed` must exist in the state. Materializations are not
+/// available during a preview, so rules reading one resolve as errors.
+pub fn what_if<H: Host>(
+    state: &ResolverState,
+    client_secret: &str,
+    proposed: &Flag,
+    contexts: &[Struct],
+) -> Result<WhatIfReport, String> {
+    let client = get_client(state, client_secret)?;
+    let current = state.flags.get(&proposed.name);
+
+    let mut report = WhatIfReport::default();
+    for context in contexts {
+        let resolver: AccountResolver<'_, H> = resolver(state, client, context);
+        let after = outcome(&resolver, proposed);
+        if let Some(current) = current {
+            let before = outcome(&resolver, current);
+            if before != after {
+                report.changed = report.changed.saturating_add(1);
+            }
+            increment(&mut report.before, before);
+        } else {
+            report.changed = report.changed.saturating_add(1);
+        }
+        increment(&mut report.after, after);
+        report.samples = report.samples.saturating_add(1);
+    }
+    Ok(report)
+}
+
+/// Joint assignment counts of two flags over the same set of contexts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JointDistribution {
+    pub samples: u64,
+    /// Counts per pair of outcomes (first flag, second flag), keyed as in [`WhatIfReport`].
+    pub counts: BTreeMap<(String, String), u64>,
+    /// Pearson's chi-squared statistic for independence of the two assignments.
+    pub chi_squared: f64,
+    pub degrees_of_freedom: usize,
+    /// Whether `chi_squared` exceeds the critical value at a significance level of about
+    /// 0.001, i.e. the assignments are unlikely to be independent.
+    pub dependent: bool,
+}
+
+impl JointDistribution {
+    /// Outcome counts of the first flag.
+    pub fn first(&self) -> BTreeMap<String, u64> {
+        marginal(self.counts.iter().map(|((a, _), count)| (a, *count)))
+    }
+
+    /// Outcome counts of the second flag.
+    pub fn second(&self) -> BTreeMap<String, u64> {
+        marginal(self.counts.iter().map(|((_, b), count)| (b, *count)))
+    }
+}
+
+/// Resolves the flags `first` and `second` from the state for each of `contexts` and reports
+/// how their assignments co-occur. Flags of independent experiments are expected to come out
+/// independent; flags deliberately aligned (same salt and bucket ranges) fully dependent.
+pub fn joint_distribution<H: Host>(
+    state: &ResolverState,
+    client_secret: &str,
+    first: &str,
+    second: &str,
+    contexts: &[Struct],
+) -> Result<JointDistribution, String> {
+    let client = get_client(state, client_secret)?;
+    let first_flag = state
+        .flags
+        .get(first)
+        .ok_or_else(|| format!("flag {} not found", first))?;
+    let second_flag = state
+        .flags
+        .get(second)
+        .ok_or_else(|| format!("flag {} not found", second))?;
+
+    let mut joint = JointDistribution::default();
+    for context in contexts {
+        let resolver: AccountResolver<'_, H> = resolver(state, client, context);
+        let pair = (
+            outcome(&resolver, first_flag),
+            outcome(&resolver, second_flag),
+        );
+        let count = joint.counts.entry(pair).or_insert(0);
+        *count = count.saturating_add(1);
+        joint.samples = joint.samples.saturating_add(1);
+    }
+
+    let first_counts = joint.first();
+    let second_counts = joint.second();
+    let samples = joint.samples as f64;
+    joint.chi_squared = first_counts
+        .iter()
+        .flat_map(|(a, a_count)| {
+            second_counts.iter().map(move |(b, b_count)| {
+                (
+                    a.clone(),
+                    b.clone(),
+                    *a_count as f64 * *b_count as f64 / samples,
+                )
+            })
+        })
+        .map(|(a, b, expected)| {
+            let observed = joint.counts.get(&(a, b)).copied().unwrap_or(0) as f64;
+            (observed - expected) * (observed - expected) / expected
+        })
+        .sum();
+    joint.degrees_of_freedom = first_counts
+        .len()
+        .saturating_sub(1)
+        .saturating_mul(second_counts.len().saturating_sub(1));
+    joint.dependent = joint.degrees_of_freedom > 0
+        && joint.chi_squared
+            > chi_squared_critical_value(joint.degrees_of_freedom, DEPENDENCE_Z_THRESHOLD);
+    Ok(joint)
+}
+
+// upper-tail normal quantile for p ~ 0.001, as in the drift check
+const DEPENDENCE_Z_THRESHOLD: f64 = 3.09;
+
+fn marginal<'s>(pairs: impl Iterator<Item = (&'s String, u64)>) -> BTreeMap<String, u64> {
+    let mut counts = BTreeMap::new();
+    for (outcome, count) in pairs {
+        let total: &mut u64 = counts.entry(outcome.clone()).or_insert(0);
+        *total = total.saturating_add(count);
+    }
+    counts
+}
+
+fn get_client<'s>(state: &'s ResolverState, client_secret: &str) -> Result<&'s Client, String> {
+    state
+        .secrets
+        .get(client_secret)
+        .ok_or("client secret not found".to_string())
+}
+
+fn resolver<'a, H: Host>(
+    state: &'a ResolverState,
+    client: &'a Client,
+    context: &Struct,
+) -> AccountResolver<'a, H> {
+    AccountResolver::new(
+        client,
+        state,
+        EvaluationContext {
+            context: context.clone(),
+        },
+        &EncryptionKey::ZERO,
+    )
+}
+
+fn outcome<'a, H: Host>(resolver: &'a AccountResolver<'a, H>, flag: &'a Flag) -> String {
+    match resolver.resolve_flag(flag, BTreeMap::new()) {
+        Ok(result) => {
+            let resolved = result.resolved_value;
+            match resolved.assignment_match.and_then(|m| m.variant) {
+                Some(variant) => variant.name.clone(),
+                None => resolved.reason.openfeature_reason().to_string(),
+            }
+        }
+        Err(_) => openfeature::ERROR.to_string(),
+    }
+}
+
+fn increment(counts: &mut BTreeMap<String, u64>, outcome: String) {
+    let count = counts.entry(outcome).or_insert(0);
+    *count = count.saturating_add(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestHost;
+    use std::sync::Arc;
+
+    const EXAMPLE_STATE: &[u8] = include_bytes!("../test-payloads/resolver_state.pb");
+    const SECRET: &str = "mkjJruAATQWjeY7foFIWfVAcBWnci2YF";
+    const FLAG: &str = "flags/tutorial-feature";
+
+    fn contexts(n: usize) -> Vec<Struct> {
+        (0..n)
+            .map(|i| {
+                serde_json::from_str(&format!(r#"{{"visitor_id": "visitor-{}"}}"#, i)).unwrap()
+            })
+            .collect()
+    }
+
+    fn state() -> ResolverState {
+        ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn unchanged_flag_has_no_impact() {
+        let state = state();
+        let flag = state.flags[FLAG].clone();
+        let report = what_if::<TestHost>(&state, SECRET, &flag, &contexts(50)).unwrap();
+        assert_eq!(report.samples, 50);
+        assert_eq!(report.changed, 0);
+        assert_eq!(report.before, report.after);
+    }
+
+    #[test]
+    fn removing_rules_moves_everyone_to_default() {
+        let state = state();
+        let mut flag = state.flags[FLAG].clone();
+        flag.rules.clear();
+        let report = what_if::<TestHost>(&state, SECRET, &flag, &contexts(50)).unwrap();
+        assert_eq!(
+            report.after,
+            BTreeMap::from([(openfeature::DEFAULT.to_string(), 50)])
+        );
+        assert_eq!(
+            report.changed,
+            50 - report
+                .before
+                .get(openfeature::DEFAULT)
+                .copied()
+                .unwrap_or(0)
+        );
+    }
+
+    /// Adds a flag splitting all units 50/50 between two variants, salted by `segment`.
+    fn add_split_flag(state: &mut ResolverState, name: &str, segment: &str) {
+        use crate::proto::confidence::flags::admin::v1::flag::rule::{
+            assignment, Assignment, AssignmentSpec, BucketRange,
+        };
+        use crate::proto::confidence::flags::admin::v1::flag::{Rule, Variant};
+        use crate::proto::confidence::flags::admin::v1::Segment;
+
+        let variant = |v: &str| format!("{}/variants/{}", name, v);
+        let assignment = |v: &str, lower: i32, upper: i32| Assignment {
+            assignment_id: v.to_string(),
+            assignment: Some(assignment::Assignment::Variant(
+                assignment::VariantAssignment {
+                    variant: variant(v),
+                },
+            )),
+            bucket_ranges: vec![BucketRange { lower, upper }],
+        };
+        let flag = Flag {
+            name: name.to_string(),
+            variants: ["a", "b"]
+                .iter()
+                .map(|v| Variant {
+                    name: variant(v),
+                    value: Some(Struct::default()),
+                    ..Default::default()
+                })
+                .collect(),
+            rules: vec![Rule {
+                name: format!("{}/rules/split", name),
+                segment: segment.to_string(),
+                enabled: true,
+                assignment_spec: Some(AssignmentSpec {
+                    bucket_count: 2,
+                    assignments: vec![assignment("a", 0, 1), assignment("b", 1, 2)],
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        Arc::make_mut(&mut state.segments).insert(
+            segment.to_string(),
+            Segment {
+                name: segment.to_string(),
+                ..Default::default()
+            },
+        );
+        Arc::make_mut(&mut state.flags).insert(name.to_string(), flag);
+    }
+
+    fn unit_contexts(n: usize) -> Vec<Struct> {
+        (0..n)
+            .map(|i| {
+                serde_json::from_str(&format!(r#"{{"targeting_key": "unit-{}"}}"#, i)).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flags_on_the_same_segment_are_aligned() {
+        let mut state = state();
+        add_split_flag(&mut state, "flags/first", "segments/shared");
+        add_split_flag(&mut state, "flags/second", "segments/shared");
+        let joint = joint_distribution::<TestHost>(
+            &state,
+            SECRET,
+            "flags/first",
+            "flags/second",
+            &unit_contexts(500),
+        )
+        .unwrap();
+        assert_eq!(joint.samples, 500);
+        assert_eq!(joint.degrees_of_freedom, 1);
+        assert_eq!(joint.counts.len(), 2);
+        assert!(joint.dependent, "{:?}", joint);
+    }
+
+    #[test]
+    fn differently_salted_flags_are_independent() {
+        let mut state = state();
+        add_split_flag(&mut state, "flags/first", "segments/one");
+        add_split_flag(&mut state, "flags/second", "segments/two");
+        let joint = joint_distribution::<TestHost>(
+            &state,
+            SECRET,
+            "flags/first",
+            "flags/second",
+            &unit_contexts(2000),
+        )
+        .unwrap();
+        assert_eq!(joint.counts.len(), 4);
+        assert_eq!(joint.first().values().sum::<u64>(), 2000);
+        assert!(!joint.dependent, "{:?}", joint);
+    }
+
+    #[test]
+    fn missing_flag_is_an_error() {
+        let state = state();
+        assert!(joint_distribution::<TestHost>(
+            &state,
+            SECRET,
+            FLAG,
+            "flags/missing",
+            &contexts(1)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn new_flag_has_no_before() {
+        let state = state();
+        let mut flag = state.flags[FLAG].clone();
+        flag.name = "flags/not-yet-created".to_string();
+        let report = what_if::<TestHost>(&state, SECRET, &flag, &contexts(5)).unwrap();
+        assert!(report.before.is_empty());
+        assert_eq!(report.changed, 5);
+    }
+}
@@ -8,9 +8,9 @@ pub mod google {
     }
 
     #[cfg(feature = "json")]
-    pub use pbjson_types::{value, Struct, Timestamp, Value};
+    pub use pbjson_types::{value, ListValue, Struct, Timestamp, Value};
     #[cfg(not(feature = "json"))]
-    pub use prost_types::{value, Struct, Timestamp, Value};
+    pub use prost_types::{value, ListValue, Struct, Timestamp, Value};
 }
 
 // Include the `target` module, which is generated from items.proto.
@@ -93,3 +93,91 @@ pub mod confidence {
 }
 
 pub use prost::Message;
+
+/// Encoded `google.protobuf.FileDescriptorSet` of every proto compiled into this crate,
+/// including their imports. Lets tooling work with the resolver messages dynamically
+/// without compiling the vendored protos again.
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/proto_descriptor.bin"));
+
+/// Decodes [`FILE_DESCRIPTOR_SET`].
+pub fn file_descriptor_set() -> Result<prost_types::FileDescriptorSet, String> {
+    prost_types::FileDescriptorSet::decode(FILE_DESCRIPTOR_SET)
+        .map_err(|e| format!("failed to decode file descriptor set: {}", e))
+}
+
+/// CRC32 of [`FILE_DESCRIPTOR_SET`], to tell whether two builds embed the same protos.
+pub fn file_descriptor_set_checksum() -> u32 {
+    crc32fast::hash(FILE_DESCRIPTOR_SET)
+}
+
+/// API version of a message given by its fully qualified name, e.g. `"v1"` for
+/// `confidence.flags.resolver.v1.ResolveFlagsRequest`. `None` if the message isn't part of
+/// the embedded descriptors or its package isn't versioned.
+pub fn message_version(full_name: &str) -> Option<String> {
+    let descriptors = file_descriptor_set().ok()?;
+    let file = descriptors.file.iter().find(|file| {
+        full_name
+            .strip_prefix(file.package())
+            .and_then(|name| name.strip_prefix('.'))
+            .is_some_and(|name| contains_message(&file.message_type, name))
+    })?;
+    file.package()
+        .split('.')
+        .rev()
+        .find(|part| is_version(part))
+        .map(str::to_string)
+}
+
+fn contains_message(messages: &[prost_types::DescriptorProto], name: &str) -> bool {
+    let (head, rest) = match name.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (name, None),
+    };
+    messages
+        .iter()
+        .filter(|message| message.name() == head)
+        .any(|message| match rest {
+            Some(rest) => contains_message(&message.nested_type, rest),
+            None => true,
+        })
+}
+
+// v1, v2beta1, ...
+fn is_version(part: &str) -> bool {
+    part.strip_prefix('v')
+        .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor_set_contains_resolver_api() {
+        let descriptors = file_descriptor_set().unwrap();
+        assert!(descriptors
+            .file
+            .iter()
+            .any(|file| file.name() == "confidence/flags/resolver/v1/api.proto"));
+        assert_ne!(file_descriptor_set_checksum(), 0);
+    }
+
+    #[test]
+    fn message_versions() {
+        assert_eq!(
+            message_version("confidence.flags.resolver.v1.ResolveFlagsRequest").as_deref(),
+            Some("v1")
+        );
+        assert_eq!(
+            message_version("confidence.flags.resolver.v1.ResolveWithStickyResponse.Success")
+                .as_deref(),
+            Some("v1")
+        );
+        assert_eq!(
+            message_version("confidence.flags.resolver.v1.DoesNotExist"),
+            None
+        );
+        assert_eq!(message_version("google.protobuf.Struct"), None);
+    }
+}
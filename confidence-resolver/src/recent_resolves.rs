@@ -0,0 +1,185 @@
+//! What recent resolves returned, by resolve id.
+//!
+//! Resolve logs are aggregated and reach the backend only at the next checkpoint, so support
+//! tooling at the edge can't tell from them what a given resolve returned. Hosts that need to
+//! can record a summary of every resolve in a [`RecentResolves`] from [`Host::log_resolve`] and
+//! look it up by the `resolve_id` of the response for `ttl_seconds` afterwards.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use crate::{Client, Host, ResolveReason, ResolvedValue};
+
+/// The outcome of a resolve, see [`RecentResolves::get`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveSummary {
+    pub resolve_id: String,
+    pub client_credential: String,
+    /// When the resolve was recorded, in seconds of [`Host::current_time`].
+    pub time_seconds: i64,
+    /// The outcome of each resolved flag, by flag name.
+    pub flags: BTreeMap<String, FlagOutcome>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlagOutcome {
+    pub reason: ResolveReason,
+    /// The name of the variant the flag resolved to, if any.
+    pub variant: Option<String>,
+}
+
+/// The summaries of the last `capacity` resolves, kept for `ttl_seconds`.
+#[derive(Debug)]
+pub struct RecentResolves<H> {
+    capacity: usize,
+    ttl_seconds: i64,
+    entries: Mutex<Entries>,
+    _phantom: PhantomData<H>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_id: HashMap<String, ResolveSummary>,
+    /// Resolve ids, oldest first.
+    order: VecDeque<String>,
+}
+
+impl<H: Host> RecentResolves<H> {
+    pub fn new(capacity: usize, ttl_seconds: i64) -> Self {
+        RecentResolves {
+            capacity,
+            ttl_seconds,
+            entries: Mutex::new(Entries::default()),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Records the outcome of a resolve, evicting the oldest one when full.
+    pub fn record(&self, resolve_id: &str, values: &[ResolvedValue<'_>], client: &Client) {
+        if self.capacity == 0 {
+            return;
+        }
+        let flags = values
+            .iter()
+            .map(|value| {
+                let variant = value
+                    .assignment_match
+                    .as_ref()
+                    .and_then(|m| m.variant)
+                    .or(value.killed_variant)
+                    .map(|variant| variant.name.clone());
+                (
+                    value.flag.name.clone(),
+                    FlagOutcome {
+                        reason: value.reason,
+                        variant,
+                    },
+                )
+            })
+            .collect();
+        let summary = ResolveSummary {
+            resolve_id: resolve_id.to_string(),
+            client_credential: client.client_credential_name.clone(),
+            time_seconds: H::current_time().seconds,
+            flags,
+        };
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries
+            .by_id
+            .insert(resolve_id.to_string(), summary)
+            .is_none()
+        {
+            entries.order.push_back(resolve_id.to_string());
+        }
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.by_id.remove(&oldest);
+            }
+        }
+    }
+
+    /// The outcome of the resolve with `resolve_id`, `None` if it wasn't recorded, has been
+    /// evicted or is older than `ttl_seconds`.
+    pub fn get(&self, resolve_id: &str) -> Option<ResolveSummary> {
+        let now = H::current_time().seconds;
+        let entries = self.entries.lock().ok()?;
+        entries
+            .by_id
+            .get(resolve_id)
+            .filter(|summary| now.saturating_sub(summary.time_seconds) < self.ttl_seconds)
+            .cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|entries| entries.order.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::admin::v1::Flag;
+    use crate::test_util::TestHost;
+    use crate::{Account, ContextLogging};
+
+    fn client() -> Client {
+        Client {
+            account: Account::new("accounts/test"),
+            client_name: "clients/test".to_string(),
+            client_credential_name: "clients/test/clientCredentials/test".to_string(),
+            context_logging: ContextLogging::default(),
+        }
+    }
+
+    #[test]
+    fn looks_up_recent_resolves_until_they_expire() {
+        TestHost::reset();
+        let recent = RecentResolves::<TestHost>::new(10, 60);
+        let flag = Flag {
+            name: "flags/a".to_string(),
+            ..Default::default()
+        };
+        recent.record("r1", &[ResolvedValue::new(&flag)], &client());
+
+        let summary = recent.get("r1").unwrap();
+        assert_eq!(
+            summary.client_credential,
+            "clients/test/clientCredentials/test"
+        );
+        assert_eq!(
+            summary.flags["flags/a"],
+            FlagOutcome {
+                reason: ResolveReason::NoSegmentMatch,
+                variant: None,
+            }
+        );
+        assert_eq!(recent.get("r2"), None);
+
+        TestHost::advance_time(60);
+        assert_eq!(recent.get("r1"), None);
+    }
+
+    #[test]
+    fn evicts_the_oldest_resolves() {
+        TestHost::reset();
+        let recent = RecentResolves::<TestHost>::new(2, 60);
+        let flag = Flag::default();
+        for id in ["r1", "r2", "r3"] {
+            recent.record(id, &[ResolvedValue::new(&flag)], &client());
+        }
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent.get("r1"), None);
+        assert!(recent.get("r2").is_some());
+        assert!(recent.get("r3").is_some());
+    }
+}
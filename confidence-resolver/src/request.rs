@@ -0,0 +1,291 @@
+//! Builders for resolve and apply requests. They catch what the resolver would reject, or
+//! silently not match, when the request is built rather than when it's resolved:
+//!
+//! ```ignore
+//! let request = ResolveFlagsRequestBuilder::new(client_secret)
+//!     .with_flags(["my-flag", "flags/other-flag"])
+//!     .with_context_json(r#"{"targeting_key": "user-1"}"#)
+//!     .with_apply(true)
+//!     .build()?;
+//! ```
+
+use prost::Message;
+
+use crate::proto::confidence::flags::resolver::v1::{
+    AppliedFlag, ApplyFlagsRequest, ResolveFlagsRequest, Sdk,
+};
+use crate::proto::google::{Struct, Timestamp};
+use crate::resource_name::{self, ResourceKind};
+use crate::{time, ResolverConfig};
+
+/// Default limit of the encoded size of an evaluation context.
+pub const DEFAULT_MAX_CONTEXT_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ResolveFlagsRequestBuilder {
+    request: ResolveFlagsRequest,
+    max_flags: usize,
+    max_context_bytes: usize,
+    error: Option<String>,
+}
+
+impl ResolveFlagsRequestBuilder {
+    pub fn new(client_secret: &str) -> Self {
+        ResolveFlagsRequestBuilder {
+            request: ResolveFlagsRequest {
+                client_secret: client_secret.to_string(),
+                ..Default::default()
+            },
+            max_flags: ResolverConfig::default().max_flags_per_resolve,
+            max_context_bytes: DEFAULT_MAX_CONTEXT_BYTES,
+            error: None,
+        }
+    }
+
+    /// Adds flags to resolve, by name like `flags/my-flag` or by id like `my-flag`. A request
+    /// without flags resolves all flags of the client.
+    pub fn with_flags<I, S>(mut self, flags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for flag in flags {
+            match resource_name::qualify(ResourceKind::Flag, flag.as_ref()) {
+                Ok(name) => self.request.flags.push(name),
+                Err(err) => self.fail(err.into()),
+            }
+        }
+        self
+    }
+
+    pub fn with_context(mut self, evaluation_context: Struct) -> Self {
+        self.request.evaluation_context = Some(evaluation_context);
+        self
+    }
+
+    /// Sets the evaluation context from a JSON object.
+    #[cfg(feature = "json")]
+    pub fn with_context_json(mut self, evaluation_context: &str) -> Self {
+        match serde_json::from_str(evaluation_context) {
+            Ok(context) => self.request.evaluation_context = Some(context),
+            Err(err) => self.fail(format!("failed to parse evaluation context: {}", err)),
+        }
+        self
+    }
+
+    pub fn with_apply(mut self, apply: bool) -> Self {
+        self.request.apply = apply;
+        self
+    }
+
+    pub fn with_sdk(mut self, sdk: Sdk) -> Self {
+        self.request.sdk = Some(sdk);
+        self
+    }
+
+    /// Checks the number of flags against the limit of `config` rather than the default one.
+    pub fn with_config(mut self, config: &ResolverConfig) -> Self {
+        self.max_flags = config.max_flags_per_resolve;
+        self
+    }
+
+    pub fn with_max_context_bytes(mut self, max_context_bytes: usize) -> Self {
+        self.max_context_bytes = max_context_bytes;
+        self
+    }
+
+    pub fn build(self) -> Result<ResolveFlagsRequest, String> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.request.client_secret.is_empty() {
+            return Err("client secret is required".to_string());
+        }
+        if self.request.flags.len() > self.max_flags {
+            return Err(format!(
+                "max {} flags allowed in a single resolve request, this request would return {} flags.",
+                self.max_flags,
+                self.request.flags.len()
+            ));
+        }
+        let context_bytes = self
+            .request
+            .evaluation_context
+            .as_ref()
+            .map_or(0, Message::encoded_len);
+        if context_bytes > self.max_context_bytes {
+            return Err(format!(
+                "evaluation context is {} bytes, max {} bytes allowed",
+                context_bytes, self.max_context_bytes
+            ));
+        }
+        Ok(self.request)
+    }
+
+    fn fail(&mut self, error: String) {
+        self.error.get_or_insert(error);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApplyFlagsRequestBuilder {
+    request: ApplyFlagsRequest,
+    error: Option<String>,
+}
+
+impl ApplyFlagsRequestBuilder {
+    /// Starts a request applying flags of the resolve that returned `resolve_token`.
+    pub fn new(client_secret: &str, resolve_token: impl Into<Vec<u8>>) -> Self {
+        ApplyFlagsRequestBuilder {
+            request: ApplyFlagsRequest {
+                client_secret: client_secret.to_string(),
+                resolve_token: resolve_token.into(),
+                ..Default::default()
+            },
+            error: None,
+        }
+    }
+
+    /// Adds flags applied at `apply_time`, by name or by id like
+    /// [`ResolveFlagsRequestBuilder::with_flags`].
+    pub fn with_flags<I, S>(mut self, flags: I, apply_time: &Timestamp) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        if time::to_nanos(apply_time).is_none() {
+            self.fail("invalid apply_time".to_string());
+        }
+        for flag in flags {
+            match resource_name::qualify(ResourceKind::Flag, flag.as_ref()) {
+                Ok(flag) => self.request.flags.push(AppliedFlag {
+                    flag,
+                    apply_time: Some(apply_time.clone()),
+                }),
+                Err(err) => self.fail(err.into()),
+            }
+        }
+        self
+    }
+
+    pub fn with_send_time(mut self, send_time: Timestamp) -> Self {
+        self.request.send_time = Some(send_time);
+        self
+    }
+
+    pub fn with_sdk(mut self, sdk: Sdk) -> Self {
+        self.request.sdk = Some(sdk);
+        self
+    }
+
+    pub fn build(self) -> Result<ApplyFlagsRequest, String> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.request.client_secret.is_empty() {
+            return Err("client secret is required".to_string());
+        }
+        if self.request.resolve_token.is_empty() {
+            return Err("resolve token is required".to_string());
+        }
+        if self.request.flags.is_empty() {
+            return Err("no flags to apply".to_string());
+        }
+        match &self.request.send_time {
+            None => Err("send_time is required".to_string()),
+            Some(send_time) if time::to_nanos(send_time).is_none() => {
+                Err("invalid send_time".to_string())
+            }
+            Some(_) => Ok(self.request),
+        }
+    }
+
+    fn fail(&mut self, error: String) {
+        self.error.get_or_insert(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_resolve_requests() {
+        let request = ResolveFlagsRequestBuilder::new("secret")
+            .with_flags(["my-flag", "flags/other-flag"])
+            .with_context_json(r#"{"targeting_key": "user-1"}"#)
+            .with_apply(true)
+            .build()
+            .unwrap();
+        assert_eq!(request.flags, vec!["flags/my-flag", "flags/other-flag"]);
+        assert!(request.apply);
+        assert!(request
+            .evaluation_context
+            .unwrap()
+            .fields
+            .contains_key("targeting_key"));
+    }
+
+    #[test]
+    fn rejects_invalid_resolve_requests() {
+        let err = |builder: ResolveFlagsRequestBuilder| builder.build().unwrap_err();
+        let builder = || ResolveFlagsRequestBuilder::new("secret");
+        assert!(err(builder().with_flags(["segments/x"])).contains("expected a name of flags"));
+        assert!(err(builder().with_context_json("[1]")).contains("evaluation context"));
+        assert_eq!(
+            err(ResolveFlagsRequestBuilder::new("")),
+            "client secret is required"
+        );
+        let config = ResolverConfig {
+            max_flags_per_resolve: 1,
+            ..Default::default()
+        };
+        assert!(err(builder().with_config(&config).with_flags(["a", "b"])).contains("max 1 flags"));
+        let large = format!(r#"{{"data": "{}"}}"#, "x".repeat(100));
+        assert!(err(builder()
+            .with_max_context_bytes(64)
+            .with_context_json(&large))
+        .contains("max 64 bytes"));
+    }
+
+    #[test]
+    fn builds_apply_requests() {
+        let time = Timestamp {
+            seconds: 1_700_000_000,
+            nanos: 0,
+        };
+        let request = ApplyFlagsRequestBuilder::new("secret", b"token".to_vec())
+            .with_flags(["my-flag"], &time)
+            .with_send_time(time.clone())
+            .build()
+            .unwrap();
+        assert_eq!(request.flags.len(), 1);
+        assert_eq!(request.flags[0].flag, "flags/my-flag");
+        assert_eq!(request.flags[0].apply_time, Some(time.clone()));
+
+        let builder = || ApplyFlagsRequestBuilder::new("secret", b"token".to_vec());
+        assert_eq!(
+            builder()
+                .with_flags(["my-flag"], &time)
+                .build()
+                .unwrap_err(),
+            "send_time is required"
+        );
+        assert_eq!(
+            builder().with_send_time(time.clone()).build().unwrap_err(),
+            "no flags to apply"
+        );
+        let invalid = Timestamp {
+            seconds: 0,
+            nanos: -1,
+        };
+        assert_eq!(
+            builder()
+                .with_flags(["my-flag"], &invalid)
+                .with_send_time(time)
+                .build()
+                .unwrap_err(),
+            "invalid apply_time"
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! The evaluation context attributes flags read, found by walking their rules and the
+//! targeting of their segments, so SDK integrators can be told which fields to send.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::proto::confidence::flags::admin::v1::flag::State;
+use crate::proto::confidence::flags::types::v1::targeting;
+use crate::{criterion, value, ResolverState};
+
+/// Type a context attribute is compared as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AttributeType {
+    Bool,
+    Number,
+    String,
+    Timestamp,
+    Version,
+    /// The criterion doesn't constrain the type, e.g. a set rule without values.
+    Any,
+}
+
+/// An attribute read by some of the flags passed to [`context_requirements`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContextRequirement {
+    /// Path of the attribute, e.g. `user.country`.
+    pub attribute: String,
+    /// Types the attribute is compared as. More than one means the criteria disagree, and
+    /// some of them can never match.
+    pub types: BTreeSet<AttributeType>,
+    /// Whether units are bucketed by the attribute. Targeting keys are strings, or integral
+    /// numbers.
+    pub targeting_key: bool,
+    /// Flags reading the attribute.
+    pub flags: BTreeSet<String>,
+}
+
+/// The attributes `flags` read when resolved, sorted by path. An empty `flags` means all active
+/// flags; names of flags that don't exist or aren't active are ignored. Rules that are
+/// disabled are never evaluated, so their attributes aren't required.
+pub fn context_requirements(state: &ResolverState, flags: &[&str]) -> Vec<ContextRequirement> {
+    let mut requirements: BTreeMap<String, ContextRequirement> = BTreeMap::new();
+
+    let active = state
+        .flags
+        .values()
+        .filter(|flag| flag.state() == State::Active)
+        .filter(|flag| flags.is_empty() || flags.contains(&flag.name.as_str()));
+    for flag in active {
+        let rules: Vec<_> = flag.rules.iter().filter(|rule| rule.enabled).collect();
+        for rule in &rules {
+            let selector = state.targeting_key_selector(flag, rule);
+            require(
+                &mut requirements,
+                selector,
+                &flag.name,
+                AttributeType::String,
+            )
+            .targeting_key = true;
+        }
+        let segments =
+            state.segments_reachable_from(rules.iter().map(|rule| rule.segment.as_str()));
+        for segment in segments
+            .into_iter()
+            .filter_map(|segment| state.segments.get(segment))
+        {
+            let Some(targeting) = &segment.targeting else {
+                continue;
+            };
+            for criterion in targeting.criteria.values() {
+                let Some(criterion::Criterion::Attribute(attribute)) = &criterion.criterion else {
+                    continue;
+                };
+                let attribute_type = attribute_type(value::expected_value_type(attribute));
+                require(
+                    &mut requirements,
+                    &attribute.attribute_name,
+                    &flag.name,
+                    attribute_type,
+                );
+            }
+        }
+    }
+    requirements.into_values().collect()
+}
+
+fn require<'r>(
+    requirements: &'r mut BTreeMap<String, ContextRequirement>,
+    attribute: &str,
+    flag: &str,
+    attribute_type: AttributeType,
+) -> &'r mut ContextRequirement {
+    let requirement = requirements
+        .entry(attribute.to_string())
+        .or_insert_with(|| ContextRequirement {
+            attribute: attribute.to_string(),
+            ..Default::default()
+        });
+    requirement.types.insert(attribute_type);
+    requirement.flags.insert(flag.to_string());
+    requirement
+}
+
+fn attribute_type(value: Option<&targeting::value::Value>) -> AttributeType {
+    use targeting::value::Value;
+    match value {
+        Some(Value::BoolValue(_)) => AttributeType::Bool,
+        Some(Value::NumberValue(_)) => AttributeType::Number,
+        Some(Value::StringValue(_)) => AttributeType::String,
+        Some(Value::TimestampValue(_)) => AttributeType::Timestamp,
+        Some(Value::VersionValue(_)) => AttributeType::Version,
+        Some(Value::ListValue(list)) => {
+            attribute_type(list.values.first().and_then(|v| v.value.as_ref()))
+        }
+        None => AttributeType::Any,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::admin::v1::Segment;
+    use crate::proto::confidence::flags::types::v1::Targeting;
+    use std::sync::Arc;
+
+    const ACCOUNT: &str = "confidence-demo-june";
+
+    fn example_state() -> ResolverState {
+        ResolverState::from_proto(
+            include_bytes!("../test-payloads/resolver_state.pb")
+                .to_vec()
+                .try_into()
+                .unwrap(),
+            ACCOUNT,
+        )
+        .unwrap()
+    }
+
+    fn segment(name: &str, criteria: Vec<(&str, criterion::Criterion)>) -> Segment {
+        Segment {
+            name: name.to_string(),
+            targeting: Some(Targeting {
+                criteria: criteria
+                    .into_iter()
+                    .map(|(key, criterion)| {
+                        (
+                            key.to_string(),
+                            targeting::Criterion {
+                                criterion: Some(criterion),
+                            },
+                        )
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn eq(attribute: &str, value: targeting::value::Value) -> criterion::Criterion {
+        criterion::Criterion::Attribute(criterion::AttributeCriterion {
+            attribute_name: attribute.to_string(),
+            rule: Some(criterion::attribute_criterion::Rule::EqRule(
+                targeting::EqRule {
+                    value: Some(targeting::Value { value: Some(value) }),
+                },
+            )),
+        })
+    }
+
+    #[test]
+    fn targeting_keys_of_enabled_rules() {
+        let state = example_state();
+        let requirements = context_requirements(&state, &["flags/tutorial-feature"]);
+        assert_eq!(requirements.len(), 1);
+        let visitor_id = &requirements[0];
+        assert_eq!(visitor_id.attribute, "visitor_id");
+        assert!(visitor_id.targeting_key);
+        assert_eq!(visitor_id.types, BTreeSet::from([AttributeType::String]));
+        assert_eq!(
+            visitor_id.flags,
+            BTreeSet::from(["flags/tutorial-feature".to_string()])
+        );
+
+        assert!(context_requirements(&state, &["flags/does-not-exist"]).is_empty());
+        assert!(!context_requirements(&state, &[]).is_empty());
+    }
+
+    #[test]
+    fn attributes_of_reachable_segments() {
+        use targeting::value::Value;
+
+        let mut state = example_state();
+        for segment in [
+            segment(
+                "segments/targeted",
+                vec![
+                    (
+                        "country",
+                        eq("user.country", Value::StringValue("SE".into())),
+                    ),
+                    (
+                        "nested",
+                        criterion::Criterion::Segment(criterion::SegmentCriterion {
+                            segment: "segments/nested".to_string(),
+                        }),
+                    ),
+                ],
+            ),
+            segment(
+                "segments/nested",
+                vec![
+                    ("age", eq("user.age", Value::NumberValue(18.0))),
+                    ("country", eq("user.country", Value::NumberValue(46.0))),
+                ],
+            ),
+            segment(
+                "segments/disabled",
+                vec![("beta", eq("user.beta", Value::BoolValue(true)))],
+            ),
+        ] {
+            Arc::make_mut(&mut state.segments).insert(segment.name.clone(), segment);
+        }
+        let flag = Arc::make_mut(&mut state.flags)
+            .get_mut("flags/tutorial-feature")
+            .unwrap();
+        let mut disabled = flag.rules[0].clone();
+        flag.rules[0].segment = "segments/targeted".to_string();
+        disabled.segment = "segments/disabled".to_string();
+        disabled.enabled = false;
+        flag.rules.push(disabled);
+
+        let requirements = context_requirements(&state, &["flags/tutorial-feature"]);
+        let attributes: Vec<_> = requirements
+            .iter()
+            .map(|r| (r.attribute.as_str(), r.targeting_key))
+            .collect();
+        assert_eq!(
+            attributes,
+            vec![
+                ("user.age", false),
+                ("user.country", false),
+                ("visitor_id", true)
+            ]
+        );
+        assert_eq!(
+            requirements[0].types,
+            BTreeSet::from([AttributeType::Number])
+        );
+        // the two segments compare the country differently
+        assert_eq!(
+            requirements[1].types,
+            BTreeSet::from([AttributeType::Number, AttributeType::String])
+        );
+    }
+}
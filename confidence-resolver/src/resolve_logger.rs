@@ -1,14 +1,19 @@
+use std::collections::VecDeque;
 use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc, RwLock,
+    atomic::{AtomicI64, AtomicU32, Ordering},
+    Arc, Mutex, RwLock,
 };
 
 use crate::{
+    canonical,
+    flag_logger::{self, LogBacklog},
+    resource_name::{ResourceKind, ResourceName},
     schema_util::{DerivedClientSchema, SchemaFromEvaluationContext},
-    Host,
+    ContextLogging, Host,
 };
 use arc_swap::ArcSwap;
 use papaya::{HashMap, HashSet};
+use prost::{length_delimiter_len, Message};
 use std::marker::PhantomData;
 
 mod pb {
@@ -16,12 +21,43 @@ mod pb {
         client_resolve_info, flag_resolve_info, ClientResolveInfo, FlagResolveInfo,
     };
     pub use crate::proto::confidence::flags::resolver::v1::TelemetryData;
-    pub use crate::proto::{confidence::flags::resolver::v1::WriteFlagLogsRequest, google::Struct};
+    pub use crate::proto::{
+        confidence::flags::resolver::v1::WriteFlagLogsRequest,
+        google::{value::Kind, Struct, Timestamp},
+    };
+}
+
+/// Evaluation contexts kept per client credential between checkpoints, for credentials with
+/// [`ContextLogging::Sampled`]. Later samples are dropped, as are samples equal to one already
+/// kept by their [`canonical::context_hash`].
+pub const MAX_CONTEXT_SAMPLES: usize = 16;
+
+/// Counters collected during a single time window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowedFlagLogs {
+    /// Start of the window, aligned to a multiple of the window length.
+    pub window_start: pb::Timestamp,
+    pub request: pb::WriteFlagLogsRequest,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TimeWindows {
+    window_seconds: i64,
+    max_windows: usize,
 }
 
 #[derive(Debug)]
 pub struct ResolveLogger<H> {
     state: ArcSwap<RwLock<Option<ResolveInfoState>>>,
+    windows: Option<TimeWindows>,
+    window_start: AtomicI64,
+    closed_windows: Mutex<VecDeque<WindowedFlagLogs>>,
+    /// Entries that didn't fit into the last [`ResolveLogger::checkpoint_with_limit`].
+    overflow: Mutex<Option<pb::WriteFlagLogsRequest>>,
+    /// Reported as `TelemetryData::client_instance_id`.
+    instance_id: String,
+    /// The `TelemetryData::sequence_number` of the last checkpoint.
+    sequence: AtomicI64,
     _phantom: PhantomData<H>,
 }
 
@@ -35,10 +71,32 @@ impl<H: Host> ResolveLogger<H> {
     pub fn new() -> ResolveLogger<H> {
         ResolveLogger {
             state: ArcSwap::new(Arc::new(RwLock::new(Some(ResolveInfoState::new())))),
+            windows: None,
+            window_start: AtomicI64::new(i64::MIN),
+            closed_windows: Mutex::new(VecDeque::new()),
+            overflow: Mutex::new(None),
+            instance_id: H::random_alphanumeric(16),
+            sequence: AtomicI64::new(0),
             _phantom: PhantomData,
         }
     }
 
+    /// Creates a logger that buckets counters into windows of `window_seconds` based on
+    /// `Host::current_time`. At most `max_windows` closed windows are retained between
+    /// checkpoints; beyond that the oldest two windows are merged, keeping memory bounded
+    /// at the cost of resolution. Windows are closed by the first resolve or checkpoint after
+    /// they end, and resolves logged by other threads while a window closes may be counted in
+    /// the window next to theirs. Such resolves are counted once, never dropped.
+    pub fn with_time_windows(window_seconds: u32, max_windows: usize) -> ResolveLogger<H> {
+        ResolveLogger {
+            windows: Some(TimeWindows {
+                window_seconds: i64::from(window_seconds.max(1)),
+                max_windows: max_windows.max(1),
+            }),
+            ..Self::new()
+        }
+    }
+
     fn with_state<F: FnOnce(&ResolveInfoState)>(&self, f: F) {
         loop {
             let lock = self.state.load_full();
@@ -59,22 +117,47 @@ impl<H: Host> ResolveLogger<H> {
         }
     }
 
+    /// Logs a resolve. What is logged of `resolve_context` follows the
+    /// [`ContextLogging`] of `client`.
     pub fn log_resolve(
         &self,
-        _resolve_id: &str,
+        resolve_id: &str,
         resolve_context: &pb::Struct,
         client_credential: &str,
         values: &[crate::ResolvedValue<'_>],
-        _client: &crate::Client,
+        client: &crate::Client,
         sdk: &Option<crate::flags_resolver::Sdk>,
     ) {
+        if let Some(windows) = self.windows {
+            self.rotate_window(windows);
+        }
+        let synthetic = flag_logger::is_synthetic(resolve_context);
         self.with_state(|state: &ResolveInfoState| {
-            state
-                .client_resolve_info
-                .with_default(client_credential, |client_resolve_info| {
-                    let schema = SchemaFromEvaluationContext::get_schema(resolve_context);
-                    client_resolve_info.schemas.pin().insert(schema);
-                });
+            state.resolve_count.fetch_add(1, Ordering::Relaxed);
+            if client.context_logging != ContextLogging::None {
+                state
+                    .client_resolve_info
+                    .with_default(client_credential, |client_resolve_info| {
+                        let schema = SchemaFromEvaluationContext::get_schema(resolve_context);
+                        client_resolve_info.schemas.pin().insert(schema);
+                        if let ContextLogging::Sampled {
+                            one_in,
+                            redacted_fields,
+                        } = &client.context_logging
+                        {
+                            if is_sampled(resolve_id, *one_in) {
+                                let sample = redacted(resolve_context, redacted_fields);
+                                let hash = canonical::context_hash(&sample);
+                                let mut samples = lock(&client_resolve_info.samples);
+                                if samples.len() < MAX_CONTEXT_SAMPLES
+                                    && samples.iter().all(|(kept, _)| *kept != hash)
+                                {
+                                    samples.push((hash, sample));
+                                }
+                            }
+                        }
+                    });
+            }
 
             // Store SDK info if not already set
             if let Some(sdk_value) = sdk {
@@ -107,7 +190,7 @@ impl<H: Host> ResolveLogger<H> {
                                     Some(variant) => &variant.name,
                                     None => "",
                                 };
-                                flag_state.variant_resolve_info.increment(variant_key);
+                                flag_state.increment_variant(variant_key, synthetic);
                                 flag_state.rule_resolve_info.with_default(
                                     &assignment.rule.name,
                                     |rule_state| {
@@ -118,8 +201,12 @@ impl<H: Host> ResolveLogger<H> {
                                     },
                                 );
                             }
+                            // a killed flag counts under its killed variant, apart from flags
+                            // that matched no rule
                             None => {
-                                flag_state.variant_resolve_info.increment("");
+                                let variant_key =
+                                    value.killed_variant.map_or("", |v| v.name.as_str());
+                                flag_state.increment_variant(variant_key, synthetic);
                             }
                         }
                     });
@@ -127,7 +214,189 @@ impl<H: Host> ResolveLogger<H> {
         })
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, name = "resolve_logger.checkpoint")
+    )]
     pub fn checkpoint(&self) -> pb::WriteFlagLogsRequest {
+        let mut overflow = lock(&self.overflow);
+        self.checkpoint_with_overflow(overflow.take())
+    }
+
+    /// Like [`ResolveLogger::checkpoint`], but the returned request encodes to at most
+    /// `limit_bytes`. Flag and client entries that don't fit are held back and returned first
+    /// by the next checkpoint. A flag entry that doesn't fit is split by variants and rules,
+    /// filling up the request and holding back the rest; its counts are per variant and rule,
+    /// so summing the parts, as [`flag_logger::aggregate_batch`] does, gives the full counts.
+    /// An entry larger than the limit that can't be split is returned on its own.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            name = "resolve_logger.checkpoint_with_limit"
+        )
+    )]
+    pub fn checkpoint_with_limit(&self, limit_bytes: usize) -> pb::WriteFlagLogsRequest {
+        let mut overflow = lock(&self.overflow);
+        let all = self.checkpoint_with_overflow(overflow.take());
+        let mut req = pb::WriteFlagLogsRequest {
+            telemetry_data: all.telemetry_data,
+            flag_assigned: all.flag_assigned,
+            ..Default::default()
+        };
+        let mut rest = pb::WriteFlagLogsRequest::default();
+        let mut budget = Budget {
+            limit: limit_bytes,
+            size: req.encoded_len(),
+            empty: true,
+        };
+        for info in all.client_resolve_info {
+            if budget.fits(entry_len(&info)) {
+                req.client_resolve_info.push(info);
+            } else {
+                rest.client_resolve_info.push(info);
+            }
+        }
+        for info in all.flag_resolve_info {
+            let len = entry_len(&info);
+            if len <= budget.remaining() {
+                budget.fits(len);
+                req.flag_resolve_info.push(info);
+                continue;
+            }
+            let (head, tail) = split_flag_resolve_info(info, budget.remaining(), budget.empty);
+            if let Some(head) = head {
+                budget.fits(entry_len(&head));
+                req.flag_resolve_info.push(head);
+            }
+            rest.flag_resolve_info.extend(tail);
+        }
+        if !rest.client_resolve_info.is_empty() || !rest.flag_resolve_info.is_empty() {
+            *overflow = Some(rest);
+        }
+        req
+    }
+
+    /// Whether entries held back by [`ResolveLogger::checkpoint_with_limit`] are waiting for
+    /// the next checkpoint.
+    pub fn has_overflow(&self) -> bool {
+        lock(&self.overflow).is_some()
+    }
+
+    /// The resolves waiting for the next checkpoint. Counters and schemas are aggregated, so
+    /// the size only grows with new flags, rules, variants and context schemas; `bytes` is
+    /// estimated from the names and schemas held rather than by encoding them.
+    pub fn backlog(&self) -> LogBacklog {
+        let mut backlog = LogBacklog::default();
+        self.with_state(|state| {
+            let resolve_count = state.resolve_count.load(Ordering::Relaxed);
+            backlog.events = usize::try_from(resolve_count).unwrap_or_default();
+            backlog.bytes = estimated_len(state);
+        });
+        let overflow = lock(&self.overflow);
+        let closed = lock(&self.closed_windows);
+        for request in overflow.iter().chain(closed.iter().map(|w| &w.request)) {
+            let resolve_count = request
+                .telemetry_data
+                .as_ref()
+                .map_or(0, |t| t.resolve_count);
+            backlog.events = backlog
+                .events
+                .saturating_add(usize::try_from(resolve_count).unwrap_or_default());
+            backlog.bytes = backlog.bytes.saturating_add(request.encoded_len());
+        }
+        backlog
+    }
+
+    fn checkpoint_with_overflow(
+        &self,
+        overflow: Option<pb::WriteFlagLogsRequest>,
+    ) -> pb::WriteFlagLogsRequest {
+        let current = self.swap_state();
+        let closed = self.take_closed_windows();
+        if closed.is_empty() && overflow.is_none() {
+            return current;
+        }
+        let mut batch: Vec<pb::WriteFlagLogsRequest> = overflow.into_iter().collect();
+        batch.extend(closed.into_iter().map(|w| w.request));
+        batch.push(current);
+        flag_logger::aggregate_batch(batch)
+    }
+
+    /// Returns one request per time window closed since the last checkpoint, oldest first.
+    /// The open window keeps its counters until it ends, so that every window start is
+    /// reported once. For loggers created with [`ResolveLogger::new`] this returns a single
+    /// entry with a zero window start.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, name = "resolve_logger.checkpoint_windows")
+    )]
+    pub fn checkpoint_windows(&self) -> Vec<WindowedFlagLogs> {
+        let Some(time_windows) = self.windows else {
+            return vec![WindowedFlagLogs {
+                window_start: pb::Timestamp::default(),
+                request: self.checkpoint(),
+            }];
+        };
+        self.rotate_window(time_windows);
+        let mut windows: Vec<WindowedFlagLogs> = self.take_closed_windows().into();
+        // entries held back by a bounded checkpoint go with the oldest window
+        if let Some(oldest) = windows.first_mut() {
+            if let Some(overflow) = lock(&self.overflow).take() {
+                let request = core::mem::take(&mut oldest.request);
+                oldest.request = flag_logger::aggregate_batch(vec![overflow, request]);
+            }
+        }
+        windows
+    }
+
+    fn take_closed_windows(&self) -> VecDeque<WindowedFlagLogs> {
+        std::mem::take(&mut *lock(&self.closed_windows))
+    }
+
+    /// Closes the open window if the current time is past it. A thread that logs a resolve
+    /// while another one closes the window may count it in the window being closed or in the
+    /// new one, whichever state it loaded; this isn't guarded against, as the counters are
+    /// lock free.
+    fn rotate_window(&self, windows: TimeWindows) {
+        let now = H::current_time().seconds;
+        let offset = now.rem_euclid(windows.window_seconds);
+        let window_start = now.saturating_sub(offset);
+        if self.window_start.load(Ordering::Acquire) >= window_start {
+            return;
+        }
+        // the closed windows lock also serializes rotation so only one thread closes a window
+        let mut closed = lock(&self.closed_windows);
+        let previous_start = self.window_start.load(Ordering::Acquire);
+        if previous_start >= window_start {
+            return;
+        }
+        let request = self.swap_state();
+        self.window_start.store(window_start, Ordering::Release);
+        if previous_start == i64::MIN {
+            // nothing has been logged yet, so there is no window to close
+            return;
+        }
+        closed.push_back(WindowedFlagLogs {
+            window_start: pb::Timestamp {
+                seconds: previous_start,
+                nanos: 0,
+            },
+            request,
+        });
+        while closed.len() > windows.max_windows {
+            let (Some(oldest), Some(next)) = (closed.pop_front(), closed.pop_front()) else {
+                break;
+            };
+            closed.push_front(WindowedFlagLogs {
+                window_start: oldest.window_start,
+                request: flag_logger::aggregate_batch(vec![oldest.request, next.request]),
+            });
+        }
+    }
+
+    fn swap_state(&self) -> pb::WriteFlagLogsRequest {
         let lock = self
             .state
             .swap(Arc::new(RwLock::new(Some(ResolveInfoState::new()))));
@@ -145,7 +414,17 @@ impl<H: Host> ResolveLogger<H> {
 
                 let telemetry_data = {
                     let sdk = state.sdk.read().ok().and_then(|s| s.clone());
-                    sdk.map(|s| pb::TelemetryData { sdk: Some(s) })
+                    let resolve_count = state.resolve_count.load(Ordering::Relaxed);
+                    (sdk.is_some() || resolve_count > 0).then(|| pb::TelemetryData {
+                        sdk,
+                        resolve_count,
+                        client_instance_id: self.instance_id.clone(),
+                        sequence_number: self
+                            .sequence
+                            .fetch_add(1, Ordering::Relaxed)
+                            .saturating_add(1),
+                        ..Default::default()
+                    })
                 };
 
                 pb::WriteFlagLogsRequest {
@@ -154,6 +433,7 @@ impl<H: Host> ResolveLogger<H> {
                     // Assignment events are handled by `AssignLogger`, so this logger
                     // only returns schema/counter data here.
                     flag_assigned: Vec::new(),
+                    flag_exposed: Vec::new(),
                     telemetry_data,
                 }
             })
@@ -170,12 +450,24 @@ struct RuleResolveInfo {
 #[derive(Debug, Default)]
 struct FlagResolveInfo {
     variant_resolve_info: HashMap<String, AtomicU32>,
+    synthetic_variant_resolve_info: HashMap<String, AtomicU32>,
     rule_resolve_info: HashMap<String, RuleResolveInfo>,
 }
 
+impl FlagResolveInfo {
+    fn increment_variant(&self, variant: &str, synthetic: bool) {
+        self.variant_resolve_info.increment(variant);
+        if synthetic {
+            self.synthetic_variant_resolve_info.increment(variant);
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 struct ClientResolveInfo {
     schemas: HashSet<DerivedClientSchema>,
+    // with their canonical hash
+    samples: Mutex<Vec<(u128, pb::Struct)>>,
 }
 
 #[derive(Debug)]
@@ -183,6 +475,7 @@ struct ResolveInfoState {
     flag_resolve_info: HashMap<String, FlagResolveInfo>,
     client_resolve_info: HashMap<String, ClientResolveInfo>,
     sdk: RwLock<Option<crate::flags_resolver::Sdk>>,
+    resolve_count: AtomicI64,
 }
 
 impl ResolveInfoState {
@@ -191,6 +484,7 @@ impl ResolveInfoState {
             flag_resolve_info: HashMap::default(),
             client_resolve_info: HashMap::default(),
             sdk: RwLock::new(None),
+            resolve_count: AtomicI64::new(0),
         }
     }
 }
@@ -201,19 +495,187 @@ impl Default for ResolveInfoState {
             flag_resolve_info: HashMap::default(),
             client_resolve_info: HashMap::default(),
             sdk: RwLock::new(None),
+            resolve_count: AtomicI64::new(0),
         }
     }
 }
 
-fn extract_client(credential: &str) -> String {
-    // split on '/', take first two segments
-    let mut it = credential.split('/');
-    match (it.next(), it.next()) {
-        (Some(a), Some(b)) => format!("{}/{}", a, b),
-        _ => credential.to_string(),
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Rough encoded size of the logs in `state`: the names it holds plus a few bytes for each
+/// counter and schema field.
+fn estimated_len(state: &ResolveInfoState) -> usize {
+    const COUNTER_LEN: usize = 8;
+    let mut len = 0usize;
+    let mut add = |name: &str| len = len.saturating_add(name.len()).saturating_add(COUNTER_LEN);
+    for (flag, info) in state.flag_resolve_info.pin().iter() {
+        add(flag);
+        for (variant, _) in info.variant_resolve_info.pin().iter() {
+            add(variant);
+        }
+        for (rule, rule_info) in info.rule_resolve_info.pin().iter() {
+            add(rule);
+            for (assignment, _) in rule_info.assignment_counts.pin().iter() {
+                add(assignment);
+            }
+        }
+    }
+    for (credential, info) in state.client_resolve_info.pin().iter() {
+        add(credential);
+        for schema in info.schemas.pin().iter() {
+            schema
+                .fields
+                .keys()
+                .chain(schema.semantic_types.keys())
+                .for_each(|field| add(field));
+        }
+        for (_, sample) in lock(&info.samples).iter() {
+            len = len.saturating_add(sample.encoded_len());
+        }
+    }
+    len
+}
+
+/// Whether the context of the resolve `resolve_id` is one of the one in `one_in` that are
+/// sampled. Resolve ids are random, so hashing them samples uniformly. Nothing is sampled for
+/// a `one_in` of 0.
+fn is_sampled(resolve_id: &str, one_in: u32) -> bool {
+    crate::hashing::hash(resolve_id)
+        .checked_rem(u128::from(one_in))
+        .is_some_and(|rem| rem == 0)
+}
+
+/// `context` without the fields at the `.`-separated `paths`. A path through a list applies to
+/// every struct in it.
+fn redacted(context: &pb::Struct, paths: &[String]) -> pb::Struct {
+    let mut context = context.clone();
+    for path in paths {
+        let parts: Vec<&str> = path.split('.').collect();
+        redact(&mut context, &parts);
+    }
+    context
+}
+
+fn redact(s: &mut pb::Struct, path: &[&str]) {
+    let [field, rest @ ..] = path else {
+        return;
+    };
+    if rest.is_empty() {
+        s.fields.remove(*field);
+        return;
+    }
+    match s.fields.get_mut(*field).and_then(|v| v.kind.as_mut()) {
+        Some(pb::Kind::StructValue(nested)) => redact(nested, rest),
+        Some(pb::Kind::ListValue(list)) => {
+            for value in list.values.iter_mut() {
+                if let Some(pb::Kind::StructValue(nested)) = value.kind.as_mut() {
+                    redact(nested, rest);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Space left in a request built by [`ResolveLogger::checkpoint_with_limit`].
+struct Budget {
+    limit: usize,
+    size: usize,
+    /// Nothing was added yet, in which case anything fits.
+    empty: bool,
+}
+
+impl Budget {
+    fn fits(&mut self, len: usize) -> bool {
+        let fits = self.empty || self.size.saturating_add(len) <= self.limit;
+        if fits {
+            self.size = self.size.saturating_add(len);
+            self.empty = false;
+        }
+        fits
+    }
+
+    fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.size)
     }
 }
 
+/// Splits `info` into a part that fits in `budget` bytes as an entry, and the rest. The first
+/// part is `None` if not even one variant or rule fits, unless `force` is set, in which case it
+/// gets at least one of them.
+fn split_flag_resolve_info(
+    info: pb::FlagResolveInfo,
+    budget: usize,
+    force: bool,
+) -> (Option<pb::FlagResolveInfo>, Option<pb::FlagResolveInfo>) {
+    if info.variant_resolve_info.is_empty() && info.rule_resolve_info.is_empty() {
+        return if force {
+            (Some(info), None)
+        } else {
+            (None, Some(info))
+        };
+    }
+    let mut head = pb::FlagResolveInfo {
+        flag: info.flag.clone(),
+        ..Default::default()
+    };
+    let mut tail = head.clone();
+    let mut len = head.encoded_len();
+    let mut force = force;
+    let mut fits = |item_len: usize| {
+        let with_item = len.saturating_add(item_len);
+        let fits = force || nested_entry_len(with_item) <= budget;
+        if fits {
+            len = with_item;
+            force = false;
+        }
+        fits
+    };
+    for variant in info.variant_resolve_info {
+        if fits(entry_len(&variant)) {
+            head.variant_resolve_info.push(variant);
+        } else {
+            tail.variant_resolve_info.push(variant);
+        }
+    }
+    for rule in info.rule_resolve_info {
+        if fits(entry_len(&rule)) {
+            head.rule_resolve_info.push(rule);
+        } else {
+            tail.rule_resolve_info.push(rule);
+        }
+    }
+    let non_empty = |info: pb::FlagResolveInfo| {
+        (!info.variant_resolve_info.is_empty() || !info.rule_resolve_info.is_empty())
+            .then_some(info)
+    };
+    (non_empty(head), non_empty(tail))
+}
+
+/// Encoded length of `message` as a repeated field entry of a request.
+fn entry_len<M: Message>(message: &M) -> usize {
+    nested_entry_len(message.encoded_len())
+}
+
+/// Encoded length of a repeated field entry of a message encoding to `len` bytes.
+fn nested_entry_len(len: usize) -> usize {
+    // the extra one is for the proto type and field id
+    len.saturating_add(length_delimiter_len(len))
+        .saturating_add(1)
+}
+
+fn extract_client(credential: &str) -> String {
+    ResourceName::parse_as(credential, ResourceKind::ClientCredential)
+        .ok()
+        .and_then(|name| name.parent())
+        .map_or(credential, |client| client.as_str())
+        .to_string()
+}
+
 fn to_pb_schema_instance(
     schema: &DerivedClientSchema,
 ) -> pb::client_resolve_info::EvaluationContextSchemaInstance {
@@ -238,6 +700,10 @@ fn build_client_resolve_info(state: &ResolveInfoState) -> Vec<pb::ClientResolveI
                 client,
                 client_credential: credential.clone(),
                 schema: schemas,
+                context_samples: lock(&info.samples)
+                    .iter()
+                    .map(|(_, sample)| sample.clone())
+                    .collect(),
             }
         })
         .collect()
@@ -245,10 +711,12 @@ fn build_client_resolve_info(state: &ResolveInfoState) -> Vec<pb::ClientResolveI
 
 fn to_pb_variant(
     (variant_key, counter): (&String, &AtomicU32),
+    synthetic_counter: Option<&AtomicU32>,
 ) -> pb::flag_resolve_info::VariantResolveInfo {
     pb::flag_resolve_info::VariantResolveInfo {
         variant: variant_key.clone(),
         count: counter.load(Ordering::Relaxed) as i64,
+        synthetic_count: synthetic_counter.map_or(0, |c| c.load(Ordering::Relaxed) as i64),
     }
 }
 
@@ -278,7 +746,11 @@ fn build_flag_resolve_info(state: &ResolveInfoState) -> Vec<pb::FlagResolveInfo>
     mp.iter()
         .map(|(flag_name, info)| {
             let vp = info.variant_resolve_info.pin();
-            let variants = vp.iter().map(to_pb_variant).collect();
+            let sp = info.synthetic_variant_resolve_info.pin();
+            let variants = vp
+                .iter()
+                .map(|variant| to_pb_variant(variant, sp.get(variant.0)))
+                .collect();
 
             let rp = info.rule_resolve_info.pin();
             let rules = rp.iter().map(to_pb_rule).collect();
@@ -340,8 +812,9 @@ mod tests {
             },
             google::Struct,
         },
-        resolve_logger::{pb::WriteFlagLogsRequest, ResolveLogger},
-        Account, Client, Host,
+        flag_logger::{self, LogBacklog},
+        resolve_logger::{pb::WriteFlagLogsRequest, ResolveLogger, MAX_CONTEXT_SAMPLES},
+        Account, Client, ContextLogging, Host,
     };
     use crate::proto::confidence::flags::admin::v1::context_field_semantic_type::country_semantic_type::CountryFormat;
     use crate::proto::google::Timestamp;
@@ -383,14 +856,14 @@ mod tests {
 
         fn encrypt_resolve_token(
             token_data: &[u8],
-            _encryption_key: &[u8],
+            _encryption_key: &crate::EncryptionKey,
         ) -> Result<Vec<u8>, String> {
             Ok(token_data.to_vec())
         }
 
         fn decrypt_resolve_token(
             token_data: &[u8],
-            _encryption_key: &[u8],
+            _encryption_key: &crate::EncryptionKey,
         ) -> Result<Vec<u8>, String> {
             Ok(token_data.to_vec())
         }
@@ -403,6 +876,7 @@ mod tests {
             },
             client_name: "test-client".to_string(),
             client_credential_name: "clients/test/clientCredentials/test".to_string(),
+            context_logging: Default::default(),
         }
     }
 
@@ -501,6 +975,97 @@ mod tests {
         assert_eq!(schema.semantic_types, expected_sem);
     }
 
+    #[test]
+    fn context_logging_none_skips_the_credential() {
+        let logger = ResolveLogger::<TestHost>::new();
+        let client = Client {
+            context_logging: ContextLogging::None,
+            ..test_client()
+        };
+        let ctx: Struct = serde_json::from_value(json!({"country": "SE"})).unwrap();
+        logger.log_resolve(
+            "id",
+            &ctx,
+            &client.client_credential_name,
+            &[],
+            &client,
+            &None,
+        );
+        let req = logger.checkpoint();
+        assert!(req.client_resolve_info.is_empty());
+        assert_eq!(req.telemetry_data.unwrap().resolve_count, 1);
+    }
+
+    #[test]
+    fn context_logging_sampled_redacts_and_bounds_samples() {
+        let logger = ResolveLogger::<TestHost>::new();
+        let client = Client {
+            context_logging: ContextLogging::Sampled {
+                one_in: 1,
+                redacted_fields: vec![
+                    "user.email".to_string(),
+                    "missing.field".to_string(),
+                    "devices.serial".to_string(),
+                ],
+            },
+            ..test_client()
+        };
+        let cred = client.client_credential_name.clone();
+        let context = |id: usize, email: &str| -> Struct {
+            serde_json::from_value(json!({
+                "country": "SE",
+                "user": {"id": id, "email": email},
+                "devices": [{"os": "ios", "serial": email}, "tablet"]
+            }))
+            .unwrap()
+        };
+        // contexts that only differ in redacted fields are sampled once
+        logger.log_resolve(
+            "id",
+            &context(0, "other@example.com"),
+            &cred,
+            &[],
+            &client,
+            &None,
+        );
+        for i in 0..MAX_CONTEXT_SAMPLES + 1 {
+            let ctx = context(i, "u1@example.com");
+            logger.log_resolve(&format!("id-{}", i), &ctx, &cred, &[], &client, &None);
+        }
+        let req = logger.checkpoint();
+        let crec = &req.client_resolve_info[0];
+        assert_eq!(crec.schema.len(), 1);
+        assert_eq!(crec.context_samples.len(), MAX_CONTEXT_SAMPLES);
+        let expected = |id: usize| -> Struct {
+            serde_json::from_value(json!({
+                "country": "SE",
+                "user": {"id": id},
+                "devices": [{"os": "ios"}, "tablet"]
+            }))
+            .unwrap()
+        };
+        assert_eq!(crec.context_samples[0], expected(0));
+        assert_eq!(crec.context_samples[1], expected(1));
+        let ctx = context(0, "u1@example.com");
+
+        // the schema only is logged by default
+        logger.log_resolve("id", &ctx, &cred, &[], &test_client(), &None);
+        let req = logger.checkpoint();
+        assert!(req.client_resolve_info[0].context_samples.is_empty());
+
+        // and when nothing is sampled
+        let unsampled = Client {
+            context_logging: ContextLogging::Sampled {
+                one_in: 0,
+                redacted_fields: vec![],
+            },
+            ..test_client()
+        };
+        logger.log_resolve("id", &ctx, &cred, &[], &unsampled, &None);
+        let req = logger.checkpoint();
+        assert!(req.client_resolve_info[0].context_samples.is_empty());
+    }
+
     #[test]
     fn decorates_with_list_schema() {
         let logger = ResolveLogger::<TestHost>::new();
@@ -608,6 +1173,63 @@ mod tests {
         );
     }
 
+    #[test]
+    fn synthetic_resolves_are_counted_apart() {
+        use crate::proto::confidence::flags::admin::v1::Flag;
+
+        let logger = ResolveLogger::<TestHost>::new();
+        let flag = Flag {
+            name: "flags/test".into(),
+            ..Default::default()
+        };
+        let rv = [crate::ResolvedValue::new(&flag)];
+        let synthetic: Struct =
+            serde_json::from_value(json!({"confidence_synthetic": true})).unwrap();
+
+        let client = test_client();
+        let cred = "clients/test/clientCredentials/test";
+        logger.log_resolve("a", &Struct::default(), cred, &rv, &client, &None);
+        logger.log_resolve("b", &synthetic, cred, &rv, &client, &None);
+        logger.log_resolve("c", &synthetic, cred, &rv, &client, &None);
+        let req = logger.checkpoint();
+
+        let variant = &req.flag_resolve_info[0].variant_resolve_info[0];
+        assert_eq!(variant.count, 3);
+        assert_eq!(variant.synthetic_count, 2);
+    }
+
+    #[test]
+    fn killed_flags_count_under_the_killed_variant() {
+        use crate::proto::confidence::flags::admin::v1::{flag::Variant, Flag};
+
+        let logger = ResolveLogger::<TestHost>::new();
+        let flag = Flag {
+            name: "flags/test".into(),
+            ..Default::default()
+        };
+        let variant = Variant {
+            name: "flags/test/variants/off".into(),
+            ..Default::default()
+        };
+        let mut killed = crate::ResolvedValue::new(&flag);
+        killed.killed_variant = Some(&variant);
+        let no_match = crate::ResolvedValue::new(&flag);
+
+        let client = test_client();
+        let cred = "clients/test/clientCredentials/test";
+        logger.log_resolve("a", &Struct::default(), cred, &[killed], &client, &None);
+        logger.log_resolve("b", &Struct::default(), cred, &[no_match], &client, &None);
+        let req = logger.checkpoint();
+
+        let mut variants: Vec<_> = req.flag_resolve_info[0]
+            .variant_resolve_info
+            .iter()
+            .map(|v| (v.variant.as_str(), v.count))
+            .collect();
+        variants.sort();
+        assert_eq!(variants, vec![("", 1), ("flags/test/variants/off", 1)]);
+    }
+
     #[test]
     fn fallthrough_resolve_stats() {
         use crate::proto::confidence::flags::admin::v1::{
@@ -640,7 +1262,21 @@ mod tests {
         };
 
         let mut rv = crate::ResolvedValue::new(&flag);
-        rv.attribute_fallthrough_rule(&fallthrough_rule, "control", "user123");
+        rv.record_fallthrough(
+            &fallthrough_rule,
+            crate::FallthroughReason::Assignment,
+            "control",
+            "user123",
+            usize::MAX,
+        );
+        // a rule the unit skipped isn't counted as resolved
+        rv.record_fallthrough(
+            &match_rule,
+            crate::FallthroughReason::MissingTargetingKey,
+            "",
+            "",
+            usize::MAX,
+        );
         let rv = [rv.with_variant_match(&match_rule, &segment, &match_variant, "final", "user123")];
 
         let client = test_client();
@@ -802,4 +1438,325 @@ mod tests {
         assert_eq!(sum_rules, total_expected);
         assert_eq!(sum_assign, total_expected);
     }
+
+    #[test]
+    fn telemetry_counts_are_deltas_with_sequence_numbers() {
+        use crate::proto::confidence::flags::admin::v1::Flag;
+
+        let logger = ResolveLogger::<TestHost>::new();
+        let flag = Flag {
+            name: "flags/counted".into(),
+            ..Default::default()
+        };
+        let client = test_client();
+        let cred = "clients/test/clientCredentials/test";
+        let log = |n: usize| {
+            for _ in 0..n {
+                let rv = [crate::ResolvedValue::new(&flag)];
+                logger.log_resolve("id", &Struct::default(), cred, &rv, &client, &None);
+            }
+        };
+
+        log(3);
+        let first = logger.checkpoint().telemetry_data.unwrap();
+        assert_eq!(first.resolve_count, 3);
+        assert_eq!(first.sequence_number, 1);
+        assert!(!first.client_instance_id.is_empty());
+
+        // nothing logged, nothing to number
+        assert!(logger.checkpoint().telemetry_data.is_none());
+
+        log(2);
+        let second = logger.checkpoint().telemetry_data.unwrap();
+        assert_eq!(second.resolve_count, 2);
+        assert_eq!(second.sequence_number, 2);
+        assert_eq!(second.client_instance_id, first.client_instance_id);
+
+        // merged checkpoints keep their own counts and sequence numbers
+        log(1);
+        let third = logger.checkpoint();
+        log(4);
+        let fourth = logger.checkpoint();
+        let merged = flag_logger::aggregate_batch(vec![third, fourth])
+            .telemetry_data
+            .unwrap();
+        assert_eq!(merged.resolve_count, 5);
+        let counts: Vec<(&str, i64, i64)> = merged
+            .instance_resolve_counts
+            .iter()
+            .map(|c| {
+                (
+                    c.client_instance_id.as_str(),
+                    c.resolve_count,
+                    c.sequence_number,
+                )
+            })
+            .collect();
+        let id = first.client_instance_id.as_str();
+        assert_eq!(counts, vec![(id, 1, 3), (id, 4, 4)]);
+    }
+
+    #[test]
+    fn backlog_counts_resolves_since_checkpoint() {
+        use crate::proto::confidence::flags::admin::v1::Flag;
+
+        let logger = ResolveLogger::<TestHost>::new();
+        let flag = Flag {
+            name: "flags/counted".into(),
+            ..Default::default()
+        };
+        let client = test_client();
+        let cred = "clients/test/clientCredentials/test";
+        assert_eq!(logger.backlog(), LogBacklog::default());
+
+        for _ in 0..3 {
+            let rv = [crate::ResolvedValue::new(&flag)];
+            logger.log_resolve("id", &Struct::default(), cred, &rv, &client, &None);
+        }
+        let backlog = logger.backlog();
+        assert_eq!(backlog.events, 3);
+        assert!(backlog.bytes >= flag.name.len() + cred.len());
+
+        logger.checkpoint();
+        assert_eq!(logger.backlog(), LogBacklog::default());
+    }
+
+    static WINDOW_NOW: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(0);
+
+    struct WindowHost;
+    impl Host for WindowHost {
+        #[cfg(not(feature = "std"))]
+        fn random_alphanumeric(_len: usize) -> String {
+            "random".to_string()
+        }
+
+        fn current_time() -> Timestamp {
+            Timestamp {
+                seconds: WINDOW_NOW.load(std::sync::atomic::Ordering::SeqCst),
+                nanos: 0,
+            }
+        }
+
+        fn log_resolve(
+            _resolve_id: &str,
+            _evaluation_context: &Struct,
+            _values: &[crate::ResolvedValue<'_>],
+            _client: &Client,
+            _sdk: &Option<crate::flags_resolver::Sdk>,
+        ) {
+        }
+
+        fn log_assign(
+            _resolve_id: &str,
+            _evaluation_context: &Struct,
+            _assigned_flags: &[crate::FlagToApply],
+            _client: &Client,
+            _sdk: &Option<crate::flags_resolver::Sdk>,
+        ) {
+        }
+    }
+
+    fn variant_count(req: &WriteFlagLogsRequest, flag: &str) -> i64 {
+        req.flag_resolve_info
+            .iter()
+            .filter(|f| f.flag == flag)
+            .flat_map(|f| f.variant_resolve_info.iter())
+            .map(|v| v.count)
+            .sum()
+    }
+
+    #[test]
+    fn time_windowed_checkpoints() {
+        use crate::proto::confidence::flags::admin::v1::Flag;
+
+        let logger = ResolveLogger::<WindowHost>::with_time_windows(60, 10);
+        let flag = Flag {
+            name: "flags/windowed".into(),
+            ..Default::default()
+        };
+        let client = test_client();
+        let cred = "clients/test/clientCredentials/test";
+        let log_at = |seconds: i64| {
+            WINDOW_NOW.store(seconds, std::sync::atomic::Ordering::SeqCst);
+            let rv = [crate::ResolvedValue::new(&flag)];
+            logger.log_resolve("id", &Struct::default(), cred, &rv, &client, &None);
+        };
+
+        log_at(600);
+        log_at(659);
+        log_at(660);
+        log_at(790);
+
+        let windows = logger.checkpoint_windows();
+        let starts: Vec<i64> = windows.iter().map(|w| w.window_start.seconds).collect();
+        assert_eq!(starts, vec![600, 660]);
+        let counts: Vec<i64> = windows
+            .iter()
+            .map(|w| variant_count(&w.request, &flag.name))
+            .collect();
+        assert_eq!(counts, vec![2, 1]);
+
+        // the open window is kept until it ends, so it is reported once with all its resolves
+        log_at(800);
+        assert!(logger.checkpoint_windows().is_empty());
+        WINDOW_NOW.store(840, std::sync::atomic::Ordering::SeqCst);
+        let windows = logger.checkpoint_windows();
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].window_start.seconds, 780);
+        assert_eq!(variant_count(&windows[0].request, &flag.name), 2);
+
+        // windows are closed on rotation; the next checkpoint only has what came after
+        log_at(850);
+        let req = logger.checkpoint();
+        assert_eq!(variant_count(&req, &flag.name), 1);
+    }
+
+    #[test]
+    fn time_windows_are_bounded() {
+        use crate::proto::confidence::flags::admin::v1::Flag;
+
+        let logger = ResolveLogger::<WindowHost>::with_time_windows(1, 2);
+        let flag = Flag {
+            name: "flags/bounded".into(),
+            ..Default::default()
+        };
+        let client = test_client();
+        let cred = "clients/test/clientCredentials/test";
+        for seconds in 1000..1005 {
+            WINDOW_NOW.store(seconds, std::sync::atomic::Ordering::SeqCst);
+            let rv = [crate::ResolvedValue::new(&flag)];
+            logger.log_resolve("id", &Struct::default(), cred, &rv, &client, &None);
+        }
+
+        let windows = logger.checkpoint_windows();
+        // two closed windows, the open one is kept
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].window_start.seconds, 1000);
+        let total: i64 = windows
+            .iter()
+            .map(|w| variant_count(&w.request, &flag.name))
+            .sum();
+        assert_eq!(total, 4);
+    }
+
+    #[test]
+    fn checkpoint_with_limit_holds_back_what_does_not_fit() {
+        use crate::proto::confidence::flags::admin::v1::Flag;
+        use prost::Message;
+
+        let logger = ResolveLogger::<TestHost>::new();
+        let flags: Vec<Flag> = (0..20)
+            .map(|i| Flag {
+                name: format!("flags/limited-{}", i),
+                ..Default::default()
+            })
+            .collect();
+        let client = test_client();
+        let cred = "clients/test/clientCredentials/test";
+        let rv: Vec<_> = flags.iter().map(crate::ResolvedValue::new).collect();
+        logger.log_resolve("id", &Struct::default(), cred, &rv, &client, &None);
+
+        let limit = 200;
+        let mut requests = vec![logger.checkpoint_with_limit(limit)];
+        while logger.has_overflow() {
+            requests.push(logger.checkpoint_with_limit(limit));
+        }
+        assert!(requests.len() > 1);
+        assert!(requests.iter().all(|r| r.encoded_len() <= limit));
+        let mut names: Vec<&str> = requests
+            .iter()
+            .flat_map(|r| r.flag_resolve_info.iter())
+            .map(|f| f.flag.as_str())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), flags.len());
+        assert_eq!(
+            requests
+                .iter()
+                .map(|r| r.client_resolve_info.len())
+                .sum::<usize>(),
+            1
+        );
+
+        // an entry larger than the limit is still returned, alone
+        logger.log_resolve("id", &Struct::default(), cred, &rv, &client, &None);
+        let req = logger.checkpoint_with_limit(1);
+        assert_eq!(
+            req.client_resolve_info.len() + req.flag_resolve_info.len(),
+            1
+        );
+        // unbounded checkpoints return everything held back
+        let req = logger.checkpoint();
+        assert!(!logger.has_overflow());
+        assert_eq!(req.flag_resolve_info.len(), flags.len());
+    }
+
+    #[test]
+    fn checkpoint_with_limit_splits_large_flags() {
+        use crate::proto::confidence::flags::admin::v1::{
+            flag::{Rule, Variant},
+            Flag, Segment,
+        };
+        use prost::Message;
+
+        let logger = ResolveLogger::<TestHost>::new();
+        let flag = Flag {
+            name: "flags/programmatic".into(),
+            ..Default::default()
+        };
+        let segment = Segment {
+            name: "segments/test".into(),
+            ..Default::default()
+        };
+        let rules: Vec<Rule> = (0..50)
+            .map(|i| Rule {
+                name: format!("flags/programmatic/rules/r{}", i),
+                ..Default::default()
+            })
+            .collect();
+        let variants: Vec<Variant> = (0..50)
+            .map(|i| Variant {
+                name: format!("flags/programmatic/variants/v{}", i),
+                ..Default::default()
+            })
+            .collect();
+        let rv: Vec<_> = rules
+            .iter()
+            .zip(&variants)
+            .map(|(rule, variant)| {
+                crate::ResolvedValue::new(&flag).with_variant_match(
+                    rule,
+                    &segment,
+                    variant,
+                    "assignment",
+                    "user",
+                )
+            })
+            .collect();
+        let client = test_client();
+        let cred = "clients/test/clientCredentials/test";
+        for _ in 0..2 {
+            logger.log_resolve("id", &Struct::default(), cred, &rv, &client, &None);
+        }
+
+        let limit = 500;
+        let mut requests = vec![logger.checkpoint_with_limit(limit)];
+        while logger.has_overflow() {
+            requests.push(logger.checkpoint_with_limit(limit));
+        }
+        assert!(requests.len() > 1);
+        assert!(requests.iter().all(|r| r.encoded_len() <= limit));
+        assert!(requests.iter().all(|r| r.flag_resolve_info.len() == 1));
+
+        let merged = flag_logger::aggregate_batch(requests);
+        let info = &merged.flag_resolve_info[0];
+        assert_eq!(info.variant_resolve_info.len(), variants.len());
+        assert!(info.variant_resolve_info.iter().all(|v| v.count == 2));
+        assert_eq!(info.rule_resolve_info.len(), rules.len());
+        assert!(info
+            .rule_resolve_info
+            .iter()
+            .all(|r| r.count == 2 && r.assignment_resolve_info[0].count == 2));
+    }
 }
@@ -0,0 +1,449 @@
+//! Framing of resolve tokens. Tokens start with a short header naming the format version and
+//! how the payload is protected, so a resolver can tell a plaintext token from an encrypted
+//! one and fail with a clear error instead of a decryption or decode failure. Tokens without
+//! the header, issued before it was introduced, are still accepted and handed to the host as
+//! before. The header's scheme is the [`EncryptionProvider::key_id`] of what encrypted the
+//! token, see [`encryption`](crate::encryption).
+//!
+//! Tokens encrypted with a key that has an [id](EncryptionKey::id) carry the id in the header,
+//! so that a resolver rotating keys decrypts them with the right one of its keys.
+
+use crate::encryption::{
+    self, EncryptionProvider, KEY_ID_HMAC_SHA256, KEY_ID_HOST, KEY_ID_PLAINTEXT,
+};
+use crate::{metrics, EncryptionKey, Host};
+
+/// Leading bytes of a framed token. `0xff` can't start an encoded `ResolveToken`, so framed
+/// tokens never collide with legacy plaintext tokens.
+const MAGIC: [u8; 4] = [0xff, b'C', b'R', b'T'];
+const VERSION: u8 = 1;
+/// Like [`VERSION`], followed by the big endian `u32` id of the encryption key.
+const VERSION_KEY_ID: u8 = 2;
+/// Framed token versions [`sniff`] accepts.
+pub const SUPPORTED_VERSIONS: &[u8] = &[VERSION, VERSION_KEY_ID];
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const KEY_ID_HEADER_LEN: usize = HEADER_LEN + 4;
+
+/// How a resolve token is protected, see [`sniff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenFormat {
+    /// A token without header, either plaintext or encrypted by the host.
+    Legacy,
+    Plaintext,
+    /// Encrypted by [`Host::encrypt_resolve_token`].
+    Encrypted,
+    /// Encrypted by the [`EncryptionProvider`] with this key id.
+    EncryptedWith(u8),
+    /// Plaintext with a signature, see [`HmacSha256`](encryption::HmacSha256).
+    Signed,
+}
+
+struct Header<'a> {
+    format: TokenFormat,
+    encryption_key_id: Option<u32>,
+    payload: &'a [u8],
+}
+
+/// Reads the format of a resolve token from its header.
+pub fn sniff(token: &[u8]) -> Result<TokenFormat, String> {
+    header(token).map(|header| header.format)
+}
+
+fn header(token: &[u8]) -> Result<Header<'_>, String> {
+    let Some(rest) = token.strip_prefix(&MAGIC) else {
+        return Ok(Header {
+            format: TokenFormat::Legacy,
+            encryption_key_id: None,
+            payload: token,
+        });
+    };
+    let truncated = || "resolve token header is truncated".to_string();
+    let (&version, rest) = rest.split_first().ok_or_else(truncated)?;
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(format!(
+            "resolve token has unsupported format version {}, this resolver supports up to {}",
+            version, VERSION_KEY_ID
+        ));
+    }
+    let (&scheme, rest) = rest.split_first().ok_or_else(truncated)?;
+    let (encryption_key_id, payload) = if version == VERSION_KEY_ID {
+        let (id, payload) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+        (Some(u32::from_be_bytes(*id)), payload)
+    } else {
+        (None, rest)
+    };
+    let format = match scheme {
+        KEY_ID_PLAINTEXT => TokenFormat::Plaintext,
+        KEY_ID_HOST => TokenFormat::Encrypted,
+        KEY_ID_HMAC_SHA256 => TokenFormat::Signed,
+        key_id => TokenFormat::EncryptedWith(key_id),
+    };
+    Ok(Header {
+        format,
+        encryption_key_id,
+        payload,
+    })
+}
+
+/// Frames an encoded token. An all-zero key means the resolver runs without encryption and the
+/// token is stored as plaintext; otherwise the host's encryption provider, or the host itself,
+/// encrypts it.
+pub(crate) fn seal<H: Host>(
+    token: &[u8],
+    encryption_key: &EncryptionKey,
+) -> Result<Vec<u8>, String> {
+    seal_with::<H>(token, encryption_key, H::encryption_provider())
+}
+
+/// Like [`seal`], with `provider` instead of the host's encryption provider.
+pub(crate) fn seal_with<H: Host>(
+    token: &[u8],
+    encryption_key: &EncryptionKey,
+    provider: Option<&dyn EncryptionProvider>,
+) -> Result<Vec<u8>, String> {
+    let (scheme, payload) = if encryption_key.is_zero() {
+        (KEY_ID_PLAINTEXT, Ok(token.to_vec()))
+    } else if let Some(provider) = provider {
+        let mut nonce = [0u8; encryption::NONCE_LEN];
+        (
+            provider.key_id(),
+            H::random_bytes(&mut nonce)
+                .and_then(|()| provider.encrypt_with_nonce(token, encryption_key, &nonce)),
+        )
+    } else {
+        (KEY_ID_HOST, H::encrypt_resolve_token(token, encryption_key))
+    };
+    let payload =
+        payload.inspect_err(|_| H::on_metric(metrics::TOKEN_ENCRYPT_FAILURE, 1.0, &[]))?;
+    let mut sealed = Vec::with_capacity(payload.len().saturating_add(KEY_ID_HEADER_LEN));
+    sealed.extend_from_slice(&MAGIC);
+    match encryption_key.id().filter(|_| !encryption_key.is_zero()) {
+        Some(id) => {
+            sealed.extend_from_slice(&[VERSION_KEY_ID, scheme]);
+            sealed.extend_from_slice(&id.to_be_bytes());
+        }
+        None => sealed.extend_from_slice(&[VERSION, scheme]),
+    }
+    sealed.extend_from_slice(&payload);
+    Ok(sealed)
+}
+
+/// Returns the encoded token inside `sealed`. Plaintext tokens are rejected by resolvers that
+/// have an encryption key, since anyone could have made them, unless `allow_plaintext` is set
+/// while moving from unencrypted to encrypted tokens, or the host uses
+/// [`NullEncryption`](encryption::NullEncryption). Tokens of another provider than the host's
+/// are opened by the built-in provider with their key id, which for signed tokens checks the
+/// signature.
+///
+/// Tokens encrypted by the host, and legacy tokens, aren't authenticated. Resolvers whose
+/// provider is [AEAD](EncryptionProvider::is_aead) reject them, so that they can't be forged
+/// to get around the provider, unless it is before `legacy_until`, in seconds since the epoch.
+///
+/// Encrypted tokens are decrypted with the key, `encryption_key` or one of `secondary_keys`,
+/// whose id is in the token. Tokens without key id are tried with the keys without id, the
+/// primary key first.
+pub(crate) fn open<H: Host>(
+    sealed: &[u8],
+    encryption_key: &EncryptionKey,
+    secondary_keys: &[EncryptionKey],
+    allow_plaintext: bool,
+    legacy_until: Option<i64>,
+) -> Result<Vec<u8>, String> {
+    let header = header(sealed)?;
+    let provider = H::encryption_provider();
+    let scheme = match header.format {
+        TokenFormat::EncryptedWith(key_id) => Some(key_id),
+        TokenFormat::Signed => Some(KEY_ID_HMAC_SHA256),
+        _ => None,
+    };
+    let decrypt = |token: &[u8], key: &EncryptionKey| match scheme {
+        Some(key_id) => provider
+            .filter(|p| p.key_id() == key_id)
+            .or_else(|| encryption::builtin(key_id))
+            .ok_or_else(|| format!("resolve token uses unknown encryption scheme {}", key_id))?
+            .decrypt(token, key),
+        None => H::decrypt_resolve_token(token, key),
+    };
+    if header.format == TokenFormat::Plaintext {
+        let null_encryption = provider.is_some_and(|p| p.key_id() == KEY_ID_PLAINTEXT);
+        return if encryption_key.is_zero() || allow_plaintext || null_encryption {
+            Ok(header.payload.to_vec())
+        } else {
+            Err(
+                "resolve token is not encrypted but this resolver requires encrypted tokens"
+                    .to_string(),
+            )
+        };
+    }
+    if header.format != TokenFormat::Legacy && encryption_key.is_zero() {
+        return Err(
+            "resolve token is encrypted but this resolver has no encryption key".to_string(),
+        );
+    }
+    if matches!(header.format, TokenFormat::Legacy | TokenFormat::Encrypted)
+        && !encryption_key.is_zero()
+        && provider.is_some_and(|p| p.is_aead())
+        && !legacy_until.is_some_and(|until| H::current_time().seconds < until)
+    {
+        return Err(
+            "resolve token is not authenticated but this resolver requires AEAD encrypted tokens"
+                .to_string(),
+        );
+    }
+
+    let keys = core::iter::once(encryption_key)
+        .chain(secondary_keys)
+        .filter(|key| key.id() == header.encryption_key_id);
+    let mut result = Err(match header.encryption_key_id {
+        Some(id) => format!("resolve token was encrypted with unknown key {}", id),
+        None => "resolve token has no key id, but all keys of this resolver have one".to_string(),
+    });
+    for key in keys {
+        result = decrypt(header.payload, key);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result.inspect_err(|_| H::on_metric(metrics::TOKEN_DECRYPT_FAILURE, 1.0, &[]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestHost;
+
+    const KEY: EncryptionKey = EncryptionKey::aes128([7; 16]);
+    const ZERO_KEY: EncryptionKey = EncryptionKey::ZERO;
+
+    #[test]
+    fn round_trips_with_and_without_key() {
+        let token = b"token".to_vec();
+        for key in [KEY, ZERO_KEY] {
+            let sealed = seal::<TestHost>(&token, &key).unwrap();
+            assert_eq!(
+                open::<TestHost>(&sealed, &key, &[], false, None).unwrap(),
+                token
+            );
+        }
+        assert_eq!(
+            sniff(&seal::<TestHost>(&token, &ZERO_KEY).unwrap()),
+            Ok(TokenFormat::Plaintext)
+        );
+        assert_eq!(
+            sniff(&seal::<TestHost>(&token, &KEY).unwrap()),
+            Ok(TokenFormat::Encrypted)
+        );
+    }
+
+    #[test]
+    fn rejects_downgraded_and_mismatched_tokens() {
+        let token = b"token".to_vec();
+        let plaintext = seal::<TestHost>(&token, &ZERO_KEY).unwrap();
+        let err = open::<TestHost>(&plaintext, &KEY, &[], false, None).unwrap_err();
+        assert!(err.contains("not encrypted"), "{}", err);
+        assert_eq!(
+            open::<TestHost>(&plaintext, &KEY, &[], true, None).unwrap(),
+            token
+        );
+
+        let encrypted = seal::<TestHost>(&token, &KEY).unwrap();
+        let err = open::<TestHost>(&encrypted, &ZERO_KEY, &[], true, None).unwrap_err();
+        assert!(err.contains("no encryption key"), "{}", err);
+    }
+
+    #[test]
+    fn reports_unknown_versions_and_schemes() {
+        let mut token = MAGIC.to_vec();
+        assert!(sniff(&token).unwrap_err().contains("truncated"));
+        token.extend_from_slice(&[3, KEY_ID_PLAINTEXT]);
+        assert!(sniff(&token).unwrap_err().contains("version 3"));
+        token[MAGIC.len()] = VERSION;
+        token[MAGIC.len() + 1] = 9;
+        assert_eq!(sniff(&token), Ok(TokenFormat::EncryptedWith(9)));
+        let err = open::<TestHost>(&token, &KEY, &[], false, None).unwrap_err();
+        assert!(err.contains("scheme 9"), "{}", err);
+    }
+
+    /// Flips the bits of tokens, which is enough to tell providers apart.
+    struct Xor(u8);
+
+    impl EncryptionProvider for Xor {
+        fn key_id(&self) -> u8 {
+            encryption::FIRST_CUSTOM_KEY_ID
+        }
+
+        fn encrypt(&self, token: &[u8], _key: &EncryptionKey) -> Result<Vec<u8>, String> {
+            Ok(token.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decrypt(&self, encrypted: &[u8], key: &EncryptionKey) -> Result<Vec<u8>, String> {
+            self.encrypt(encrypted, key)
+        }
+    }
+
+    static XOR: Xor = Xor(0x5a);
+
+    #[test]
+    fn encrypts_with_the_host_provider() {
+        TestHost::reset();
+        TestHost::set_encryption_provider(&XOR);
+        let sealed = seal::<TestHost>(b"token", &KEY).unwrap();
+        assert_eq!(
+            sniff(&sealed),
+            Ok(TokenFormat::EncryptedWith(encryption::FIRST_CUSTOM_KEY_ID))
+        );
+        assert_eq!(
+            sealed.get(HEADER_LEN..),
+            Some(XOR.encrypt(b"token", &KEY).unwrap().as_slice())
+        );
+        assert_eq!(
+            open::<TestHost>(&sealed, &KEY, &[], false, None).unwrap(),
+            b"token"
+        );
+
+        // once the host moves on, the token's provider is unknown
+        TestHost::reset();
+        let err = open::<TestHost>(&sealed, &KEY, &[], false, None).unwrap_err();
+        assert!(err.contains("unknown encryption scheme"), "{}", err);
+        // while tokens of the default encryption still open with a provider in place
+        let host_sealed = seal::<TestHost>(b"token", &KEY).unwrap();
+        TestHost::set_encryption_provider(&XOR);
+        assert_eq!(
+            open::<TestHost>(&host_sealed, &KEY, &[], false, None).unwrap(),
+            b"token"
+        );
+    }
+
+    #[test]
+    fn null_encryption_accepts_plaintext_tokens() {
+        TestHost::reset();
+        TestHost::set_encryption_provider(&encryption::NullEncryption);
+        let sealed = seal::<TestHost>(b"token", &KEY).unwrap();
+        assert_eq!(sniff(&sealed), Ok(TokenFormat::Plaintext));
+        assert_eq!(
+            open::<TestHost>(&sealed, &KEY, &[], false, None).unwrap(),
+            b"token"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn verifies_signed_tokens() {
+        TestHost::reset();
+        let sealed = seal_with::<TestHost>(b"token", &KEY, Some(&encryption::HmacSha256)).unwrap();
+        assert_eq!(sniff(&sealed), Ok(TokenFormat::Signed));
+        assert_eq!(
+            sealed.get(HEADER_LEN..HEADER_LEN + 5),
+            Some(b"token".as_slice())
+        );
+        assert_eq!(
+            open::<TestHost>(&sealed, &KEY, &[], false, None).unwrap(),
+            b"token"
+        );
+
+        let mut tampered = sealed.clone();
+        tampered[HEADER_LEN] ^= 1;
+        let err = open::<TestHost>(&tampered, &KEY, &[], false, None).unwrap_err();
+        assert!(err.contains("signature is invalid"), "{}", err);
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[test]
+    fn opens_tokens_of_builtin_providers() {
+        TestHost::reset();
+        TestHost::set_encryption_provider(&encryption::AesGcm);
+        let sealed = seal::<TestHost>(b"token", &KEY).unwrap();
+        assert_eq!(
+            sniff(&sealed),
+            Ok(TokenFormat::EncryptedWith(encryption::KEY_ID_AES_GCM))
+        );
+        TestHost::set_encryption_provider(&XOR);
+        assert_eq!(
+            open::<TestHost>(&sealed, &KEY, &[], false, None).unwrap(),
+            b"token"
+        );
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[test]
+    fn aead_resolvers_reject_host_encrypted_tokens() {
+        TestHost::reset();
+        TestHost::set_time(1_000);
+        // anyone who can produce host encrypted payloads can forge a scheme 1 token
+        let mut forged = MAGIC.to_vec();
+        forged.extend_from_slice(&[VERSION, KEY_ID_HOST]);
+        forged.extend_from_slice(&TestHost::encrypt_resolve_token(b"forged", &KEY).unwrap());
+        let legacy = TestHost::encrypt_resolve_token(b"forged", &KEY).unwrap();
+        TestHost::set_encryption_provider(&encryption::AesGcm);
+        for token in [&forged, &legacy] {
+            let err = open::<TestHost>(token, &KEY, &[], false, None).unwrap_err();
+            assert!(err.contains("not authenticated"), "{}", err);
+            let err = open::<TestHost>(token, &KEY, &[], false, Some(1_000)).unwrap_err();
+            assert!(err.contains("not authenticated"), "{}", err);
+            // until the migration ends
+            assert_eq!(
+                open::<TestHost>(token, &KEY, &[], false, Some(1_001)).unwrap(),
+                b"forged"
+            );
+        }
+
+        // tokens of the provider itself are unaffected
+        let sealed = seal::<TestHost>(b"token", &KEY).unwrap();
+        assert_eq!(
+            open::<TestHost>(&sealed, &KEY, &[], false, None).unwrap(),
+            b"token"
+        );
+    }
+
+    #[test]
+    fn decrypts_with_the_key_in_the_token() {
+        TestHost::reset();
+        let old = KEY.with_id(1);
+        let new = EncryptionKey::aes128([8; 16]).with_id(2);
+        let sealed = seal::<TestHost>(b"token", &old).unwrap();
+        assert_eq!(sniff(&sealed), Ok(TokenFormat::Encrypted));
+        assert_eq!(
+            sealed.get(MAGIC.len()..KEY_ID_HEADER_LEN),
+            Some([2, 1, 0, 0, 0, 1].as_slice())
+        );
+
+        // after rotating, tokens of the old key open with it as secondary key
+        let secondary = [old.clone()];
+        assert_eq!(
+            open::<TestHost>(&sealed, &new, &secondary, false, None).unwrap(),
+            b"token"
+        );
+        let err = open::<TestHost>(&sealed, &new, &[], false, None).unwrap_err();
+        assert!(err.contains("unknown key 1"), "{}", err);
+
+        // tokens without key id are decrypted with the keys without id
+        let unkeyed = seal::<TestHost>(b"token", &KEY).unwrap();
+        assert_eq!(
+            open::<TestHost>(&unkeyed, &new, &[KEY], false, None).unwrap(),
+            b"token"
+        );
+        let err = open::<TestHost>(&unkeyed, &new, &secondary, false, None).unwrap_err();
+        assert!(err.contains("no key id"), "{}", err);
+    }
+
+    #[test]
+    fn decrypt_failures_are_reported() {
+        TestHost::reset();
+        let mut encrypted = seal::<TestHost>(b"token", &KEY).unwrap();
+        encrypted.truncate(HEADER_LEN + 3);
+        assert!(open::<TestHost>(&encrypted, &KEY, &[], false, None).is_err());
+        let metrics = TestHost::metrics();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].name, metrics::TOKEN_DECRYPT_FAILURE);
+    }
+
+    #[test]
+    fn legacy_tokens_are_passed_to_the_host() {
+        let legacy = TestHost::encrypt_resolve_token(b"token", &KEY).unwrap();
+        assert_eq!(sniff(&legacy), Ok(TokenFormat::Legacy));
+        assert_eq!(
+            open::<TestHost>(&legacy, &KEY, &[], false, None).unwrap(),
+            b"token".to_vec()
+        );
+    }
+}
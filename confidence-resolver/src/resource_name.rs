@@ -0,0 +1,288 @@
+//! Names of the resources a resolver state refers to, like `flags/my-flag`,
+//! `flags/my-flag/variants/on` or `clients/web/clientCredentials/abc`: a collection and an id,
+//! below the name of the parent resource for nested kinds.
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResourceKind {
+    Account,
+    Client,
+    ClientCredential,
+    Flag,
+    Rule,
+    Variant,
+    Segment,
+    Materialization,
+}
+
+impl ResourceKind {
+    /// The collection part of names of this kind, e.g. `flags`.
+    pub const fn collection(self) -> &'static str {
+        match self {
+            ResourceKind::Account => "accounts",
+            ResourceKind::Client => "clients",
+            ResourceKind::ClientCredential => "clientCredentials",
+            ResourceKind::Flag => "flags",
+            ResourceKind::Rule => "rules",
+            ResourceKind::Variant => "variants",
+            ResourceKind::Segment => "segments",
+            ResourceKind::Materialization => "materializations",
+        }
+    }
+
+    /// The kind of resource names of this kind are nested in, `None` for top level kinds.
+    pub const fn parent(self) -> Option<ResourceKind> {
+        match self {
+            ResourceKind::ClientCredential => Some(ResourceKind::Client),
+            ResourceKind::Rule | ResourceKind::Variant => Some(ResourceKind::Flag),
+            _ => None,
+        }
+    }
+
+    fn from_collection(collection: &str) -> Option<ResourceKind> {
+        [
+            ResourceKind::Account,
+            ResourceKind::Client,
+            ResourceKind::ClientCredential,
+            ResourceKind::Flag,
+            ResourceKind::Rule,
+            ResourceKind::Variant,
+            ResourceKind::Segment,
+            ResourceKind::Materialization,
+        ]
+        .into_iter()
+        .find(|kind| kind.collection() == collection)
+    }
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.collection())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceNameError {
+    /// The name doesn't end in a `collection/id` pair with a non-empty id.
+    Malformed {
+        name: String,
+    },
+    UnknownKind {
+        name: String,
+        collection: String,
+    },
+    WrongKind {
+        name: String,
+        expected: ResourceKind,
+        actual: ResourceKind,
+    },
+    /// A nested kind without a valid parent name, or a top level kind with one.
+    WrongParent {
+        name: String,
+    },
+}
+
+impl fmt::Display for ResourceNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceNameError::Malformed { name } => {
+                write!(f, "malformed resource name \"{}\"", name)
+            }
+            ResourceNameError::UnknownKind { name, collection } => write!(
+                f,
+                "unknown resource kind \"{}\" in \"{}\"",
+                collection, name
+            ),
+            ResourceNameError::WrongKind {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "expected a name of {}, got one of {}: \"{}\"",
+                expected, actual, name
+            ),
+            ResourceNameError::WrongParent { name } => {
+                write!(f, "resource name \"{}\" has an invalid parent", name)
+            }
+        }
+    }
+}
+
+impl From<ResourceNameError> for String {
+    fn from(value: ResourceNameError) -> Self {
+        value.to_string()
+    }
+}
+
+/// A parsed resource name, borrowing from the string it was parsed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceName<'a> {
+    name: &'a str,
+    kind: ResourceKind,
+    id: &'a str,
+    parent: Option<&'a str>,
+}
+
+impl<'a> ResourceName<'a> {
+    pub fn parse(name: &'a str) -> Result<Self, ResourceNameError> {
+        let malformed = || ResourceNameError::Malformed {
+            name: name.to_string(),
+        };
+        let (rest, id) = name.rsplit_once('/').ok_or_else(malformed)?;
+        let (parent, collection) = match rest.rsplit_once('/') {
+            Some((parent, collection)) => (Some(parent), collection),
+            None => (None, rest),
+        };
+        if id.is_empty() || collection.is_empty() {
+            return Err(malformed());
+        }
+        let kind = ResourceKind::from_collection(collection).ok_or_else(|| {
+            ResourceNameError::UnknownKind {
+                name: name.to_string(),
+                collection: collection.to_string(),
+            }
+        })?;
+        let valid_parent = match (kind.parent(), parent) {
+            (None, None) => true,
+            (Some(parent_kind), Some(parent)) => {
+                ResourceName::parse_as(parent, parent_kind).is_ok()
+            }
+            _ => false,
+        };
+        if !valid_parent {
+            return Err(ResourceNameError::WrongParent {
+                name: name.to_string(),
+            });
+        }
+        Ok(ResourceName {
+            name,
+            kind,
+            id,
+            parent,
+        })
+    }
+
+    /// Parses `name` and checks that it names a resource of `kind`.
+    pub fn parse_as(name: &'a str, kind: ResourceKind) -> Result<Self, ResourceNameError> {
+        let parsed = ResourceName::parse(name)?;
+        if parsed.kind != kind {
+            return Err(ResourceNameError::WrongKind {
+                name: name.to_string(),
+                expected: kind,
+                actual: parsed.kind,
+            });
+        }
+        Ok(parsed)
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn kind(&self) -> ResourceKind {
+        self.kind
+    }
+
+    /// The last part of the name, e.g. `on` for `flags/my-flag/variants/on`.
+    pub fn id(&self) -> &'a str {
+        self.id
+    }
+
+    /// The resource this one is nested in, e.g. `flags/my-flag` for a variant of that flag.
+    pub fn parent(&self) -> Option<ResourceName<'a>> {
+        let kind = self.kind.parent()?;
+        ResourceName::parse_as(self.parent?, kind).ok()
+    }
+}
+
+impl fmt::Display for ResourceName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+/// Normalizes `id_or_name` to a full name of a top level `kind`: `my-flag` and `flags/my-flag`
+/// both become `flags/my-flag`.
+pub fn qualify(kind: ResourceKind, id_or_name: &str) -> Result<String, ResourceNameError> {
+    if id_or_name.contains('/') {
+        return ResourceName::parse_as(id_or_name, kind).map(|name| name.as_str().to_string());
+    }
+    if id_or_name.is_empty() || kind.parent().is_some() {
+        return Err(ResourceNameError::Malformed {
+            name: id_or_name.to_string(),
+        });
+    }
+    Ok(format!("{}/{}", kind.collection(), id_or_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_top_level_and_nested_names() {
+        let flag = ResourceName::parse("flags/my-flag").unwrap();
+        assert_eq!(flag.kind(), ResourceKind::Flag);
+        assert_eq!(flag.id(), "my-flag");
+        assert_eq!(flag.parent(), None);
+
+        let variant =
+            ResourceName::parse_as("flags/my-flag/variants/on", ResourceKind::Variant).unwrap();
+        assert_eq!(variant.id(), "on");
+        assert_eq!(variant.parent(), Some(flag));
+
+        let credential = ResourceName::parse("clients/web/clientCredentials/abc").unwrap();
+        assert_eq!(credential.parent().unwrap().as_str(), "clients/web");
+    }
+
+    #[test]
+    fn reports_invalid_names() {
+        let err = |name| ResourceName::parse(name).unwrap_err();
+        assert_eq!(
+            err("flags"),
+            ResourceNameError::Malformed {
+                name: "flags".to_string()
+            }
+        );
+        assert!(matches!(err("flags/"), ResourceNameError::Malformed { .. }));
+        assert!(matches!(
+            err("things/x"),
+            ResourceNameError::UnknownKind { collection, .. } if collection == "things"
+        ));
+        assert!(matches!(
+            err("variants/on"),
+            ResourceNameError::WrongParent { .. }
+        ));
+        assert!(matches!(
+            err("segments/x/variants/on"),
+            ResourceNameError::WrongParent { .. }
+        ));
+        assert!(matches!(
+            err("flags/a/flags/b"),
+            ResourceNameError::WrongParent { .. }
+        ));
+        assert_eq!(
+            ResourceName::parse_as("segments/x", ResourceKind::Flag)
+                .unwrap_err()
+                .to_string(),
+            "expected a name of flags, got one of segments: \"segments/x\""
+        );
+    }
+
+    #[test]
+    fn qualifies_ids() {
+        assert_eq!(
+            qualify(ResourceKind::Account, "test"),
+            Ok("accounts/test".to_string())
+        );
+        assert_eq!(
+            qualify(ResourceKind::Account, "accounts/test"),
+            Ok("accounts/test".to_string())
+        );
+        assert!(qualify(ResourceKind::Account, "flags/test").is_err());
+        assert!(qualify(ResourceKind::Variant, "on").is_err());
+        assert!(qualify(ResourceKind::Flag, "").is_err());
+    }
+}
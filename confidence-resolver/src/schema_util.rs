@@ -5,7 +5,8 @@ use crate::proto::confidence::flags::admin::v1::{
 use crate::{
     Kind, Value, Struct
 };
-use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use crate::proto::google::Timestamp;
+use crate::time;
 
 use crate::proto::confidence::flags::admin::v1::context_field_semantic_type::{
     CountrySemanticType, DateSemanticType, TimestampSemanticType, VersionSemanticType,
@@ -200,7 +201,7 @@ impl SchemaFromEvaluationContext {
             return false;
         }
 
-        NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok()
+        time::parse_date(value).is_some()
     }
 
     fn is_valid_country_code(value: &str) -> bool {
@@ -209,14 +210,14 @@ impl SchemaFromEvaluationContext {
         country_codes.contains(&value.to_uppercase().as_str())
     }
 
-    fn parse_instant(value: &str) -> Option<DateTime<Utc>> {
+    fn parse_instant(value: &str) -> Option<Timestamp> {
         if value.is_empty() {
             return None;
         }
 
         // Try parsing as RFC3339/ISO8601 with timezone
-        if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
-            return Some(dt.with_timezone(&Utc));
+        if let Some(ts) = time::parse_rfc3339(value) {
+            return Some(ts);
         }
 
         // Try parsing with custom formats
@@ -225,27 +226,15 @@ impl SchemaFromEvaluationContext {
 
             if value.ends_with('Z') || time_part.contains('+') || time_part.contains('-') {
                 // Try parsing as zoned datetime
-                if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
-                    return Some(dt.with_timezone(&Utc));
-                }
+                time::parse_rfc3339(value)
             } else {
                 // Try parsing as local datetime and assume UTC
-                if let Ok(ndt) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
-                    return Some(DateTime::from_naive_utc_and_offset(ndt, Utc));
-                }
-                if let Ok(ndt) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f") {
-                    return Some(DateTime::from_naive_utc_and_offset(ndt, Utc));
-                }
+                time::parse_naive_date_time(value)
             }
         } else {
             // Try parsing as date only
-            if let Ok(nd) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
-                let ndt = unsafe { nd.and_hms_opt(0, 0, 0).unwrap_unchecked() };
-                return Some(DateTime::from_naive_utc_and_offset(ndt, Utc));
-            }
+            time::parse_date(value)
         }
-
-        None
     }
 }
 
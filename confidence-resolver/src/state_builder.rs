@@ -0,0 +1,182 @@
+//! Loads a resolver state that replaces another one, carrying over what the two have in common.
+//!
+//! Long-lived hosts swap in a new state every time it is published, and usually only a few
+//! flags changed. Flags and segments are decoded with the new state proto either way, but the
+//! previous state holds work that doesn't need to be redone: bitsets with unchanged bytes are
+//! shared with it, keeping their decompressed bits. Encryption keys are not carried over, so
+//! that the host is asked for them again and a rotated key is picked up with the next state.
+//!
+//! ```ignore
+//! let previous = current_state();
+//! let (state, report) = ResolverStateBuilder::new(account_id)
+//!     .reusing(&previous)
+//!     .fingerprint(&encoded)
+//!     .build(state_pb)?;
+//! ```
+
+use std::sync::Arc;
+
+use crate::err::Fallible;
+use crate::proto::confidence::flags::admin::v1::ResolverState as ResolverStatePb;
+use crate::{LoadOptions, PruneReport, ResolverState, StateDuplicate};
+
+/// Builds a [`ResolverState`] from a state proto, see the [module docs](self).
+#[derive(Debug)]
+pub struct ResolverStateBuilder<'p> {
+    account_id: String,
+    previous: Option<&'p ResolverState>,
+    options: LoadOptions,
+    fingerprint: String,
+}
+
+/// What [`ResolverStateBuilder::build`] carried over from the previous state and pruned.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildReport {
+    /// Segments whose bitset is shared with the previous state, sorted by name.
+    pub reused_bitsets: Vec<String>,
+    pub pruned: PruneReport,
+    /// Flags and client secrets of the state proto that a later entry replaced.
+    pub duplicates: Vec<StateDuplicate>,
+}
+
+impl<'p> ResolverStateBuilder<'p> {
+    pub fn new(account_id: &str) -> Self {
+        ResolverStateBuilder {
+            account_id: account_id.to_string(),
+            previous: None,
+            options: LoadOptions::default(),
+            fingerprint: String::new(),
+        }
+    }
+
+    /// The state the built one replaces. Its decrypt breaker is carried over too.
+    pub fn reusing(mut self, previous: &'p ResolverState) -> Self {
+        self.previous = Some(previous);
+        self
+    }
+
+    pub fn options(mut self, options: LoadOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Fingerprints the state by `encoded`, the bytes its proto was decoded from, see
+    /// [`ResolverState::fingerprint_of`].
+    pub fn fingerprint(mut self, encoded: &[u8]) -> Self {
+        self.fingerprint = ResolverState::fingerprint_of(encoded);
+        self
+    }
+
+    pub fn build(self, state_pb: ResolverStatePb) -> Fallible<(ResolverState, BuildReport)> {
+        let (mut state, loaded) =
+            ResolverState::from_proto_with_options(state_pb, &self.account_id, &self.options)?;
+        state.fingerprint = self.fingerprint;
+        let mut report = BuildReport {
+            pruned: loaded.pruned,
+            duplicates: loaded.duplicates,
+            ..Default::default()
+        };
+        let Some(previous) = self.previous else {
+            return Ok((state, report));
+        };
+
+        for (segment, bitset) in Arc::make_mut(&mut state.bitsets).iter_mut() {
+            let Some(previous_bitset) = previous.bitsets.get(segment) else {
+                continue;
+            };
+            if bitset.same_gzipped(previous_bitset) {
+                *bitset = previous_bitset.clone();
+                report.reused_bitsets.push(segment.clone());
+            }
+        }
+
+        state.decrypt_breaker = previous.decrypt_breaker.clone();
+
+        report.reused_bitsets.sort();
+        Ok((state, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::admin::v1::resolver_state::packed_bitset;
+    use crate::EncryptionKey;
+    use prost::Message;
+
+    const EXAMPLE_STATE: &[u8] = include_bytes!("../test-payloads/resolver_state.pb");
+    const ACCOUNT: &str = "confidence-demo-june";
+
+    fn state_pb() -> ResolverStatePb {
+        ResolverStatePb::decode(EXAMPLE_STATE).unwrap()
+    }
+
+    #[test]
+    fn shares_unchanged_bitsets_with_the_previous_state() {
+        let previous = ResolverState::from_proto(state_pb(), ACCOUNT).unwrap();
+        previous.warm_up().unwrap();
+
+        let mut next_pb = state_pb();
+        let changed = next_pb
+            .bitsets
+            .iter_mut()
+            .find(|b| matches!(b.bitset, Some(packed_bitset::Bitset::GzippedBitset(_))))
+            .unwrap();
+        let changed_segment = changed.segment.clone();
+        changed.bitset = Some(packed_bitset::Bitset::GzippedBitset(
+            crate::gzip::compress_gz(&[0xff; 8]),
+        ));
+
+        let (state, report) = ResolverStateBuilder::new(ACCOUNT)
+            .reusing(&previous)
+            .build(next_pb)
+            .unwrap();
+        assert!(!report.reused_bitsets.is_empty());
+        assert!(!report.reused_bitsets.contains(&changed_segment));
+        for segment in &report.reused_bitsets {
+            let bitset = &state.bitsets[segment];
+            assert!(bitset.is_shared_with(&previous.bitsets[segment]));
+            assert!(bitset.is_loaded());
+        }
+        assert!(!state.bitsets[&changed_segment].is_loaded());
+    }
+
+    #[test]
+    fn asks_for_encryption_keys_again() {
+        let previous = ResolverState::from_proto(state_pb(), ACCOUNT).unwrap();
+        let credential = previous
+            .secrets
+            .values()
+            .next()
+            .unwrap()
+            .client_credential_name
+            .clone();
+        previous
+            .encryption_keys
+            .write()
+            .unwrap()
+            .insert(credential, EncryptionKey::ZERO);
+
+        // the key may have been rotated since
+        let (state, _) = ResolverStateBuilder::new(ACCOUNT)
+            .reusing(&previous)
+            .build(state_pb())
+            .unwrap();
+        assert!(state.encryption_keys.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn builds_without_a_previous_state() {
+        let (state, report) = ResolverStateBuilder::new(ACCOUNT)
+            .fingerprint(EXAMPLE_STATE)
+            .build(state_pb())
+            .unwrap();
+        assert_eq!(report, BuildReport::default());
+        assert_eq!(
+            state.fingerprint,
+            ResolverState::from_bytes(EXAMPLE_STATE, ACCOUNT)
+                .unwrap()
+                .fingerprint
+        );
+    }
+}
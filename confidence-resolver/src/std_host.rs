@@ -0,0 +1,160 @@
+//! A ready-made [`Host`] for server environments with `std`.
+//!
+//! `StdHost` relies on the trait defaults for randomness, time and AES resolve token
+//! encryption, forwards diagnostic messages to `tracing` when the `tracing` feature is
+//! enabled, times flag and rule evaluations with a monotonic clock, and collects resolve and
+//! assign logs in process-wide loggers that are drained with [`StdHost::checkpoint`]. With the
+//! `reqwest` feature the logs can be delivered to Confidence directly with
+//! [`StdHost::flush_logs`].
+
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use crate::flag_logs::{FlagLogs, LogScope};
+use crate::proto::confidence::flags::resolver::v1::{Sdk, WriteFlagLogsRequest};
+use crate::proto::google::Struct;
+use crate::{Client, FlagToApply, Host, ResolvedValue};
+
+static FLAG_LOGS: LazyLock<FlagLogs<StdHost>> = LazyLock::new(FlagLogs::new);
+static CLOCK_ORIGIN: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+#[cfg(feature = "reqwest")]
+const FLAG_LOGS_URL: &str = "https://resolver.confidence.dev/v1/clientFlagLogs:write";
+
+pub struct StdHost;
+
+impl StdHost {
+    /// Drains the resolve and assign logs collected since the last checkpoint.
+    pub fn checkpoint() -> WriteFlagLogsRequest {
+        FLAG_LOGS.checkpoint()
+    }
+
+    /// Keeps [`StdHost::checkpoint`] from running until the scope is dropped. Resolving within
+    /// a scope makes sure the resolve counters and assigns of a resolve are drained together.
+    pub fn log_scope() -> LogScope<'static> {
+        FLAG_LOGS.log_scope()
+    }
+
+    /// Drains the collected logs and writes them to Confidence, authenticating with
+    /// `client_secret`. Nothing is sent when there is nothing to report.
+    #[cfg(feature = "reqwest")]
+    pub fn flush_logs(client_secret: &str) -> Result<(), String> {
+        use crate::proto::Message;
+
+        let req = Self::checkpoint();
+        if req.flag_assigned.is_empty()
+            && req.flag_resolve_info.is_empty()
+            && req.client_resolve_info.is_empty()
+        {
+            return Ok(());
+        }
+        let response = reqwest::blocking::Client::new()
+            .post(FLAG_LOGS_URL)
+            .header("Content-Type", "application/x-protobuf")
+            .header("Authorization", format!("ClientSecret {}", client_secret))
+            .body(req.encode_to_vec())
+            .send()
+            .map_err(|e| format!("failed to write flag logs: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("failed to write flag logs: {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+impl Host for StdHost {
+    fn log(message: &str) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(target: "confidence_resolver", "{}", message);
+        #[cfg(not(feature = "tracing"))]
+        let _ = message;
+    }
+
+    fn monotonic_nanos() -> Option<u64> {
+        u64::try_from(CLOCK_ORIGIN.elapsed().as_nanos()).ok()
+    }
+
+    fn log_resolve(
+        resolve_id: &str,
+        evaluation_context: &Struct,
+        values: &[ResolvedValue<'_>],
+        client: &Client,
+        sdk: &Option<Sdk>,
+    ) {
+        FLAG_LOGS.resolve_logger().log_resolve(
+            resolve_id,
+            evaluation_context,
+            &client.client_credential_name,
+            values,
+            client,
+            sdk,
+        );
+    }
+
+    fn log_assign(
+        resolve_id: &str,
+        evaluation_context: &Struct,
+        assigned_flags: &[FlagToApply],
+        client: &Client,
+        sdk: &Option<Sdk>,
+    ) {
+        FLAG_LOGS.assign_logger().log_assigns(
+            resolve_id,
+            evaluation_context,
+            assigned_flags,
+            client,
+            sdk,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::ResolveFlagsRequestBuilder;
+    use crate::{AccountResolver, EncryptionKey, ResolverState};
+
+    const EXAMPLE_STATE: &[u8] = include_bytes!("../test-payloads/resolver_state.pb");
+    const SECRET: &str = "mkjJruAATQWjeY7foFIWfVAcBWnci2YF";
+
+    #[test]
+    fn monotonic_clock() {
+        let first = StdHost::monotonic_nanos().unwrap();
+        let second = StdHost::monotonic_nanos().unwrap();
+        assert!(second >= first);
+    }
+
+    #[test]
+    fn resolves_and_collects_logs() {
+        let state = ResolverState::from_proto(
+            EXAMPLE_STATE.to_owned().try_into().unwrap(),
+            "confidence-demo-june",
+        )
+        .unwrap();
+        let resolver: AccountResolver<'_, StdHost> = state
+            .get_resolver_with_json_context(
+                SECRET,
+                r#"{"visitor_id": "tutorial_visitor"}"#,
+                &EncryptionKey::ZERO,
+            )
+            .unwrap();
+        let request = ResolveFlagsRequestBuilder::new(SECRET)
+            .with_flags(["tutorial-feature"])
+            .with_apply(true)
+            .build()
+            .unwrap();
+        let response = resolver.resolve_flags(&request).unwrap();
+        assert_eq!(response.resolved_flags.len(), 1);
+        assert!(!response.resolve_id.is_empty());
+
+        let logs = StdHost::checkpoint();
+        assert!(logs
+            .flag_resolve_info
+            .iter()
+            .any(|f| f.flag == "flags/tutorial-feature"));
+        assert!(logs
+            .flag_assigned
+            .iter()
+            .any(|a| a.resolve_id == response.resolve_id));
+    }
+}
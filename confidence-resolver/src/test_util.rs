@@ -0,0 +1,440 @@
+//! A deterministic [`Host`] for tests, available with the `test-util` feature.
+//!
+//! `Host` functions are associated functions without a receiver, so the clock, the random
+//! generator and the captured logs live in thread-local state. Each test thread therefore sees
+//! its own `TestHost`; call [`TestHost::reset`] at the start of a test to get a clean slate.
+//!
+//! ```ignore
+//! TestHost::reset();
+//! TestHost::set_time(1_700_000_000);
+//! let resolver: AccountResolver<'_, TestHost> = state.get_resolver(secret, ctx, &key)?;
+//! resolver.resolve_flags(&request)?;
+//! TestHost::assert_assigned("flags/my-flag");
+//! ```
+
+// the assertion helpers are meant to panic
+#![allow(clippy::panic)]
+
+pub mod state_gen;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::encryption::EncryptionProvider;
+use crate::proto::confidence::flags::admin::v1::Flag;
+use crate::proto::confidence::flags::resolver::v1::{
+    ResolveFlagsRequest, ResolveFlagsResponse, Sdk,
+};
+use crate::proto::google::{Struct, Timestamp};
+use crate::{Client, DenyReason, EncryptionKey, FlagToApply, Host, ResolveReason, ResolvedValue};
+
+const DEFAULT_TIME_SECONDS: i64 = 1_700_000_000;
+const ALPHANUMERIC: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// A flag as it was passed to `Host::log_resolve`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedFlag {
+    pub flag: String,
+    pub reason: ResolveReason,
+    pub rule: Option<String>,
+    pub variant: Option<String>,
+    pub should_apply: bool,
+}
+
+/// A call to `Host::log_resolve`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedResolve {
+    pub resolve_id: String,
+    pub evaluation_context: Struct,
+    pub flags: Vec<LoggedFlag>,
+    pub client_credential: String,
+    pub sdk: Option<Sdk>,
+}
+
+/// A call to `Host::log_assign`.
+#[derive(Debug, Clone)]
+pub struct LoggedAssign {
+    pub resolve_id: String,
+    pub evaluation_context: Struct,
+    pub assigned_flags: Vec<FlagToApply>,
+    pub client_credential: String,
+    pub sdk: Option<Sdk>,
+}
+
+/// A metric as it was passed to `Host::on_metric`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedMetric {
+    pub name: String,
+    pub value: f64,
+    pub tags: Vec<(String, String)>,
+}
+
+struct TestHostState {
+    now: Timestamp,
+    rng: u64,
+    messages: Vec<String>,
+    resolves: Vec<LoggedResolve>,
+    assigns: Vec<LoggedAssign>,
+    encryption_keys: HashMap<String, EncryptionKey>,
+    key_requests: Vec<String>,
+    unknown_secret_response: Option<ResolveFlagsResponse>,
+    denied_flags: Vec<String>,
+    monotonic_nanos: u64,
+    metrics: Vec<LoggedMetric>,
+    segment_members: HashMap<String, Vec<String>>,
+    membership_lookups: Vec<(String, String)>,
+    encryption_provider: Option<&'static dyn EncryptionProvider>,
+}
+
+impl Default for TestHostState {
+    fn default() -> Self {
+        TestHostState {
+            now: Timestamp {
+                seconds: DEFAULT_TIME_SECONDS,
+                nanos: 0,
+            },
+            rng: 0,
+            messages: Vec::new(),
+            resolves: Vec::new(),
+            assigns: Vec::new(),
+            encryption_keys: HashMap::new(),
+            key_requests: Vec::new(),
+            unknown_secret_response: None,
+            denied_flags: Vec::new(),
+            monotonic_nanos: 0,
+            metrics: Vec::new(),
+            segment_members: HashMap::new(),
+            membership_lookups: Vec::new(),
+            encryption_provider: None,
+        }
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<TestHostState> = RefCell::new(TestHostState::default());
+}
+
+pub struct TestHost;
+
+impl TestHost {
+    /// Restores the clock, the random seed and clears all captured logs.
+    pub fn reset() {
+        STATE.with_borrow_mut(|state| *state = TestHostState::default());
+    }
+
+    pub fn set_time(seconds: i64) {
+        STATE.with_borrow_mut(|state| state.now = Timestamp { seconds, nanos: 0 });
+    }
+
+    pub fn advance_time(seconds: i64) {
+        STATE.with_borrow_mut(|state| {
+            state.now.seconds = state.now.seconds.saturating_add(seconds);
+        });
+    }
+
+    /// Seeds the generator used for resolve ids.
+    pub fn seed(seed: u64) {
+        STATE.with_borrow_mut(|state| state.rng = seed);
+    }
+
+    /// Sets the key returned by `Host::get_encryption_key` for `client_credential`.
+    pub fn set_encryption_key(client_credential: &str, key: EncryptionKey) {
+        STATE.with_borrow_mut(|state| {
+            state
+                .encryption_keys
+                .insert(client_credential.to_string(), key)
+        });
+    }
+
+    /// Makes `Host::encryption_provider` return `provider`.
+    pub fn set_encryption_provider(provider: &'static dyn EncryptionProvider) {
+        STATE.with_borrow_mut(|state| state.encryption_provider = Some(provider));
+    }
+
+    /// Credentials passed to `Host::get_encryption_key` since the last reset, in order.
+    pub fn encryption_key_requests() -> Vec<String> {
+        STATE.with_borrow(|state| state.key_requests.clone())
+    }
+
+    /// Makes `Host::resolve_unknown_secret` answer with `response`.
+    pub fn set_unknown_secret_response(response: ResolveFlagsResponse) {
+        STATE.with_borrow_mut(|state| state.unknown_secret_response = Some(response));
+    }
+
+    /// Makes `Host::authorize_resolve` deny resolves that would evaluate `flag`.
+    pub fn deny_flag(flag: &str) {
+        STATE.with_borrow_mut(|state| state.denied_flags.push(flag.to_string()));
+    }
+
+    /// Makes `Host::segment_membership` answer for the external `segment`, with `units` as
+    /// its members. Lookups in other segments fail.
+    pub fn set_segment_members(segment: &str, units: &[&str]) {
+        STATE.with_borrow_mut(|state| {
+            state.segment_members.insert(
+                segment.to_string(),
+                units.iter().map(|unit| unit.to_string()).collect(),
+            )
+        });
+    }
+
+    /// Segments and units passed to `Host::segment_membership` since the last reset, in order.
+    pub fn membership_lookups() -> Vec<(String, String)> {
+        STATE.with_borrow(|state| state.membership_lookups.clone())
+    }
+
+    /// Metrics passed to `Host::on_metric` since the last reset, in order. The monotonic
+    /// clock advances a millisecond per reading, so durations are whole milliseconds.
+    pub fn metrics() -> Vec<LoggedMetric> {
+        STATE.with_borrow(|state| state.metrics.clone())
+    }
+
+    pub fn messages() -> Vec<String> {
+        STATE.with_borrow(|state| state.messages.clone())
+    }
+
+    pub fn resolve_logs() -> Vec<LoggedResolve> {
+        STATE.with_borrow(|state| state.resolves.clone())
+    }
+
+    pub fn assign_logs() -> Vec<LoggedAssign> {
+        STATE.with_borrow(|state| state.assigns.clone())
+    }
+
+    /// Names of all flags passed to `log_assign` since the last reset, in order.
+    pub fn assigned_flags() -> Vec<String> {
+        STATE.with_borrow(|state| {
+            state
+                .assigns
+                .iter()
+                .flat_map(|a| a.assigned_flags.iter())
+                .map(|f| f.assigned_flag.flag.clone())
+                .collect()
+        })
+    }
+
+    #[track_caller]
+    pub fn assert_assigned(flag: &str) {
+        let assigned = Self::assigned_flags();
+        assert!(
+            assigned.iter().any(|f| f == flag),
+            "expected {} to be assigned, assigned flags: {:?}",
+            flag,
+            assigned
+        );
+    }
+
+    #[track_caller]
+    pub fn assert_not_assigned(flag: &str) {
+        let assigned = Self::assigned_flags();
+        assert!(
+            !assigned.iter().any(|f| f == flag),
+            "expected {} not to be assigned, assigned flags: {:?}",
+            flag,
+            assigned
+        );
+    }
+
+    /// Asserts that the most recent resolve log of `flag` has the given reason and variant.
+    #[track_caller]
+    pub fn assert_resolved(flag: &str, reason: ResolveReason, variant: Option<&str>) {
+        let logged = STATE.with_borrow(|state| {
+            state
+                .resolves
+                .iter()
+                .rev()
+                .flat_map(|r| r.flags.iter())
+                .find(|f| f.flag == flag)
+                .cloned()
+        });
+        let Some(logged) = logged else {
+            panic!("expected {} to be resolved, but it was not logged", flag);
+        };
+        assert_eq!(logged.reason, reason, "unexpected reason for {}", flag);
+        assert_eq!(
+            logged.variant.as_deref(),
+            variant,
+            "unexpected variant for {}",
+            flag
+        );
+    }
+
+    fn next_random() -> u64 {
+        // splitmix64
+        STATE.with_borrow_mut(|state| {
+            state.rng = state.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state.rng;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        })
+    }
+}
+
+impl Host for TestHost {
+    fn random_alphanumeric(len: usize) -> String {
+        (0..len)
+            .map(|_| {
+                let index = Self::next_random()
+                    .checked_rem(ALPHANUMERIC.len() as u64)
+                    .unwrap_or_default() as usize;
+                ALPHANUMERIC.get(index).copied().unwrap_or(b'0') as char
+            })
+            .collect()
+    }
+
+    fn log(message: &str) {
+        STATE.with_borrow_mut(|state| state.messages.push(message.to_string()));
+    }
+
+    fn current_time() -> Timestamp {
+        STATE.with_borrow(|state| state.now.clone())
+    }
+
+    fn monotonic_nanos() -> Option<u64> {
+        STATE.with_borrow_mut(|state| {
+            state.monotonic_nanos = state.monotonic_nanos.saturating_add(1_000_000);
+            Some(state.monotonic_nanos)
+        })
+    }
+
+    fn on_metric(name: &str, value: f64, tags: &[(&str, &str)]) {
+        STATE.with_borrow_mut(|state| {
+            state.metrics.push(LoggedMetric {
+                name: name.to_string(),
+                value,
+                tags: tags
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            })
+        });
+    }
+
+    fn get_encryption_key(client_credential: &str) -> Result<EncryptionKey, String> {
+        STATE.with_borrow_mut(|state| {
+            state.key_requests.push(client_credential.to_string());
+            state
+                .encryption_keys
+                .get(client_credential)
+                .cloned()
+                .ok_or_else(|| "unknown client credential".to_string())
+        })
+    }
+
+    fn encryption_provider() -> Option<&'static dyn EncryptionProvider> {
+        STATE.with_borrow(|state| state.encryption_provider)
+    }
+
+    fn resolve_unknown_secret(
+        _request: &ResolveFlagsRequest,
+    ) -> Option<Result<ResolveFlagsResponse, String>> {
+        STATE.with_borrow(|state| state.unknown_secret_response.clone().map(Ok))
+    }
+
+    fn authorize_resolve(
+        _client: &Client,
+        flags: &[&Flag],
+        _evaluation_context: &Struct,
+    ) -> Result<(), DenyReason> {
+        STATE.with_borrow(|state| {
+            match flags
+                .iter()
+                .find(|flag| state.denied_flags.contains(&flag.name))
+            {
+                Some(flag) => Err(DenyReason::Flag(flag.name.clone())),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn segment_membership(segment: &str, unit: &str) -> Result<bool, String> {
+        STATE.with_borrow_mut(|state| {
+            state
+                .membership_lookups
+                .push((segment.to_string(), unit.to_string()));
+            state
+                .segment_members
+                .get(segment)
+                .map(|members| members.iter().any(|member| member == unit))
+                .ok_or_else(|| format!("unknown external segment {}", segment))
+        })
+    }
+
+    fn log_resolve(
+        resolve_id: &str,
+        evaluation_context: &Struct,
+        values: &[ResolvedValue<'_>],
+        client: &Client,
+        sdk: &Option<Sdk>,
+    ) {
+        let flags = values
+            .iter()
+            .map(|v| LoggedFlag {
+                flag: v.flag.name.clone(),
+                reason: v.reason,
+                rule: v.assignment_match.as_ref().map(|m| m.rule.name.clone()),
+                variant: v
+                    .assignment_match
+                    .as_ref()
+                    .and_then(|m| m.variant)
+                    .map(|variant| variant.name.clone()),
+                should_apply: v.should_apply,
+            })
+            .collect();
+        STATE.with_borrow_mut(|state| {
+            state.resolves.push(LoggedResolve {
+                resolve_id: resolve_id.to_string(),
+                evaluation_context: evaluation_context.clone(),
+                flags,
+                client_credential: client.client_credential_name.clone(),
+                sdk: sdk.clone(),
+            })
+        });
+    }
+
+    fn log_assign(
+        resolve_id: &str,
+        evaluation_context: &Struct,
+        assigned_flags: &[FlagToApply],
+        client: &Client,
+        sdk: &Option<Sdk>,
+    ) {
+        STATE.with_borrow_mut(|state| {
+            state.assigns.push(LoggedAssign {
+                resolve_id: resolve_id.to_string(),
+                evaluation_context: evaluation_context.clone(),
+                assigned_flags: assigned_flags.to_vec(),
+                client_credential: client.client_credential_name.clone(),
+                sdk: sdk.clone(),
+            })
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_random_is_deterministic() {
+        TestHost::reset();
+        TestHost::seed(7);
+        let a = TestHost::random_alphanumeric(32);
+        TestHost::seed(7);
+        let b = TestHost::random_alphanumeric(32);
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 32);
+        assert!(a.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(a, TestHost::random_alphanumeric(32));
+    }
+
+    #[test]
+    fn clock_is_controllable() {
+        TestHost::reset();
+        TestHost::set_time(100);
+        TestHost::advance_time(20);
+        assert_eq!(TestHost::current_time().seconds, 120);
+        TestHost::reset();
+        assert_eq!(TestHost::current_time().seconds, DEFAULT_TIME_SECONDS);
+    }
+}
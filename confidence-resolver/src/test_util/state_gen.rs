@@ -0,0 +1,334 @@
+//! Synthetic resolver states of configurable size, for tests and benchmarks that need more
+//! than the committed example state.
+//!
+//! ```ignore
+//! let generator = StateGenerator { flags: 1_000, segments: 200, ..Default::default() };
+//! let state = ResolverState::from_proto(generator.build(), StateGenerator::ACCOUNT)?;
+//! let resolver = state.get_resolver::<TestHost>(&StateGenerator::client_secret(0), ctx, &key)?;
+//! ```
+
+use std::collections::BTreeMap;
+
+use crate::gzip::compress_gz;
+use crate::hashing::SEGMENT_BUCKETS;
+use crate::proto::confidence::flags::admin::v1 as flags_admin;
+use crate::proto::confidence::flags::types::v1 as flags_types;
+use crate::proto::confidence::iam::v1 as iam;
+use crate::proto::google::{value::Kind, Struct, Value};
+use flags_admin::flag::rule::{assignment, Assignment, AssignmentSpec, BucketRange};
+use flags_admin::flag::{Rule, Variant};
+use flags_admin::resolver_state::{packed_bitset, PackedBitset};
+use flags_admin::{Flag, ResolverState as ResolverStatePb, Segment};
+
+/// Describes the state to generate. Every flag is enabled for every client; rule `r` of flag
+/// `f` targets segment `(f * rules_per_flag + r) % segments` and splits its buckets evenly
+/// over the flag's variants.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateGenerator {
+    pub flags: usize,
+    pub segments: usize,
+    pub variants_per_flag: usize,
+    pub rules_per_flag: usize,
+    pub clients: usize,
+    /// Every n:th flag is archived, none when 0.
+    pub archive_every: usize,
+    /// Fraction of the buckets included in each segment. 1.0 produces full bitsets, which are
+    /// shipped without bits, and 0.0 segments nobody falls into.
+    pub bitset_density: f64,
+    pub seed: u64,
+}
+
+impl Default for StateGenerator {
+    fn default() -> Self {
+        StateGenerator {
+            flags: 10,
+            segments: 5,
+            variants_per_flag: 2,
+            rules_per_flag: 1,
+            clients: 1,
+            archive_every: 0,
+            bitset_density: 1.0,
+            seed: 0,
+        }
+    }
+}
+
+impl StateGenerator {
+    /// Account id to load generated states with.
+    pub const ACCOUNT: &'static str = "generated";
+
+    pub fn flag_name(index: usize) -> String {
+        format!("flags/flag-{}", index)
+    }
+
+    pub fn segment_name(index: usize) -> String {
+        format!("segments/segment-{}", index)
+    }
+
+    pub fn client_name(index: usize) -> String {
+        format!("clients/client-{}", index)
+    }
+
+    pub fn client_secret(index: usize) -> String {
+        format!("secret-{}", index)
+    }
+
+    pub fn build(&self) -> ResolverStatePb {
+        let mut random = SplitMix64(self.seed);
+        let clients: Vec<String> = (0..self.clients).map(Self::client_name).collect();
+        let flags = (0..self.flags)
+            .map(|index| self.flag(index, &clients))
+            .collect();
+        let segments = (0..self.segments)
+            .map(|index| Segment {
+                name: Self::segment_name(index),
+                ..Default::default()
+            })
+            .collect();
+        let bitsets = (0..self.segments)
+            .map(|index| PackedBitset {
+                segment: Self::segment_name(index),
+                bitset: Some(self.bitset(&mut random)),
+            })
+            .collect();
+        let client_credentials = (0..self.clients)
+            .map(|index| iam::ClientCredential {
+                name: format!("{}/clientCredentials/credential", Self::client_name(index)),
+                credential: Some(iam::client_credential::Credential::ClientSecret(
+                    iam::client_credential::ClientSecret {
+                        secret: Self::client_secret(index),
+                    },
+                )),
+                ..Default::default()
+            })
+            .collect();
+        ResolverStatePb {
+            flags,
+            segments_no_bitsets: segments,
+            bitsets,
+            clients: clients
+                .into_iter()
+                .map(|name| iam::Client {
+                    name,
+                    ..Default::default()
+                })
+                .collect(),
+            client_credentials,
+            ..Default::default()
+        }
+    }
+
+    fn flag(&self, index: usize, clients: &[String]) -> Flag {
+        let name = Self::flag_name(index);
+        let variants: Vec<Variant> = (0..self.variants_per_flag)
+            .map(|v| Variant {
+                name: format!("{}/variants/variant-{}", name, v),
+                value: Some(Struct {
+                    fields: [(
+                        "value".to_string(),
+                        Value {
+                            kind: Some(Kind::NumberValue(v as f64)),
+                        },
+                    )]
+                    .into_iter()
+                    .collect(),
+                }),
+                ..Default::default()
+            })
+            .collect();
+        let bucket_count = variants.len().max(1) as i32;
+        let rules = (0..self.rules_per_flag)
+            .map(|r| Rule {
+                name: format!("{}/rules/rule-{}", name, r),
+                segment: Self::segment_name(
+                    index
+                        .saturating_mul(self.rules_per_flag)
+                        .saturating_add(r)
+                        .checked_rem(self.segments)
+                        .unwrap_or_default(),
+                ),
+                enabled: true,
+                assignment_spec: Some(AssignmentSpec {
+                    bucket_count,
+                    assignments: variants
+                        .iter()
+                        .enumerate()
+                        .map(|(v, variant)| Assignment {
+                            assignment_id: format!("rule-{}-variant-{}", r, v),
+                            assignment: Some(assignment::Assignment::Variant(
+                                assignment::VariantAssignment {
+                                    variant: variant.name.clone(),
+                                },
+                            )),
+                            bucket_ranges: vec![BucketRange {
+                                lower: v as i32,
+                                upper: (v as i32).saturating_add(1),
+                            }],
+                        })
+                        .collect(),
+                }),
+                ..Default::default()
+            })
+            .collect();
+        let archived = self.archive_every > 0 && index.is_multiple_of(self.archive_every);
+        Flag {
+            name,
+            schema: Some(flags_types::flag_schema::StructFlagSchema {
+                schema: BTreeMap::from([(
+                    "value".to_string(),
+                    flags_types::FlagSchema {
+                        schema_type: Some(flags_types::flag_schema::SchemaType::DoubleSchema(
+                            Default::default(),
+                        )),
+                    },
+                )]),
+            }),
+            variants,
+            state: if archived {
+                flags_admin::flag::State::Archived
+            } else {
+                flags_admin::flag::State::Active
+            } as i32,
+            rules,
+            clients: clients.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn bitset(&self, random: &mut SplitMix64) -> packed_bitset::Bitset {
+        if self.bitset_density >= 1.0 {
+            return packed_bitset::Bitset::FullBitset(true);
+        }
+        // each bit is set when a 16 bit random number falls below the density
+        let threshold = (self.bitset_density.max(0.0) * 65536.0) as u32;
+        let mut bytes = vec![0u8; (SEGMENT_BUCKETS as usize).div_ceil(8)];
+        for byte in &mut bytes {
+            for word in [random.next_u64(), random.next_u64()] {
+                for shift in [0, 16, 32, 48] {
+                    *byte = byte.wrapping_shl(1);
+                    if u32::from(word.wrapping_shr(shift) as u16) < threshold {
+                        *byte |= 1;
+                    }
+                }
+            }
+        }
+        packed_bitset::Bitset::GzippedBitset(compress_gz(&bytes))
+    }
+}
+
+/// Small deterministic generator so that generated states only depend on the seed.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::resolver::v1::ResolveFlagsRequest;
+    use crate::test_util::TestHost;
+    use crate::{EncryptionKey, ResolveReason, ResolverState};
+
+    fn resolve_all(state: &ResolverState, unit: &str) -> Vec<(String, i32)> {
+        let secret = StateGenerator::client_secret(0);
+        let resolver = state
+            .get_resolver_with_json_context::<TestHost>(
+                &secret,
+                &format!(r#"{{"targeting_key": "{}"}}"#, unit),
+                &EncryptionKey::ZERO,
+            )
+            .unwrap();
+        let mut flags: Vec<(String, i32)> = resolver
+            .resolve_flags(&ResolveFlagsRequest {
+                client_secret: secret,
+                ..Default::default()
+            })
+            .unwrap()
+            .resolved_flags
+            .into_iter()
+            .map(|f| (f.flag, f.reason))
+            .collect();
+        flags.sort();
+        flags
+    }
+
+    fn load(generator: &StateGenerator) -> ResolverState {
+        ResolverState::from_proto(generator.build(), StateGenerator::ACCOUNT).unwrap()
+    }
+
+    #[test]
+    fn generates_requested_shape() {
+        let generator = StateGenerator {
+            flags: 20,
+            segments: 4,
+            rules_per_flag: 3,
+            variants_per_flag: 4,
+            clients: 3,
+            archive_every: 5,
+            ..Default::default()
+        };
+        let pb = generator.build();
+        assert_eq!(pb.flags.len(), 20);
+        assert_eq!(pb.segments_no_bitsets.len(), 4);
+        assert!(pb.flags.iter().all(|f| f.rules.len() == 3));
+        assert!(pb.flags.iter().all(|f| f.variants.len() == 4));
+
+        let state = ResolverState::from_proto(pb, StateGenerator::ACCOUNT).unwrap();
+        assert_eq!(state.secrets.len(), 3);
+        // full bitsets are shipped without bits
+        assert!(state.bitsets.is_empty());
+        assert_eq!(state.referenced_segments().len(), 4);
+
+        let resolved = resolve_all(&state, "unit");
+        // archived flags are not resolved
+        assert_eq!(resolved.len(), 16);
+        assert!(resolved
+            .iter()
+            .all(|(_, reason)| *reason == ResolveReason::Match as i32));
+    }
+
+    #[test]
+    fn bitset_density_controls_matches() {
+        let empty = StateGenerator {
+            bitset_density: 0.0,
+            ..Default::default()
+        };
+        assert!(resolve_all(&load(&empty), "unit")
+            .iter()
+            .all(|(_, reason)| *reason == ResolveReason::NoSegmentMatch as i32));
+
+        let half = load(&StateGenerator {
+            flags: 1,
+            segments: 1,
+            bitset_density: 0.5,
+            ..Default::default()
+        });
+        let matched = (0..200)
+            .filter(|i| {
+                resolve_all(&half, &format!("unit-{}", i))[0].1 == ResolveReason::Match as i32
+            })
+            .count();
+        assert!((60..140).contains(&matched), "{}", matched);
+    }
+
+    #[test]
+    fn output_only_depends_on_the_seed() {
+        let generator = StateGenerator {
+            bitset_density: 0.3,
+            ..Default::default()
+        };
+        assert_eq!(generator.build(), generator.build());
+        let other_seed = StateGenerator {
+            seed: 1,
+            ..generator.clone()
+        };
+        assert_ne!(generator.build(), other_seed.build());
+    }
+}
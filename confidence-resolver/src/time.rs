@@ -0,0 +1,304 @@
+//! Timestamp parsing and arithmetic. Arithmetic is done on `Timestamp`s directly. Parsing uses
+//! chrono with the `time` feature; without it a smaller parser handles the formats targeting
+//! and schema inference accept: dates like `2024-01-31` and date times like
+//! `2024-01-31T12:00:00.25`, the latter optionally with a `Z` or `+01:00` offset.
+
+use crate::proto::google::Timestamp;
+
+const NANOS_PER_SECOND: i128 = 1_000_000_000;
+const NANOS_PER_MILLI: i128 = 1_000_000;
+
+/// Nanoseconds since the epoch, `None` if the nanos of `ts` are out of range.
+pub(crate) fn to_nanos(ts: &Timestamp) -> Option<i128> {
+    if !(0..1_000_000_000).contains(&ts.nanos) {
+        return None;
+    }
+    i128::from(ts.seconds)
+        .checked_mul(NANOS_PER_SECOND)?
+        .checked_add(i128::from(ts.nanos))
+}
+
+pub(crate) fn from_nanos(nanos: i128) -> Option<Timestamp> {
+    Some(Timestamp {
+        seconds: i64::try_from(nanos.checked_div_euclid(NANOS_PER_SECOND)?).ok()?,
+        nanos: i32::try_from(nanos.checked_rem_euclid(NANOS_PER_SECOND)?).ok()?,
+    })
+}
+
+/// Whole milliseconds from `earlier` to `later`, rounded towards zero.
+pub(crate) fn millis_between(earlier: &Timestamp, later: &Timestamp) -> Option<i64> {
+    let nanos = to_nanos(later)?.checked_sub(to_nanos(earlier)?)?;
+    i64::try_from(nanos.checked_div(NANOS_PER_MILLI)?).ok()
+}
+
+/// Parses an RFC 3339 date time, e.g. `2024-01-31T12:00:00+01:00`.
+pub(crate) fn parse_rfc3339(s: &str) -> Option<Timestamp> {
+    imp::parse_rfc3339(s)
+}
+
+/// Parses a date time without an offset as UTC. Date and time are separated by `T` or a
+/// space, e.g. `2024-01-31T12:00:00`.
+pub(crate) fn parse_naive_date_time(s: &str) -> Option<Timestamp> {
+    imp::parse_naive_date_time(s)
+}
+
+/// Parses a date as midnight UTC, e.g. `2024-01-31`.
+pub(crate) fn parse_date(s: &str) -> Option<Timestamp> {
+    imp::parse_date(s)
+}
+
+#[cfg(feature = "time")]
+mod imp {
+    use super::Timestamp;
+    use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+    const NAIVE_FORMATS: [&str; 4] = [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M:%S%.f",
+    ];
+
+    fn to_timestamp(dt: DateTime<Utc>) -> Timestamp {
+        Timestamp {
+            seconds: dt.timestamp(),
+            nanos: dt.timestamp_subsec_nanos() as i32,
+        }
+    }
+
+    pub(super) fn parse_rfc3339(s: &str) -> Option<Timestamp> {
+        DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|dt| to_timestamp(dt.with_timezone(&Utc)))
+    }
+
+    pub(super) fn parse_naive_date_time(s: &str) -> Option<Timestamp> {
+        NAIVE_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+            .map(|ndt| to_timestamp(ndt.and_utc()))
+    }
+
+    pub(super) fn parse_date(s: &str) -> Option<Timestamp> {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .ok()
+            .and_then(|nd| nd.and_hms_opt(0, 0, 0))
+            .map(|ndt| to_timestamp(ndt.and_utc()))
+    }
+}
+
+#[cfg(not(feature = "time"))]
+use fallback as imp;
+
+#[cfg(any(test, not(feature = "time")))]
+mod fallback {
+    use super::Timestamp;
+
+    pub(super) fn parse_rfc3339(s: &str) -> Option<Timestamp> {
+        let (date_time, offset_seconds) = split_offset(s)?;
+        let (date, time) = date_time.split_once(['T', 't', ' '])?;
+        let (seconds, nanos) = time_seconds(time, Padding::Strict)?;
+        Some(Timestamp {
+            seconds: date_seconds(date, Padding::Strict)?
+                .checked_add(seconds)?
+                .checked_sub(offset_seconds)?,
+            nanos,
+        })
+    }
+
+    pub(super) fn parse_naive_date_time(s: &str) -> Option<Timestamp> {
+        let (date, time) = s.split_once(['T', ' '])?;
+        let (seconds, nanos) = time_seconds(time, Padding::Optional)?;
+        Some(Timestamp {
+            seconds: date_seconds(date, Padding::Optional)?.checked_add(seconds)?,
+            nanos,
+        })
+    }
+
+    pub(super) fn parse_date(s: &str) -> Option<Timestamp> {
+        Some(Timestamp {
+            seconds: date_seconds(s, Padding::Optional)?,
+            nanos: 0,
+        })
+    }
+
+    /// RFC 3339 requires two digit months, days, hours, minutes and seconds, while the
+    /// `%Y-%m-%d %H:%M:%S` formats also accept a single digit.
+    #[derive(Clone, Copy)]
+    enum Padding {
+        Strict,
+        Optional,
+    }
+
+    fn digits(s: &str, len: usize) -> Option<i64> {
+        if s.len() != len || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    }
+
+    fn two_digits(s: &str, padding: Padding) -> Option<i64> {
+        match (padding, s.len()) {
+            (Padding::Optional, 1) => digits(s, 1),
+            _ => digits(s, 2),
+        }
+    }
+
+    /// Seconds from the epoch to midnight of a `YYYY-MM-DD` date.
+    fn date_seconds(s: &str, padding: Padding) -> Option<i64> {
+        let mut parts = s.split('-');
+        let year = digits(parts.next()?, 4)?;
+        let month = two_digits(parts.next()?, padding)?;
+        let day = two_digits(parts.next()?, padding)?;
+        if parts.next().is_some() || !(1..=12).contains(&month) {
+            return None;
+        }
+        if day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        days_from_civil(year, month, day).checked_mul(86_400)
+    }
+
+    /// Seconds into the day and nanos of a `HH:MM:SS` time with optional fractional seconds.
+    fn time_seconds(s: &str, padding: Padding) -> Option<(i64, i32)> {
+        let (hms, fraction) = match s.split_once('.') {
+            Some((hms, fraction)) => (hms, Some(fraction)),
+            None => (s, None),
+        };
+        let mut parts = hms.split(':');
+        let hour = two_digits(parts.next()?, padding)?;
+        let minute = two_digits(parts.next()?, padding)?;
+        let second = two_digits(parts.next()?, padding)?;
+        if parts.next().is_some() || hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+        let nanos = match fraction {
+            Some(fraction) if (1..=9).contains(&fraction.len()) => {
+                let padded = format!("{:0<9}", fraction);
+                i32::try_from(digits(&padded, 9)?).ok()?
+            }
+            Some(_) => return None,
+            None => 0,
+        };
+        let seconds = hour
+            .checked_mul(3600)?
+            .checked_add(minute.checked_mul(60)?)?
+            .checked_add(second)?;
+        Some((seconds, nanos))
+    }
+
+    /// Splits a trailing `Z` or `+HH:MM` offset off `s`, returning the offset in seconds.
+    fn split_offset(s: &str) -> Option<(&str, i64)> {
+        if let Some(rest) = s.strip_suffix(['Z', 'z']) {
+            return Some((rest, 0));
+        }
+        let (rest, offset) = s.split_at_checked(s.len().checked_sub(6)?)?;
+        let sign = match offset.get(..1)? {
+            "+" => 1,
+            "-" => -1,
+            _ => return None,
+        };
+        let (hours, minutes) = offset.get(1..)?.split_once(':')?;
+        let (hours, minutes) = (digits(hours, 2)?, digits(minutes, 2)?);
+        if hours > 23 || minutes > 59 {
+            return None;
+        }
+        let seconds = hours
+            .checked_mul(3600)?
+            .checked_add(minutes.checked_mul(60)?)?;
+        Some((rest, seconds.checked_mul(sign)?))
+    }
+
+    fn days_in_month(year: i64, month: i64) -> i64 {
+        match month {
+            2 if year.rem_euclid(4) == 0
+                && (year.rem_euclid(100) != 0 || year.rem_euclid(400) == 0) =>
+            {
+                29
+            }
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    /// Days from 1970-01-01 to a proleptic Gregorian date, see
+    /// <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+    #[allow(clippy::arithmetic_side_effects)] // four digit years, months and days are checked
+    fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMESTAMPS: [&str; 11] = [
+        "2022-11-17T15:16:17Z",
+        "2022-11-17T15:16:17.118Z",
+        "2022-11-17t15:16:17.5z",
+        "2022-11-17T15:16:17+01:00",
+        "2022-11-17 15:16:17-02:30",
+        "1969-12-31T23:59:59.999999999Z",
+        "2024-02-29T00:00:00Z",
+        "2023-02-29T00:00:00Z",
+        "2022-11-17T24:00:00Z",
+        "2022-11-17T15:16Z",
+        "2022-1-17T15:16:17Z",
+    ];
+    const NAIVE: [&str; 7] = [
+        "2022-11-17T15:16:17",
+        "2022-11-17T15:16:17.118",
+        "2022-11-17 15:16:17",
+        "2022-11-17 15:16:17.000001",
+        "2022-11-17X15:16:17",
+        "2022-11-17T15:16:17.",
+        "2022-1-7T5:06:7",
+    ];
+    const DATES: [&str; 6] = [
+        "2022-11-17",
+        "1900-02-28",
+        "2000-02-29",
+        "1900-02-29",
+        "2022-13-01",
+        "2022-1-01",
+    ];
+
+    #[test]
+    fn fallback_parses_like_chrono() {
+        for s in TIMESTAMPS {
+            assert_eq!(fallback::parse_rfc3339(s), parse_rfc3339(s), "{}", s);
+        }
+        for s in NAIVE {
+            assert_eq!(
+                fallback::parse_naive_date_time(s),
+                parse_naive_date_time(s),
+                "{}",
+                s
+            );
+        }
+        for s in DATES {
+            assert_eq!(fallback::parse_date(s), parse_date(s), "{}", s);
+        }
+    }
+
+    #[test]
+    fn millis_round_towards_zero() {
+        let ts = |seconds, nanos| Timestamp { seconds, nanos };
+        assert_eq!(millis_between(&ts(10, 0), &ts(11, 500_000_000)), Some(1500));
+        assert_eq!(
+            millis_between(&ts(11, 234_500_000), &ts(10, 0)),
+            Some(-1234)
+        );
+        assert_eq!(millis_between(&ts(10, 1_000_000_000), &ts(10, 0)), None);
+        let nanos = to_nanos(&ts(-1, 250_000_000)).unwrap();
+        assert_eq!(nanos, -750_000_000);
+        assert_eq!(from_nanos(nanos), Some(ts(-1, 250_000_000)));
+    }
+}
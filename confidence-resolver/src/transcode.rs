@@ -0,0 +1,91 @@
+//! Conversion between JSON and protobuf for any message in the embedded descriptors,
+//! available with the `transcode` feature.
+//!
+//! Hosts that accept both `application/json` and `application/x-protobuf` can transcode
+//! request and response bodies by message name instead of relying on per-type serde
+//! implementations. JSON follows the canonical protobuf JSON mapping.
+
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+
+use crate::proto::FILE_DESCRIPTOR_SET;
+
+pub struct Transcoder {
+    pool: DescriptorPool,
+}
+
+impl Transcoder {
+    pub fn new() -> Result<Self, String> {
+        let pool = DescriptorPool::decode(FILE_DESCRIPTOR_SET)
+            .map_err(|e| format!("failed to load descriptors: {}", e))?;
+        Ok(Transcoder { pool })
+    }
+
+    /// Converts a JSON encoded `message`, e.g. `confidence.flags.resolver.v1.ResolveFlagsRequest`,
+    /// to its binary protobuf encoding. Unknown JSON fields are rejected.
+    pub fn json_to_proto(&self, message: &str, json: &[u8]) -> Result<Vec<u8>, String> {
+        let descriptor = self.descriptor(message)?;
+        let mut deserializer = serde_json::Deserializer::from_slice(json);
+        let dynamic = DynamicMessage::deserialize(descriptor, &mut deserializer)
+            .map_err(|e| format!("invalid {} json: {}", message, e))?;
+        deserializer
+            .end()
+            .map_err(|e| format!("invalid {} json: {}", message, e))?;
+        Ok(prost::Message::encode_to_vec(&dynamic))
+    }
+
+    /// Converts a binary protobuf encoded `message` to JSON.
+    pub fn proto_to_json(&self, message: &str, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let descriptor = self.descriptor(message)?;
+        let dynamic = DynamicMessage::decode(descriptor, bytes)
+            .map_err(|e| format!("invalid {} protobuf: {}", message, e))?;
+        serde_json::to_vec(&dynamic).map_err(|e| format!("failed to write {} json: {}", message, e))
+    }
+
+    fn descriptor(&self, message: &str) -> Result<MessageDescriptor, String> {
+        self.pool
+            .get_message_by_name(message)
+            .ok_or_else(|| format!("unknown message {}", message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::confidence::flags::resolver::v1::ResolveFlagsRequest;
+    use crate::proto::Message;
+
+    const REQUEST: &str = "confidence.flags.resolver.v1.ResolveFlagsRequest";
+
+    #[test]
+    fn json_roundtrip_matches_generated_types() {
+        let transcoder = Transcoder::new().unwrap();
+        let json = br#"{
+            "flags": ["flags/tutorial-feature"],
+            "clientSecret": "secret",
+            "apply": true,
+            "evaluationContext": {"visitor_id": "v1", "age": 42}
+        }"#;
+
+        let bytes = transcoder.json_to_proto(REQUEST, json).unwrap();
+        let decoded = ResolveFlagsRequest::decode(bytes.as_slice()).unwrap();
+        let expected: ResolveFlagsRequest = serde_json::from_slice(json).unwrap();
+        assert_eq!(decoded, expected);
+
+        let back = transcoder.proto_to_json(REQUEST, &bytes).unwrap();
+        let reparsed: ResolveFlagsRequest = serde_json::from_slice(&back).unwrap();
+        assert_eq!(reparsed, expected);
+    }
+
+    #[test]
+    fn errors() {
+        let transcoder = Transcoder::new().unwrap();
+        assert!(transcoder
+            .json_to_proto("confidence.NoSuchMessage", b"{}")
+            .unwrap_err()
+            .contains("unknown message"));
+        assert!(transcoder
+            .json_to_proto(REQUEST, br#"{"flags": 1}"#)
+            .is_err());
+        assert!(transcoder.proto_to_json(REQUEST, &[0xff]).is_err());
+    }
+}
@@ -1,14 +1,10 @@
-use chrono::DateTime;
-use chrono::LocalResult;
-use chrono::NaiveDate;
-use chrono::NaiveDateTime;
-use chrono::TimeZone;
-use chrono::Utc;
-
-use crate::err::ErrorCode;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
 use crate::err::Fallible;
 use crate::err::OrFailExt;
 use crate::proto::google::{value::Kind, Timestamp, Value};
+use crate::time;
 
 use crate::proto::confidence::flags::types::v1::targeting;
 use crate::proto::confidence::flags::types::v1::targeting::criterion;
@@ -72,9 +68,51 @@ pub fn convert_to_targeting_value(
     })
 }
 
+/// Parsed semantic versions by their string, see [`collect_versions`].
+pub type Versions = HashMap<String, semver::Version>;
+
+/// Parses the versions that bound the range rules of `attribute_criterion` into `versions`,
+/// so they aren't parsed again for every evaluation. Versions that don't parse are left out.
+pub fn collect_versions(
+    attribute_criterion: &criterion::AttributeCriterion,
+    versions: &mut Versions,
+) {
+    let range_rule = match &attribute_criterion.rule {
+        Some(criterion::attribute_criterion::Rule::RangeRule(range_rule)) => range_rule,
+        Some(criterion::attribute_criterion::Rule::AnyRule(targeting::AnyRule {
+            rule: Some(inner_rule),
+        }))
+        | Some(criterion::attribute_criterion::Rule::AllRule(targeting::AllRule {
+            rule: Some(inner_rule),
+        })) => match &inner_rule.rule {
+            Some(targeting::inner_rule::Rule::RangeRule(range_rule)) => range_rule,
+            _ => return,
+        },
+        _ => return,
+    };
+    let start = match &range_rule.start {
+        Some(targeting::range_rule::Start::StartInclusive(value))
+        | Some(targeting::range_rule::Start::StartExclusive(value)) => Some(value),
+        None => None,
+    };
+    let end = match &range_rule.end {
+        Some(targeting::range_rule::End::EndInclusive(value))
+        | Some(targeting::range_rule::End::EndExclusive(value)) => Some(value),
+        None => None,
+    };
+    for value in start.into_iter().chain(end) {
+        if let Some(targeting::value::Value::VersionValue(version)) = &value.value {
+            if let Ok(parsed) = semver::Version::parse(&version.version) {
+                versions.insert(version.version.clone(), parsed);
+            }
+        }
+    }
+}
+
 pub fn evaluate_criterion(
     attribute_criterion: &criterion::AttributeCriterion,
     wrapped: &targeting::ListValue,
+    versions: &Versions,
 ) -> bool {
     let Some(rule) = &attribute_criterion.rule else {
         return false;
@@ -89,17 +127,17 @@ pub fn evaluate_criterion(
         }
         criterion::attribute_criterion::Rule::RangeRule(range_rule) => context_values
             .iter()
-            .any(|v| evaluate_range_rule(range_rule, v)),
+            .any(|v| evaluate_range_rule(range_rule, v, versions)),
         criterion::attribute_criterion::Rule::AnyRule(targeting::AnyRule {
             rule: Some(inner_rule),
         }) => context_values
             .iter()
-            .any(|v| evaluate_inner_rule(inner_rule, v)),
+            .any(|v| evaluate_inner_rule(inner_rule, v, versions)),
         criterion::attribute_criterion::Rule::AllRule(targeting::AllRule {
             rule: Some(inner_rule),
         }) => context_values
             .iter()
-            .all(|v| evaluate_inner_rule(inner_rule, v)),
+            .all(|v| evaluate_inner_rule(inner_rule, v, versions)),
         _ => false,
     }
 }
@@ -107,6 +145,7 @@ pub fn evaluate_criterion(
 fn evaluate_inner_rule(
     inner_rule: &targeting::InnerRule,
     context_value: &targeting::Value,
+    versions: &Versions,
 ) -> bool {
     let Some(rule) = &inner_rule.rule else {
         return false;
@@ -119,7 +158,7 @@ fn evaluate_inner_rule(
             values.contains(context_value)
         }
         targeting::inner_rule::Rule::RangeRule(range_rule) => {
-            evaluate_range_rule(range_rule, context_value)
+            evaluate_range_rule(range_rule, context_value, versions)
         }
         _ => false,
     }
@@ -128,23 +167,24 @@ fn evaluate_inner_rule(
 fn evaluate_range_rule(
     range_rule: &targeting::RangeRule,
     context_value: &targeting::Value,
+    versions: &Versions,
 ) -> bool {
     let after_start = match &range_rule.start {
         Some(targeting::range_rule::Start::StartInclusive(start_inclusive)) => {
-            start_inclusive.lte(context_value)
+            start_inclusive.lte(context_value, versions)
         }
         Some(targeting::range_rule::Start::StartExclusive(start_exclusive)) => {
-            start_exclusive.lt(context_value)
+            start_exclusive.lt(context_value, versions)
         }
         _ => false,
     };
 
     let before_end = match &range_rule.end {
         Some(targeting::range_rule::End::EndInclusive(end_inclusive)) => {
-            context_value.lte(end_inclusive)
+            context_value.lte(end_inclusive, versions)
         }
         Some(targeting::range_rule::End::EndExclusive(end_exclusive)) => {
-            context_value.lt(end_exclusive)
+            context_value.lt(end_exclusive, versions)
         }
         _ => false,
     };
@@ -153,12 +193,12 @@ fn evaluate_range_rule(
 }
 
 trait Ord {
-    fn lt(&self, other: &Self) -> bool;
-    fn lte(&self, other: &Self) -> bool;
+    fn lt(&self, other: &Self, versions: &Versions) -> bool;
+    fn lte(&self, other: &Self, versions: &Versions) -> bool;
 }
 
 impl Ord for targeting::Value {
-    fn lt(&self, other: &Self) -> bool {
+    fn lt(&self, other: &Self, versions: &Versions) -> bool {
         let Some(a) = &self.value else { return false };
         let Some(b) = &other.value else { return false };
         match (a, b) {
@@ -171,16 +211,16 @@ impl Ord for targeting::Value {
             (
                 targeting::value::Value::TimestampValue(a),
                 targeting::value::Value::TimestampValue(b),
-            ) => a.lt(b),
+            ) => a.lt(b, versions),
             (
                 targeting::value::Value::VersionValue(a),
                 targeting::value::Value::VersionValue(b),
-            ) => a.lt(b),
+            ) => a.lt(b, versions),
             _ => false,
         }
     }
 
-    fn lte(&self, other: &Self) -> bool {
+    fn lte(&self, other: &Self, versions: &Versions) -> bool {
         let Some(a) = &self.value else { return false };
         let Some(b) = &other.value else { return false };
         match (a, b) {
@@ -193,18 +233,18 @@ impl Ord for targeting::Value {
             (
                 targeting::value::Value::TimestampValue(a),
                 targeting::value::Value::TimestampValue(b),
-            ) => a.lte(b),
+            ) => a.lte(b, versions),
             (
                 targeting::value::Value::VersionValue(a),
                 targeting::value::Value::VersionValue(b),
-            ) => a.lte(b),
+            ) => a.lte(b, versions),
             _ => false,
         }
     }
 }
 
 impl Ord for Timestamp {
-    fn lt(&self, other: &Self) -> bool {
+    fn lt(&self, other: &Self, _versions: &Versions) -> bool {
         if self.seconds < other.seconds {
             true
         } else if self.seconds == other.seconds {
@@ -214,7 +254,7 @@ impl Ord for Timestamp {
         }
     }
 
-    fn lte(&self, other: &Self) -> bool {
+    fn lte(&self, other: &Self, _versions: &Versions) -> bool {
         if self.seconds < other.seconds {
             true
         } else if self.seconds == other.seconds {
@@ -228,18 +268,23 @@ impl Ord for Timestamp {
 const ZERO_VERSION: semver::Version = semver::Version::new(0, 0, 0);
 
 impl Ord for targeting::SemanticVersion {
-    fn lt(&self, other: &Self) -> bool {
-        // this use of ZERO_VERSION is questionable
-        let a = semver::Version::parse(&self.version).unwrap_or(ZERO_VERSION);
-        let b = semver::Version::parse(&other.version).unwrap_or(ZERO_VERSION);
-        a < b
+    fn lt(&self, other: &Self, versions: &Versions) -> bool {
+        parse_version(self, versions) < parse_version(other, versions)
+    }
+
+    fn lte(&self, other: &Self, versions: &Versions) -> bool {
+        parse_version(self, versions) <= parse_version(other, versions)
     }
+}
 
-    fn lte(&self, other: &Self) -> bool {
+fn parse_version<'a>(
+    version: &targeting::SemanticVersion,
+    versions: &'a Versions,
+) -> Cow<'a, semver::Version> {
+    match versions.get(&version.version) {
+        Some(parsed) => Cow::Borrowed(parsed),
         // this use of ZERO_VERSION is questionable
-        let a = semver::Version::parse(&self.version).unwrap_or(ZERO_VERSION);
-        let b = semver::Version::parse(&other.version).unwrap_or(ZERO_VERSION);
-        a <= b
+        None => Cow::Owned(semver::Version::parse(&version.version).unwrap_or(ZERO_VERSION)),
     }
 }
 
@@ -249,40 +294,12 @@ fn from_str(s: &str) -> Fallible<Timestamp> {
         // split at position of T or space
         let time_part = s.split(['T', ' ']).nth(1).or_fail()?;
         if time_part.contains(['Z', '+', '-']) {
-            DateTime::parse_from_rfc3339(s)
-                .or_fail()
-                .map(|dt| dt.with_timezone(&Utc))
-                .map(|dt| Timestamp {
-                    seconds: dt.timestamp(),
-                    nanos: dt.timestamp_subsec_nanos() as i32,
-                })
+            time::parse_rfc3339(s).or_fail()
         } else {
-            NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
-                .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f"))
-                .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S"))
-                .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f"))
-                .or_fail()
-                .and_then(|ndt| match Utc.from_local_datetime(&ndt) {
-                    LocalResult::Single(dt) => Ok(dt),
-                    _ => Err(ErrorCode::from_location()),
-                })
-                .map(|dt| Timestamp {
-                    seconds: dt.timestamp(),
-                    nanos: dt.timestamp_subsec_nanos() as i32,
-                })
+            time::parse_naive_date_time(s).or_fail()
         }
     } else {
-        NaiveDate::parse_from_str(s, "%Y-%m-%d")
-            .or_fail()
-            .map(|nd| unsafe { nd.and_hms_opt(0, 0, 0).unwrap_unchecked() })
-            .and_then(|ndt| match Utc.from_local_datetime(&ndt) {
-                chrono::LocalResult::Single(dt) => Ok(dt),
-                _ => Err(ErrorCode::from_location()),
-            })
-            .map(|dt| Timestamp {
-                seconds: dt.timestamp(),
-                nanos: dt.timestamp_subsec_nanos() as i32,
-            })
+        time::parse_date(s).or_fail()
     }
 }
 
@@ -369,6 +386,44 @@ mod tests {
     use super::*;
     use chrono::FixedOffset;
 
+    #[test]
+    fn collects_range_rule_versions() {
+        let version = |v: &str| targeting::Value {
+            value: Some(targeting::value::Value::VersionValue(
+                targeting::SemanticVersion {
+                    version: v.to_string(),
+                },
+            )),
+        };
+        let criterion = criterion::AttributeCriterion {
+            attribute_name: "client.version".to_string(),
+            rule: Some(criterion::attribute_criterion::Rule::RangeRule(
+                targeting::RangeRule {
+                    start: Some(targeting::range_rule::Start::StartInclusive(version(
+                        "not-a-version",
+                    ))),
+                    end: Some(targeting::range_rule::End::EndExclusive(version("2.0.0"))),
+                },
+            )),
+        };
+        let mut versions = Versions::new();
+        collect_versions(&criterion, &mut versions);
+        assert_eq!(
+            versions.keys().collect::<Vec<_>>(),
+            vec!["2.0.0"],
+            "unparseable versions are left out"
+        );
+
+        let context = targeting::ListValue {
+            values: vec![version("1.4.2")],
+        };
+        assert!(evaluate_criterion(&criterion, &context, &versions));
+        assert_eq!(
+            evaluate_criterion(&criterion, &context, &versions),
+            evaluate_criterion(&criterion, &context, &Versions::new())
+        );
+    }
+
     #[cfg(test)]
     macro_rules! bool_type {
         () => {
@@ -1,16 +1,17 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::sync::LazyLock;
 
 use arc_swap::ArcSwapOption;
-use bytes::Bytes;
-use confidence_resolver::assign_logger::AssignLogger;
 use prost::Message;
 
+use confidence_resolver::flag_logs::FlagLogs;
 use confidence_resolver::proto::confidence::flags::resolver::v1::{
-    LogMessage, ResolveWithStickyRequest, WriteFlagLogsRequest,
+    LogMessage, ResolveWithStickyRequest, ResolverSettings, WriteFlagLogsRequest,
 };
-use confidence_resolver::resolve_logger::ResolveLogger;
+use confidence_resolver::recent_resolves::RecentResolves;
+use confidence_resolver::state_builder::ResolverStateBuilder;
 use rand::distr::Alphanumeric;
 use rand::distr::SampleString;
 use rand::rngs::SmallRng;
@@ -23,8 +24,14 @@ use wasm_msg::WasmResult;
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/rust_guest.rs"));
 }
-use crate::proto::SetResolverStateRequest;
+use crate::proto::{
+    DiffSegmentsRequest, ExplainFlagRequest, GetRecentResolveRequest, ResolveFinishRequest,
+    ResolveSession, ResolveStepRequest, ResolveStepResponse, SegmentPopulationRequest,
+    SetDeterministicModeRequest, SetResolverStateRequest,
+};
 use confidence_resolver::{
+    drift,
+    explain::FlagExplanation,
     proto::{
         confidence::flags::admin::v1::ResolverState as ResolverStatePb,
         confidence::flags::resolver::v1::{
@@ -32,7 +39,8 @@ use confidence_resolver::{
         },
         google::{Struct, Timestamp},
     },
-    Client, FlagToApply, Host, ResolveReason, ResolvedValue, ResolverState,
+    AccountResolver, Client, EncryptionKey, FlagToApply, Host, ResolveProgress, ResolveReason,
+    ResolvedValue, ResolverState,
 };
 use proto::Void;
 
@@ -54,18 +62,55 @@ impl
 
 const LOG_TARGET_BYTES: usize = 4 * 1024 * 1024; // 4 mb
 const VOID: Void = Void {};
-const ENCRYPTION_KEY: Bytes = Bytes::from_static(&[0; 16]);
+// sessions beyond this are dropped, oldest first, in case hosts abandon them
+const MAX_RESOLVE_SESSIONS: usize = 16;
+// resolves that can be looked up by resolve id, and for how long
+const RECENT_RESOLVES: usize = 1024;
+const RECENT_RESOLVE_TTL_SECONDS: i64 = 300;
 
 // TODO simplify by assuming single threaded?
 static RESOLVER_STATE: ArcSwapOption<ResolverState> = ArcSwapOption::const_empty();
-static RESOLVE_LOGGER: LazyLock<ResolveLogger<WasmHost>> = LazyLock::new(ResolveLogger::new);
-static ASSIGN_LOGGER: LazyLock<AssignLogger> = LazyLock::new(AssignLogger::new);
+static FLAG_LOGS: LazyLock<FlagLogs<WasmHost>> = LazyLock::new(FlagLogs::new);
+static RECENT: LazyLock<RecentResolves<WasmHost>> =
+    LazyLock::new(|| RecentResolves::new(RECENT_RESOLVES, RECENT_RESOLVE_TTL_SECONDS));
+
+/// A stepped resolve, pinned to the state it was started with.
+struct ResolveSessionState {
+    resolver: SessionResolver,
+    progress: ResolveProgress,
+}
+
+struct SessionResolver {
+    state: Arc<ResolverState>,
+    client_secret: String,
+    evaluation_context: Struct,
+}
+
+impl SessionResolver {
+    fn resolver(&self) -> Result<AccountResolver<'_, WasmHost>, String> {
+        Ok(self.state.get_resolver::<WasmHost>(
+            &self.client_secret,
+            self.evaluation_context.clone(),
+            &encryption_key(),
+        )?)
+    }
+}
 
 thread_local! {
+    static RESOLVE_SESSIONS: RefCell<(u64, BTreeMap<u64, ResolveSessionState>)> =
+        const { RefCell::new((0, BTreeMap::new())) };
     static RNG: RefCell<SmallRng> = RefCell::new({
         let t = WasmHost::current_time();
         SmallRng::seed_from_u64((t.seconds as u64) ^ (t.nanos as u64))
     });
+    // the time of every resolve in deterministic mode, see set_deterministic_mode
+    static FIXED_TIME: RefCell<Option<Timestamp>> = const { RefCell::new(None) };
+    // only set with the encryption feature, tokens are plaintext while it is zero
+    static ENCRYPTION_KEY: RefCell<EncryptionKey> = const { RefCell::new(EncryptionKey::ZERO) };
+}
+
+fn encryption_key() -> EncryptionKey {
+    ENCRYPTION_KEY.with_borrow(|key| key.clone())
 }
 
 impl<'a> From<&ResolvedValue<'a>> for proto::ResolvedValue {
@@ -104,12 +149,46 @@ impl<'a> From<&ResolvedValue<'a>> for proto::ResolvedValue {
     }
 }
 
+impl From<FlagExplanation> for proto::FlagExplanation {
+    fn from(val: FlagExplanation) -> Self {
+        proto::FlagExplanation {
+            flag: val.flag,
+            reason: convert_reason(val.reason),
+            rules: val
+                .rules
+                .into_iter()
+                .map(|rule| proto::flag_explanation::Rule {
+                    rule: rule.rule,
+                    outcome: format!("{:?}", rule.outcome),
+                    targeting_key: rule.targeting_key,
+                    segment: rule
+                        .segment
+                        .map(|segment| proto::flag_explanation::Segment {
+                            segment: segment.segment,
+                            targeting_matched: segment.targeting_matched,
+                            criteria: segment.criteria.into_iter().collect(),
+                            bitset_bucket: segment.bitset.map(|bitset| bitset.bucket as u64),
+                            bitset_member: segment.bitset.is_some_and(|bitset| bitset.member),
+                            external_member: segment.external_member,
+                        }),
+                    bucket: rule.bucket,
+                    assignment_id: rule.assignment_id,
+                    variant: rule.variant,
+                })
+                .collect(),
+        }
+    }
+}
+
 fn convert_reason(reason: ResolveReason) -> i32 {
     match reason {
         ResolveReason::Match => i32::from(proto::ResolveReason::Match),
         ResolveReason::NoSegmentMatch => i32::from(proto::ResolveReason::NoSegmentMatch),
+        ResolveReason::NoTreatmentMatch => i32::from(proto::ResolveReason::NoTreatmentMatch),
         ResolveReason::FlagArchived => i32::from(proto::ResolveReason::FlagArchived),
         ResolveReason::TargetingKeyError => i32::from(proto::ResolveReason::TargetingKeyError),
+        ResolveReason::Error => i32::from(proto::ResolveReason::Error),
+        ResolveReason::FlagKilled => i32::from(proto::ResolveReason::FlagKilled),
     }
 }
 
@@ -120,6 +199,22 @@ impl Host for WasmHost {
         RNG.with_borrow_mut(|rng| Alphanumeric.sample_string(rng, len))
     }
 
+    // RNG is seeded from the clock, which is fine for resolve ids but not for nonces
+    #[cfg(feature = "encryption")]
+    fn random_bytes(buf: &mut [u8]) -> Result<(), String> {
+        encryption::fill_random(buf)
+    }
+    #[cfg(not(feature = "encryption"))]
+    fn random_bytes(_buf: &mut [u8]) -> Result<(), String> {
+        Err("the guest is built without the encryption feature".to_string())
+    }
+
+    #[cfg(feature = "encryption")]
+    fn encryption_provider(
+    ) -> Option<&'static dyn confidence_resolver::encryption::EncryptionProvider> {
+        Some(&encryption::AES_GCM)
+    }
+
     fn log(message: &str) {
         log_message(LogMessage {
             message: message.to_string(),
@@ -128,7 +223,9 @@ impl Host for WasmHost {
     }
 
     fn current_time() -> Timestamp {
-        current_time(Void {}).unwrap()
+        FIXED_TIME
+            .with_borrow(|time| time.clone())
+            .unwrap_or_else(|| current_time(Void {}).unwrap())
     }
 
     fn log_resolve(
@@ -138,7 +235,7 @@ impl Host for WasmHost {
         client: &Client,
         _sdk: &Option<Sdk>,
     ) {
-        RESOLVE_LOGGER.log_resolve(
+        FLAG_LOGS.resolve_logger().log_resolve(
             resolve_id,
             evaluation_context,
             &client.client_credential_name,
@@ -146,6 +243,7 @@ impl Host for WasmHost {
             client,
             _sdk,
         );
+        RECENT.record(resolve_id, values, client);
     }
 
     fn log_assign(
@@ -155,14 +253,26 @@ impl Host for WasmHost {
         client: &Client,
         sdk: &Option<Sdk>,
     ) {
-        ASSIGN_LOGGER.log_assigns(resolve_id, evaluation_context, assigned_flags, client, sdk);
+        FLAG_LOGS.assign_logger().log_assigns(
+            resolve_id,
+            evaluation_context,
+            assigned_flags,
+            client,
+            sdk,
+        );
     }
 
-    fn encrypt_resolve_token(token_data: &[u8], _encryption_key: &[u8]) -> Result<Vec<u8>, String> {
+    fn encrypt_resolve_token(
+        token_data: &[u8],
+        _encryption_key: &EncryptionKey,
+    ) -> Result<Vec<u8>, String> {
         Ok(token_data.to_vec())
     }
 
-    fn decrypt_resolve_token(token_data: &[u8], _encryption_key: &[u8]) -> Result<Vec<u8>, String> {
+    fn decrypt_resolve_token(
+        token_data: &[u8],
+        _encryption_key: &EncryptionKey,
+    ) -> Result<Vec<u8>, String> {
         Ok(token_data.to_vec())
     }
 }
@@ -178,11 +288,43 @@ fn get_resolver_state() -> Result<Arc<ResolverState>, String> {
         .ok_or_else(|| "Resolver state not set".to_string())
 }
 
+/// `logs` with the rules that drifted in them reported in their telemetry, see
+/// [`drift::report_drift`].
+fn with_drift(mut logs: WriteFlagLogsRequest) -> WriteFlagLogsRequest {
+    if let Ok(state) = get_resolver_state() {
+        drift::report_drift(&state, &mut logs);
+    }
+    logs
+}
+
+fn take_session(session_id: u64) -> Result<ResolveSessionState, String> {
+    RESOLVE_SESSIONS
+        .with_borrow_mut(|(_, sessions)| sessions.remove(&session_id))
+        .ok_or_else(|| format!("unknown resolve session {}", session_id))
+}
+
 wasm_msg_guest! {
     fn set_resolver_state(request: SetResolverStateRequest) -> WasmResult<Void> {
         let state_pb = ResolverStatePb::decode(request.state.as_slice())
             .map_err(|e| format!("Failed to decode resolver state: {}", e))?;
-        let new_state = ResolverState::from_proto(state_pb, request.account_id.as_str())?;
+        for warning in confidence_resolver::compat::check(&state_pb).warnings() {
+            WasmHost::log(&warning);
+        }
+        let settings = ResolverSettings::decode(request.settings.as_slice())
+            .map_err(|e| format!("Failed to decode resolver settings: {}", e))?;
+        // share what is unchanged with the state being replaced
+        let previous = RESOLVER_STATE.load_full();
+        let mut builder = ResolverStateBuilder::new(request.account_id.as_str())
+            .fingerprint(&request.state)
+            .options(confidence_resolver::LoadOptions {
+                config: (&settings).into(),
+                ..Default::default()
+            });
+        if let Some(previous) = &previous {
+            builder = builder.reusing(previous);
+        }
+        let (new_state, report) = builder.build(state_pb)?;
+        confidence_resolver::StateDuplicate::report::<WasmHost>(&report.duplicates);
         RESOLVER_STATE.store(Some(Arc::new(new_state)));
         Ok(VOID)
     }
@@ -191,32 +333,167 @@ wasm_msg_guest! {
         let resolver_state = get_resolver_state()?;
         let resolve_request = &request.resolve_request.clone().unwrap();
         let evaluation_context = resolve_request.evaluation_context.clone().unwrap();
-        let resolver = resolver_state.get_resolver::<WasmHost>(resolve_request.client_secret.as_str(), evaluation_context, &ENCRYPTION_KEY)?;
+        let resolver = resolver_state.get_resolver::<WasmHost>(resolve_request.client_secret.as_str(), evaluation_context, &encryption_key())?;
+        let _scope = FLAG_LOGS.log_scope();
+        #[cfg(feature = "materialization-callbacks")]
+        return materialization_callbacks::resolve_with_sticky(&resolver, request);
+        #[cfg(not(feature = "materialization-callbacks"))]
         resolver.resolve_flags_sticky(&request)
     }
 
+    // Stepped resolve: resolve_begin, then resolve_step until done, then resolve_finish,
+    // letting the host yield to its event loop between steps. A failed step ends the session.
+    fn resolve_begin(request: ResolveWithStickyRequest) -> WasmResult<ResolveSession> {
+        let state = get_resolver_state()?;
+        let resolve_request = request.resolve_request.clone().ok_or("missing resolve request")?;
+        let evaluation_context = resolve_request.evaluation_context.unwrap_or_default();
+        let progress = state
+            .get_resolver::<WasmHost>(&resolve_request.client_secret, evaluation_context.clone(), &encryption_key())?
+            .resolve_begin(request)?;
+        let flag_count = u32::try_from(progress.remaining()).unwrap_or(u32::MAX);
+        let session = ResolveSessionState {
+            resolver: SessionResolver {
+                state,
+                client_secret: resolve_request.client_secret,
+                evaluation_context,
+            },
+            progress,
+        };
+        let session_id = RESOLVE_SESSIONS.with_borrow_mut(|(next_id, sessions)| {
+            while sessions.len() >= MAX_RESOLVE_SESSIONS {
+                sessions.pop_first();
+            }
+            *next_id += 1;
+            sessions.insert(*next_id, session);
+            *next_id
+        });
+        Ok(ResolveSession { session_id, flag_count })
+    }
+
+    fn resolve_step(request: ResolveStepRequest) -> WasmResult<ResolveStepResponse> {
+        let mut session = take_session(request.session_id)?;
+        let done = session
+            .resolver
+            .resolver()?
+            .resolve_step(&mut session.progress, request.max_flags as usize)?;
+        let remaining = u32::try_from(session.progress.remaining()).unwrap_or(u32::MAX);
+        RESOLVE_SESSIONS.with_borrow_mut(|(_, sessions)| sessions.insert(request.session_id, session));
+        Ok(ResolveStepResponse { done, remaining })
+    }
+
+    fn resolve_finish(request: ResolveFinishRequest) -> WasmResult<ResolveWithStickyResponse> {
+        let session = take_session(request.session_id)?;
+        let _scope = FLAG_LOGS.log_scope();
+        session.resolver.resolver()?.resolve_finish(session.progress)
+    }
+
     fn resolve(request: ResolveFlagsRequest) -> WasmResult<ResolveFlagsResponse> {
-        let resolver_state = get_resolver_state()?;
-        let evaluation_context = request.evaluation_context.as_ref().cloned().unwrap_or_default();
-        let resolver = resolver_state.get_resolver::<WasmHost>(&request.client_secret, evaluation_context, &ENCRYPTION_KEY)?;
-        resolver.resolve_flags(&request)
+        let _scope = FLAG_LOGS.log_scope();
+        get_resolver_state()?.resolve_flags::<WasmHost>(&request, &encryption_key())
+    }
+
+    fn memory_stats(_request: Void) -> WasmResult<proto::MemoryStats> {
+        let stats = get_resolver_state()?.memory_stats();
+        Ok(proto::MemoryStats {
+            flags: stats.flags as u64,
+            segments: stats.segments as u64,
+            bitsets: stats.bitsets as u64,
+            secrets: stats.secrets as u64,
+            total: stats.total() as u64,
+        })
+    }
+
+    fn build_info(_request: Void) -> WasmResult<proto::BuildInfo> {
+        let info = confidence_resolver::build_info();
+        Ok(proto::BuildInfo {
+            version: info.version.to_string(),
+            proto_checksum: info.proto_checksum,
+            token_versions: info.token_versions.iter().map(|&v| u32::from(v)).collect(),
+            features: info.features.iter().map(|f| f.to_string()).collect(),
+            guest_version: env!("CARGO_PKG_VERSION").to_string(),
+        })
+    }
+
+    fn log_backlog(_request: Void) -> WasmResult<proto::LogBacklog> {
+        let resolve = FLAG_LOGS.resolve_logger().backlog();
+        let assign = FLAG_LOGS.assign_logger().backlog();
+        Ok(proto::LogBacklog {
+            resolve_events: resolve.events as u64,
+            resolve_bytes: resolve.bytes as u64,
+            assign_events: assign.events as u64,
+            assign_bytes: assign.bytes as u64,
+        })
+    }
+
+    fn segment_population(request: SegmentPopulationRequest) -> WasmResult<proto::SegmentPopulation> {
+        let population = get_resolver_state()?
+            .segment_population(&request.segment, request.sample_size as usize)?;
+        Ok(proto::SegmentPopulation {
+            population: population.population as u64,
+            bucket_count: population.bucket_count as u64,
+            sample: population.sample.into_iter().map(|bucket| bucket as u64).collect(),
+        })
+    }
+
+    fn explain_flag(request: ExplainFlagRequest) -> WasmResult<proto::FlagExplanation> {
+        let evaluation_context = request.evaluation_context.unwrap_or_default();
+        let explanation = get_resolver_state()?
+            .get_resolver::<WasmHost>(&request.client_secret, evaluation_context, &encryption_key())?
+            .explain_flag(&request.flag)?;
+        Ok(explanation.into())
+    }
+
+    fn set_deterministic_mode(request: SetDeterministicModeRequest) -> WasmResult<Void> {
+        RNG.with_borrow_mut(|rng| *rng = SmallRng::seed_from_u64(request.seed));
+        FIXED_TIME.with_borrow_mut(|time| *time = request.time);
+        Ok(VOID)
+    }
+
+    fn get_recent_resolve(request: GetRecentResolveRequest) -> WasmResult<proto::RecentResolve> {
+        let summary = RECENT
+            .get(&request.resolve_id)
+            .ok_or_else(|| format!("no recent resolve {}", request.resolve_id))?;
+        Ok(proto::RecentResolve {
+            resolve_id: summary.resolve_id,
+            client_credential: summary.client_credential,
+            time_seconds: summary.time_seconds,
+            flags: summary
+                .flags
+                .into_iter()
+                .map(|(flag, outcome)| proto::recent_resolve::Flag {
+                    flag,
+                    reason: outcome.reason as i32,
+                    variant: outcome.variant,
+                })
+                .collect(),
+        })
+    }
+
+    fn diff_segments(request: DiffSegmentsRequest) -> WasmResult<proto::SegmentDiff> {
+        let from_pb = ResolverStatePb::decode(request.from_state.as_slice())
+            .map_err(|e| format!("Failed to decode resolver state: {}", e))?;
+        let from = ResolverState::from_proto(from_pb, &request.account_id)?;
+        let diff = ResolverState::diff_segments(&from, &get_resolver_state()?, &request.segment)?;
+        Ok(proto::SegmentDiff {
+            added: diff.added as u64,
+            removed: diff.removed as u64,
+        })
     }
 
     // deprecated
     fn flush_logs(_request:Void) -> WasmResult<WriteFlagLogsRequest> {
-        let mut req = RESOLVE_LOGGER.checkpoint();
-        ASSIGN_LOGGER.checkpoint_fill(&mut req);
-        Ok(req)
+        Ok(with_drift(FLAG_LOGS.checkpoint()))
     }
 
     fn bounded_flush_logs(_request:Void) -> WasmResult<WriteFlagLogsRequest> {
-        let mut req = RESOLVE_LOGGER.checkpoint();
-        ASSIGN_LOGGER.checkpoint_fill_with_limit(&mut req, LOG_TARGET_BYTES, false);
-        Ok(req)
+        let (req, _) = FLAG_LOGS.checkpoint_with_limit(LOG_TARGET_BYTES);
+        Ok(with_drift(req))
     }
 
     fn bounded_flush_assign(_request:Void) -> WasmResult<WriteFlagLogsRequest> {
-        Ok(ASSIGN_LOGGER.checkpoint_with_limit(LOG_TARGET_BYTES, true))
+        Ok(FLAG_LOGS
+            .assign_logger()
+            .checkpoint_with_limit(LOG_TARGET_BYTES, true))
     }
 
 
@@ -228,3 +505,89 @@ wasm_msg_host! {
     fn log_message(message: LogMessage) -> WasmResult<Void>;
     fn current_time(request: Void) -> WasmResult<Timestamp>;
 }
+
+/// Encrypted resolve tokens. Once the host sets a key with `set_encryption_key`, tokens are
+/// encrypted with AES-GCM, with nonces from the host's `random_bytes`.
+#[cfg(feature = "encryption")]
+mod encryption {
+    use confidence_resolver::encryption::AesGcm;
+    use confidence_resolver::EncryptionKey;
+    use wasm_msg::{wasm_msg_guest, wasm_msg_host, WasmResult};
+
+    use super::proto::{RandomBytes, RandomBytesRequest, SetEncryptionKeyRequest};
+    use super::{Void, ENCRYPTION_KEY, VOID};
+
+    pub static AES_GCM: AesGcm = AesGcm;
+
+    wasm_msg_host! {
+        fn random_bytes(request: RandomBytesRequest) -> WasmResult<RandomBytes>;
+    }
+
+    wasm_msg_guest! {
+        fn set_encryption_key(request: SetEncryptionKeyRequest) -> WasmResult<Void> {
+            let key = EncryptionKey::try_from(request.key.as_slice())?;
+            ENCRYPTION_KEY.with_borrow_mut(|current| *current = key);
+            Ok(VOID)
+        }
+    }
+
+    pub fn fill_random(buf: &mut [u8]) -> Result<(), String> {
+        let len = u32::try_from(buf.len()).map_err(|e| e.to_string())?;
+        let random = random_bytes(RandomBytesRequest { len })?.bytes;
+        if random.len() != buf.len() {
+            return Err(format!(
+                "host returned {} random bytes instead of {}",
+                random.len(),
+                buf.len()
+            ));
+        }
+        buf.copy_from_slice(&random);
+        Ok(())
+    }
+}
+
+/// Sticky resolves backed by host storage. Instead of returning missing materializations to
+/// the caller, the guest asks the host for them with `read_materializations` and resolves
+/// again; the updates of a successful resolve are handed to `write_materializations`, which
+/// keeps the variants the host already stored like `materialization::write_updates`. The
+/// host is only asked when the request has `fail_fast_on_sticky` unset, since a fail-fast
+/// response doesn't list what is missing.
+#[cfg(feature = "materialization-callbacks")]
+mod materialization_callbacks {
+    use confidence_resolver::materialization;
+    use confidence_resolver::proto::confidence::flags::resolver::v1::{
+        ReadMaterializationsRequest, ReadMaterializationsResponse, ResolveWithStickyRequest,
+        ResolveWithStickyResponse, WriteMaterializationsRequest,
+    };
+    use confidence_resolver::AccountResolver;
+    use wasm_msg::wasm_msg_host;
+    use wasm_msg::WasmResult;
+
+    use super::{Void, WasmHost};
+
+    wasm_msg_host! {
+        fn read_materializations(request: ReadMaterializationsRequest) -> WasmResult<ReadMaterializationsResponse>;
+        fn write_materializations(request: WriteMaterializationsRequest) -> WasmResult<Void>;
+    }
+
+    pub fn resolve_with_sticky(
+        resolver: &AccountResolver<'_, WasmHost>,
+        request: ResolveWithStickyRequest,
+    ) -> WasmResult<ResolveWithStickyResponse> {
+        let mut response = resolver.resolve_flags_sticky(&request)?;
+        if let Some(items) = materialization::missing_items(&response) {
+            let read = read_materializations(ReadMaterializationsRequest {
+                items: items.to_vec(),
+            })?
+            .materializations_per_unit;
+            let request = materialization::with_read_materializations(&request, items, read)?;
+            response = resolver.resolve_flags_sticky(&request)?;
+        }
+        if let Some(updates) = materialization::written_updates(&response) {
+            write_materializations(WriteMaterializationsRequest {
+                updates: updates.to_vec(),
+            })?;
+        }
+        Ok(response)
+    }
+}